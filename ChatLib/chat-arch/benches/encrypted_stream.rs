@@ -0,0 +1,124 @@
+//! Throughput of `EncryptedStream` over an in-memory pipe.
+
+use chat_arch::config::StreamOptions;
+use chat_arch::conn::EncryptedStream;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+
+const KEY: [u8; 32] = [7; 32];
+const TRANSFER_SIZE: usize = 8 * 1024 * 1024;
+const WRITE_SIZE: usize = 16 * 1024;
+
+/// Sends `TRANSFER_SIZE` bytes from one encrypted end to the other.
+async fn transfer(options: StreamOptions) {
+    let (a, b) = duplex(256 * 1024);
+    let mut writer = EncryptedStream::with_options(a, &KEY, options);
+    let mut reader = EncryptedStream::with_options(b, &KEY, options);
+    let write = tokio::spawn(async move {
+        let data = [1; WRITE_SIZE];
+        for _ in 0..TRANSFER_SIZE / WRITE_SIZE {
+            writer.write_all(&data).await.unwrap();
+        }
+        writer.flush().await.unwrap();
+        writer
+    });
+    let mut buffer = vec![0; 64 * 1024];
+    let mut received = 0;
+    while received < TRANSFER_SIZE {
+        let n = reader.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "the transfer ended early");
+        received += n;
+    }
+    write.await.unwrap();
+}
+
+/// What an encrypted end writes for `TRANSFER_SIZE` bytes, frames included.
+async fn sealed() -> Vec<u8> {
+    let (a, mut b) = duplex(256 * 1024);
+    let mut writer = EncryptedStream::new(a, &KEY);
+    let write = tokio::spawn(async move {
+        let data = [1; WRITE_SIZE];
+        for _ in 0..TRANSFER_SIZE / WRITE_SIZE {
+            writer.write_all(&data).await.unwrap();
+        }
+        writer.shutdown().await.unwrap();
+    });
+    let mut frames = Vec::new();
+    b.read_to_end(&mut frames).await.unwrap();
+    write.await.unwrap();
+    frames
+}
+
+/// Decrypts `frames` written to the other end of the pipe.
+async fn read(frames: &'static [u8]) {
+    let (mut a, b) = duplex(256 * 1024);
+    let mut reader = EncryptedStream::new(b, &KEY);
+    let write = tokio::spawn(async move { a.write_all(frames).await.unwrap() });
+    let mut buffer = vec![0; 64 * 1024];
+    let mut received = 0;
+    while received < TRANSFER_SIZE {
+        let n = reader.read(&mut buffer).await.unwrap();
+        assert!(n > 0, "the transfer ended early");
+        received += n;
+    }
+    write.await.unwrap();
+}
+
+/// Encrypts `TRANSFER_SIZE` bytes, the other end of the pipe only drains the frames.
+async fn write() {
+    let (a, mut b) = duplex(256 * 1024);
+    let mut writer = EncryptedStream::new(a, &KEY);
+    let drain = tokio::spawn(async move {
+        let mut buffer = vec![0; 64 * 1024];
+        while b.read(&mut buffer).await.unwrap() > 0 {}
+    });
+    let data = [1; WRITE_SIZE];
+    for _ in 0..TRANSFER_SIZE / WRITE_SIZE {
+        writer.write_all(&data).await.unwrap();
+    }
+    writer.shutdown().await.unwrap();
+    drop(writer);
+    drain.await.unwrap();
+}
+
+fn read_size(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("encrypted_stream_read_size");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    for size in [1024, 64 * 1024] {
+        let options = StreamOptions {
+            read_chunk_size: size,
+            read_buffer_capacity: size,
+            ..StreamOptions::default()
+        };
+        let id = BenchmarkId::new("read_chunk_size", size);
+        group.bench_with_input(id, &options, |b, &options| {
+            b.to_async(&runtime).iter(|| transfer(options))
+        });
+    }
+    group.finish();
+}
+
+fn read_path(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let frames: &'static [u8] = runtime.block_on(sealed()).leak();
+    let mut group = c.benchmark_group("encrypted_stream_read");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.bench_function("decrypt", |b| b.to_async(&runtime).iter(|| read(frames)));
+    group.finish();
+}
+
+fn write_path(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("encrypted_stream_write");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(TRANSFER_SIZE as u64));
+    group.bench_function("encrypt", |b| b.to_async(&runtime).iter(write));
+    group.finish();
+}
+
+criterion_group!(benches, read_size, read_path, write_path);
+criterion_main!(benches);
@@ -0,0 +1,75 @@
+//! Own messages written to many repositories at once with a global order, each one
+//! taking the next order from the counter shared by all repositories.
+
+use std::sync::Arc;
+
+use chat_arch::app_context::{prepare_deps, AppContext};
+use chat_arch::config::Config;
+use chat_arch::models::MessageBuilder;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::{Builder, Runtime};
+
+const MESSAGES_PER_REPO: usize = 16;
+
+async fn add_messages(ctx: &AppContext, repos: usize) {
+    let mut tasks = Vec::new();
+    for repo in 0..repos {
+        let manager = ctx.sync_engine.get_manager();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..MESSAGES_PER_REPO {
+                let message = MessageBuilder::new(
+                    uuid::Uuid::new_v4().to_string(),
+                    1,
+                    format!("repo{}", repo),
+                )
+                .text("hello".to_owned())
+                .build();
+                manager.clone().add_own_message(message).await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn order_counter(c: &mut Criterion) {
+    let runtime = Arc::new(
+        Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap(),
+    );
+    let folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+    std::fs::create_dir_all(&folder).unwrap();
+    let config = Config {
+        global_ordering: true,
+        ..Config::default()
+    };
+    let ctx = runtime
+        .block_on(prepare_deps(
+            "alice",
+            "127.0.0.1:0",
+            &folder.to_string_lossy(),
+            config,
+            runtime.clone(),
+        ))
+        .unwrap();
+
+    let mut group = c.benchmark_group("order_counter");
+    group.sample_size(20);
+    for repos in [1, 8, 32] {
+        group.throughput(Throughput::Elements((repos * MESSAGES_PER_REPO) as u64));
+        let id = BenchmarkId::new("repos", repos);
+        group.bench_with_input(id, &repos, |b, &repos| {
+            b.to_async(runtime.as_ref() as &Runtime)
+                .iter(|| add_messages(&ctx, repos))
+        });
+    }
+    group.finish();
+    std::fs::remove_dir_all(&folder).unwrap();
+}
+
+criterion_group!(benches, order_counter);
+criterion_main!(benches);
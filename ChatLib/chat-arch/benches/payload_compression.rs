@@ -0,0 +1,101 @@
+//! Size and write time of a text-heavy conversation stored with and without
+//! payload compression. The database sizes are printed before the timings.
+
+use std::path::Path;
+use std::sync::Arc;
+
+use chat_arch::app_context::{prepare_deps, AppContext};
+use chat_arch::config::Config;
+use chat_arch::models::{DbMessage, MessageBuilder};
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use tokio::runtime::Runtime;
+
+const MESSAGES: usize = 2000;
+const WORDS: &[&str] = &[
+    "the", "a", "to", "and", "I", "you", "it", "is", "that", "for", "on", "we", "be", "this",
+    "with", "have", "are", "not", "at", "so", "what", "can", "just", "will", "do", "but",
+    "meeting", "tomorrow", "today", "sounds", "good", "thanks", "let", "me", "know", "when",
+    "think", "time", "see", "later", "going", "about", "back", "need", "should", "call",
+    "project", "update", "file", "sent", "maybe", "yes", "no", "okay", "sure", "lunch",
+];
+
+/// Messages of one peer, mostly short replies with some longer paragraphs.
+fn conversation(rng: &mut StdRng) -> Vec<DbMessage> {
+    let peer_id = uuid::Uuid::new_v4().to_string();
+    (1..=MESSAGES as u64)
+        .map(|counter| {
+            let words = match rng.gen_range(0..20) {
+                0 => rng.gen_range(100..300),
+                1..=5 => rng.gen_range(20..60),
+                _ => rng.gen_range(2..15),
+            };
+            let text: Vec<&str> = (0..words)
+                .map(|_| WORDS[rng.gen_range(0..WORDS.len())])
+                .collect();
+            let id = uuid::Uuid::new_v4().to_string();
+            let mut message = MessageBuilder::new(id, counter as i64, peer_id.clone())
+                .text(text.join(" "))
+                .build();
+            message.counter = counter;
+            message.order = counter;
+            message
+        })
+        .collect()
+}
+
+fn open(runtime: &Arc<Runtime>, folder: &Path, compress_payloads: bool) -> AppContext {
+    std::fs::create_dir_all(folder).unwrap();
+    let config = Config {
+        compress_payloads,
+        ..Config::default()
+    };
+    let root = folder.to_string_lossy();
+    let deps = prepare_deps("alice", "127.0.0.1:0", &root, config, runtime.clone());
+    runtime.block_on(deps).unwrap()
+}
+
+/// Bytes of the database once everything written is checkpointed into it.
+fn database_size(runtime: &Runtime, ctx: &AppContext, folder: &Path) -> u64 {
+    runtime.block_on(ctx.message_db.checkpoint()).unwrap();
+    std::fs::metadata(folder.join("message.db")).unwrap().len()
+}
+
+fn payload_compression(c: &mut Criterion) {
+    let runtime = Arc::new(Runtime::new().unwrap());
+    let mut rng = StdRng::seed_from_u64(7);
+    let mut group = c.benchmark_group("payload_compression");
+    group.sample_size(10);
+    for compress_payloads in [false, true] {
+        let folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        let ctx = open(&runtime, &folder, compress_payloads);
+        let empty = database_size(&runtime, &ctx, &folder);
+        let messages = conversation(&mut rng);
+        runtime
+            .block_on(ctx.message_db.save_many(&messages))
+            .unwrap();
+        let stored = database_size(&runtime, &ctx, &folder) - empty;
+        println!(
+            "compress_payloads {}: {} messages take {} bytes",
+            compress_payloads, MESSAGES, stored
+        );
+
+        let id = BenchmarkId::new("compress_payloads", compress_payloads);
+        group.bench_function(id, |b| {
+            b.to_async(runtime.as_ref()).iter_batched(
+                || conversation(&mut rng),
+                |messages| {
+                    let message_db = ctx.message_db.clone();
+                    async move { message_db.save_many(&messages).await.unwrap() }
+                },
+                BatchSize::LargeInput,
+            )
+        });
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, payload_compression);
+criterion_main!(benches);
@@ -0,0 +1,85 @@
+//! Messages stored by concurrent sync workers while the conversation is being
+//! read, at different sizes of the SQLite pool.
+
+use std::sync::Arc;
+
+use chat_arch::app_context::{prepare_deps, AppContext};
+use chat_arch::config::Config;
+use chat_arch::models::MessageBuilder;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use tokio::runtime::{Builder, Runtime};
+
+const WRITERS: usize = 10;
+const READERS: usize = 4;
+const ROUNDS: usize = 8;
+
+async fn sync_and_query(ctx: &AppContext) {
+    let mut tasks = Vec::new();
+    for writer in 0..WRITERS {
+        let manager = ctx.sync_engine.get_manager();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..ROUNDS {
+                let message = MessageBuilder::new(
+                    uuid::Uuid::new_v4().to_string(),
+                    1,
+                    format!("peer{}", writer),
+                )
+                .text("hello".to_owned())
+                .build();
+                manager.clone().add_own_message(message).await.unwrap();
+            }
+        }));
+    }
+    for reader in 0..READERS {
+        let message_db = ctx.message_db.clone();
+        tasks.push(tokio::spawn(async move {
+            for _ in 0..ROUNDS {
+                let peer_id = format!("peer{}", reader);
+                message_db.get_page(&peer_id, 0, 50).await.unwrap();
+                message_db.get_highest_counter(&peer_id).await.unwrap();
+            }
+        }));
+    }
+    for task in tasks {
+        task.await.unwrap();
+    }
+}
+
+fn pool_size(c: &mut Criterion) {
+    let runtime = Arc::new(
+        Builder::new_multi_thread()
+            .worker_threads(4)
+            .enable_all()
+            .build()
+            .unwrap(),
+    );
+    let mut group = c.benchmark_group("pool_size");
+    group.sample_size(20);
+    for connections in [1, 4, Config::default().db_max_connections, 32] {
+        let folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&folder).unwrap();
+        let config = Config {
+            db_max_connections: connections,
+            ..Config::default()
+        };
+        let ctx = runtime
+            .block_on(prepare_deps(
+                "alice",
+                "127.0.0.1:0",
+                &folder.to_string_lossy(),
+                config,
+                runtime.clone(),
+            ))
+            .unwrap();
+        let id = BenchmarkId::new("max_connections", connections);
+        group.bench_function(id, |b| {
+            b.to_async(runtime.as_ref() as &Runtime)
+                .iter(|| sync_and_query(&ctx))
+        });
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+    group.finish();
+}
+
+criterion_group!(benches, pool_size);
+criterion_main!(benches);
@@ -0,0 +1,69 @@
+//! A file sent over an encrypted yamux session the way `upload_file` sends it,
+//! flushing after every chunk against flushing once per `UPLOAD_FLUSH_EVERY_CHUNKS`.
+
+use chat_arch::config::{
+    SessionOptions, StreamOptions, UPLOAD_CHUNK_SIZE, UPLOAD_FLUSH_EVERY_CHUNKS,
+};
+use chat_arch::conn::EncryptedStream;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::StreamExt;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio_yamux::Session;
+
+const FILE_SIZE: usize = 4 * 1024 * 1024;
+
+async fn upload(flush_every: usize) {
+    let (client, server) = duplex(64 * 1024);
+    let config = SessionOptions::default().yamux_config();
+    let options = StreamOptions::default();
+    let client = EncryptedStream::with_options(client, &[7; 32], options);
+    let server = EncryptedStream::with_options(server, &[7; 32], options);
+    let mut client = Session::new_client(client, config);
+    let mut server = Session::new_server(server, config);
+
+    let download = tokio::spawn(async move {
+        let mut stream = server.next().await.unwrap().unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+        let mut buffer = vec![0; UPLOAD_CHUNK_SIZE];
+        let mut received = 0;
+        while received < FILE_SIZE {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "the upload ended early");
+            received += n;
+        }
+    });
+    let stream = client.open_stream().unwrap();
+    let mut control = client.control();
+    tokio::spawn(async move { while client.next().await.is_some() {} });
+    // yamux only takes in window updates while the stream is read
+    let (mut reader, mut stream) = tokio::io::split(stream);
+    tokio::spawn(async move { reader.read(&mut [0; 1]).await });
+    let chunk = [1; UPLOAD_CHUNK_SIZE];
+    for sent in 1..=FILE_SIZE / UPLOAD_CHUNK_SIZE {
+        stream.write_all(&chunk).await.unwrap();
+        if sent % flush_every == 0 {
+            stream.flush().await.unwrap();
+        }
+    }
+    stream.flush().await.unwrap();
+    download.await.unwrap();
+    control.close().await;
+}
+
+fn upload_flush(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("upload_flush");
+    group.sample_size(20);
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    for flush_every in [1, UPLOAD_FLUSH_EVERY_CHUNKS] {
+        let id = BenchmarkId::new("chunks_per_flush", flush_every);
+        group.bench_with_input(id, &flush_every, |b, &flush_every| {
+            b.to_async(&runtime).iter(|| upload(flush_every))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, upload_flush);
+criterion_main!(benches);
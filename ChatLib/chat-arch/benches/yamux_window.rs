@@ -0,0 +1,65 @@
+//! A large file sent over an encrypted yamux session at different stream windows.
+
+use chat_arch::config::{SessionOptions, StreamOptions, UPLOAD_CHUNK_SIZE};
+use chat_arch::conn::EncryptedStream;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use futures::StreamExt;
+use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt};
+use tokio::runtime::Runtime;
+use tokio_yamux::Session;
+
+const FILE_SIZE: usize = 16 * 1024 * 1024;
+
+async fn transfer(options: SessionOptions) {
+    let (client, server) = duplex(1024 * 1024);
+    let config = options.yamux_config();
+    let client = EncryptedStream::with_options(client, &[7; 32], StreamOptions::default());
+    let server = EncryptedStream::with_options(server, &[7; 32], StreamOptions::default());
+    let mut client = Session::new_client(client, config);
+    let mut server = Session::new_server(server, config);
+
+    let download = tokio::spawn(async move {
+        let mut stream = server.next().await.unwrap().unwrap();
+        tokio::spawn(async move { while server.next().await.is_some() {} });
+        let mut buffer = vec![0; 64 * 1024];
+        let mut received = 0;
+        while received < FILE_SIZE {
+            let n = stream.read(&mut buffer).await.unwrap();
+            assert!(n > 0, "the transfer ended early");
+            received += n;
+        }
+    });
+    let stream = client.open_stream().unwrap();
+    let mut control = client.control();
+    tokio::spawn(async move { while client.next().await.is_some() {} });
+    // yamux only takes in window updates while the stream is read
+    let (mut reader, mut stream) = tokio::io::split(stream);
+    tokio::spawn(async move { reader.read(&mut [0; 1]).await });
+    let chunk = [1; UPLOAD_CHUNK_SIZE];
+    for _ in 0..FILE_SIZE / UPLOAD_CHUNK_SIZE {
+        stream.write_all(&chunk).await.unwrap();
+    }
+    download.await.unwrap();
+    control.close().await;
+}
+
+fn yamux_window(c: &mut Criterion) {
+    let runtime = Runtime::new().unwrap();
+    let mut group = c.benchmark_group("yamux_window");
+    group.sample_size(10);
+    group.throughput(Throughput::Bytes(FILE_SIZE as u64));
+    for window in [256 * 1024, 1024 * 1024, 4 * 1024 * 1024, 16 * 1024 * 1024] {
+        let options = SessionOptions {
+            max_stream_window_size: window,
+            ..SessionOptions::default()
+        };
+        let id = BenchmarkId::new("max_stream_window_size", window);
+        group.bench_with_input(id, &options, |b, &options| {
+            b.to_async(&runtime).iter(|| transfer(options))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, yamux_window);
+criterion_main!(benches);
@@ -1,5 +1,5 @@
 use crate::{
-    dialer::Dialer, events::Events, file_resolver::{FileResolver, FileResolverStorage}, indexer::Indexer, message_database::create_pool, peer_database::Peer, peer_pool::PeerPool, repository_manager::RepositoryManager, server::Server, sync_engine::SyncEngine
+    clock::Clock, config::Config, dialer::Dialer, direct_message::DirectCipher, events::Events, handshake::ResumptionCache, inbound_policy::InboundGate, file_resolver::{FileResolver, FileResolverStorage}, indexer::Indexer, message_database::create_pool, message_expiry::MessageExpiry, models::{MessageBuilder, SystemKind}, outbox::Outbox, peer_database::Peer, peer_pool::PeerPool, peer_pruner::PeerPruner, protocol_recorder::ProtocolRecorder, repository_manager::RepositoryManager, server::Server, sync_engine::SyncEngine
 };
 use ed25519_dalek::SigningKey;
 use std::sync::{Arc, Weak};
@@ -17,52 +17,125 @@ pub struct AppContext {
     pub peer: Peer,
     pub peer_db: Arc<crate::peer_database::PeerDatabase>,
     pub file_db: Arc<crate::file_database::FileDatabase>,
+    pub message_expiry: Arc<MessageExpiry>,
+    pub peer_pruner: Arc<PeerPruner>,
+    pub inbound_gate: Arc<InboundGate>,
+    pub message_db: Arc<crate::message_database::MessageDatabase>,
+    pub direct_cipher: Arc<DirectCipher>,
+    pub inline_file_limit: u64,
+    pub clock: Arc<dyn Clock>,
+    pub protocol_recorder: Option<Arc<ProtocolRecorder>>,
+    pub observer: bool,
 }
 
 pub async fn prepare_deps(
     name: &str,
     addr: &str,
     root_path: &str,
+    config: Config,
     runtime: Arc<tokio::runtime::Runtime>,
 ) -> anyhow::Result<AppContext> {
     let events = Arc::new(Events::new());
-    let db_pool = create_pool(root_path).await?;
-    
-    let peer_db = Arc::new(crate::peer_database::PeerDatabase::new(db_pool.clone(), events.clone()));
+    let db_pool = create_pool(root_path, config.db_max_connections).await?;
+
+    // peer records read avatar paths from the files table
+    let file_db = Arc::new(crate::file_database::FileDatabase::new(db_pool.clone(), events.clone()));
+    file_db.init().await?;
+
+    let peer_db = Arc::new(crate::peer_database::PeerDatabase::new(
+        db_pool.clone(),
+        events.clone(),
+        config.text_policy,
+        config.clock.clone(),
+    ));
     peer_db.init().await?;
-    let existing_peer = match peer_db.get_local_peer().await? {
-        Some(peer) => peer,
-        None => peer_db.create_local_peer(Some(name.to_owned())).await?,
+    let (existing_peer, is_new_peer) = match peer_db.get_local_peer().await? {
+        Some(peer) => (peer, false),
+        None => (peer_db.create_local_peer(Some(name.to_owned())).await?, true),
     };
     
     let message_db = Arc::new(crate::message_database::MessageDatabase::new(
         db_pool.clone(),
+        events.clone(),
+        config.compress_payloads,
     ));
-    let counter = message_db.init().await?.unwrap_or_else(|| 1);
+    let counter = message_db.init().await?.unwrap_or(1);
 
-    let file_db = Arc::new(crate::file_database::FileDatabase::new(db_pool.clone()));
-    file_db.init().await?;
-    let file_storage = Arc::new(FileResolverStorage::new(file_db.clone()));
+    let file_storage = Arc::new(FileResolverStorage::new(file_db.clone(), config.clock.clone()));
+
+    let signing_key = existing_peer.signing_key.clone().ok_or(anyhow!("no signing key"))?;
+    let peer_id = crate::peer_database::peer_id(&signing_key.verifying_key());
+    let direct_cipher = Arc::new(DirectCipher::new(signing_key.clone()));
 
     let index_db = crate::index_database::IndexedMessageDatabase::new(db_pool.clone());
     index_db.init().await?;
-    let indexer = Arc::new(Indexer::new(index_db, file_db.clone(), events.clone()));
+    // moves the rows of every table, not only the peer records
+    peer_db.merge_duplicates().await?;
+    let indexer = Arc::new(Indexer::new(
+        peer_id.clone(),
+        index_db,
+        message_db.clone(),
+        file_db.clone(),
+        peer_db.clone(),
+        events.clone(),
+        direct_cipher.clone(),
+        config.text_policy,
+        config.coalesce_batch_events,
+        config.max_clock_skew,
+        root_path.to_owned(),
+        config.clock.clone(),
+    ));
     let cloned_indexer = indexer.clone();
 
-    let signing_key = existing_peer.signing_key.clone().ok_or(anyhow!("no signing key"))?;
-    let peer_id = hex::encode(signing_key.verifying_key().to_bytes());
-
-    let dialer = Arc::new(Dialer::new(signing_key.clone()));
+    // shared by both directions, a peer that dialed us can later be dialed back with it
+    let resumption = config
+        .resumption_ttl
+        .map(|ttl| Arc::new(ResumptionCache::new(ttl, config.clock.clone())));
+    let dialer = Arc::new(Dialer::new(
+        signing_key.clone(),
+        config.handshake_context.clone(),
+        config.handshake_scheme.clone(),
+        resumption.clone(),
+        config.stream_options,
+        config.session_options,
+    ));
     let dialer_clone = dialer.clone();
+    let outbox = Arc::new(Outbox::new(
+        peer_id.clone(),
+        message_db.clone(),
+        indexer.clone(),
+        events.clone(),
+    ));
+
+    let protocol_recorder = (config.protocol_trace_size > 0).then(|| {
+        Arc::new(ProtocolRecorder::new(
+            config.protocol_trace_size,
+            config.clock.clone(),
+        ))
+    });
 
     let sync_engine = Arc::new_cyclic(|weak: &Weak<SyncEngine>| {
         let manager = Arc::new(RepositoryManager::new(
-            message_db,
+            message_db.clone(),
             counter,
             cloned_indexer,
             weak.clone(),
+            config.global_ordering,
+            config.unknown_peer_policy,
+            config.observer,
+        ));
+        let peer_pool = Arc::new(PeerPool::new(
+            peer_id.clone(),
+            dialer_clone,
+            weak.clone(),
+            events.clone(),
+            config.decrypt_failure_policy,
+            config.max_concurrent_dials,
+            config.session_options.idle_timeout,
+            protocol_recorder.clone(),
+            config.clock.clone(),
+            runtime.clone(),
         ));
-        let peer_pool = Arc::new(PeerPool::new(dialer_clone, weak.clone(), runtime.clone()));
         SyncEngine::new(
             peer_id.clone(),
             root_path.to_owned(),
@@ -71,22 +144,76 @@ pub async fn prepare_deps(
             manager,
             file_storage.clone(),
             events.clone(),
+            outbox,
+            config.max_upload_size,
+            config.max_upload_in_flight,
+            config.file_bandwidth,
+            config.max_pending_tasks,
             runtime.clone(),
         )
     });
 
+    // tombstones of expired messages are written through the repository manager
+    let message_expiry = Arc::new(MessageExpiry::new(
+        peer_id.clone(),
+        message_db.clone(),
+        file_db.clone(),
+        indexer.clone(),
+        sync_engine.get_manager(),
+        direct_cipher.clone(),
+        root_path.to_owned(),
+        config.clock.clone(),
+        runtime.clone(),
+    ));
+
+    // an observer never announces itself, its key only authenticates connections
+    if is_new_peer && !config.observer {
+        let joined = MessageBuilder::new(
+            uuid::Uuid::new_v4().to_string(),
+            config.clock.timestamp(),
+            peer_id.clone(),
+        )
+        .system(SystemKind::Joined, existing_peer.get_name())
+        .build();
+        sync_engine.get_manager().add_own_message(joined).await?;
+    }
+
+    let inbound_gate = Arc::new(InboundGate::new(
+        config.inbound_policy,
+        sync_engine.peer_pool.clone(),
+        peer_db.clone(),
+        events.clone(),
+        config.clock.clone(),
+    ));
+
     let server = Server::new(
         addr.to_owned(),
         signing_key.clone(),
-        sync_engine.peer_pool.clone(),
+        config.handshake_context.clone(),
+        config.handshake_scheme.clone(),
+        resumption,
+        config.listen_backlog,
+        config.stream_options,
+        config.session_options,
+        inbound_gate.clone(),
         runtime.clone(),
     );
 
+    let peer_pruner = Arc::new(PeerPruner::new(
+        config.peer_pruning,
+        peer_db.clone(),
+        sync_engine.peer_pool.clone(),
+        events.clone(),
+        runtime.clone(),
+    ));
+
     let file_resolver = Arc::new(FileResolver::new(
+        root_path.to_owned(),
         runtime,
         indexer.clone(),
         file_storage,
         sync_engine.clone(),
+        events.clone(),
     ));
 
     Ok(AppContext {
@@ -100,5 +227,116 @@ pub async fn prepare_deps(
         peer: existing_peer,
         peer_db,
         file_db,
+        message_expiry,
+        peer_pruner,
+        inbound_gate,
+        message_db,
+        direct_cipher,
+        inline_file_limit: config.inline_file_limit,
+        clock: config.clock,
+        protocol_recorder,
+        observer: config.observer,
     })
-}
\ No newline at end of file
+}
+/// A node in its own folder on a free local port, for tests that sync between
+/// nodes. Nothing runs before `start`.
+#[cfg(test)]
+pub(crate) struct TestNode {
+    pub ctx: AppContext,
+    pub addr: String,
+    pub root: String,
+}
+
+#[cfg(test)]
+impl TestNode {
+    pub(crate) async fn new(
+        name: &str,
+        config: Config,
+        runtime: Arc<tokio::runtime::Runtime>,
+    ) -> Self {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&root).unwrap();
+        let port = std::net::TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let addr = format!("127.0.0.1:{}", port);
+        let root = root.to_string_lossy().into_owned();
+        Self::open(name, addr, root, config, runtime).await
+    }
+
+    /// Opens the node kept in `root` again, as after a restart.
+    pub(crate) async fn open(
+        name: &str,
+        addr: String,
+        root: String,
+        config: Config,
+        runtime: Arc<tokio::runtime::Runtime>,
+    ) -> Self {
+        let ctx = prepare_deps(name, &addr, &root, config, runtime)
+            .await
+            .unwrap();
+        Self { ctx, addr, root }
+    }
+
+    pub(crate) fn id(&self) -> String {
+        self.ctx.peer.id.clone()
+    }
+
+    /// Starts the server and the sync engine like an app after startup, returns
+    /// once the server accepts connections.
+    pub(crate) async fn start(&self) {
+        let server = self.ctx.server.clone();
+        tokio::spawn(async move { server.run().await });
+        self.ctx.sync_engine.run();
+        self.ctx.file_resolver.clone().run();
+        let server = self.ctx.server.clone();
+        wait_until("the server listens", || {
+            let server = server.clone();
+            async move { server.is_listening() }
+        })
+        .await;
+    }
+
+    /// Lets this node dial `other`, as if discovery had found it.
+    pub(crate) async fn learn(&self, other: &TestNode) {
+        use crate::peer_pool::Dialer as _;
+        self.ctx.dialer.add(other.id(), other.addr.clone()).await;
+    }
+
+    /// Waits until this node holds the messages of `repo_id` up to `counter`.
+    pub(crate) async fn wait_for_counter(&self, repo_id: &str, counter: u64) {
+        let message_db = self.ctx.message_db.clone();
+        wait_until("the repository is synced", || async {
+            message_db.get_highest_counter(repo_id).await.unwrap() >= counter
+        })
+        .await;
+    }
+
+    /// Stops serving and syncing, the folder stays for `open`.
+    pub(crate) async fn stop(&self) {
+        self.ctx.server.stop();
+        self.ctx.sync_engine.shutdown();
+        self.ctx.file_resolver.shutdown();
+        self.ctx.sync_engine.peer_pool.close_all().await;
+    }
+}
+
+/// Polls `condition` until it holds, fails the test after ten seconds.
+#[cfg(test)]
+pub(crate) async fn wait_until<F, Fut>(what: &str, mut condition: F)
+where
+    F: FnMut() -> Fut,
+    Fut: std::future::Future<Output = bool>,
+{
+    let deadline = tokio::time::Instant::now() + std::time::Duration::from_secs(10);
+    while !condition().await {
+        assert!(
+            tokio::time::Instant::now() < deadline,
+            "timed out waiting until {}",
+            what
+        );
+        tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+    }
+}
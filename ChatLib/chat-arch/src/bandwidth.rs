@@ -0,0 +1,187 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use tokio::time::Instant;
+
+/// Bytes exchanged with one peer since startup, counted per protocol frame
+/// before encryption.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PeerTraffic {
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// Counts the traffic of every stream opened with `StreamProtocol::metered`.
+#[derive(Default)]
+pub struct BandwidthMeter {
+    peers: Mutex<HashMap<String, PeerTraffic>>,
+}
+
+impl BandwidthMeter {
+    pub fn record_received(&self, peer_id: &str, bytes: u64) {
+        let mut peers = self.peers.lock().unwrap();
+        let traffic = peers.entry(peer_id.to_owned()).or_default();
+        traffic.bytes_in = traffic.bytes_in.saturating_add(bytes);
+    }
+
+    pub fn record_sent(&self, peer_id: &str, bytes: u64) {
+        let mut peers = self.peers.lock().unwrap();
+        let traffic = peers.entry(peer_id.to_owned()).or_default();
+        traffic.bytes_out = traffic.bytes_out.saturating_add(bytes);
+    }
+
+    pub fn traffic(&self, peer_id: &str) -> PeerTraffic {
+        self.peers
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Traffic of every peer seen since startup.
+    pub fn all(&self) -> HashMap<String, PeerTraffic> {
+        self.peers.lock().unwrap().clone()
+    }
+}
+
+/// Caps on file transfer rates in bytes per second, `None` leaves a rate unlimited.
+/// Uploads and downloads count against the same budget.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BandwidthLimits {
+    /// Per peer, so one large transfer leaves room for the others.
+    pub per_peer: Option<u64>,
+    /// Across all peers, e.g. on a metered link.
+    pub global: Option<u64>,
+}
+
+/// Holds file transfers to `BandwidthLimits`, a no-op without limits.
+pub struct FileThrottle {
+    per_peer: Option<u64>,
+    global: Option<TokenBucket>,
+    peers: Mutex<HashMap<String, Arc<TokenBucket>>>,
+}
+
+impl FileThrottle {
+    pub fn new(limits: BandwidthLimits) -> Self {
+        Self {
+            per_peer: limits.per_peer,
+            global: limits.global.map(TokenBucket::new),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Waits until `bytes` more may be transferred with `peer_id`.
+    pub async fn acquire(&self, peer_id: &str, bytes: u64) {
+        if let Some(global) = &self.global {
+            global.acquire(bytes).await;
+        }
+        if let Some(rate) = self.per_peer {
+            let bucket = self
+                .peers
+                .lock()
+                .unwrap()
+                .entry(peer_id.to_owned())
+                .or_insert_with(|| Arc::new(TokenBucket::new(rate)))
+                .clone();
+            bucket.acquire(bytes).await;
+        }
+    }
+}
+
+/// Allows `rate` bytes per second with bursts of up to a second's worth. A caller
+/// taking more than is left goes into debt and sleeps it off, so concurrent
+/// transfers share the rate instead of exceeding it.
+struct TokenBucket {
+    rate: u64,
+    // available bytes, negative while in debt, and when they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl TokenBucket {
+    fn new(rate: u64) -> Self {
+        // zero would never refill
+        let rate = rate.max(1);
+        Self {
+            rate,
+            state: Mutex::new((rate as f64, Instant::now())),
+        }
+    }
+
+    async fn acquire(&self, bytes: u64) {
+        let wait = {
+            let mut state = self.state.lock().unwrap();
+            let now = Instant::now();
+            let refill = now.duration_since(state.1).as_secs_f64() * self.rate as f64;
+            state.0 = (state.0 + refill).min(self.rate as f64) - bytes as f64;
+            state.1 = now;
+            if state.0 < 0.0 {
+                Duration::from_secs_f64(-state.0 / self.rate as f64)
+            } else {
+                Duration::ZERO
+            }
+        };
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u64 = 20_000;
+    const CHUNK: u64 = 1_000;
+
+    /// Transfers `total` bytes in chunks and returns the rate beyond the initial burst.
+    async fn transfer(throttle: &FileThrottle, peer_id: &str, total: u64) -> f64 {
+        let start = Instant::now();
+        let mut sent = 0;
+        while sent < total {
+            throttle.acquire(peer_id, CHUNK).await;
+            sent += CHUNK;
+        }
+        // the bucket starts full, a second's worth goes out right away
+        (total - RATE) as f64 / start.elapsed().as_secs_f64()
+    }
+
+    #[tokio::test]
+    async fn throttled_transfer_stays_under_the_rate() {
+        let throttle = FileThrottle::new(BandwidthLimits {
+            per_peer: Some(RATE),
+            global: None,
+        });
+        let rate = transfer(&throttle, "alice", 2 * RATE).await;
+        assert!(rate <= RATE as f64, "{} bytes/s", rate);
+        assert!(rate > RATE as f64 * 0.8, "{} bytes/s", rate);
+    }
+
+    #[tokio::test]
+    async fn global_limit_is_shared_by_all_peers() {
+        let throttle = FileThrottle::new(BandwidthLimits {
+            per_peer: None,
+            global: Some(RATE),
+        });
+        let start = Instant::now();
+        tokio::join!(
+            transfer(&throttle, "alice", RATE),
+            transfer(&throttle, "bob", 2 * RATE),
+        );
+        // three seconds' worth, less the initial burst
+        assert!(start.elapsed() >= Duration::from_millis(1900));
+    }
+
+    #[tokio::test]
+    async fn without_limits_nothing_waits() {
+        let throttle = FileThrottle::new(BandwidthLimits::default());
+        let start = Instant::now();
+        for _ in 0..1000 {
+            throttle.acquire("alice", u32::MAX as u64).await;
+        }
+        assert!(start.elapsed() < Duration::from_millis(100));
+    }
+}
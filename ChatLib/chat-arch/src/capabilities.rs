@@ -0,0 +1,64 @@
+use std::{collections::HashMap, sync::Mutex};
+
+/// Protocol features a peer understands, exchanged in every compare request and
+/// response. A node only opens streams of a kind the other side has announced, so
+/// peers on an older version keep syncing through the exchanges they know.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Capabilities(u64);
+
+impl Capabilities {
+    /// Own messages are pushed with `Messages` as soon as they are written.
+    pub const MESSAGE_PUSH: Capabilities = Capabilities(1 << 0);
+    /// Missing files are looked up with `FileWantRequest`.
+    pub const FILE_WANT: Capabilities = Capabilities(1 << 1);
+
+    /// What peers that predate the exchange understand, they announce nothing.
+    pub const LEGACY: Capabilities = Self::MESSAGE_PUSH.union(Self::FILE_WANT);
+    /// What this build understands.
+    pub const LOCAL: Capabilities = Self::LEGACY;
+
+    pub fn from_bits(bits: u64) -> Self {
+        if bits == 0 {
+            Self::LEGACY
+        } else {
+            Capabilities(bits)
+        }
+    }
+
+    pub fn bits(self) -> u64 {
+        self.0
+    }
+
+    pub fn contains(self, other: Capabilities) -> bool {
+        self.0 & other.0 == other.0
+    }
+
+    pub const fn union(self, other: Capabilities) -> Capabilities {
+        Capabilities(self.0 | other.0)
+    }
+}
+
+/// Capabilities last announced by each peer.
+#[derive(Default)]
+pub struct PeerCapabilities {
+    known: Mutex<HashMap<String, Capabilities>>,
+}
+
+impl PeerCapabilities {
+    pub fn set(&self, peer_id: &str, capabilities: Capabilities) {
+        self.known
+            .lock()
+            .unwrap()
+            .insert(peer_id.to_owned(), capabilities);
+    }
+
+    /// Peers we haven't compared with yet are assumed to speak the legacy protocol.
+    pub fn get(&self, peer_id: &str) -> Capabilities {
+        self.known
+            .lock()
+            .unwrap()
+            .get(peer_id)
+            .copied()
+            .unwrap_or(Capabilities::LEGACY)
+    }
+}
@@ -1,7 +1,34 @@
-use crate::{proto::chat::ChatMessage, stream_protocol::MessageEncoding};
+use crate::{
+    proto::chat::{chat_message::Variant, ChatMessage},
+    stream_protocol::MessageEncoding,
+};
 use anyhow::{anyhow, Result};
 use prost::Message;
 
+/// Wire format spoken in `ChatMessage`. Only bumped for changes an older peer
+/// can't read, additive ones are negotiated with capabilities.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+impl ChatMessage {
+    /// Wire format of the sender, peers that predate the field speak version 1.
+    pub fn sender_version(&self) -> u32 {
+        self.protocol_version.max(1)
+    }
+
+    /// Fails when the sender speaks a wire format this version can't read.
+    pub fn check_version(&self) -> Result<()> {
+        let version = self.sender_version();
+        if version != PROTOCOL_VERSION {
+            return Err(anyhow!(
+                "incompatible peer: it speaks protocol version {}, this version speaks {}",
+                version,
+                PROTOCOL_VERSION
+            ));
+        }
+        Ok(())
+    }
+}
+
 impl MessageEncoding for ChatMessage {
     fn encode_message(&self) -> Vec<u8> {
         let mut buf = Vec::with_capacity(self.encoded_len());
@@ -12,4 +39,22 @@ impl MessageEncoding for ChatMessage {
     fn decode_message(bytes: &[u8]) -> Result<Self> {
         ChatMessage::decode(bytes).map_err(|e| anyhow!("Decode error: {}", e))
     }
+
+    fn variant_name(&self) -> &'static str {
+        match &self.variant {
+            Some(Variant::FileDownloadRequest(_)) => "FileDownloadRequest",
+            Some(Variant::FileDownloadResponse(_)) => "FileDownloadResponse",
+            Some(Variant::Messages(_)) => "Messages",
+            Some(Variant::MessageAccept(_)) => "MessageAccept",
+            Some(Variant::BatchMessageRequest(_)) => "BatchMessageRequest",
+            Some(Variant::BatchMessageResponse(_)) => "BatchMessageResponse",
+            Some(Variant::CompareRequest(_)) => "CompareRequest",
+            Some(Variant::CompareResponse(_)) => "CompareResponse",
+            Some(Variant::FileWantRequest(_)) => "FileWantRequest",
+            Some(Variant::FileWantResponse(_)) => "FileWantResponse",
+            Some(Variant::Unsupported(_)) => "Unsupported",
+            Some(Variant::FileDownloadAck(_)) => "FileDownloadAck",
+            None => "Unknown",
+        }
+    }
 }
@@ -0,0 +1,192 @@
+use std::{io, sync::Arc, time::Duration};
+
+use tokio::runtime::{Builder, Runtime};
+
+use crate::sync_engine::SYNC_WORKERS;
+
+pub use crate::bandwidth::BandwidthLimits;
+pub use crate::clock::{Clock, ManualClock, SystemClock};
+pub use crate::conn::{CipherSuite, FrameVersion, StreamOptions};
+pub use crate::handshake_scheme::HandshakeScheme;
+pub use crate::inbound_policy::InboundPolicy;
+pub use crate::peer_database::PeerPrunePolicy;
+pub use crate::peer_pool::{DecryptFailurePolicy, SessionOptions};
+pub use crate::repository_manager::{ObserverError, UnknownPeerPolicy};
+pub use crate::sanitize::{TextPolicy, MAX_NAME_CHARS};
+pub use crate::sync_engine::{UPLOAD_CHUNK_SIZE, UPLOAD_FLUSH_EVERY_CHUNKS};
+
+/// Tunables of the chat core. `Config::default()` keeps the built-in behaviour.
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// HKDF info used to derive the session key during the handshake. Both sides
+    /// must use the same value, so embedders can keep their deployments apart.
+    pub handshake_context: Vec<u8>,
+    /// Signature and key agreement algorithms of the handshake. Both sides must
+    /// use the same scheme, a peer with another one fails the handshake.
+    pub handshake_scheme: HandshakeScheme,
+    /// How long a session secret is kept to reconnect to the same peer without a
+    /// full handshake. `None` disables resumption.
+    pub resumption_ttl: Option<Duration>,
+    /// Assign every message a global order so messages of all peers interleave
+    /// into one timeline. When disabled a message is ordered by its peer's own
+    /// counter only, `order_id`s are then comparable within a single peer and
+    /// conversations should be read with `Indexer::get_peer_after_order_id`.
+    pub global_ordering: bool,
+    /// Accept backlog of the server socket.
+    pub listen_backlog: u32,
+    /// Cipher, frame size and buffer sizes of every encrypted connection.
+    pub stream_options: StreamOptions,
+    /// Yamux window, stream limit and keepalive of every session.
+    pub session_options: SessionOptions,
+    /// Which peers may open a session with the server.
+    pub inbound_policy: InboundPolicy,
+    /// Size of the SQLite connection pool. The default leaves one connection per
+    /// sync worker plus two for the file resolver and UI queries, more than that
+    /// only contends on SQLite's single writer.
+    pub db_max_connections: u32,
+    /// Sync tasks waiting for a worker beyond which background work, comparing
+    /// state, paging history and asking for files, is dropped with a warning. The
+    /// next sweep does it again. Sending messages and downloading files are never
+    /// dropped. `None` queues without a limit.
+    pub max_pending_tasks: Option<usize>,
+    /// Dials (TCP connect and handshake) running at once, further dials wait for a
+    /// slot. Keeps a sweep over many offline peers from spiking CPU and sockets.
+    pub max_concurrent_dials: usize,
+    /// Run as a read-only observer, e.g. a backup server: messages of peers are
+    /// synced, stored and served to other peers as usual, but the node never
+    /// writes messages of its own. `RepositoryManager::add_own_message`, and so
+    /// every send, fails with `ObserverError` and no join message is written for
+    /// a new local peer. The local signing key is still created and used, only to
+    /// authenticate connections.
+    pub observer: bool,
+    /// Handling of messages whose author has no peer record yet.
+    pub unknown_peer_policy: UnknownPeerPolicy,
+    /// Files above this many bytes are not served to peers. `None` serves any size.
+    pub max_upload_size: Option<u64>,
+    /// Bytes of a file sent to a downloader ahead of its acknowledgement, a slow
+    /// downloader then holds the upload back. Downloaders that predate it are served
+    /// without a limit. `None` disables the limit.
+    pub max_upload_in_flight: Option<u64>,
+    /// Rates file uploads and downloads are held to, per peer and overall. Messages
+    /// and sync traffic are never throttled. Unlimited by default.
+    pub file_bandwidth: BandwidthLimits,
+    /// Files up to this many bytes travel inside their message instead of being
+    /// downloaded separately. Zero disables inlining.
+    pub inline_file_limit: u64,
+    /// Announce the messages of a synced batch with one `ChatEvent::MessagesBatch`
+    /// instead of a `ChatEvent::Message` each.
+    pub coalesce_batch_events: bool,
+    /// Compress message payloads in the database, trading CPU for disk. Rows are
+    /// flagged, so the setting can change between runs.
+    pub compress_payloads: bool,
+    /// How far ahead of the local clock a message timestamp may be. Later timestamps
+    /// are replaced with the receive time in the index so they can't pin a message
+    /// to the end of the timeline. `None` trusts the author's clock.
+    pub max_clock_skew: Option<Duration>,
+    /// Cleaning applied to peer names and message text before they are stored for display.
+    pub text_policy: TextPolicy,
+    /// Whether a session torn down by a frame that failed to decrypt is redialed.
+    pub decrypt_failure_policy: DecryptFailurePolicy,
+    /// Time source for timestamps, expiry and retry windows. Tests can pass a
+    /// `ManualClock` and advance it instead of sleeping.
+    pub clock: Arc<dyn Clock>,
+    /// Number of protocol frames kept for `AppContext::protocol_recorder`, only the
+    /// variant, peer, size and outcome of each. Zero disables recording.
+    pub protocol_trace_size: usize,
+    /// Removal of peers not connected for a while, their messages are kept. `None`
+    /// keeps every peer.
+    pub peer_pruning: Option<PeerPrunePolicy>,
+    /// Threads of the runtime built with `RuntimeOptions::build` for embedders that
+    /// don't bring their own. `app_context::prepare_deps` takes the runtime ready-made.
+    pub runtime: RuntimeOptions,
+}
+
+/// Shape of the tokio runtime the chat runs on when the embedder doesn't pass one in.
+/// The default matches `Runtime::new`, one worker thread per core.
+#[derive(Clone, Debug, Default)]
+pub struct RuntimeOptions {
+    /// Worker threads, e.g. fewer on constrained devices. `None` uses one per core.
+    pub worker_threads: Option<usize>,
+    /// Name of the worker threads, shown in profilers and crash reports. `None`
+    /// keeps tokio's name.
+    pub thread_name: Option<String>,
+}
+
+impl RuntimeOptions {
+    /// A multi-threaded runtime with I/O and timers enabled.
+    pub fn build(&self) -> io::Result<Runtime> {
+        let mut builder = Builder::new_multi_thread();
+        builder.enable_all();
+        if let Some(worker_threads) = self.worker_threads {
+            // tokio panics on zero
+            builder.worker_threads(worker_threads.max(1));
+        }
+        if let Some(thread_name) = &self.thread_name {
+            builder.thread_name(thread_name);
+        }
+        builder.build()
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            handshake_context: b"p2p-chat".to_vec(),
+            handshake_scheme: HandshakeScheme::default(),
+            resumption_ttl: None,
+            global_ordering: true,
+            listen_backlog: 1024,
+            stream_options: StreamOptions::default(),
+            session_options: SessionOptions::default(),
+            inbound_policy: InboundPolicy::default(),
+            db_max_connections: SYNC_WORKERS as u32 + 2,
+            decrypt_failure_policy: DecryptFailurePolicy::default(),
+            text_policy: TextPolicy::default(),
+            coalesce_batch_events: false,
+            max_clock_skew: None,
+            compress_payloads: false,
+            inline_file_limit: 16 * 1024,
+            max_upload_size: None,
+            max_upload_in_flight: Some(1024 * 1024),
+            file_bandwidth: BandwidthLimits::default(),
+            observer: false,
+            unknown_peer_policy: UnknownPeerPolicy::default(),
+            max_concurrent_dials: 8,
+            max_pending_tasks: Some(1000),
+            clock: Arc::new(SystemClock),
+            protocol_trace_size: 0,
+            peer_pruning: None,
+            runtime: RuntimeOptions::default(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn runtime_options_shape_the_runtime() {
+        let options = RuntimeOptions {
+            worker_threads: Some(2),
+            thread_name: Some("chat-worker".to_owned()),
+        };
+        let runtime = options.build().unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 2);
+        let name = runtime
+            .block_on(runtime.spawn(async { std::thread::current().name().map(str::to_owned) }))
+            .unwrap();
+        assert_eq!(name.as_deref(), Some("chat-worker"));
+    }
+
+    #[test]
+    fn zero_worker_threads_still_builds_a_runtime() {
+        let options = RuntimeOptions {
+            worker_threads: Some(0),
+            ..RuntimeOptions::default()
+        };
+        let runtime = options.build().unwrap();
+        assert_eq!(runtime.metrics().num_workers(), 1);
+        assert_eq!(runtime.block_on(runtime.spawn(async { 1 })).unwrap(), 1);
+    }
+}
@@ -1,66 +1,384 @@
 use aes_gcm::{
-    aead::{Aead, KeyInit},
-    Aes256Gcm, Key, Nonce,
+    aead::{self, AeadInPlace, KeyInit},
+    Aes256Gcm,
 };
-use bytes::BytesMut;
+use aes_gcm_siv::Aes256GcmSiv;
+use bytes::{Buf, Bytes, BytesMut};
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+use std::future::Future;
 use std::task::ready;
 use std::{
     pin::Pin,
     task::{Context, Poll},
+    time::Duration,
 };
 use tokio::io::{self, AsyncRead, AsyncWrite, ReadBuf};
+use tokio::time::Sleep;
 
 const NONCE_SIZE: usize = 12;
+const TAG_SIZE: usize = 16;
+const LEN_SIZE: usize = 2;
+/// Largest frame body (nonce and ciphertext), the frame length is encoded as `u16`.
+pub const MAX_FRAME_LEN: usize = u16::MAX as usize;
+/// Largest plaintext that fits into a single frame.
+pub const MAX_PLAINTEXT_LEN: usize = MAX_FRAME_LEN - NONCE_SIZE - TAG_SIZE;
 type SymKey = [u8; 32];
 
+/// Layout of the frames of a session. Both sides must use the same version, it is
+/// bound into the session key so peers on different versions fail the handshake.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum FrameVersion {
+    /// `len | nonce | ciphertext`, the original format.
+    #[default]
+    V0,
+    /// `len | header | nonce | ciphertext`, the header byte carries the version and
+    /// leaves room for flags. It is authenticated together with the ciphertext.
+    V1,
+}
+
+impl FrameVersion {
+    fn header(self) -> &'static [u8] {
+        match self {
+            FrameVersion::V0 => &[],
+            FrameVersion::V1 => &[0x01],
+        }
+    }
+
+    /// HKDF info of the handshake for this version. Version 0 keeps the plain context
+    /// so it still talks to peers that predate frame versions.
+    pub fn handshake_context(self, context: &[u8]) -> Vec<u8> {
+        match self {
+            FrameVersion::V0 => context.to_vec(),
+            FrameVersion::V1 => [context, b"/frame-v1"].concat(),
+        }
+    }
+}
+
+/// AEAD used to seal frames, both sides of a connection must use the same one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum CipherSuite {
+    #[default]
+    Aes256Gcm,
+    /// Nonce misuse-resistant, a repeated random nonce only reveals repeated plaintexts.
+    /// Slower than plain AES-GCM.
+    Aes256GcmSiv,
+}
+
+impl CipherSuite {
+    /// HKDF info of the handshake for this suite. AES-GCM keeps the plain context so
+    /// it still talks to peers that predate cipher suites.
+    pub fn handshake_context(self, context: &[u8]) -> Vec<u8> {
+        match self {
+            CipherSuite::Aes256Gcm => context.to_vec(),
+            CipherSuite::Aes256GcmSiv => [context, b"/aes-256-gcm-siv"].concat(),
+        }
+    }
+}
+
+/// Per-connection settings of `EncryptedStream`. The defaults keep memory use low
+/// for mobile, file heavy deployments can raise the read sizes.
+#[derive(Clone, Copy, Debug)]
+pub struct StreamOptions {
+    /// AEAD sealing the frames. It is not negotiated but bound into the session key
+    /// like the frame version, peers with different suites fail the handshake.
+    pub cipher_suite: CipherSuite,
+    /// Frame layout, see `FrameVersion`.
+    pub frame_version: FrameVersion,
+    /// Largest frame accepted from and written to the peer, at most `MAX_FRAME_LEN`.
+    pub max_frame_len: usize,
+    /// Bytes requested from the socket per read.
+    pub read_chunk_size: usize,
+    /// Initial capacity of the buffer collecting encrypted frames.
+    pub read_buffer_capacity: usize,
+    /// How long the peer may send nothing in the middle of a frame before reads fail
+    /// with `TimedOut`. Every received byte restarts it, so a slow link finishes its
+    /// frames, and an idle connection between frames never times out.
+    pub stall_timeout: Duration,
+}
+
+impl StreamOptions {
+    /// HKDF info of the handshake, bound to the frame version and the cipher suite.
+    pub fn handshake_context(&self, context: &[u8]) -> Vec<u8> {
+        self.cipher_suite
+            .handshake_context(&self.frame_version.handshake_context(context))
+    }
+}
+
+impl Default for StreamOptions {
+    fn default() -> Self {
+        Self {
+            cipher_suite: CipherSuite::default(),
+            frame_version: FrameVersion::default(),
+            max_frame_len: MAX_FRAME_LEN,
+            read_chunk_size: 8192,
+            read_buffer_capacity: 1024,
+            stall_timeout: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Source of the `InvalidData` error returned when a frame fails authentication,
+/// see `is_decrypt_error`.
+#[derive(Debug)]
+pub struct DecryptError;
+
+impl std::fmt::Display for DecryptError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Decryption failed")
+    }
+}
+
+impl std::error::Error for DecryptError {}
+
+/// Whether a read failed because a frame was tampered with or sealed with another key.
+pub fn is_decrypt_error(err: &io::Error) -> bool {
+    err.get_ref()
+        .is_some_and(|inner| inner.is::<DecryptError>())
+}
+
+enum FrameCipher {
+    Gcm(Aes256Gcm),
+    GcmSiv(Aes256GcmSiv),
+}
+
+impl FrameCipher {
+    fn new(suite: CipherSuite, sym_key: &SymKey) -> Self {
+        match suite {
+            CipherSuite::Aes256Gcm => FrameCipher::Gcm(Aes256Gcm::new(sym_key.into())),
+            CipherSuite::Aes256GcmSiv => FrameCipher::GcmSiv(Aes256GcmSiv::new(sym_key.into())),
+        }
+    }
+
+    fn encode_frame(
+        &self,
+        version: FrameVersion,
+        nonce_bytes: &[u8; NONCE_SIZE],
+        plaintext: &[u8],
+        buffer: &mut BytesMut,
+    ) -> io::Result<()> {
+        match self {
+            FrameCipher::Gcm(cipher) => {
+                encode_frame(cipher, version, nonce_bytes, plaintext, buffer)
+            }
+            FrameCipher::GcmSiv(cipher) => {
+                encode_frame(cipher, version, nonce_bytes, plaintext, buffer)
+            }
+        }
+    }
+
+    fn decode_frame(&self, version: FrameVersion, frame_data: BytesMut) -> io::Result<Bytes> {
+        match self {
+            FrameCipher::Gcm(cipher) => decode_frame(cipher, version, frame_data),
+            FrameCipher::GcmSiv(cipher) => decode_frame(cipher, version, frame_data),
+        }
+    }
+}
+
+/// Encodes a single frame: `len (u16, big endian) | header | nonce (12 bytes) | ciphertext`,
+/// where `len` covers everything after it and the header depends on `version`. The frame
+/// is appended to `buffer` and encrypted where it lies, so a reused buffer costs no
+/// allocation per frame.
+pub fn encode_frame<C: AeadInPlace>(
+    cipher: &C,
+    version: FrameVersion,
+    nonce_bytes: &[u8; NONCE_SIZE],
+    plaintext: &[u8],
+    buffer: &mut BytesMut,
+) -> io::Result<()> {
+    let header = version.header();
+    if plaintext.len() > MAX_PLAINTEXT_LEN - header.len() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "Plaintext does not fit into a frame",
+        ));
+    }
+    let nonce = aead::Nonce::<C>::from_slice(nonce_bytes);
+    let frame_len = (header.len() + NONCE_SIZE + plaintext.len() + TAG_SIZE) as u16;
+    buffer.reserve(LEN_SIZE + frame_len as usize);
+    buffer.extend_from_slice(&frame_len.to_be_bytes());
+    buffer.extend_from_slice(header);
+    buffer.extend_from_slice(nonce_bytes);
+    let start = buffer.len();
+    buffer.extend_from_slice(plaintext);
+    let tag = cipher
+        .encrypt_in_place_detached(nonce, header, &mut buffer[start..])
+        .map_err(|_| io::Error::other("Encryption failed"))?;
+    buffer.extend_from_slice(&tag);
+    Ok(())
+}
+
+/// Parses the length prefix of a frame, rejecting frames too short to hold the
+/// header and a nonce or longer than `max_frame_len`.
+pub fn decode_frame_len(
+    len_bytes: [u8; LEN_SIZE],
+    version: FrameVersion,
+    max_frame_len: usize,
+) -> io::Result<usize> {
+    let frame_len = u16::from_be_bytes(len_bytes) as usize;
+    if frame_len < version.header().len() + NONCE_SIZE {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame length smaller than nonce size",
+        ));
+    }
+    if frame_len > max_frame_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame length exceeds the maximum",
+        ));
+    }
+    Ok(frame_len)
+}
+
+/// Decrypts the body of a frame (`header | nonce | ciphertext`, without the length prefix)
+/// in place and returns the plaintext, which shares the allocation of `frame_data`.
+pub fn decode_frame<C: AeadInPlace>(
+    cipher: &C,
+    version: FrameVersion,
+    mut frame_data: BytesMut,
+) -> io::Result<Bytes> {
+    let header = version.header();
+    let prefix_len = header.len() + NONCE_SIZE;
+    if frame_data.len() < prefix_len {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame length smaller than nonce size",
+        ));
+    }
+    // too short for a tag, the frame can't authenticate
+    if frame_data.len() < prefix_len + TAG_SIZE {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, DecryptError));
+    }
+    let (prefix, body) = frame_data.split_at_mut(prefix_len);
+    let (frame_header, nonce_bytes) = prefix.split_at(header.len());
+    if frame_header != header {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Unsupported frame version",
+        ));
+    }
+    let nonce = aead::Nonce::<C>::from_slice(nonce_bytes);
+    let (ciphertext, tag) = body.split_at_mut(body.len() - TAG_SIZE);
+    cipher
+        .decrypt_in_place_detached(nonce, header, ciphertext, aead::Tag::<C>::from_slice(tag))
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, DecryptError))?;
+    let plaintext_len = frame_data.len() - prefix_len - TAG_SIZE;
+    frame_data.advance(prefix_len);
+    frame_data.truncate(plaintext_len);
+    Ok(frame_data.freeze())
+}
+
+/// Source of frame nonces.
+trait NonceRng: RngCore + CryptoRng + Send {}
+
+impl<R: RngCore + CryptoRng + Send> NonceRng for R {}
+
 enum ReadState {
     ReadingLength,
     ReadingFrame { frame_len: usize },
+    /// A frame failed to parse or decrypt, nothing read after it can be trusted.
+    Failed,
 }
 
 enum WriteState {
     Idle,
-    WritingFrame {
-        buffer: BytesMut,
-        offset: usize,
-        data_len: usize,
-    },
+    /// The frame in `write_buffer` is written up to `offset`.
+    WritingFrame { offset: usize, data_len: usize },
 }
 
+/// Seals everything written into frames, see `encode_frame`. A write longer than
+/// the plaintext of one frame is accepted only up to what fits and the returned
+/// count says how much, as `AsyncWrite` allows. `write_all` and yamux keep writing
+/// the rest, callers of `poll_write` must do the same. A frame that fails to parse or
+/// decrypt fails the read and every read after it.
 pub struct EncryptedStream<S> {
     inner: S,
-    cipher: Aes256Gcm,
+    cipher: FrameCipher,
+    frame_version: FrameVersion,
+    nonce_rng: Box<dyn NonceRng>,
 
     read_buffer: BytesMut,
-    decrypted_buffer: BytesMut,
+    read_chunk: Vec<u8>,
+    // holds at most one decrypted frame, a new frame is only read once it is drained
+    decrypted_buffer: Bytes,
     read_state: ReadState,
+    max_frame_len: usize,
+    stall_timeout: Duration,
+    stall_timer: Option<Pin<Box<Sleep>>>,
 
     write_state: WriteState,
+    // holds the frame being written, cleared and refilled for every frame
+    write_buffer: BytesMut,
 }
 
 impl<S: AsyncRead + AsyncWrite + Unpin> EncryptedStream<S> {
     pub fn new(inner: S, sym_key: &SymKey) -> Self {
+        Self::with_options(inner, sym_key, StreamOptions::default())
+    }
+
+    /// `options.max_frame_len` limits both the frames accepted from the peer and the frames
+    /// written to it, which bounds the memory a single frame can pin while it is being read.
+    pub fn with_options(inner: S, sym_key: &SymKey, options: StreamOptions) -> Self {
+        Self::with_rng(inner, sym_key, options, OsRng)
+    }
+
+    /// Draws frame nonces from `rng` instead of the OS, so tests get reproducible frames.
+    pub fn with_rng<R>(inner: S, sym_key: &SymKey, options: StreamOptions, rng: R) -> Self
+    where
+        R: RngCore + CryptoRng + Send + 'static,
+    {
         Self {
             inner,
-            cipher: Aes256Gcm::new(Key::<aes_gcm::aes::Aes256>::from_slice(sym_key)),
-            read_buffer: BytesMut::with_capacity(1024),
-            decrypted_buffer: BytesMut::new(),
+            cipher: FrameCipher::new(options.cipher_suite, sym_key),
+            frame_version: options.frame_version,
+            nonce_rng: Box::new(rng),
+            read_buffer: BytesMut::with_capacity(options.read_buffer_capacity),
+            read_chunk: vec![0u8; options.read_chunk_size.max(1)],
+            decrypted_buffer: Bytes::new(),
             read_state: ReadState::ReadingLength,
+            max_frame_len: options.max_frame_len.clamp(
+                options.frame_version.header().len() + NONCE_SIZE + TAG_SIZE + 1,
+                MAX_FRAME_LEN,
+            ),
+            stall_timeout: options.stall_timeout,
+            stall_timer: None,
             write_state: WriteState::Idle,
+            write_buffer: BytesMut::new(),
+        }
+    }
+
+    /// Called when the socket has no data. Waiting is fine between frames, but a peer
+    /// that stops halfway through a frame would otherwise wedge the session forever.
+    fn poll_stall(&mut self, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        let mid_frame = !self.read_buffer.is_empty()
+            || matches!(self.read_state, ReadState::ReadingFrame { .. });
+        if !mid_frame {
+            self.stall_timer = None;
+            return Poll::Pending;
         }
+        let stall_timeout = self.stall_timeout;
+        let timer = self
+            .stall_timer
+            .get_or_insert_with(|| Box::pin(tokio::time::sleep(stall_timeout)));
+        ready!(timer.as_mut().poll(cx));
+        Poll::Ready(Err(io::Error::new(
+            io::ErrorKind::TimedOut,
+            "Peer stalled in the middle of a frame",
+        )))
     }
 }
 
 fn read_more<S>(
     inner: &mut S,
+    read_chunk: &mut [u8],
     read_buffer: &mut BytesMut,
     cx: &mut Context<'_>,
 ) -> Poll<io::Result<usize>>
 where
     S: AsyncRead + Unpin,
 {
-    let mut local_buf = [0u8; 8192];
-    let mut read_buf = ReadBuf::new(&mut local_buf);
+    let mut read_buf = ReadBuf::new(read_chunk);
 
     match Pin::new(inner).poll_read(cx, &mut read_buf) {
         Poll::Pending => Poll::Pending,
@@ -85,33 +403,66 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<S> {
             if !self.decrypted_buffer.is_empty() {
                 let to_read = std::cmp::min(self.decrypted_buffer.len(), buf.remaining());
                 buf.put_slice(&self.decrypted_buffer.split_to(to_read));
+                if self.decrypted_buffer.is_empty() {
+                    // the plaintext shares the read buffer's allocation, letting go of
+                    // it lets the read buffer reuse its memory for the next frame
+                    self.decrypted_buffer = Bytes::new();
+                }
                 return Poll::Ready(Ok(()));
             }
 
             let this = self.as_mut().get_mut();
             match &mut this.read_state {
                 ReadState::ReadingLength => {
-                    if this.read_buffer.len() < 2 {
-                        let n = ready!(read_more(&mut this.inner, &mut this.read_buffer, cx))?;
-                        if n == 0 && this.read_buffer.is_empty() {
-                            return Poll::Ready(Ok(()));
+                    if this.read_buffer.len() < LEN_SIZE {
+                        let n = match read_more(
+                            &mut this.inner,
+                            &mut this.read_chunk,
+                            &mut this.read_buffer,
+                            cx,
+                        ) {
+                            Poll::Ready(res) => res?,
+                            Poll::Pending => return this.poll_stall(cx),
+                        };
+                        if n > 0 {
+                            // the peer is still sending, only silence counts as a stall
+                            this.stall_timer = None;
+                        }
+                        if n == 0 {
+                            if this.read_buffer.is_empty() {
+                                return Poll::Ready(Ok(()));
+                            }
+                            return Poll::Ready(Err(io::Error::new(
+                                io::ErrorKind::UnexpectedEof,
+                                "EOF in the middle of a frame length",
+                            )));
                         }
                         continue;
                     }
-                    let len_bytes = this.read_buffer.split_to(2);
-                    let frame_len = u16::from_be_bytes([len_bytes[0], len_bytes[1]]) as usize;
-                    if frame_len < NONCE_SIZE {
-                        return Poll::Ready(Err(io::Error::new(
-                            io::ErrorKind::InvalidData,
-                            "Frame length smaller than nonce size",
-                        )));
-                    }
+                    let len_bytes = this.read_buffer.split_to(LEN_SIZE);
+                    let frame_len = decode_frame_len(
+                        [len_bytes[0], len_bytes[1]],
+                        this.frame_version,
+                        this.max_frame_len,
+                    )
+                    .inspect_err(|_| this.read_state = ReadState::Failed)?;
                     this.read_state = ReadState::ReadingFrame { frame_len };
                 }
 
                 ReadState::ReadingFrame { frame_len } => {
                     if this.read_buffer.len() < *frame_len {
-                        let n = ready!(read_more(&mut this.inner, &mut this.read_buffer, cx))?;
+                        let n = match read_more(
+                            &mut this.inner,
+                            &mut this.read_chunk,
+                            &mut this.read_buffer,
+                            cx,
+                        ) {
+                            Poll::Ready(res) => res?,
+                            Poll::Pending => return this.poll_stall(cx),
+                        };
+                        if n > 0 {
+                            this.stall_timer = None;
+                        }
                         if n == 0 && this.read_buffer.len() < *frame_len {
                             return Poll::Ready(Err(io::Error::new(
                                 io::ErrorKind::UnexpectedEof,
@@ -121,15 +472,21 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for EncryptedStream<S> {
                         continue;
                     }
 
+                    // decrypted where it was read, no copy of the plaintext is made
                     let frame_data = this.read_buffer.split_to(*frame_len);
-                    let (nonce_bytes, ciphertext) = frame_data.split_at(NONCE_SIZE);
-                    let nonce = Nonce::from_slice(nonce_bytes);
-                    let plaintext = this.cipher.decrypt(nonce, ciphertext).map_err(|_| {
-                        io::Error::new(io::ErrorKind::InvalidData, "Decryption failed")
-                    })?;
-
-                    this.decrypted_buffer.extend_from_slice(&plaintext);
+                    this.decrypted_buffer = this
+                        .cipher
+                        .decode_frame(this.frame_version, frame_data)
+                        .inspect_err(|_| this.read_state = ReadState::Failed)?;
                     this.read_state = ReadState::ReadingLength;
+                    this.stall_timer = None;
+                }
+
+                ReadState::Failed => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Stream failed on an earlier frame",
+                    )));
                 }
             }
         }
@@ -144,47 +501,42 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
     ) -> Poll<io::Result<usize>> {
         let this = self.as_mut().get_mut();
         let cipher = &this.cipher;
+        let frame_version = this.frame_version;
+        let nonce_rng = &mut this.nonce_rng;
+        let max_plaintext_len =
+            this.max_frame_len - frame_version.header().len() - NONCE_SIZE - TAG_SIZE;
         let write_state = &mut this.write_state;
+        let buffer = &mut this.write_buffer;
         let inner = &mut this.inner;
 
         loop {
             match write_state {
                 WriteState::Idle => {
                     let mut nonce_bytes = [0u8; NONCE_SIZE];
-                    getrandom::getrandom(&mut nonce_bytes)?;
-                    let nonce = Nonce::from_slice(&nonce_bytes);
-
-                    let ciphertext = cipher
-                        .encrypt(nonce, data)
-                        .map_err(|_| io::Error::new(io::ErrorKind::Other, "Encryption failed"))?;
-
-                    let frame_len = (NONCE_SIZE + ciphertext.len()) as u16;
-                    let mut buffer = BytesMut::with_capacity(2 + NONCE_SIZE + ciphertext.len());
-                    buffer.extend_from_slice(&frame_len.to_be_bytes());
-                    buffer.extend_from_slice(&nonce_bytes);
-                    buffer.extend_from_slice(&ciphertext);
+                    nonce_rng.try_fill_bytes(&mut nonce_bytes)?;
+                    // larger writes are split, the caller gets the number of bytes that fit
+                    let data = &data[..std::cmp::min(data.len(), max_plaintext_len)];
+                    buffer.clear();
+                    cipher.encode_frame(frame_version, &nonce_bytes, data, buffer)?;
 
                     *write_state = WriteState::WritingFrame {
-                        buffer,
                         offset: 0,
                         data_len: data.len(),
                     };
                 }
-                WriteState::WritingFrame {
-                    buffer,
-                    offset,
-                    data_len,
-                } => {
-                    let pinned_inner = Pin::new(inner);
-                    let n = ready!(pinned_inner.poll_write(cx, &buffer[*offset..]))?;
+                WriteState::WritingFrame { offset, data_len } => {
+                    // a short write keeps going until the socket is full, so a
+                    // pending result always comes with a registered waker
+                    let n = ready!(Pin::new(&mut *inner).poll_write(cx, &buffer[*offset..]))?;
+                    if n == 0 {
+                        return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+                    }
                     *offset += n;
 
                     if *offset >= buffer.len() {
                         let data_len = *data_len;
                         *write_state = WriteState::Idle;
                         return Poll::Ready(Ok(data_len));
-                    } else {
-                        return Poll::Pending;
                     }
                 }
             }
@@ -194,27 +546,21 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
     fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
         let this = self.as_mut().get_mut();
         let write_state = &mut this.write_state; // Mutable borrow of the write_state
+        let buffer = &this.write_buffer;
         let inner = &mut this.inner;
 
-        match write_state {
-            WriteState::Idle => Pin::new(inner).poll_flush(cx),
-            WriteState::WritingFrame {
-                buffer,
-                offset,
-                data_len,
-            } => {
-                if *offset < buffer.len() {
-                    let n = ready!(Pin::new(inner).poll_write(cx, &buffer[*offset..]))?;
-                    *offset += n;
-                }
-                if *offset >= buffer.len() {
-                    self.write_state = WriteState::Idle;
-                    Pin::new(&mut self.inner).poll_flush(cx)
-                } else {
-                    Poll::Pending
-                }
+        while let WriteState::WritingFrame { offset, .. } = write_state {
+            if *offset >= buffer.len() {
+                *write_state = WriteState::Idle;
+                break;
             }
+            let n = ready!(Pin::new(&mut *inner).poll_write(cx, &buffer[*offset..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            *offset += n;
         }
+        Pin::new(inner).poll_flush(cx)
     }
 
     fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
@@ -222,3 +568,608 @@ impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for EncryptedStream<S> {
         Pin::new(&mut self.inner).poll_shutdown(cx)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sha2::{Digest, Sha256};
+    use tokio::io::{duplex, AsyncReadExt, AsyncWriteExt, DuplexStream};
+    use tokio::time::{sleep, timeout};
+
+    const KEY: SymKey = [7; 32];
+
+    fn stalling_after(stall_timeout: Duration) -> StreamOptions {
+        StreamOptions {
+            stall_timeout,
+            ..StreamOptions::default()
+        }
+    }
+
+    /// A stream reading what the test writes into the returned end.
+    fn reader(options: StreamOptions) -> (EncryptedStream<DuplexStream>, DuplexStream) {
+        let (ours, theirs) = duplex(MAX_FRAME_LEN * 2);
+        (EncryptedStream::with_options(ours, &KEY, options), theirs)
+    }
+
+    fn sealed(plaintext: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        encode_frame(
+            &Aes256Gcm::new(&KEY.into()),
+            FrameVersion::V0,
+            &[1; NONCE_SIZE],
+            plaintext,
+            &mut frame,
+        )
+        .unwrap();
+        frame
+    }
+
+    #[tokio::test]
+    async fn silent_peer_never_stalls() {
+        let (mut stream, _peer) = reader(stalling_after(Duration::from_millis(50)));
+        let mut buf = [0u8; 16];
+        // connected but nothing sent, the read just keeps waiting
+        assert!(timeout(Duration::from_millis(300), stream.read(&mut buf))
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn peer_silent_mid_frame_stalls() {
+        let (mut stream, mut peer) = reader(stalling_after(Duration::from_millis(50)));
+        let frame = sealed(b"hello");
+        peer.write_all(&frame[..LEN_SIZE + 1]).await.unwrap();
+        let mut buf = [0u8; 16];
+        let err = timeout(Duration::from_secs(5), stream.read(&mut buf))
+            .await
+            .expect("stall was not detected")
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn peer_closing_mid_length_is_an_early_eof() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        peer.write_all(&sealed(b"hello")[..1]).await.unwrap();
+        peer.shutdown().await.unwrap();
+        // spawned, a read that spins instead of failing would never let a timeout fire
+        let read = tokio::spawn(async move {
+            let mut buf = [0u8; 16];
+            stream.read(&mut buf).await
+        });
+        let err = timeout(Duration::from_secs(5), read)
+            .await
+            .expect("read kept spinning after the peer closed")
+            .unwrap()
+            .unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn frame_arriving_in_pieces_is_reassembled() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        let mut frames = sealed(b"hello");
+        frames.extend_from_slice(&sealed(b" world"));
+        for byte in frames.iter() {
+            peer.write_all(&[*byte]).await.unwrap();
+        }
+        let mut buf = [0u8; 11];
+        stream.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"hello world");
+    }
+
+    #[tokio::test]
+    async fn empty_frame_is_not_the_end_of_the_stream() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        peer.write_all(&sealed(b"")).await.unwrap();
+        peer.write_all(&sealed(b"hi")).await.unwrap();
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hi");
+    }
+
+    #[tokio::test]
+    async fn corrupted_frame_fails_the_stream() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        let mut corrupted = sealed(b"hello");
+        corrupted[LEN_SIZE + NONCE_SIZE] ^= 0x01;
+        peer.write_all(&corrupted).await.unwrap();
+        peer.write_all(&sealed(b"world")).await.unwrap();
+        let mut buf = [0u8; 16];
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(is_decrypt_error(&err));
+        // the intact frame after it is not handed out either
+        let err = stream.read(&mut buf).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(buf, [0u8; 16]);
+    }
+
+    #[tokio::test]
+    async fn long_write_is_split_into_frames() {
+        let (ours, theirs) = duplex(MAX_FRAME_LEN * 4);
+        let mut writer = EncryptedStream::new(ours, &KEY);
+        let mut reader = EncryptedStream::new(theirs, &KEY);
+        let data: Vec<u8> = (0..MAX_PLAINTEXT_LEN * 2 + 10).map(|i| i as u8).collect();
+        // a single write takes what fits into one frame
+        assert_eq!(writer.write(&data).await.unwrap(), MAX_PLAINTEXT_LEN);
+        writer.write_all(&data[MAX_PLAINTEXT_LEN..]).await.unwrap();
+        writer.flush().await.unwrap();
+        let mut received = vec![0u8; data.len()];
+        reader.read_exact(&mut received).await.unwrap();
+        assert_eq!(received, data);
+    }
+
+    #[test]
+    fn frames_decrypt_where_they_were_read() {
+        for version in [FrameVersion::V0, FrameVersion::V1] {
+            let mut frame = vector_frame(CipherSuite::Aes256Gcm, version, b"hello");
+            let body = frame.split_off(LEN_SIZE);
+            let ciphertext = body[version.header().len() + NONCE_SIZE..].as_ptr();
+            let plaintext = FrameCipher::new(CipherSuite::Aes256Gcm, &vector_key())
+                .decode_frame(version, body)
+                .unwrap();
+            assert_eq!(plaintext, &b"hello"[..]);
+            // the plaintext is the ciphertext's memory, nothing was copied
+            assert_eq!(plaintext.as_ptr(), ciphertext);
+        }
+    }
+
+    #[tokio::test]
+    async fn stream_decrypts_in_the_read_buffer() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        peer.write_all(&sealed(&[3u8; 1000])).await.unwrap();
+        peer.write_all(&sealed(b"next")).await.unwrap();
+        let mut first = [0u8; 1];
+        stream.read_exact(&mut first).await.unwrap();
+        // the rest of the plaintext sits right before the tag, followed by the next frame
+        let plaintext = stream.decrypted_buffer.as_ptr_range();
+        assert_eq!(stream.decrypted_buffer.len(), 999);
+        assert_eq!(
+            plaintext.end.wrapping_add(TAG_SIZE),
+            stream.read_buffer.as_ptr()
+        );
+    }
+
+    #[test]
+    fn frames_are_appended_to_the_buffer() {
+        let cipher = Aes256Gcm::new(&KEY.into());
+        let mut buffer = BytesMut::new();
+        encode_frame(
+            &cipher,
+            FrameVersion::V0,
+            &[1; NONCE_SIZE],
+            b"hello",
+            &mut buffer,
+        )
+        .unwrap();
+        let first_len = buffer.len();
+        encode_frame(
+            &cipher,
+            FrameVersion::V0,
+            &[2; NONCE_SIZE],
+            b"world",
+            &mut buffer,
+        )
+        .unwrap();
+        let second = buffer.split_off(first_len);
+        for (frame, plaintext) in [(buffer, b"hello"), (second, b"world")] {
+            let body = BytesMut::from(&frame[LEN_SIZE..]);
+            let opened = decode_frame(&cipher, FrameVersion::V0, body).unwrap();
+            assert_eq!(opened, &plaintext[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn write_buffer_is_reused_across_frames() {
+        let (ours, mut theirs) = duplex(MAX_FRAME_LEN * 4);
+        let mut writer = EncryptedStream::new(ours, &KEY);
+        writer.write_all(&[5u8; 10_000]).await.unwrap();
+        let buffer = writer.write_buffer.as_ptr();
+        let capacity = writer.write_buffer.capacity();
+        for _ in 0..50 {
+            writer.write_all(&[6u8; 10]).await.unwrap();
+            writer.flush().await.unwrap();
+            // the small frames are encrypted in the allocation of the first one
+            assert_eq!(writer.write_buffer.as_ptr(), buffer);
+            assert_eq!(writer.write_buffer.capacity(), capacity);
+        }
+        drop(writer);
+        let mut reader = EncryptedStream::new(&mut theirs, &KEY);
+        let mut received = Vec::new();
+        reader.read_to_end(&mut received).await.unwrap();
+        assert_eq!(received.len(), 10_000 + 50 * 10);
+        assert!(received[..10_000].iter().all(|byte| *byte == 5));
+        assert!(received[10_000..].iter().all(|byte| *byte == 6));
+    }
+
+    /// Key and nonce of the vectors, 0x00, 0x01, ... so other implementations can
+    /// reproduce them.
+    fn vector_key() -> SymKey {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn vector_nonce() -> [u8; NONCE_SIZE] {
+        std::array::from_fn(|i| i as u8)
+    }
+
+    fn vector_frame(suite: CipherSuite, version: FrameVersion, plaintext: &[u8]) -> BytesMut {
+        let mut frame = BytesMut::new();
+        FrameCipher::new(suite, &vector_key())
+            .encode_frame(version, &vector_nonce(), plaintext, &mut frame)
+            .unwrap();
+        frame
+    }
+
+    fn open_vector(suite: CipherSuite, version: FrameVersion, frame: &[u8]) -> io::Result<Bytes> {
+        let frame_len = decode_frame_len([frame[0], frame[1]], version, MAX_FRAME_LEN)?;
+        assert_eq!(frame_len, frame.len() - LEN_SIZE);
+        FrameCipher::new(suite, &vector_key())
+            .decode_frame(version, BytesMut::from(&frame[LEN_SIZE..]))
+    }
+
+    // computed with an independent AES-GCM implementation
+    const GCM_EMPTY_V0: &str = "001c000102030405060708090a0bf4c2db1dc38805a37b92171c5d0a81cc";
+    const GCM_EMPTY_V1: &str = "001d01000102030405060708090a0b021c41a565ec06057d1731aa725debe8";
+    const GCM_HELLO_V0: &str =
+        "0021000102030405060708090a0b2f67ba77aa2797ff353b8a046d28236dcd9d057bbb";
+    /// SHA-256 of the largest frames, the plaintext is `i % 251` for every index.
+    const GCM_MAX_V0_SHA256: &str =
+        "9aa632867a3315850a7f9de06646257de0b99b42adaa0a5c55818f55e4c0ae9f";
+    const GCM_MAX_V1_SHA256: &str =
+        "8b49f4cc674256d507d6c09cc99a757ba5ca3424230e4ddd0502753b0ce0ed37";
+
+    #[test]
+    fn empty_plaintext_matches_the_vectors() {
+        for (version, vector) in [
+            (FrameVersion::V0, GCM_EMPTY_V0),
+            (FrameVersion::V1, GCM_EMPTY_V1),
+        ] {
+            let frame = vector_frame(CipherSuite::Aes256Gcm, version, b"");
+            assert_eq!(hex::encode(&frame), vector);
+            let plaintext = open_vector(CipherSuite::Aes256Gcm, version, &frame).unwrap();
+            assert!(plaintext.is_empty());
+        }
+    }
+
+    #[test]
+    fn max_size_frames_match_the_vectors() {
+        for (version, digest) in [
+            (FrameVersion::V0, GCM_MAX_V0_SHA256),
+            (FrameVersion::V1, GCM_MAX_V1_SHA256),
+        ] {
+            let max_len = MAX_PLAINTEXT_LEN - version.header().len();
+            let plaintext: Vec<u8> = (0..max_len).map(|i| (i % 251) as u8).collect();
+            let frame = vector_frame(CipherSuite::Aes256Gcm, version, &plaintext);
+            assert_eq!(frame.len(), LEN_SIZE + MAX_FRAME_LEN);
+            assert_eq!(hex::encode(Sha256::digest(&frame)), digest);
+            let opened = open_vector(CipherSuite::Aes256Gcm, version, &frame).unwrap();
+            assert_eq!(opened, plaintext);
+
+            let err = FrameCipher::new(CipherSuite::Aes256Gcm, &vector_key())
+                .encode_frame(
+                    version,
+                    &vector_nonce(),
+                    &vec![0u8; max_len + 1],
+                    &mut BytesMut::new(),
+                )
+                .unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        }
+    }
+
+    #[test]
+    fn tampered_frames_fail_to_decrypt() {
+        let frame = hex::decode(GCM_HELLO_V0).unwrap();
+        let opened = open_vector(CipherSuite::Aes256Gcm, FrameVersion::V0, &frame).unwrap();
+        assert_eq!(opened, &b"hello"[..]);
+        // nonce, ciphertext and tag are all covered
+        for i in LEN_SIZE..frame.len() {
+            let mut tampered = frame.clone();
+            tampered[i] ^= 0x01;
+            let err = open_vector(CipherSuite::Aes256Gcm, FrameVersion::V0, &tampered).unwrap_err();
+            assert!(is_decrypt_error(&err), "byte {}: {}", i, err);
+        }
+        // the header is authenticated too, an unknown one is refused before decrypting
+        let mut frame = hex::decode(GCM_EMPTY_V1).unwrap();
+        frame[LEN_SIZE] = 0x02;
+        let err = open_vector(CipherSuite::Aes256Gcm, FrameVersion::V1, &frame).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!is_decrypt_error(&err));
+    }
+
+    #[test]
+    fn frames_too_short_or_too_long_are_rejected() {
+        let short = (NONCE_SIZE as u16 - 1).to_be_bytes();
+        let err = decode_frame_len(short, FrameVersion::V0, MAX_FRAME_LEN).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        let short = (NONCE_SIZE as u16).to_be_bytes();
+        assert!(decode_frame_len(short, FrameVersion::V1, MAX_FRAME_LEN).is_err());
+        assert!(decode_frame_len(1024u16.to_be_bytes(), FrameVersion::V0, 1023).is_err());
+        // a nonce but no room for a tag can't authenticate
+        let err = FrameCipher::new(CipherSuite::Aes256Gcm, &vector_key())
+            .decode_frame(
+                FrameVersion::V0,
+                BytesMut::from(&[0u8; NONCE_SIZE + TAG_SIZE - 1][..]),
+            )
+            .unwrap_err();
+        assert!(is_decrypt_error(&err));
+    }
+
+    /// Records how many bytes each read of the stream it wraps asks for.
+    struct RecordReads {
+        inner: DuplexStream,
+        reads: Vec<usize>,
+    }
+
+    impl AsyncRead for RecordReads {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            let asked = buf.remaining();
+            let res = Pin::new(&mut self.inner).poll_read(cx, buf);
+            if res.is_ready() {
+                self.reads.push(asked);
+            }
+            res
+        }
+    }
+
+    impl AsyncWrite for RecordReads {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Reads ten 1000 byte frames with `read_chunk_size`, returns the reads of the socket.
+    async fn socket_reads(read_chunk_size: usize) -> Vec<usize> {
+        let (ours, mut theirs) = duplex(MAX_FRAME_LEN * 2);
+        let options = StreamOptions {
+            read_chunk_size,
+            ..StreamOptions::default()
+        };
+        let inner = RecordReads {
+            inner: ours,
+            reads: Vec::new(),
+        };
+        let mut stream = EncryptedStream::with_options(inner, &KEY, options);
+        let plaintext = [9u8; 1000];
+        for _ in 0..10 {
+            theirs.write_all(&sealed(&plaintext)).await.unwrap();
+        }
+        let mut received = vec![0u8; plaintext.len() * 10];
+        stream.read_exact(&mut received).await.unwrap();
+        assert!(received.iter().all(|byte| *byte == 9));
+        stream.inner.reads
+    }
+
+    #[tokio::test]
+    async fn socket_reads_ask_for_the_read_chunk_size() {
+        let small = socket_reads(64).await;
+        assert!(small.iter().all(|asked| *asked == 64), "{:?}", small);
+        let large = socket_reads(16 * 1024).await;
+        assert!(large.iter().all(|asked| *asked == 16 * 1024), "{:?}", large);
+        // the whole input is buffered, so larger reads take fewer of them
+        assert!(
+            large.len() < small.len() / 10,
+            "{} vs {}",
+            large.len(),
+            small.len()
+        );
+        // a zero chunk would never make progress
+        assert!(socket_reads(0).await.iter().all(|asked| *asked == 1));
+    }
+
+    #[test]
+    fn read_buffer_starts_at_its_capacity() {
+        let (ours, _theirs) = duplex(64);
+        let options = StreamOptions {
+            read_buffer_capacity: 64 * 1024,
+            ..StreamOptions::default()
+        };
+        let stream = EncryptedStream::with_options(ours, &KEY, options);
+        assert!(stream.read_buffer.capacity() >= 64 * 1024);
+    }
+
+    #[tokio::test]
+    async fn largest_frames_read_in_small_pieces_stay_bounded() {
+        let (mut stream, mut peer) = reader(StreamOptions::default());
+        let plaintext: Vec<u8> = (0..MAX_PLAINTEXT_LEN).map(|i| i as u8).collect();
+        let frame = sealed(&plaintext);
+        assert_eq!(frame.len(), LEN_SIZE + MAX_FRAME_LEN);
+        let writer = tokio::spawn(async move {
+            for _ in 0..8 {
+                peer.write_all(&frame).await.unwrap();
+            }
+            peer
+        });
+
+        // enough frames that memory kept from each would blow the bound
+        for _ in 0..8 {
+            let mut received = Vec::with_capacity(plaintext.len());
+            let mut piece = [0u8; 100];
+            while received.len() < plaintext.len() {
+                let n = stream.read(&mut piece).await.unwrap();
+                received.extend_from_slice(&piece[..n]);
+                // a frame being drained and the next being read, whatever the read size
+                assert!(
+                    stream.read_buffer.capacity() + stream.decrypted_buffer.len()
+                        <= 2 * (LEN_SIZE + MAX_FRAME_LEN) + stream.read_chunk.len()
+                );
+                assert!(stream.decrypted_buffer.len() <= MAX_PLAINTEXT_LEN);
+            }
+            assert_eq!(received, plaintext);
+        }
+        writer.await.unwrap();
+    }
+
+    // computed with an independent AES-GCM-SIV implementation
+    const SIV_EMPTY_V0: &str = "001c000102030405060708090a0bd8658c39ee886a7711e7557535c5b89c";
+    const SIV_HELLO_V0: &str =
+        "0021000102030405060708090a0bfd6e1082bf94aae0926164db28900995e3e0d475f2";
+    const SIV_HELLO_V1: &str =
+        "002201000102030405060708090a0b8fd527591a9e160823f67de00050d878d1c38c20e6";
+
+    #[test]
+    fn siv_frames_match_the_vectors() {
+        for (version, plaintext, vector) in [
+            (FrameVersion::V0, &b""[..], SIV_EMPTY_V0),
+            (FrameVersion::V0, &b"hello"[..], SIV_HELLO_V0),
+            (FrameVersion::V1, &b"hello"[..], SIV_HELLO_V1),
+        ] {
+            let frame = vector_frame(CipherSuite::Aes256GcmSiv, version, plaintext);
+            assert_eq!(hex::encode(&frame), vector);
+            let opened = open_vector(CipherSuite::Aes256GcmSiv, version, &frame).unwrap();
+            assert_eq!(opened, plaintext);
+            // a frame of the other suite doesn't open
+            let err = open_vector(CipherSuite::Aes256Gcm, version, &frame).unwrap_err();
+            assert!(is_decrypt_error(&err));
+        }
+        let mut tampered = hex::decode(SIV_HELLO_V0).unwrap();
+        tampered[LEN_SIZE + NONCE_SIZE] ^= 0x01;
+        let err = open_vector(CipherSuite::Aes256GcmSiv, FrameVersion::V0, &tampered).unwrap_err();
+        assert!(is_decrypt_error(&err));
+    }
+
+    /// A handshake between two nodes configured with `dialing` and `accepting`, then
+    /// a frame from the dialing side over the same connection.
+    async fn connect_with(dialing: StreamOptions, accepting: StreamOptions) -> io::Result<Bytes> {
+        use crate::handshake::{read_handshake, write_handshake};
+        use crate::handshake_scheme::HandshakeScheme;
+        use ed25519_dalek::SigningKey;
+
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let bob_id = hex::encode(bob.verifying_key().to_bytes());
+        let scheme = &HandshakeScheme::default();
+        let dialing_context = dialing.handshake_context(b"p2p-chat");
+        let accepting_context = accepting.handshake_context(b"p2p-chat");
+        let (mut a, mut b) = duplex(4096);
+        // each side owns its end, so the side that fails hangs up on the other
+        let (dialed, accepted) = tokio::join!(
+            async move {
+                let handshake =
+                    write_handshake(&mut a, &alice, scheme, &dialing_context, &bob_id, None)
+                        .await?;
+                io::Result::Ok((a, handshake))
+            },
+            async move {
+                let handshake =
+                    read_handshake(&mut b, &bob, scheme, &accepting_context, None).await?;
+                io::Result::Ok((b, handshake))
+            },
+        );
+        let ((a, dialed), (b, accepted)) = (dialed?, accepted?);
+        let mut writer = EncryptedStream::with_options(a, &dialed.symmetric_key, dialing);
+        let mut reader = EncryptedStream::with_options(b, &accepted.symmetric_key, accepting);
+        writer.write_all(b"hello").await?;
+        writer.flush().await?;
+        let mut buf = [0u8; 5];
+        reader.read_exact(&mut buf).await?;
+        Ok(Bytes::copy_from_slice(&buf))
+    }
+
+    fn with_suite(cipher_suite: CipherSuite) -> StreamOptions {
+        StreamOptions {
+            cipher_suite,
+            ..StreamOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn same_cipher_suite_talks() {
+        for suite in [CipherSuite::Aes256Gcm, CipherSuite::Aes256GcmSiv] {
+            let received = connect_with(with_suite(suite), with_suite(suite)).await;
+            assert_eq!(received.unwrap(), &b"hello"[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn different_cipher_suites_fail_the_handshake() {
+        let gcm = with_suite(CipherSuite::Aes256Gcm);
+        let siv = with_suite(CipherSuite::Aes256GcmSiv);
+        for (dialing, accepting) in [(gcm, siv), (siv, gcm)] {
+            let err = connect_with(dialing, accepting).await.unwrap_err();
+            assert!(
+                !is_decrypt_error(&err),
+                "failed only at the first frame: {}",
+                err
+            );
+        }
+    }
+
+    fn with_frame_version(frame_version: FrameVersion) -> StreamOptions {
+        StreamOptions {
+            frame_version,
+            ..StreamOptions::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn different_frame_versions_fail_the_handshake() {
+        let v0 = with_frame_version(FrameVersion::V0);
+        let v1 = with_frame_version(FrameVersion::V1);
+        assert_eq!(connect_with(v1, v1).await.unwrap(), &b"hello"[..]);
+        for (dialing, accepting) in [(v0, v1), (v1, v0)] {
+            let err = connect_with(dialing, accepting).await.unwrap_err();
+            assert!(
+                !is_decrypt_error(&err),
+                "failed only at the first frame: {}",
+                err
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn frames_of_another_version_are_refused() {
+        // the same key on both sides, as if the handshake had not told them apart
+        for (writing, reading) in [
+            (FrameVersion::V0, FrameVersion::V1),
+            (FrameVersion::V1, FrameVersion::V0),
+        ] {
+            let (ours, theirs) = duplex(4096);
+            let mut writer = EncryptedStream::with_options(ours, &KEY, with_frame_version(writing));
+            let mut reader =
+                EncryptedStream::with_options(theirs, &KEY, with_frame_version(reading));
+            writer.write_all(b"hello").await.unwrap();
+            writer.flush().await.unwrap();
+            let mut buf = [0u8; 16];
+            let err = reader.read(&mut buf).await.unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert_eq!(buf, [0u8; 16], "{:?} frame read as {:?}", writing, reading);
+        }
+    }
+
+    #[tokio::test]
+    async fn slow_peer_finishes_its_frame() {
+        let stall_timeout = Duration::from_millis(100);
+        let (mut stream, mut peer) = reader(stalling_after(stall_timeout));
+        let frame = sealed(b"hi");
+        let writer = tokio::spawn(async move {
+            // the frame as a whole takes far longer than the stall timeout
+            for byte in frame.iter() {
+                peer.write_all(&[*byte]).await.unwrap();
+                sleep(stall_timeout / 3).await;
+            }
+            peer
+        });
+        let mut buf = [0u8; 16];
+        let n = stream.read(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"hi");
+        writer.await.unwrap();
+    }
+}
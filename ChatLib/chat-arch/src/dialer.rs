@@ -8,22 +8,41 @@ use async_trait::async_trait;
 use ed25519_dalek::SigningKey;
 use log::info;
 use tokio::sync::Mutex;
-use tokio_yamux::{Config, Session};
+use tokio_yamux::Session;
 
 use crate::{
-    handshake::write_handshake,
-    peer_pool::{self, EncryptedSession},
+    conn::{EncryptedStream, StreamOptions},
+    handshake::{write_handshake, ResumptionCache},
+    handshake_scheme::HandshakeScheme,
+    peer_pool::{self, ConnectionError, EncryptedSession, SessionOptions},
 };
 
 pub struct Dialer {
     signing_key: SigningKey,
+    handshake_context: Vec<u8>,
+    handshake_scheme: HandshakeScheme,
+    resumption: Option<Arc<ResumptionCache>>,
+    stream_options: StreamOptions,
+    session_options: SessionOptions,
     addrs: Arc<Mutex<HashMap<String, String>>>,
 }
 
 impl Dialer {
-    pub fn new(signing_key: SigningKey) -> Self {
+    pub fn new(
+        signing_key: SigningKey,
+        handshake_context: Vec<u8>,
+        handshake_scheme: HandshakeScheme,
+        resumption: Option<Arc<ResumptionCache>>,
+        stream_options: StreamOptions,
+        session_options: SessionOptions,
+    ) -> Self {
         Self {
             signing_key,
+            handshake_context,
+            handshake_scheme,
+            resumption,
+            stream_options,
+            session_options,
             addrs: Arc::new(Mutex::new(HashMap::new())),
         }
     }
@@ -34,27 +53,35 @@ impl Dialer {
             .lock()
             .await
             .get(&peer_id)
-            .map(|entry| entry.clone())
+            .cloned()
     }
 }
 
 #[async_trait]
 impl peer_pool::Dialer for Dialer {
-    async fn dial(&self, peer_id: &str) -> anyhow::Result<EncryptedSession> {
-        let addr = self.get(peer_id).await.ok_or(anyhow::anyhow!(
-            "failed to find addr for peer_id {}",
-            peer_id
-        ))?;
+    async fn dial(&self, peer_id: &str) -> Result<EncryptedSession, ConnectionError> {
+        let addr = self.get(peer_id).await.ok_or(ConnectionError::NoAddress)?;
         info!("dialing {}", addr);
-        let sock_addr = addr.parse::<SocketAddr>()?;
+        let sock_addr = addr
+            .parse::<SocketAddr>()
+            .map_err(|_| ConnectionError::NoAddress)?;
         let mut socket = tokio::net::TcpStream::connect(sock_addr).await?;
         socket.peer_addr()?;
-        info!("connected {:?}", &socket.peer_addr());
-        let res = write_handshake(&mut socket, &self.signing_key).await?;
-        let socket = crate::conn::EncryptedStream::new(socket, &res.symmetric_key);
+        info!("connected {:?}", socket.peer_addr());
+        let res = write_handshake(
+            &mut socket,
+            &self.signing_key,
+            &self.handshake_scheme,
+            &self.stream_options.handshake_context(&self.handshake_context),
+            peer_id,
+            self.resumption.as_deref(),
+        )
+        .await
+        .map_err(ConnectionError::from_handshake)?;
+        let socket = EncryptedStream::with_options(socket, &res.symmetric_key, self.stream_options);
         let session = std::sync::Arc::new(tokio::sync::Mutex::new(Session::new_client(
             socket,
-            Config::default(),
+            self.session_options.yamux_config(),
         )));
         Ok(session)
     }
@@ -66,4 +93,8 @@ impl peer_pool::Dialer for Dialer {
     async fn all_peers(&self) -> Vec<String> {
         self.addrs.lock().await.keys().cloned().collect()
     }
+
+    async fn remove(&self, peer_id: &str) {
+        self.addrs.lock().await.remove(peer_id);
+    }
 }
@@ -0,0 +1,368 @@
+use aes_gcm::{
+    aead::{Aead, KeyInit, Payload},
+    Aes256Gcm, Nonce,
+};
+use anyhow::{anyhow, Result};
+use ed25519_dalek::{SigningKey, VerifyingKey};
+use hkdf::Hkdf;
+use prost::Message;
+use rand::{rngs::OsRng, RngCore};
+use sha2::Sha256;
+
+use crate::peer_database;
+use crate::proto::chat::MessagePayload;
+
+const DIRECT_MESSAGE_INFO: &[u8] = b"direct-message";
+const NONCE_SIZE: usize = 12;
+/// First payload version whose sealed payload is bound to its message, see `associated_data`.
+const BOUND_PAYLOAD_VERSION: u32 = 2;
+
+/// Seals direct message payloads so only the sender and the recipient can read them.
+///
+/// A direct message still goes into the sender's repository and takes the next
+/// counter and global order like any other message, so repositories stay gapless
+/// and every peer syncs and relays it. Only the payload is scoped: the key comes
+/// from an X25519 exchange between the sender's and the recipient's identity keys,
+/// so both of them derive it while everybody else just stores the ciphertext.
+pub struct DirectCipher {
+    signing_key: SigningKey,
+    peer_id: String,
+}
+
+impl DirectCipher {
+    pub fn new(signing_key: SigningKey) -> Self {
+        let peer_id = peer_database::peer_id(&signing_key.verifying_key());
+        Self {
+            signing_key,
+            peer_id,
+        }
+    }
+
+    /// Wraps `payload` of message `message_id` into an envelope carrying only the
+    /// recipient and the sealed payload.
+    pub fn seal(
+        &self,
+        message_id: &str,
+        recipient: &str,
+        payload: &MessagePayload,
+    ) -> Result<MessagePayload> {
+        let cipher = self.cipher_with(recipient)?;
+        let mut nonce = [0u8; NONCE_SIZE];
+        OsRng.fill_bytes(&mut nonce);
+        let aad = associated_data(message_id, &self.peer_id, recipient);
+        let plaintext = payload.encode_to_vec();
+        let ciphertext = cipher
+            .encrypt(
+                Nonce::from_slice(&nonce),
+                Payload {
+                    msg: &plaintext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("failed to seal direct message"))?;
+        Ok(MessagePayload {
+            recipient: recipient.to_owned(),
+            sealed: [nonce.as_slice(), &ciphertext].concat(),
+            version: payload.version,
+            ..Default::default()
+        })
+    }
+
+    /// Returns the inner payload of direct message `message_id` from `sender`, or
+    /// `None` when the message is between two other peers. Fails for a sealed payload
+    /// copied into another message or repository.
+    pub fn open(
+        &self,
+        message_id: &str,
+        sender: &str,
+        envelope: &MessagePayload,
+    ) -> Result<Option<MessagePayload>> {
+        let other = if sender == self.peer_id {
+            &envelope.recipient
+        } else if envelope.recipient == self.peer_id {
+            sender
+        } else {
+            return Ok(None);
+        };
+        if envelope.sealed.len() < NONCE_SIZE {
+            return Err(anyhow!("sealed payload too short"));
+        }
+        let (nonce, ciphertext) = envelope.sealed.split_at(NONCE_SIZE);
+        // sealed before the id and the peers were bound to the ciphertext
+        let aad = if envelope.version < BOUND_PAYLOAD_VERSION {
+            Vec::new()
+        } else {
+            associated_data(message_id, sender, &envelope.recipient)
+        };
+        let plaintext = self
+            .cipher_with(other)?
+            .decrypt(
+                Nonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad: &aad,
+                },
+            )
+            .map_err(|_| anyhow!("failed to open direct message from {}", sender))?;
+        Ok(Some(MessagePayload::decode(plaintext.as_slice())?))
+    }
+
+    fn cipher_with(&self, peer_id: &str) -> Result<Aes256Gcm> {
+        let their_key: [u8; 32] = hex::decode(peer_id)?
+            .try_into()
+            .map_err(|_| anyhow!("bad peer id {}", peer_id))?;
+        let their_public = VerifyingKey::from_bytes(&their_key)?.to_montgomery();
+        let my_secret = x25519_dalek::StaticSecret::from(self.signing_key.to_scalar_bytes());
+        let shared =
+            my_secret.diffie_hellman(&x25519_dalek::PublicKey::from(their_public.to_bytes()));
+        let mut key = [0u8; 32];
+        Hkdf::<Sha256>::new(None, shared.as_bytes())
+            .expand(DIRECT_MESSAGE_INFO, &mut key)
+            .map_err(|_| anyhow!("HKDF expand error"))?;
+        Ok(Aes256Gcm::new(&key.into()))
+    }
+}
+
+/// Authenticated along with a sealed payload, so a peer relaying it can't present it
+/// under another message id, sender or recipient.
+fn associated_data(message_id: &str, sender: &str, recipient: &str) -> Vec<u8> {
+    [
+        message_id.as_bytes(),
+        &[0],
+        sender.as_bytes(),
+        &[0],
+        recipient.as_bytes(),
+    ]
+    .concat()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::{wait_until, TestNode};
+    use crate::config::Config;
+    use crate::indexer::memory_indexer;
+    use crate::message_database::memory_pool;
+    use crate::models::MessageBuilder;
+    use std::sync::Arc;
+
+    struct Peer {
+        key: SigningKey,
+        cipher: DirectCipher,
+    }
+
+    fn peer() -> Peer {
+        let key = SigningKey::generate(&mut OsRng);
+        Peer {
+            cipher: DirectCipher::new(key.clone()),
+            key,
+        }
+    }
+
+    fn payload(text: &str) -> MessagePayload {
+        MessagePayload {
+            text: text.to_owned(),
+            version: BOUND_PAYLOAD_VERSION,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn only_sender_and_recipient_can_read_it() {
+        let (alice, bob, carol) = (peer(), peer(), peer());
+        let envelope = alice
+            .cipher
+            .seal("m1", &bob.cipher.peer_id, &payload("hi"))
+            .unwrap();
+        assert!(envelope.text.is_empty());
+
+        let opened = bob
+            .cipher
+            .open("m1", &alice.cipher.peer_id, &envelope)
+            .unwrap();
+        assert_eq!(opened.unwrap().text, "hi");
+        let opened = alice
+            .cipher
+            .open("m1", &alice.cipher.peer_id, &envelope)
+            .unwrap();
+        assert_eq!(opened.unwrap().text, "hi");
+        assert!(carol
+            .cipher
+            .open("m1", &alice.cipher.peer_id, &envelope)
+            .unwrap()
+            .is_none());
+    }
+
+    #[test]
+    fn sealed_payload_is_bound_to_its_message() {
+        let (alice, bob, carol) = (peer(), peer(), peer());
+        let envelope = alice
+            .cipher
+            .seal("m1", &bob.cipher.peer_id, &payload("hi"))
+            .unwrap();
+
+        // replayed under another id by anyone relaying it
+        assert!(bob
+            .cipher
+            .open("m2", &alice.cipher.peer_id, &envelope)
+            .is_err());
+        // copied into somebody else's repository
+        assert!(bob
+            .cipher
+            .open("m1", &carol.cipher.peer_id, &envelope)
+            .is_err());
+        // version lowered to skip the binding
+        let mut downgraded = envelope.clone();
+        downgraded.version = 1;
+        assert!(bob
+            .cipher
+            .open("m1", &alice.cipher.peer_id, &downgraded)
+            .is_err());
+    }
+
+    #[test]
+    fn unbound_envelope_of_an_older_release_still_opens() {
+        let (alice, bob) = (peer(), peer());
+        let nonce = [7u8; NONCE_SIZE];
+        let inner = MessagePayload {
+            text: "hi".to_owned(),
+            version: 1,
+            ..Default::default()
+        };
+        let ciphertext = alice
+            .cipher
+            .cipher_with(&bob.cipher.peer_id)
+            .unwrap()
+            .encrypt(Nonce::from_slice(&nonce), inner.encode_to_vec().as_slice())
+            .unwrap();
+        let envelope = MessagePayload {
+            recipient: bob.cipher.peer_id.clone(),
+            sealed: [nonce.as_slice(), &ciphertext].concat(),
+            version: 1,
+            ..Default::default()
+        };
+
+        let opened = bob
+            .cipher
+            .open("m1", &alice.cipher.peer_id, &envelope)
+            .unwrap();
+        assert_eq!(opened.unwrap().text, "hi");
+    }
+
+    #[tokio::test]
+    async fn direct_message_is_only_indexed_by_its_two_peers() {
+        let (alice, bob, carol) = (peer(), peer(), peer());
+        let msg = MessageBuilder::new("m1".to_owned(), 1, alice.cipher.peer_id.clone())
+            .text("hi".to_owned())
+            .build_direct(&bob.cipher.peer_id, &alice.cipher)
+            .unwrap();
+
+        for reader in [&alice, &bob] {
+            let indexer = memory_indexer(memory_pool().await, reader.key.clone()).await;
+            indexer.index_message(&msg).await.unwrap();
+            let indexed = indexer.get_by_id("m1").await.unwrap().unwrap();
+            assert_eq!(indexed.text, "hi");
+            assert_eq!(
+                indexed.recipient.as_deref(),
+                Some(bob.cipher.peer_id.as_str())
+            );
+        }
+        let indexer = memory_indexer(memory_pool().await, carol.key.clone()).await;
+        indexer.index_message(&msg).await.unwrap();
+        assert!(indexer.get_by_id("m1").await.unwrap().is_none());
+    }
+
+    #[test]
+    fn third_peer_syncs_a_direct_message_it_cannot_read() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            let carol = TestNode::new("carol", Config::default(), runtime.clone()).await;
+            for other in [&bob, &carol] {
+                alice.learn(other).await;
+                other.learn(&alice).await;
+                other.start().await;
+            }
+            alice.start().await;
+
+            let manager = alice.ctx.sync_engine.get_manager();
+            let mut sent = Vec::new();
+            for (text, recipient) in [
+                ("before", None),
+                ("secret", Some(bob.id())),
+                ("after", None),
+            ] {
+                let id = uuid::Uuid::new_v4().to_string();
+                let builder = MessageBuilder::new(id, 1, alice.id()).text(text.to_owned());
+                let msg = match recipient {
+                    Some(recipient) => builder
+                        .build_direct(&recipient, &alice.ctx.direct_cipher)
+                        .unwrap(),
+                    None => builder.build(),
+                };
+                let msg = manager.clone().add_own_message(msg).await.unwrap();
+                sent.push(
+                    alice
+                        .ctx
+                        .message_db
+                        .get_by_id(&msg.id)
+                        .await
+                        .unwrap()
+                        .unwrap(),
+                );
+            }
+            let (before, direct, after) = (&sent[0], &sent[1], &sent[2]);
+            // the direct message takes the next counter of alice's repository like any other
+            assert_eq!(direct.counter, before.counter + 1);
+            assert_eq!(after.counter, direct.counter + 1);
+            for reader in [&bob, &carol] {
+                // pushes may overtake each other, a pull gets whatever they missed
+                reader
+                    .ctx
+                    .sync_engine
+                    .sync_now(Some(alice.id()))
+                    .await
+                    .unwrap();
+                reader.wait_for_counter(&alice.id(), after.counter).await;
+            }
+
+            let indexed = |node: &TestNode, id: &str| {
+                let (indexer, id) = (node.ctx.indexer.clone(), id.to_owned());
+                async move { indexer.get_by_id(&id).await.unwrap() }
+            };
+            wait_until("bob shows the direct message", || async {
+                indexed(&bob, &direct.id).await.is_some()
+            })
+            .await;
+            let read = indexed(&bob, &direct.id).await.unwrap();
+            assert_eq!(read.text, "secret");
+            // carol keeps it so alice's repository has no gap, but never shows it
+            wait_until("carol shows the later message", || async {
+                indexed(&carol, &after.id).await.is_some()
+            })
+            .await;
+            let stored = carol
+                .ctx
+                .message_db
+                .get_by_id(&direct.id)
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(stored.counter, direct.counter);
+            assert!(indexed(&carol, &direct.id).await.is_none());
+            assert!(indexed(&carol, &before.id).await.is_some());
+            let envelope = MessagePayload::decode(&*stored.payload).unwrap();
+            assert!(envelope.text.is_empty());
+            let cipher = &carol.ctx.direct_cipher;
+            assert!(cipher
+                .open(&direct.id, &alice.id(), &envelope)
+                .unwrap()
+                .is_none());
+            // claiming to be the recipient doesn't help, the key is bob's
+            let mut forged = envelope.clone();
+            forged.recipient = carol.id();
+            assert!(cipher.open(&direct.id, &alice.id(), &forged).is_err());
+        });
+    }
+}
@@ -1,10 +1,70 @@
-use crate::models::IndexedMessage;
+use crate::models::{IndexedMessage, MessageStatus};
 use log::warn;
 use crate::peer_database::Peer;
+use crate::peer_pool::ConnectionError;
+use crate::storage_error::{self, StorageErrorKind};
 
 pub enum ChatEvent {
     Message(IndexedMessage),
+    /// Messages indexed together from one synced batch, sent instead of one `Message`
+    /// each when `Config::coalesce_batch_events` is set.
+    MessagesBatch(Vec<IndexedMessage>),
+    MessageRemoved(String),
     Peer(Peer),
+    ConnectionFailed {
+        peer_id: String,
+        error: ConnectionError,
+    },
+    /// An unknown peer connected and waits for approval, see `InboundPolicy::PromptViaEvent`.
+    ConnectionRequest(String),
+    /// One of our messages was accepted by `peer_id`.
+    MessageDelivered {
+        message_id: String,
+        peer_id: String,
+    },
+    /// One of our messages moved on, see `MessageStatus`.
+    MessageStatusChanged {
+        message_id: String,
+        status: MessageStatus,
+    },
+    /// The member roster of a group changed.
+    GroupChanged(String),
+    /// The tally of a poll changed, see `Indexer::get_poll`.
+    PollChanged(String),
+    /// A peer published a newer profile, the peer carries the new name and avatar.
+    PeerProfileUpdated(Peer),
+    /// A known peer showed up with a different public key. Its verification is
+    /// cleared, `was_verified` tells whether the user had confirmed the old key.
+    PeerKeyChanged {
+        peer_id: String,
+        was_verified: bool,
+    },
+    /// Data couldn't be stored, e.g. because the disk is full. Network failures are
+    /// reported with `ConnectionFailed` instead.
+    StorageError { kind: StorageErrorKind },
+    /// No peer offered the file through all retries, it is no longer looked for
+    /// until a peer announces it or it is resolved again.
+    FileUnresolvable(String),
+    /// The last session with the peer was closed because it went unused, see
+    /// `SessionOptions::idle_timeout`. The peer is dialed again when needed.
+    PeerOffline(String),
+    /// The peer record was removed for going too long without a connection, see
+    /// `Config::peer_pruning`. Its messages are kept.
+    PeerRemoved(String),
+    /// A page of `peer_id`'s history was stored while catching up with it.
+    /// `received` counts the messages stored so far, `estimated_total` those to
+    /// expect in all, `None` if the other side doesn't report its counter.
+    SyncProgress {
+        peer_id: String,
+        received: u64,
+        estimated_total: Option<u64>,
+    },
+}
+
+/// Receives file bytes while a download is in progress, `offset` is the position
+/// of the chunk in the file. Completion is still reported through `ChatEvent::Message`.
+pub trait FileChunkListener: Send + Sync {
+    fn on_file_chunk(&self, file_id: &str, offset: u64, chunk: &[u8]);
 }
 
 pub struct Events {
@@ -18,6 +78,14 @@ impl Events {
         Self { tx, rx }
     }
 
+    /// Events nobody receives, every send fails as if the app dropped its receiver.
+    #[cfg(test)]
+    pub(crate) fn disconnected() -> Self {
+        let (tx, _) = flume::unbounded();
+        let (_, rx) = flume::unbounded();
+        Self { tx, rx }
+    }
+
     pub fn get_rx(&self) -> flume::Receiver<ChatEvent> {
         self.rx.clone()
     }
@@ -28,9 +96,67 @@ impl Events {
                 ChatEvent::Message(message) => {
                     warn!("message received: {:?}", message);
                 }
+                ChatEvent::MessagesBatch(messages) => {
+                    warn!("{} messages received", messages.len());
+                }
+                ChatEvent::MessageRemoved(id) => {
+                    warn!("message removed: {}", id);
+                }
                 ChatEvent::Peer(peer) => {
                     warn!("peer received: {:?}", peer);
                 }
+                ChatEvent::ConnectionFailed { peer_id, error } => {
+                    warn!("connection to {} failed: {}", peer_id, error);
+                }
+                ChatEvent::ConnectionRequest(peer_id) => {
+                    warn!("connection request from {}", peer_id);
+                }
+                ChatEvent::MessageDelivered {
+                    message_id,
+                    peer_id,
+                } => {
+                    warn!("message {} delivered to {}", message_id, peer_id);
+                }
+                ChatEvent::MessageStatusChanged { message_id, status } => {
+                    warn!("message {} is now {:?}", message_id, status);
+                }
+                ChatEvent::GroupChanged(group_id) => {
+                    warn!("group {} changed", group_id);
+                }
+                ChatEvent::PeerKeyChanged {
+                    peer_id,
+                    was_verified,
+                } => {
+                    warn!("key of {} changed, was verified: {}", peer_id, was_verified);
+                }
+                ChatEvent::StorageError { kind } => {
+                    warn!("storage error: {:?}", kind);
+                }
+                ChatEvent::PollChanged(poll_id) => {
+                    warn!("poll {} changed", poll_id);
+                }
+                ChatEvent::PeerProfileUpdated(peer) => {
+                    warn!("profile of {} updated", peer.id);
+                }
+                ChatEvent::FileUnresolvable(file_id) => {
+                    warn!("file {} is unresolvable", file_id);
+                }
+                ChatEvent::PeerOffline(peer_id) => {
+                    warn!("peer {} went offline", peer_id);
+                }
+                ChatEvent::PeerRemoved(peer_id) => {
+                    warn!("peer {} was removed", peer_id);
+                }
+                ChatEvent::SyncProgress {
+                    peer_id,
+                    received,
+                    estimated_total,
+                } => {
+                    warn!(
+                        "synced {} of {:?} messages of {}",
+                        received, estimated_total, peer_id
+                    );
+                }
             }
         }
     }
@@ -39,9 +165,142 @@ impl Events {
         self.tx.send_async(ChatEvent::Message(message)).await?;
         Ok(())
     }
+
+    pub async fn send_messages_batch(&self, messages: Vec<IndexedMessage>) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::MessagesBatch(messages)).await?;
+        Ok(())
+    }
     
+    pub async fn send_message_removed(&self, id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::MessageRemoved(id)).await?;
+        Ok(())
+    }
+
     pub async fn send_peer(&self, peer: Peer) -> anyhow::Result<()> {
         self.tx.send_async(ChatEvent::Peer(peer)).await?;
         Ok(())
     }
+
+    pub async fn send_connection_request(&self, peer_id: String) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::ConnectionRequest(peer_id))
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_message_delivered(
+        &self,
+        message_id: String,
+        peer_id: String,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::MessageDelivered {
+                message_id,
+                peer_id,
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_message_status(
+        &self,
+        message_id: String,
+        status: MessageStatus,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::MessageStatusChanged { message_id, status })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_group_changed(&self, group_id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::GroupChanged(group_id)).await?;
+        Ok(())
+    }
+
+    pub async fn send_poll_changed(&self, poll_id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::PollChanged(poll_id)).await?;
+        Ok(())
+    }
+
+    pub async fn send_peer_profile_updated(&self, peer: Peer) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::PeerProfileUpdated(peer)).await?;
+        Ok(())
+    }
+
+    pub async fn send_peer_key_changed(
+        &self,
+        peer_id: String,
+        was_verified: bool,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::PeerKeyChanged {
+                peer_id,
+                was_verified,
+            })
+            .await?;
+        Ok(())
+    }
+
+    pub async fn send_storage_error(&self, kind: StorageErrorKind) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::StorageError { kind }).await?;
+        Ok(())
+    }
+
+    pub async fn send_file_unresolvable(&self, file_id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::FileUnresolvable(file_id)).await?;
+        Ok(())
+    }
+
+    pub async fn send_peer_offline(&self, peer_id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::PeerOffline(peer_id)).await?;
+        Ok(())
+    }
+
+    pub async fn send_peer_removed(&self, peer_id: String) -> anyhow::Result<()> {
+        self.tx.send_async(ChatEvent::PeerRemoved(peer_id)).await?;
+        Ok(())
+    }
+
+    pub async fn send_sync_progress(
+        &self,
+        peer_id: String,
+        received: u64,
+        estimated_total: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::SyncProgress {
+                peer_id,
+                received,
+                estimated_total,
+            })
+            .await?;
+        Ok(())
+    }
+
+    /// Sends `ChatEvent::StorageError` if `err` comes from a full or unwritable disk.
+    pub async fn report_storage_error(&self, err: &anyhow::Error) {
+        if let Some(kind) = storage_error::classify(err) {
+            if let Err(e) = self.send_storage_error(kind).await {
+                warn!("failed to send storage error event: {:?}", e);
+            }
+        }
+    }
+
+    pub async fn send_connection_failed(
+        &self,
+        peer_id: String,
+        error: ConnectionError,
+    ) -> anyhow::Result<()> {
+        self.tx
+            .send_async(ChatEvent::ConnectionFailed { peer_id, error })
+            .await?;
+        Ok(())
+    }
+}
+
+impl Default for Events {
+    fn default() -> Self {
+        Self::new()
+    }
 }
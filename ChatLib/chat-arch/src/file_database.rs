@@ -1,8 +1,13 @@
+use std::sync::Arc;
+
+use crate::events::Events;
+use crate::message_database::add_column_if_missing;
 use anyhow::Result;
 use sqlx::{Row, SqlitePool};
 
 pub struct FileDatabase {
     pool: SqlitePool,
+    events: Arc<Events>,
 }
 
 pub struct FileDescription {
@@ -13,8 +18,16 @@ pub struct FileDescription {
 }
 
 impl FileDatabase {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+    pub fn new(pool: SqlitePool, events: Arc<Events>) -> Self {
+        Self { pool, events }
+    }
+
+    /// Reports a failed write that was caused by the disk, see `ChatEvent::StorageError`.
+    async fn checked(&self, res: Result<()>) -> Result<()> {
+        if let Err(e) = &res {
+            self.events.report_storage_error(e).await;
+        }
+        res
     }
 
     pub async fn init(&self) -> Result<()> {
@@ -30,23 +43,102 @@ impl FileDatabase {
         )
         .execute(&self.pool)
         .await?;
+        add_column_if_missing(&self.pool, "files", "size", "INTEGER").await?;
+        add_column_if_missing(&self.pool, "files", "mtime", "INTEGER").await?;
+        add_column_if_missing(&self.pool, "files", "owned", "INTEGER NOT NULL DEFAULT 0").await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS files_local_path ON files (local_path)")
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
+    /// Saves a file together with its size and modification time, so registering
+    /// the same unchanged file again can reuse the id.
+    pub async fn save_with_fingerprint(
+        &self,
+        msg: &FileDescription,
+        size: u64,
+        mtime: i64,
+    ) -> Result<()> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO files (id, timestamp, local_path, format, size, mtime)
+            VALUES (?, ?, ?, ?, ?, ?)"#,
+        )
+        .bind(&msg.id)
+        .bind(msg.timestamp)
+        .bind(&msg.local_path)
+        .bind(&msg.format)
+        .bind(size as i64)
+        .bind(mtime)
+        .execute(&self.pool)
+        .await;
+        self.checked(res.map(|_| ()).map_err(|e| e.into())).await
+    }
+
+    pub async fn get_by_fingerprint(
+        &self,
+        local_path: &str,
+        size: u64,
+        mtime: i64,
+    ) -> Result<Option<String>> {
+        let row = sqlx::query(
+            r#"
+            SELECT id
+            FROM files
+            WHERE local_path = ? AND size = ? AND mtime = ?
+            LIMIT 1
+            "#,
+        )
+        .bind(local_path)
+        .bind(size as i64)
+        .bind(mtime)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| row.get("id")))
+    }
+
     pub async fn save(&self, msg: &FileDescription) -> Result<()> {
-        sqlx::query(
+        let res = sqlx::query(
             r#"
             INSERT INTO files (id, timestamp, local_path, format)
             VALUES (?, ?, ?, ?)"#,
         )
         .bind(&msg.id)
-        .bind(&msg.timestamp)
+        .bind(msg.timestamp)
         .bind(&msg.local_path)
         .bind(&msg.format)
         .execute(&self.pool)
-        .await?;
+        .await;
+        self.checked(res.map(|_| ()).map_err(|e| e.into())).await
+    }
 
-        Ok(())
+    /// Saves a file the library wrote into the root folder itself, a download or an
+    /// inline file. Only those are deleted along with their message, files the app
+    /// registered stay where they are.
+    pub async fn save_owned(&self, msg: &FileDescription) -> Result<()> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO files (id, timestamp, local_path, format, owned)
+            VALUES (?, ?, ?, ?, 1)"#,
+        )
+        .bind(&msg.id)
+        .bind(msg.timestamp)
+        .bind(&msg.local_path)
+        .bind(&msg.format)
+        .execute(&self.pool)
+        .await;
+        self.checked(res.map(|_| ()).map_err(|e| e.into())).await
+    }
+
+    /// Whether the file was saved with `save_owned`.
+    pub async fn is_owned(&self, id: &str) -> Result<bool> {
+        let row = sqlx::query("SELECT owned FROM files WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row.is_some_and(|row| row.get("owned")))
     }
 
     pub async fn get_by_id(&self, id: &str) -> Result<Option<FileDescription>> {
@@ -69,6 +161,29 @@ impl FileDatabase {
         }))
     }
 
+    pub async fn get_all(&self) -> Result<Vec<FileDescription>> {
+        let rows = sqlx::query("SELECT id, timestamp, local_path, format FROM files")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| FileDescription {
+                id: row.get("id"),
+                timestamp: row.get("timestamp"),
+                local_path: row.get("local_path"),
+                format: row.get("format"),
+            })
+            .collect())
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM files WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn contains(&self, id: &str) -> Result<bool> {
         let row = sqlx::query(
             r#"
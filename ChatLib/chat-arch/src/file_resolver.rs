@@ -1,24 +1,91 @@
-use futures::TryFutureExt;
 use log::info;
+use rand::Rng;
 use tokio::time::sleep;
 use std::collections::{HashMap, HashSet};
+use std::path::Path;
 use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 use tokio::runtime::Runtime;
 use tokio::sync::Mutex;
+use tokio_util::sync::CancellationToken;
 
+use crate::clock::Clock;
+use crate::events::Events;
 use crate::file_database::FileDatabase;
 use crate::indexer::Indexer;
 use crate::sync_engine::{FileProvider, SyncEngine};
 
+/// How often the retry timer looks for files that are due to be resolved again.
+const RETRY_TICK: Duration = Duration::from_secs(1);
+/// Wait before the first retry of a file no peer is known to have, doubled on every
+/// further attempt up to `MAX_RETRY_DELAY`.
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+/// Retries of a file without a source before it is reported with `ChatEvent::FileUnresolvable`.
+const MAX_RETRY_ATTEMPTS: u32 = 10;
+
+/// Backoff before retry number `attempt`, jittered so files that lost their source
+/// together don't retry in lockstep.
+fn retry_delay(attempt: u32) -> Duration {
+    let delay = RETRY_INTERVAL
+        .saturating_mul(2u32.saturating_pow(attempt.saturating_sub(1)))
+        .min(MAX_RETRY_DELAY);
+    delay.mul_f64(rand::thread_rng().gen_range(0.5..=1.0))
+}
+
 struct ResolverData {
     need_resolve: HashSet<String>,
     peers_have: HashMap<String, Vec<String>>,
+    // ids waiting in the resolve channel, an id is queued at most once so the
+    // channel never holds more entries than there are files to resolve
+    queued: HashSet<String>,
+    // ids without a source and when the retry timer queues them again
+    retry: HashMap<String, Instant>,
+    // retries so far of files without a source, reset when a peer announces the file
+    attempts: HashMap<String, u32>,
+}
+
+impl ResolverData {
+    fn queue(&mut self, sender: &flume::Sender<ResolveWant>, file_id: &str) {
+        if !self.queued.insert(file_id.to_owned()) {
+            return;
+        }
+        if let Err(e) = sender.send(file_id.to_owned().into()) {
+            log::warn!("failed to send to resolve: {}", e);
+        }
+    }
+
+    /// Schedules another attempt for a file without a source. Returns false once the
+    /// file ran out of attempts, it is no longer resolved then.
+    fn schedule_retry(&mut self, file_id: &str, now: Instant) -> bool {
+        if self.retry.contains_key(file_id) {
+            return true;
+        }
+        let attempt = {
+            let attempts = self.attempts.entry(file_id.to_owned()).or_insert(0);
+            *attempts += 1;
+            *attempts
+        };
+        if attempt > MAX_RETRY_ATTEMPTS {
+            self.attempts.remove(file_id);
+            self.need_resolve.remove(file_id);
+            return false;
+        }
+        self.retry.insert(file_id.to_owned(), now + retry_delay(attempt));
+        true
+    }
+
+    /// A peer announced the file, it gets a fresh set of retries.
+    fn reset_retry(&mut self, file_id: &str) {
+        self.retry.remove(file_id);
+        self.attempts.remove(file_id);
+    }
 }
 
 pub struct FileResolverStorage {
     data: Arc<Mutex<ResolverData>>,
     pub file_db: Arc<FileDatabase>,
+    pub clock: Arc<dyn Clock>,
     to_resolve_send: Arc<flume::Sender<ResolveWant>>,
     to_resolve_recv: Arc<flume::Receiver<ResolveWant>>,
 }
@@ -38,15 +105,26 @@ impl From<String> for ResolveWant {
     }
 }
 
+fn add_peer(peers_have: &mut HashMap<String, Vec<String>>, file_id: &str, peer_id: &str) {
+    let peers = peers_have.entry(file_id.to_string()).or_default();
+    if !peers.iter().any(|peer| peer == peer_id) {
+        peers.push(peer_id.to_string());
+    }
+}
+
 impl FileResolverStorage {
-    pub fn new(file_db: Arc<FileDatabase>) -> Self {
+    pub fn new(file_db: Arc<FileDatabase>, clock: Arc<dyn Clock>) -> Self {
         let (sender, receiver) = flume::unbounded();
         Self {
             data: Arc::new(Mutex::new(ResolverData {
                 need_resolve: HashSet::new(),
                 peers_have: HashMap::new(),
+                queued: HashSet::new(),
+                retry: HashMap::new(),
+                attempts: HashMap::new(),
             })),
             file_db,
+            clock,
             to_resolve_recv: Arc::new(receiver),
             to_resolve_send: Arc::new(sender),
         }
@@ -56,49 +134,24 @@ impl FileResolverStorage {
         let mut data = self.data.lock().await;
         data.need_resolve.insert(file_id.to_string());
         if let Some(peer_id) = peer_id {
-            data.peers_have
-                .entry(file_id.to_string())
-                .or_insert(Vec::new())
-                .push(peer_id);
-        }
-        if let Err(e) = self
-            .to_resolve_send
-            .send_async(file_id.to_owned().into())
-            .await
-        {
-            log::warn!("failed to send to resolve: {}", e);
+            add_peer(&mut data.peers_have, file_id, &peer_id);
         }
+        data.queue(&self.to_resolve_send, file_id);
     }
 
     pub async fn add_peer_have(&self, file_id: &str, peer_id: &str) {
         let mut data = self.data.lock().await;
-        data.peers_have
-            .entry(file_id.to_string())
-            .or_insert(Vec::new())
-            .push(peer_id.to_string());
-        if let Err(e) = self
-            .to_resolve_send
-            .send_async(file_id.to_owned().into())
-            .await
-        {
-            log::warn!("failed to send to resolve: {}", e);
-        }
+        add_peer(&mut data.peers_have, file_id, peer_id);
+        data.reset_retry(file_id);
+        data.queue(&self.to_resolve_send, file_id);
     }
 
     pub async fn add_peer_have_many(&self, file_ids: Vec<String>, peer_id: &str) {
         let mut data = self.data.lock().await;
         for file_id in file_ids {
-            data.peers_have
-                .entry(file_id.to_string())
-                .or_insert(Vec::new())
-                .push(peer_id.to_string());
-            if let Err(e) = self
-                .to_resolve_send
-                .send_async(file_id.to_owned().into())
-                .await
-            {
-                log::warn!("failed to send to resolve: {}", e);
-            }
+            add_peer(&mut data.peers_have, &file_id, peer_id);
+            data.reset_retry(&file_id);
+            data.queue(&self.to_resolve_send, &file_id);
         }
     }
 
@@ -112,6 +165,20 @@ impl FileResolverStorage {
         data.need_resolve.iter().cloned().collect()
     }
 
+    /// The files of `file_ids` the peer is not yet known to have.
+    pub async fn unknown_to(&self, peer_id: &str, file_ids: &[String]) -> Vec<String> {
+        let data = self.data.lock().await;
+        file_ids
+            .iter()
+            .filter(|file_id| {
+                data.peers_have
+                    .get(*file_id)
+                    .is_none_or(|peers| !peers.iter().any(|peer| peer == peer_id))
+            })
+            .cloned()
+            .collect()
+    }
+
     pub async fn db_contains(&self, file_id: &str) -> anyhow::Result<bool> {
         self.file_db.contains(file_id).await
     }
@@ -122,50 +189,83 @@ pub struct ResolveResult {
     pub file_path: String,
 }
 
+/// Outcome of `FileResolver::verify_files`.
+pub struct FileCheck {
+    pub checked: u64,
+    /// Ids of files whose local copy is gone.
+    pub missing: Vec<String>,
+}
+
 pub struct FileResolver {
+    root_path: String,
     storage: Arc<FileResolverStorage>,
     indexer: Arc<Indexer>,
     runtime: Arc<Runtime>,
     to_index_send: Arc<flume::Sender<ResolveResult>>,
     to_index_recv: Arc<flume::Receiver<ResolveResult>>,
     sync_engine: Arc<SyncEngine>,
+    events: Arc<Events>,
+    // cancelled on shutdown, ends the resolve, retry and index loops
+    shutdown: CancellationToken,
 }
 
 impl FileResolver {
     pub fn new(
+        root_path: String,
         runtime: Arc<Runtime>,
         indexer: Arc<Indexer>,
         storage: Arc<FileResolverStorage>,
         sync_engine: Arc<SyncEngine>,
+        events: Arc<Events>,
     ) -> Self {
         let (to_index_send, to_index_recv) = flume::unbounded();
         Self {
+            root_path,
             storage,
             indexer,
             runtime,
             to_index_recv: Arc::new(to_index_recv),
             to_index_send: Arc::new(to_index_send),
             sync_engine,
+            events,
+            shutdown: CancellationToken::new(),
         }
     }
 
+    /// Stops the resolver loops, files queued for resolution stay where they are.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
     pub fn run(self: Arc<Self>) {
         let resolver = self.clone();
         let indexer = self.clone();
+        let retrier = self.clone();
         self.runtime.spawn(async move {
-            let _ = resolver.clone().runtime.spawn(async move {
+            resolver.clone().runtime.spawn(async move {
                 resolver.run_resolve_async().await;
             });
-            let _ = indexer.clone().runtime.spawn(async move {
+            retrier.clone().runtime.spawn(async move {
+                retrier.run_retry_async().await;
+            });
+            indexer.clone().runtime.spawn(async move {
                 indexer.run_index_async().await;
             });
         });
     }
 
     async fn run_resolve_async(self: Arc<Self>) {
-        while let Ok(want) = self.storage.to_resolve_recv.recv_async().await {
+        loop {
+            let want = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                want = self.storage.to_resolve_recv.recv_async() => match want {
+                    Ok(want) => want,
+                    Err(_) => break,
+                },
+            };
             let file_id = want.file_id;
-            info!("resolve file: {}", &file_id);
+            info!("resolve file: {}", file_id);
+            self.storage.data.lock().await.queued.remove(&file_id);
             if let Some(descr) = self
                 .storage
                 .file_db
@@ -177,8 +277,9 @@ impl FileResolver {
                 {
                     let mut guard = self.storage.data.lock().await;
                     guard.need_resolve.remove(&file_id);
+                    guard.attempts.remove(&file_id);
                 }
-                info!("already have the file {}", &file_id);
+                info!("already have the file {}", file_id);
                 if let Err(e) = self
                     .to_index_send
                     .send_async(ResolveResult {
@@ -199,13 +300,14 @@ impl FileResolver {
                     guard.peers_have.insert(file_id.clone(), peers_have.clone());
                 }
                 if !guard.need_resolve.contains(&file_id) || peers_have.is_empty() {
-                    if peers_have.is_empty() {
-                        let self_clone = self.clone();
-                        let file_id = file_id.clone();
-                        tokio::spawn(async move {
-                            sleep(Duration::from_secs(5)).await;
-                            self_clone.storage.add_need_resolve(&file_id, None).await;
-                        });
+                    if peers_have.is_empty()
+                        && !guard.schedule_retry(&file_id, self.storage.clock.instant())
+                    {
+                        drop(guard);
+                        info!("giving up on file {}, no peer has it", file_id);
+                        if let Err(e) = self.events.send_file_unresolvable(file_id).await {
+                            log::warn!("failed to send unresolvable file event: {}", e);
+                        }
                     }
                     continue;
                 }
@@ -231,8 +333,39 @@ impl FileResolver {
         info!("resolve finished");
     }
 
+    /// Single timer for all files without a source, instead of one sleeping task per file.
+    async fn run_retry_async(self: Arc<Self>) {
+        loop {
+            tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                _ = sleep(RETRY_TICK) => {}
+            }
+            let now = self.storage.clock.instant();
+            let mut guard = self.storage.data.lock().await;
+            let due: Vec<String> = guard
+                .retry
+                .iter()
+                .filter(|(_, at)| **at <= now)
+                .map(|(file_id, _)| file_id.clone())
+                .collect();
+            for file_id in due {
+                guard.retry.remove(&file_id);
+                guard.need_resolve.insert(file_id.clone());
+                guard.queue(&self.storage.to_resolve_send, &file_id);
+            }
+        }
+        info!("retry finished");
+    }
+
     async fn run_index_async(self: Arc<Self>) {
-        while let Ok(res) = self.to_index_recv.recv_async().await {
+        loop {
+            let res = tokio::select! {
+                _ = self.shutdown.cancelled() => break,
+                res = self.to_index_recv.recv_async() => match res {
+                    Ok(res) => res,
+                    Err(_) => break,
+                },
+            };
             if let Err(e) = self
                 .indexer
                 .index_file_path(res.file_id, res.file_path)
@@ -241,13 +374,211 @@ impl FileResolver {
                 log::warn!("failed to index file: {}", e);
             }
         }
+        info!("index finished");
     }
 
     pub async fn add_need_resolve(&self, file_id: &str, peer_id: Option<String>) {
         self.storage.add_need_resolve(file_id, peer_id).await;
     }
 
+    /// Checks that every stored file still exists. A missing file is forgotten, its
+    /// messages show it as not downloaded and, if messages reference it, it is
+    /// resolved again from peers that may still have it.
+    pub async fn verify_files(&self) -> anyhow::Result<FileCheck> {
+        let files = self.storage.file_db.get_all().await?;
+        let mut check = FileCheck {
+            checked: files.len() as u64,
+            missing: Vec::new(),
+        };
+        for file in files {
+            let path = Path::new(&self.root_path).join(&file.local_path);
+            if tokio::fs::try_exists(&path).await? {
+                continue;
+            }
+            info!("file {} is missing at {}", file.id, file.local_path);
+            self.storage.file_db.delete(&file.id).await?;
+            if self.indexer.clear_file_path(&file.id).await? {
+                self.add_need_resolve(&file.id, None).await;
+            }
+            check.missing.push(file.id);
+        }
+        Ok(check)
+    }
+
     pub async fn add_peer_have(&self, file_id: &str, peer_id: &str) {
         self.storage.add_peer_have(file_id, peer_id).await;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::{wait_until, TestNode};
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::config::Config;
+    use crate::message_database::memory_pool;
+
+    const FILES: usize = 50;
+
+    fn file_ids() -> Vec<String> {
+        (0..FILES).map(|i| format!("file{}", i)).collect()
+    }
+
+    #[tokio::test]
+    async fn file_is_queued_once_however_often_it_is_wanted() {
+        let events = Arc::new(Events::disconnected());
+        let file_db = Arc::new(FileDatabase::new(memory_pool().await, events));
+        let storage = FileResolverStorage::new(file_db, Arc::new(SystemClock));
+        let bob = Some("bob".to_owned());
+        for _ in 0..3 {
+            for file_id in file_ids() {
+                storage.add_need_resolve(&file_id, None).await;
+                storage.add_need_resolve(&file_id, bob.clone()).await;
+                storage.add_peer_have(&file_id, "carol").await;
+            }
+            storage.add_peer_have_many(file_ids(), "dave").await;
+        }
+        assert_eq!(storage.to_resolve_recv.len(), FILES);
+        let peers = storage.get_peers_have("file0").await;
+        assert_eq!(peers, ["bob", "carol", "dave"]);
+
+        // taken off the channel, the file can be queued again
+        let want = storage.to_resolve_recv.recv_async().await.unwrap();
+        storage.data.lock().await.queued.remove(&want.file_id);
+        storage.add_need_resolve(&want.file_id, None).await;
+        storage.add_need_resolve(&want.file_id, None).await;
+        assert_eq!(storage.to_resolve_recv.len(), FILES);
+    }
+
+    #[test]
+    fn unresolvable_files_wait_on_the_retry_timer() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            let config = Config {
+                clock: clock.clone(),
+                ..Config::default()
+            };
+            let alice = TestNode::new("alice", config, runtime.clone()).await;
+            alice.start().await;
+            let storage = alice.ctx.file_resolver.storage.clone();
+            // the only peer said to have the files can't be reached
+            let ghost = Some("ghost".to_owned());
+            for file_id in file_ids() {
+                storage.add_need_resolve(&file_id, ghost.clone()).await;
+            }
+            let attempted = |attempt: u32| {
+                let storage = storage.clone();
+                async move {
+                    let data = storage.data.lock().await;
+                    data.retry.len() == FILES
+                        && data.attempts.values().all(|attempts| *attempts == attempt)
+                }
+            };
+            wait_until("every file waits for its first retry", || attempted(1)).await;
+
+            // nothing is queued before a retry is due
+            sleep(RETRY_TICK * 2).await;
+            assert!(attempted(1).await);
+            assert!(storage.to_resolve_recv.is_empty());
+
+            // a single tick queues them all, each once
+            clock.advance(MAX_RETRY_DELAY);
+            wait_until("every file waits for its second retry", || attempted(2)).await;
+            assert!(storage.to_resolve_recv.len() <= FILES);
+            assert!(storage.data.lock().await.queued.is_empty());
+        });
+    }
+
+    #[tokio::test]
+    async fn retries_are_spread_out_and_run_out() {
+        let events = Arc::new(Events::disconnected());
+        let file_db = Arc::new(FileDatabase::new(memory_pool().await, events));
+        let storage = FileResolverStorage::new(file_db, Arc::new(SystemClock));
+        let mut data = storage.data.lock().await;
+        let now = Instant::now();
+        for file_id in file_ids() {
+            data.need_resolve.insert(file_id.clone());
+            assert!(data.schedule_retry(&file_id, now));
+        }
+        // one deadline per file on the shared timer, not all at the same moment
+        assert_eq!(data.retry.len(), FILES);
+        let deadlines: HashSet<Instant> = data.retry.values().copied().collect();
+        assert!(deadlines.len() > 1);
+        for at in &deadlines {
+            assert!(*at >= now + RETRY_INTERVAL / 2 && *at <= now + RETRY_INTERVAL);
+        }
+        // a pending retry isn't scheduled twice
+        assert!(data.schedule_retry("file0", now));
+        assert_eq!(data.attempts["file0"], 1);
+
+        for attempt in 2..=MAX_RETRY_ATTEMPTS {
+            data.retry.remove("file0");
+            assert!(data.schedule_retry("file0", now));
+            assert_eq!(data.attempts["file0"], attempt);
+        }
+        let last = data.retry["file0"];
+        assert!(last >= now + MAX_RETRY_DELAY / 2 && last <= now + MAX_RETRY_DELAY);
+
+        data.retry.remove("file0");
+        assert!(!data.schedule_retry("file0", now));
+        assert!(!data.need_resolve.contains("file0"));
+        assert!(!data.attempts.contains_key("file0"));
+    }
+
+    #[test]
+    fn deleted_files_are_forgotten_and_resolved_again() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            for file_id in ["kept", "gone", "orphan"] {
+                let local_path = format!("{}.bin", file_id);
+                let path = Path::new(&alice.root).join(&local_path);
+                tokio::fs::write(path, file_id).await.unwrap();
+                let file = crate::file_database::FileDescription {
+                    id: file_id.to_owned(),
+                    format: "bin".to_owned(),
+                    local_path,
+                    timestamp: 0,
+                };
+                alice.ctx.file_db.save_owned(&file).await.unwrap();
+            }
+            // messages show two of the files, nothing references the orphan
+            let messages: Vec<_> = ["kept", "gone"]
+                .into_iter()
+                .map(|file_id| {
+                    crate::models::MessageBuilder::new(file_id.to_owned(), 1, alice.id())
+                        .file_id(file_id.to_owned())
+                        .build()
+                })
+                .collect();
+            let indexer = &alice.ctx.indexer;
+            indexer.index_messages(&messages).await.unwrap();
+            for file_id in ["gone", "orphan"] {
+                let path = Path::new(&alice.root).join(format!("{}.bin", file_id));
+                tokio::fs::remove_file(path).await.unwrap();
+            }
+
+            let mut check = alice.ctx.file_resolver.verify_files().await.unwrap();
+            check.missing.sort();
+            assert_eq!(check.checked, 3);
+            assert_eq!(check.missing, ["gone", "orphan"]);
+            let file_db = &alice.ctx.file_db;
+            assert!(file_db.contains("kept").await.unwrap());
+            assert!(!file_db.contains("gone").await.unwrap());
+            assert!(!file_db.contains("orphan").await.unwrap());
+            let kept = indexer.get_by_id("kept").await.unwrap().unwrap();
+            assert_eq!(kept.file_path.as_deref(), Some("kept.bin"));
+            let gone = indexer.get_by_id("gone").await.unwrap().unwrap();
+            assert_eq!(gone.file_path, None);
+            // only the file a message shows is looked for again
+            let storage = &alice.ctx.file_resolver.storage;
+            assert_eq!(storage.get_need_resolve().await, ["gone"]);
+
+            // nothing is missing the second time
+            let check = alice.ctx.file_resolver.verify_files().await.unwrap();
+            assert_eq!(check.checked, 1);
+            assert!(check.missing.is_empty());
+        });
+    }
+}
@@ -0,0 +1,100 @@
+use ed25519_dalek::VerifyingKey;
+use sha2::{Digest, Sha512};
+
+const FINGERPRINT_VERSION: u16 = 0;
+/// Hash rounds slowing down the search for a key with a colliding fingerprint.
+const ITERATIONS: usize = 5200;
+const CHUNKS: usize = 6;
+const CHUNK_DIGITS: usize = 5;
+
+/// 30 digit fingerprint of a single key in six groups of five, the same on every
+/// device that stores the key. Users read it to each other to spot a key that was
+/// swapped during discovery.
+pub fn fingerprint(key: &VerifyingKey) -> String {
+    group(&digits(key))
+}
+
+/// 60 digit safety number of a conversation, made of both fingerprints in a fixed
+/// order so both users see the same number.
+pub fn safety_number(a: &VerifyingKey, b: &VerifyingKey) -> String {
+    let (first, second) = {
+        let a = digits(a);
+        let b = digits(b);
+        if a <= b {
+            (a, b)
+        } else {
+            (b, a)
+        }
+    };
+    group(&(first + &second))
+}
+
+fn digits(key: &VerifyingKey) -> String {
+    let mut hash = Sha512::new()
+        .chain_update(FINGERPRINT_VERSION.to_be_bytes())
+        .chain_update(key.as_bytes())
+        .finalize();
+    for _ in 1..ITERATIONS {
+        hash = Sha512::new()
+            .chain_update(hash)
+            .chain_update(key.as_bytes())
+            .finalize();
+    }
+    // each 5 byte chunk becomes a five digit group
+    let mut digits = String::with_capacity(CHUNKS * CHUNK_DIGITS);
+    for chunk in hash.chunks(5).take(CHUNKS) {
+        let value = chunk.iter().fold(0u64, |acc, byte| (acc << 8) | *byte as u64);
+        digits.push_str(&format!("{:05}", value % 100_000));
+    }
+    digits
+}
+
+fn group(digits: &str) -> String {
+    digits
+        .as_bytes()
+        .chunks(CHUNK_DIGITS)
+        .map(|chunk| String::from_utf8_lossy(chunk).into_owned())
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    #[test]
+    fn fingerprint_is_stable() {
+        let key = SigningKey::generate(&mut OsRng).verifying_key();
+        let stored = VerifyingKey::from_bytes(key.as_bytes()).unwrap();
+        assert_eq!(fingerprint(&key), fingerprint(&stored));
+
+        let printed = fingerprint(&key);
+        let groups: Vec<&str> = printed.split(' ').collect();
+        assert_eq!(groups.len(), CHUNKS);
+        for group in groups {
+            assert_eq!(group.len(), CHUNK_DIGITS);
+            assert!(group.chars().all(|c| c.is_ascii_digit()));
+        }
+
+        let other = SigningKey::generate(&mut OsRng).verifying_key();
+        assert_ne!(fingerprint(&key), fingerprint(&other));
+    }
+
+    #[test]
+    fn safety_number_is_the_same_for_both_peers() {
+        let alice = SigningKey::generate(&mut OsRng).verifying_key();
+        let bob = SigningKey::generate(&mut OsRng).verifying_key();
+        let carol = SigningKey::generate(&mut OsRng).verifying_key();
+
+        let number = safety_number(&alice, &bob);
+        assert_eq!(number, safety_number(&bob, &alice));
+        assert_eq!(number.split(' ').count(), CHUNKS * 2);
+        // both fingerprints are in it, so either side can check their own half
+        let halves = [fingerprint(&alice), fingerprint(&bob)];
+        assert!(halves.iter().all(|half| number.contains(half.as_str())));
+
+        assert_ne!(number, safety_number(&alice, &carol));
+    }
+}
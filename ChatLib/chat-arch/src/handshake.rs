@@ -1,130 +1,1024 @@
-use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use hkdf::hmac::{Hmac, Mac};
 use hkdf::Hkdf;
 use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
 use sha2::Sha256;
+use std::collections::HashMap;
 use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
 
-const DERIVATION_TEXT: &[u8] = b"p2p-chat";
+use crate::clock::Clock;
+use crate::handshake_scheme::{HandshakeIdentity, HandshakeScheme};
+
+const CONFIRM_LABEL: &[u8] = b"key-confirmation";
+const INITIATOR_ROLE: &[u8] = b"initiator";
+const RESPONDER_ROLE: &[u8] = b"responder";
+const TAG_SIZE: usize = 32;
+const RESUMPTION_LABEL: &[u8] = b"resumption";
+const RESUME_INITIATOR_ROLE: &[u8] = b"resume-initiator";
+const RESUME_RESPONDER_ROLE: &[u8] = b"resume-responder";
+const NONCE_SIZE: usize = 32;
+
+/// Opens every handshake of this version. The original handshake started straight
+/// with the initiator's ephemeral key, a responder that doesn't find the magic
+/// serves that one instead.
+const HANDSHAKE_MAGIC: &[u8; 8] = b"p2pchat\x01";
+/// Length of an ephemeral key of the original handshake, always x25519.
+const LEGACY_EPHEMERAL_LEN: usize = 32;
+
+/// A full handshake with the default scheme. It carries no scheme id, as peers
+/// from before schemes existed send it.
+const MODE_FULL: u8 = 0x00;
+const MODE_RESUME: u8 = 0x01;
+/// A full handshake with a scheme other than the default, the scheme id follows.
+const MODE_SCHEME: u8 = 0x02;
+const RESUME_ACCEPT: u8 = 0x01;
+const RESUME_REJECT: u8 = 0x00;
+const SCHEME_ACCEPT: u8 = 0x01;
+const SCHEME_REJECT: u8 = 0x00;
 
 pub struct Handshake {
     pub symmetric_key: [u8; 32],
-    pub their_pub_key: [u8; 32],
+    pub their_pub_key: Vec<u8>,
 }
 
 impl Handshake {
     pub fn hex_key(&self) -> String {
-        hex::encode(self.their_pub_key.clone())
+        hex::encode(&self.their_pub_key)
+    }
+}
+
+struct ResumptionSecret {
+    secret: [u8; 32],
+    created_at: Instant,
+}
+
+/// Resumption secrets of recently connected peers, keyed by their hex public key.
+/// A secret is removed when used and replaced by a freshly derived one, so each
+/// secret can resume at most one session.
+pub struct ResumptionCache {
+    ttl: Duration,
+    clock: Arc<dyn Clock>,
+    secrets: Mutex<HashMap<String, ResumptionSecret>>,
+}
+
+impl ResumptionCache {
+    pub fn new(ttl: Duration, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            ttl,
+            clock,
+            secrets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn insert(&self, peer_id: String, secret: [u8; 32]) {
+        self.secrets.lock().unwrap().insert(
+            peer_id,
+            ResumptionSecret {
+                secret,
+                created_at: self.clock.instant(),
+            },
+        );
+    }
+
+    fn take(&self, peer_id: &str) -> Option<[u8; 32]> {
+        self.take_if(peer_id, |_| true)
+    }
+
+    /// Removes and returns the secret of `peer_id` if it is still fresh and `accept`
+    /// approves it. A rejected secret stays, so a forged attempt can't burn it.
+    fn take_if(&self, peer_id: &str, accept: impl FnOnce(&[u8; 32]) -> bool) -> Option<[u8; 32]> {
+        let mut secrets = self.secrets.lock().unwrap();
+        let entry = secrets.get(peer_id)?;
+        if self.clock.instant().duration_since(entry.created_at) >= self.ttl {
+            secrets.remove(peer_id);
+            return None;
+        }
+        if !accept(&entry.secret) {
+            return None;
+        }
+        secrets.remove(peer_id).map(|entry| entry.secret)
     }
 }
 
 pub async fn read_handshake<RW: AsyncReadExt + AsyncWriteExt + Unpin>(
     transport: &mut RW,
-    my_signing_key: &SigningKey,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    resumption: Option<&ResumptionCache>,
 ) -> io::Result<Handshake> {
-    let mut their_ephemeral_pub_bytes = [0u8; 32]; // [k]G
-    transport.read_exact(&mut their_ephemeral_pub_bytes).await?;
-    let their_ephemeral_pub = x25519_dalek::PublicKey::from(their_ephemeral_pub_bytes);
-
-    let my_ephemeral_secret = x25519_dalek::StaticSecret::new(&mut OsRng);
-    let my_ephemeral_pub = x25519_dalek::PublicKey::from(&my_ephemeral_secret);
-
-    let transcript: Vec<u8> = their_ephemeral_pub
-        .as_bytes()
-        .iter()
-        .chain(my_ephemeral_pub.as_bytes().iter())
-        .copied()
-        .collect();
-    let my_signature = my_signing_key.sign(&transcript);
-
-    let my_verifying_key = VerifyingKey::from(my_signing_key);
-
-    transport.write_all(my_ephemeral_pub.as_bytes()).await?;
-    transport.write_all(my_verifying_key.as_bytes()).await?;
-    transport.write_all(&my_signature.to_bytes()).await?;
-    transport.flush().await?;
-
-    let mut their_verifying_key_bytes = [0u8; 32];
-    let mut their_signature_bytes = [0u8; 64];
-    transport.read_exact(&mut their_verifying_key_bytes).await?;
-    transport.read_exact(&mut their_signature_bytes).await?;
-
-    let their_verifying_key = VerifyingKey::from_bytes(&their_verifying_key_bytes)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Bad verifying key"))?;
-    let their_signature = Signature::from_bytes(&their_signature_bytes);
-
-    their_verifying_key
-        .verify(&transcript, &their_signature)
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "signature verify fail")
-        })?;
+    read_handshake_with_rng(transport, identity, scheme, context, resumption, &mut OsRng).await
+}
 
-    let shared_secret = my_ephemeral_secret.diffie_hellman(&their_ephemeral_pub);
-    let shared_secret_bytes = shared_secret.to_bytes();
+/// `read_handshake` taking ephemeral keys and nonces from `rng`, so tests can pin
+/// them and compare transcripts and derived keys against known vectors.
+pub async fn read_handshake_with_rng<RW, R>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    resumption: Option<&ResumptionCache>,
+    rng: &mut R,
+) -> io::Result<Handshake>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let mut magic = [0u8; HANDSHAKE_MAGIC.len()];
+    read_field(transport, &mut magic, "handshake version").await?;
+    if &magic != HANDSHAKE_MAGIC {
+        // an older peer, what we read is the start of its ephemeral key
+        if scheme.id != HandshakeScheme::DEFAULT_ID {
+            return Err(scheme_mismatch());
+        }
+        let mut their_ephemeral_pub = magic.to_vec();
+        their_ephemeral_pub.resize(LEGACY_EPHEMERAL_LEN, 0);
+        read_field(
+            transport,
+            &mut their_ephemeral_pub[HANDSHAKE_MAGIC.len()..],
+            "ephemeral public key",
+        )
+        .await?;
+        let (handshake, _) = respond_full_handshake(
+            transport,
+            identity,
+            scheme,
+            context,
+            their_ephemeral_pub,
+            false,
+            rng,
+        )
+        .await?;
+        return Ok(handshake);
+    }
+    let mut mode = [0u8; 1];
+    read_field(transport, &mut mode, "mode").await?;
+    if mode[0] == MODE_RESUME {
+        if let Some(handshake) =
+            read_resumption(transport, scheme, context, resumption, rng).await?
+        {
+            return Ok(handshake);
+        }
+        // the initiator falls back to a full handshake after a reject
+        read_field(transport, &mut mode, "mode after a rejected resumption").await?;
+    }
+    match mode[0] {
+        MODE_FULL if scheme.id == HandshakeScheme::DEFAULT_ID => {}
+        MODE_FULL => return Err(scheme_mismatch()),
+        MODE_SCHEME => {
+            let mut their_scheme = [0u8; 1];
+            read_field(transport, &mut their_scheme, "scheme id").await?;
+            if their_scheme[0] != scheme.id {
+                write_field(transport, &[SCHEME_REJECT], "scheme reject").await?;
+                flush(transport, "scheme reject").await?;
+                return Err(scheme_mismatch());
+            }
+            write_field(transport, &[SCHEME_ACCEPT], "scheme accept").await?;
+        }
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "unexpected handshake mode",
+            ))
+        }
+    }
+    let (handshake, resumption_secret) =
+        read_full_handshake(transport, identity, scheme, context, rng).await?;
+    if let Some(cache) = resumption {
+        cache.insert(handshake.hex_key(), resumption_secret);
+    }
+    Ok(handshake)
+}
 
-    let hk = Hkdf::<Sha256>::new(None, &shared_secret_bytes);
-    let mut symmetric_key = [0u8; 32];
-    hk.expand(DERIVATION_TEXT, &mut symmetric_key)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "HKDF expand error"))?;
+pub async fn write_handshake<RW: AsyncReadExt + AsyncWriteExt + Unpin>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    peer_id: &str,
+    resumption: Option<&ResumptionCache>,
+) -> io::Result<Handshake> {
+    write_handshake_with_rng(
+        transport,
+        identity,
+        scheme,
+        context,
+        peer_id,
+        resumption,
+        &mut OsRng,
+    )
+    .await
+}
 
-    Ok(Handshake {
+/// `write_handshake` taking ephemeral keys and nonces from `rng`, see `read_handshake_with_rng`.
+pub async fn write_handshake_with_rng<RW, R>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    peer_id: &str,
+    resumption: Option<&ResumptionCache>,
+    rng: &mut R,
+) -> io::Result<Handshake>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    write_field(transport, HANDSHAKE_MAGIC, "handshake version").await?;
+    let secret = resumption.and_then(|cache| cache.take(peer_id));
+    if let Some(secret) = &secret {
+        if let Some(handshake) =
+            write_resumption(transport, identity, context, peer_id, secret, resumption, rng)
+                .await?
+        {
+            return Ok(handshake);
+        }
+    }
+    if scheme.id == HandshakeScheme::DEFAULT_ID {
+        // the default scheme goes without an id, peers from before schemes expect that
+        write_field(transport, &[MODE_FULL], "mode").await?;
+    } else {
+        write_field(transport, &[MODE_SCHEME, scheme.id], "mode and scheme id").await?;
+        flush(transport, "mode and scheme id").await?;
+        let mut status = [0u8; 1];
+        read_field(transport, &mut status, "scheme status").await?;
+        if status[0] != SCHEME_ACCEPT {
+            return Err(scheme_mismatch());
+        }
+    }
+    // what an older responder took for the start of our ephemeral key, a peer we
+    // resumed with before can't be one
+    let legacy_prefix = (secret.is_none() && scheme.id == HandshakeScheme::DEFAULT_ID)
+        .then(|| [HANDSHAKE_MAGIC.as_slice(), &[MODE_FULL]].concat());
+    let (handshake, resumption_secret) =
+        write_full_handshake(transport, identity, scheme, context, legacy_prefix, rng).await?;
+    if let Some(cache) = resumption {
+        cache.insert(handshake.hex_key(), resumption_secret);
+    }
+    Ok(handshake)
+}
+
+/// Reads `what` of the handshake, errors say which field failed. A peer that closed
+/// the connection is reported as `UnexpectedEof`.
+async fn read_field<RW: AsyncReadExt + Unpin>(
+    transport: &mut RW,
+    buf: &mut [u8],
+    what: &str,
+) -> io::Result<()> {
+    transport
+        .read_exact(buf)
+        .await
+        .map(|_| ())
+        .map_err(|err| phase_error(err, "reading", what))
+}
+
+/// Writes `what` of the handshake, see `read_field`.
+async fn write_field<RW: AsyncWriteExt + Unpin>(
+    transport: &mut RW,
+    buf: &[u8],
+    what: &str,
+) -> io::Result<()> {
+    transport
+        .write_all(buf)
+        .await
+        .map_err(|err| phase_error(err, "writing", what))
+}
+
+async fn flush<RW: AsyncWriteExt + Unpin>(transport: &mut RW, what: &str) -> io::Result<()> {
+    transport
+        .flush()
+        .await
+        .map_err(|err| phase_error(err, "flushing", what))
+}
+
+/// Keeps the kind of `err` so callers can still tell auth failures apart, except
+/// that the ways a socket reports a closed peer all become `UnexpectedEof`.
+fn phase_error(err: io::Error, action: &str, what: &str) -> io::Error {
+    match err.kind() {
+        io::ErrorKind::UnexpectedEof
+        | io::ErrorKind::BrokenPipe
+        | io::ErrorKind::ConnectionReset
+        | io::ErrorKind::ConnectionAborted
+        | io::ErrorKind::WriteZero => io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("peer closed the connection while {} the {}", action, what),
+        ),
+        kind => io::Error::new(kind, format!("{} the {}: {}", action, what, err)),
+    }
+}
+
+fn scheme_mismatch() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        "handshake scheme mismatch, the peer uses a different key exchange",
+    )
+}
+
+/// Responder side of a resumption attempt, returns `None` if the attempt was rejected.
+async fn read_resumption<RW, R>(
+    transport: &mut RW,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    resumption: Option<&ResumptionCache>,
+    rng: &mut R,
+) -> io::Result<Option<Handshake>>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let mut their_pub_key = vec![0u8; scheme.verifier.public_key_len()];
+    let mut their_nonce = [0u8; NONCE_SIZE];
+    let mut their_tag = [0u8; TAG_SIZE];
+    read_field(transport, &mut their_pub_key, "resumption public key").await?;
+    read_field(transport, &mut their_nonce, "resumption nonce").await?;
+    read_field(transport, &mut their_tag, "resumption tag").await?;
+
+    let secret = resumption.and_then(|cache| {
+        cache.take_if(&hex::encode(&their_pub_key), |secret| {
+            verify_confirmation(secret, RESUME_INITIATOR_ROLE, &their_nonce, &their_tag).is_ok()
+        })
+    });
+    let secret = match secret {
+        Some(secret) => secret,
+        None => {
+            write_field(transport, &[RESUME_REJECT], "resumption reject").await?;
+            flush(transport, "resumption reject").await?;
+            return Ok(None);
+        }
+    };
+
+    let mut my_nonce = [0u8; NONCE_SIZE];
+    rng.fill_bytes(&mut my_nonce);
+    let nonces = [their_nonce, my_nonce].concat();
+    let my_tag = confirmation_tag(&secret, RESUME_RESPONDER_ROLE, &nonces)?;
+    write_field(transport, &[RESUME_ACCEPT], "resumption accept").await?;
+    write_field(transport, &my_nonce, "resumption nonce").await?;
+    write_field(transport, &my_tag, "resumption tag").await?;
+    flush(transport, "resumption accept").await?;
+
+    let (symmetric_key, next_secret) = derive_resumed_keys(&secret, &nonces, context)?;
+    if let Some(cache) = resumption {
+        cache.insert(hex::encode(&their_pub_key), next_secret);
+    }
+    Ok(Some(Handshake {
         symmetric_key,
-        their_pub_key: their_verifying_key_bytes.clone(),
-    })
+        their_pub_key,
+    }))
 }
 
-pub async fn write_handshake<RW: AsyncReadExt + AsyncWriteExt + Unpin>(
+/// Initiator side of a resumption attempt, returns `None` if the responder rejected it.
+async fn write_resumption<RW, R>(
     transport: &mut RW,
-    my_signing_key: &SigningKey,
-) -> io::Result<Handshake> {
-    let my_ephemeral_secret = x25519_dalek::StaticSecret::new(&mut OsRng);
-    let my_ephemeral_pub = x25519_dalek::PublicKey::from(&my_ephemeral_secret);
-
-    transport.write_all(my_ephemeral_pub.as_bytes()).await?;
-    transport.flush().await?;
-    let mut their_ephemeral_pub_bytes = [0u8; 32];
-    let mut their_verifying_key_bytes = [0u8; 32];
-    let mut their_signature_bytes = [0u8; 64];
-
-    transport.read_exact(&mut their_ephemeral_pub_bytes).await?;
-    transport.read_exact(&mut their_verifying_key_bytes).await?;
-    transport.read_exact(&mut their_signature_bytes).await?;
-
-    let their_ephemeral_pub = x25519_dalek::PublicKey::from(their_ephemeral_pub_bytes);
-    let their_verifying_key = VerifyingKey::from_bytes(&their_verifying_key_bytes)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::InvalidData, "Bad verifying key"))?;
-    let their_signature = ed25519_dalek::Signature::from_bytes(&their_signature_bytes);
-
-    let transcript: Vec<u8> = my_ephemeral_pub
-        .as_bytes()
-        .iter()
-        .chain(their_ephemeral_pub.as_bytes().iter())
-        .copied()
-        .collect();
-
-    their_verifying_key
-        .verify(&transcript, &their_signature)
-        .map_err(|_| {
-            std::io::Error::new(std::io::ErrorKind::InvalidData, "signature verify fail")
-        })?;
+    identity: &dyn HandshakeIdentity,
+    context: &[u8],
+    peer_id: &str,
+    secret: &[u8; 32],
+    resumption: Option<&ResumptionCache>,
+    rng: &mut R,
+) -> io::Result<Option<Handshake>>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let their_pub_key = hex::decode(peer_id)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "Bad peer id"))?;
+    let mut my_nonce = [0u8; NONCE_SIZE];
+    rng.fill_bytes(&mut my_nonce);
+    let my_tag = confirmation_tag(secret, RESUME_INITIATOR_ROLE, &my_nonce)?;
+    write_field(transport, &[MODE_RESUME], "mode").await?;
+    write_field(transport, &identity.public_key(), "resumption public key").await?;
+    write_field(transport, &my_nonce, "resumption nonce").await?;
+    write_field(transport, &my_tag, "resumption tag").await?;
+    flush(transport, "resumption request").await?;
+
+    let mut status = [0u8; 1];
+    read_field(transport, &mut status, "resumption status").await?;
+    if status[0] != RESUME_ACCEPT {
+        return Ok(None);
+    }
+    let mut their_nonce = [0u8; NONCE_SIZE];
+    let mut their_tag = [0u8; TAG_SIZE];
+    read_field(transport, &mut their_nonce, "resumption nonce").await?;
+    read_field(transport, &mut their_tag, "resumption tag").await?;
+    let nonces = [my_nonce, their_nonce].concat();
+    verify_confirmation(secret, RESUME_RESPONDER_ROLE, &nonces, &their_tag)?;
+
+    let (symmetric_key, next_secret) = derive_resumed_keys(secret, &nonces, context)?;
+    if let Some(cache) = resumption {
+        cache.insert(peer_id.to_owned(), next_secret);
+    }
+    Ok(Some(Handshake {
+        symmetric_key,
+        their_pub_key,
+    }))
+}
+
+async fn read_full_handshake<RW, R>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    rng: &mut R,
+) -> io::Result<(Handshake, [u8; 32])>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let mut their_ephemeral_pub = vec![0u8; scheme.key_agreement.public_key_len()]; // [k]G
+    read_field(transport, &mut their_ephemeral_pub, "ephemeral public key").await?;
+    respond_full_handshake(transport, identity, scheme, context, their_ephemeral_pub, true, rng).await
+}
+
+/// Responder side of a full handshake once the initiator's ephemeral key is read.
+/// Older peers skip the key confirmation, `confirm` is false for them.
+async fn respond_full_handshake<RW, R>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    their_ephemeral_pub: Vec<u8>,
+    confirm: bool,
+    rng: &mut R,
+) -> io::Result<(Handshake, [u8; 32])>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let (my_ephemeral_secret, my_ephemeral_pub) = scheme.key_agreement.generate(rng);
+
+    let transcript = [their_ephemeral_pub.as_slice(), &my_ephemeral_pub].concat();
+    let my_signature = identity.sign(&transcript);
+
+    write_field(transport, &my_ephemeral_pub, "ephemeral public key").await?;
+    write_field(transport, &identity.public_key(), "public key").await?;
+    write_field(transport, &my_signature, "signature").await?;
+    flush(transport, "signature").await?;
+
+    let mut their_pub_key = vec![0u8; scheme.verifier.public_key_len()];
+    let mut their_signature = vec![0u8; scheme.verifier.signature_len()];
+    read_field(transport, &mut their_pub_key, "public key").await?;
+    read_field(transport, &mut their_signature, "signature").await?;
+    scheme
+        .verifier
+        .verify(&their_pub_key, &transcript, &their_signature)?;
+
+    let shared_secret = my_ephemeral_secret.agree(&their_ephemeral_pub)?;
+    let (symmetric_key, confirm_key, resumption_secret) = derive_keys(&shared_secret, context)?;
+
+    if confirm {
+        let mut their_tag = [0u8; TAG_SIZE];
+        read_field(transport, &mut their_tag, "confirmation tag").await?;
+        verify_confirmation(&confirm_key, INITIATOR_ROLE, &transcript, &their_tag)?;
+        let my_tag = confirmation_tag(&confirm_key, RESPONDER_ROLE, &transcript)?;
+        write_field(transport, &my_tag, "confirmation tag").await?;
+        flush(transport, "confirmation tag").await?;
+    }
 
-    let my_signature = my_signing_key.sign(&transcript);
-    let my_verifying_key = VerifyingKey::from(my_signing_key);
+    Ok((
+        Handshake {
+            symmetric_key,
+            their_pub_key,
+        },
+        resumption_secret,
+    ))
+}
+
+/// Initiator side of a full handshake. With `legacy_prefix`, the bytes sent ahead of
+/// the ephemeral key, a responder of an older release that read them as its key is
+/// reported as such instead of as a bad signature.
+async fn write_full_handshake<RW, R>(
+    transport: &mut RW,
+    identity: &dyn HandshakeIdentity,
+    scheme: &HandshakeScheme,
+    context: &[u8],
+    legacy_prefix: Option<Vec<u8>>,
+    rng: &mut R,
+) -> io::Result<(Handshake, [u8; 32])>
+where
+    RW: AsyncReadExt + AsyncWriteExt + Unpin,
+    R: RngCore + CryptoRng,
+{
+    let (my_ephemeral_secret, my_ephemeral_pub) = scheme.key_agreement.generate(rng);
+
+    write_field(transport, &my_ephemeral_pub, "ephemeral public key").await?;
+    flush(transport, "ephemeral public key").await?;
+    let mut their_ephemeral_pub = vec![0u8; scheme.key_agreement.public_key_len()];
+    let mut their_pub_key = vec![0u8; scheme.verifier.public_key_len()];
+    let mut their_signature = vec![0u8; scheme.verifier.signature_len()];
+
+    read_field(transport, &mut their_ephemeral_pub, "ephemeral public key").await?;
+    read_field(transport, &mut their_pub_key, "public key").await?;
+    read_field(transport, &mut their_signature, "signature").await?;
 
-    transport.write_all(my_verifying_key.as_bytes()).await?;
-    transport.write_all(&my_signature.to_bytes()).await?;
-    transport.flush().await?;
+    let transcript = [my_ephemeral_pub.as_slice(), &their_ephemeral_pub].concat();
+    if let Err(err) = scheme
+        .verifier
+        .verify(&their_pub_key, &transcript, &their_signature)
+    {
+        if let Some(prefix) = legacy_prefix {
+            let mut legacy_transcript = [prefix.as_slice(), &my_ephemeral_pub].concat();
+            legacy_transcript.truncate(LEGACY_EPHEMERAL_LEN);
+            legacy_transcript.extend_from_slice(&their_ephemeral_pub);
+            if scheme
+                .verifier
+                .verify(&their_pub_key, &legacy_transcript, &their_signature)
+                .is_ok()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "peer runs an older release without handshake versioning, it can only dial us",
+                ));
+            }
+        }
+        return Err(err);
+    }
+
+    let my_signature = identity.sign(&transcript);
+    write_field(transport, &identity.public_key(), "public key").await?;
+    write_field(transport, &my_signature, "signature").await?;
+    flush(transport, "signature").await?;
+
+    let shared_secret = my_ephemeral_secret.agree(&their_ephemeral_pub)?;
+    let (symmetric_key, confirm_key, resumption_secret) = derive_keys(&shared_secret, context)?;
 
-    let shared_secret = my_ephemeral_secret.diffie_hellman(&their_ephemeral_pub);
-    let shared_secret_bytes = shared_secret.to_bytes();
+    let my_tag = confirmation_tag(&confirm_key, INITIATOR_ROLE, &transcript)?;
+    write_field(transport, &my_tag, "confirmation tag").await?;
+    flush(transport, "confirmation tag").await?;
+    let mut their_tag = [0u8; TAG_SIZE];
+    read_field(transport, &mut their_tag, "confirmation tag").await?;
+    verify_confirmation(&confirm_key, RESPONDER_ROLE, &transcript, &their_tag)?;
+
+    Ok((
+        Handshake {
+            symmetric_key,
+            their_pub_key,
+        },
+        resumption_secret,
+    ))
+}
 
-    let hk = Hkdf::<Sha256>::new(None, &shared_secret_bytes);
+/// Derives the session key, a separate key used only for key confirmation
+/// and the secret a later session can be resumed from.
+fn derive_keys(
+    shared_secret: &[u8],
+    context: &[u8],
+) -> io::Result<([u8; 32], [u8; 32], [u8; 32])> {
+    let hk = Hkdf::<Sha256>::new(None, shared_secret);
     let mut symmetric_key = [0u8; 32];
-    hk.expand(DERIVATION_TEXT, &mut symmetric_key)
-        .map_err(|_| std::io::Error::new(std::io::ErrorKind::Other, "HKDF expand error"))?;
+    hk.expand(context, &mut symmetric_key)
+        .map_err(|_| std::io::Error::other("HKDF expand error"))?;
+    let mut confirm_key = [0u8; 32];
+    hk.expand_multi_info(&[context, CONFIRM_LABEL], &mut confirm_key)
+        .map_err(|_| std::io::Error::other("HKDF expand error"))?;
+    let mut resumption_secret = [0u8; 32];
+    hk.expand_multi_info(&[context, RESUMPTION_LABEL], &mut resumption_secret)
+        .map_err(|_| std::io::Error::other("HKDF expand error"))?;
+    Ok((symmetric_key, confirm_key, resumption_secret))
+}
 
-    Ok(Handshake {
-        symmetric_key,
-        their_pub_key: their_verifying_key_bytes.clone(),
-    })
+/// Derives the session key of a resumed session and the secret replacing the used one.
+fn derive_resumed_keys(
+    secret: &[u8],
+    nonces: &[u8],
+    context: &[u8],
+) -> io::Result<([u8; 32], [u8; 32])> {
+    let hk = Hkdf::<Sha256>::new(Some(nonces), secret);
+    let mut symmetric_key = [0u8; 32];
+    hk.expand(context, &mut symmetric_key)
+        .map_err(|_| std::io::Error::other("HKDF expand error"))?;
+    let mut next_secret = [0u8; 32];
+    hk.expand_multi_info(&[context, RESUMPTION_LABEL], &mut next_secret)
+        .map_err(|_| std::io::Error::other("HKDF expand error"))?;
+    Ok((symmetric_key, next_secret))
+}
+
+fn confirmation_mac(
+    confirm_key: &[u8],
+    role: &[u8],
+    transcript: &[u8],
+) -> io::Result<Hmac<Sha256>> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(confirm_key)
+        .map_err(|_| std::io::Error::other("HMAC key error"))?;
+    mac.update(role);
+    mac.update(transcript);
+    Ok(mac)
+}
+
+fn confirmation_tag(
+    confirm_key: &[u8],
+    role: &[u8],
+    transcript: &[u8],
+) -> io::Result<[u8; TAG_SIZE]> {
+    let mac = confirmation_mac(confirm_key, role, transcript)?;
+    let mut tag = [0u8; TAG_SIZE];
+    tag.copy_from_slice(&mac.finalize().into_bytes());
+    Ok(tag)
+}
+
+fn verify_confirmation(
+    confirm_key: &[u8],
+    role: &[u8],
+    transcript: &[u8],
+    tag: &[u8],
+) -> io::Result<()> {
+    confirmation_mac(confirm_key, role, transcript)?
+        .verify_slice(tag)
+        .map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::PermissionDenied,
+                "key confirmation failed, peers derived different keys",
+            )
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use ed25519_dalek::{Signer, SigningKey, Verifier, VerifyingKey};
+    use std::collections::HashSet;
+    use tokio::io::DuplexStream;
+
+    const CONTEXT: &[u8] = b"p2p-chat";
+
+    fn cache() -> ResumptionCache {
+        ResumptionCache::new(Duration::from_secs(60), Arc::new(SystemClock))
+    }
+
+    fn id(key: &SigningKey) -> String {
+        hex::encode(key.verifying_key().to_bytes())
+    }
+
+    /// Runs both sides over an in-memory pipe, each end closes when its side is done.
+    async fn connect(
+        initiator: &SigningKey,
+        responder: &SigningKey,
+        initiator_cache: Option<&ResumptionCache>,
+        responder_cache: Option<&ResumptionCache>,
+    ) -> (io::Result<Handshake>, io::Result<Handshake>) {
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let scheme = &HandshakeScheme::default();
+        let peer_id = &id(responder);
+        tokio::join!(
+            async move {
+                write_handshake(&mut a, initiator, scheme, CONTEXT, peer_id, initiator_cache).await
+            },
+            async move { read_handshake(&mut b, responder, scheme, CONTEXT, responder_cache).await },
+        )
+    }
+
+    #[tokio::test]
+    async fn full_handshake_agrees_on_a_key() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (dialed, accepted) = connect(&alice, &bob, None, None).await;
+        let (dialed, accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(dialed.symmetric_key, accepted.symmetric_key);
+        assert_eq!(dialed.their_pub_key, bob.verifying_key().to_bytes());
+        assert_eq!(accepted.their_pub_key, alice.verifying_key().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn reconnect_resumes_with_a_fresh_key() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (alice_cache, bob_cache) = (cache(), cache());
+        let (first, _) = connect(&alice, &bob, Some(&alice_cache), Some(&bob_cache)).await;
+        let (dialed, accepted) = connect(&alice, &bob, Some(&alice_cache), Some(&bob_cache)).await;
+        let (dialed, accepted) = (dialed.unwrap(), accepted.unwrap());
+        assert_eq!(dialed.symmetric_key, accepted.symmetric_key);
+        assert_ne!(dialed.symmetric_key, first.unwrap().symmetric_key);
+        assert_eq!(accepted.their_pub_key, alice.verifying_key().to_bytes());
+        // both sides rotated to the same next secret
+        assert_eq!(alice_cache.take(&id(&bob)), bob_cache.take(&id(&alice)));
+    }
+
+    #[tokio::test]
+    async fn forged_resumption_leaves_the_secret_in_place() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (alice_cache, bob_cache) = (cache(), cache());
+        connect(&alice, &bob, Some(&alice_cache), Some(&bob_cache)).await.0.unwrap();
+
+        // someone who only knows alice's public key tries to resume as her
+        let (mut forger, mut b) = tokio::io::duplex(4096);
+        let scheme = &HandshakeScheme::default();
+        let bob_cache = &bob_cache;
+        let bob_key = &bob;
+        let alice_key = alice.verifying_key().to_bytes();
+        let (accepted, status) = tokio::join!(
+            async move { read_handshake(&mut b, bob_key, scheme, CONTEXT, Some(bob_cache)).await },
+            async move {
+                forger.write_all(HANDSHAKE_MAGIC).await.unwrap();
+                forger.write_all(&[MODE_RESUME]).await.unwrap();
+                forger.write_all(&alice_key).await.unwrap();
+                forger.write_all(&[7u8; NONCE_SIZE]).await.unwrap();
+                forger.write_all(&[7u8; TAG_SIZE]).await.unwrap();
+                let mut status = [0u8; 1];
+                forger.read_exact(&mut status).await.unwrap();
+                status[0]
+            },
+        );
+        assert_eq!(status, RESUME_REJECT);
+        assert!(accepted.is_err());
+
+        let secret = bob_cache.take(&id(&alice));
+        assert!(secret.is_some());
+        assert_eq!(secret, alice_cache.take(&id(&bob)));
+    }
+
+    /// The initiator side of the handshake before it was versioned, returns its key.
+    async fn legacy_write_handshake(transport: &mut DuplexStream, key: &SigningKey) -> [u8; 32] {
+        let secret = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let public = x25519_dalek::PublicKey::from(&secret);
+        transport.write_all(public.as_bytes()).await.unwrap();
+        let mut their_ephemeral = [0u8; 32];
+        let mut their_key = [0u8; 32];
+        let mut their_signature = [0u8; 64];
+        transport.read_exact(&mut their_ephemeral).await.unwrap();
+        transport.read_exact(&mut their_key).await.unwrap();
+        transport.read_exact(&mut their_signature).await.unwrap();
+        let transcript = [public.as_bytes().as_slice(), &their_ephemeral].concat();
+        VerifyingKey::from_bytes(&their_key)
+            .unwrap()
+            .verify(&transcript, &ed25519_dalek::Signature::from_bytes(&their_signature))
+            .unwrap();
+        transport.write_all(&key.verifying_key().to_bytes()).await.unwrap();
+        transport
+            .write_all(&Signer::sign(key, &transcript).to_bytes())
+            .await
+            .unwrap();
+        let shared = secret.diffie_hellman(&x25519_dalek::PublicKey::from(their_ephemeral));
+        derive_keys(shared.as_bytes(), CONTEXT).unwrap().0
+    }
+
+    /// The responder side of the handshake before it was versioned.
+    async fn legacy_read_handshake(mut transport: DuplexStream, key: &SigningKey) {
+        let mut their_ephemeral = [0u8; 32];
+        transport.read_exact(&mut their_ephemeral).await.unwrap();
+        let secret = x25519_dalek::StaticSecret::random_from_rng(OsRng);
+        let public = x25519_dalek::PublicKey::from(&secret);
+        let transcript = [their_ephemeral.as_slice(), public.as_bytes()].concat();
+        transport.write_all(public.as_bytes()).await.unwrap();
+        transport.write_all(&key.verifying_key().to_bytes()).await.unwrap();
+        transport
+            .write_all(&Signer::sign(key, &transcript).to_bytes())
+            .await
+            .unwrap();
+        // the initiator gives up here, closing the connection
+        let mut rest = [0u8; 96];
+        let _ = transport.read_exact(&mut rest).await;
+    }
+
+    #[tokio::test]
+    async fn older_initiator_can_still_dial_us() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let scheme = HandshakeScheme::default();
+        let cache = cache();
+        let (legacy_key, accepted) = tokio::join!(
+            legacy_write_handshake(&mut a, &alice),
+            read_handshake(&mut b, &bob, &scheme, CONTEXT, Some(&cache)),
+        );
+        let accepted = accepted.unwrap();
+        assert_eq!(accepted.symmetric_key, legacy_key);
+        assert_eq!(accepted.their_pub_key, alice.verifying_key().to_bytes());
+    }
+
+    #[tokio::test]
+    async fn dialing_an_older_responder_fails_clearly() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (mut a, b) = tokio::io::duplex(4096);
+        let scheme = &HandshakeScheme::default();
+        let peer_id = &id(&bob);
+        let alice_key = &alice;
+        let (dialed, _) = tokio::join!(
+            async move { write_handshake(&mut a, alice_key, scheme, CONTEXT, peer_id, None).await },
+            legacy_read_handshake(b, &bob),
+        );
+        let err = dialed.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("older release"), "{}", err);
+    }
+
+    /// The default ed25519/x25519 primitives under another scheme id.
+    fn scheme(id: u8) -> HandshakeScheme {
+        HandshakeScheme {
+            id,
+            ..HandshakeScheme::default()
+        }
+    }
+
+    async fn connect_with_schemes(
+        initiator_scheme: &HandshakeScheme,
+        responder_scheme: &HandshakeScheme,
+    ) -> (io::Result<Handshake>, io::Result<Handshake>) {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let peer_id = &id(&bob);
+        let (alice, bob) = (&alice, &bob);
+        tokio::join!(
+            async move {
+                write_handshake(&mut a, alice, initiator_scheme, CONTEXT, peer_id, None).await
+            },
+            async move { read_handshake(&mut b, bob, responder_scheme, CONTEXT, None).await },
+        )
+    }
+
+    fn assert_scheme_mismatch(res: io::Result<Handshake>) {
+        let err = res.err().expect("handshake with another scheme succeeded");
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.to_string().contains("scheme mismatch"), "{}", err);
+    }
+
+    #[tokio::test]
+    async fn same_custom_scheme_agrees_on_a_key() {
+        let (dialed, accepted) = connect_with_schemes(&scheme(7), &scheme(7)).await;
+        assert_eq!(
+            dialed.unwrap().symmetric_key,
+            accepted.unwrap().symmetric_key
+        );
+    }
+
+    #[tokio::test]
+    async fn scheme_mismatch_fails_on_both_sides() {
+        // both sides sent an id, the responder answers with a reject
+        let (dialed, accepted) = connect_with_schemes(&scheme(7), &scheme(8)).await;
+        assert_scheme_mismatch(dialed);
+        assert_scheme_mismatch(accepted);
+        let (dialed, accepted) =
+            connect_with_schemes(&scheme(7), &HandshakeScheme::default()).await;
+        assert_scheme_mismatch(dialed);
+        assert_scheme_mismatch(accepted);
+    }
+
+    #[tokio::test]
+    async fn default_initiator_is_refused_by_a_custom_responder() {
+        // the default scheme sends no id, the responder hangs up without an answer
+        let (dialed, accepted) =
+            connect_with_schemes(&HandshakeScheme::default(), &scheme(7)).await;
+        assert_scheme_mismatch(accepted);
+        assert_eq!(dialed.err().unwrap().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[tokio::test]
+    async fn mismatched_keys_fail_the_confirmation() {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (mut a, mut b) = tokio::io::duplex(4096);
+        let scheme = &HandshakeScheme::default();
+        let peer_id = &id(&bob);
+        let (alice, bob) = (&alice, &bob);
+        // different contexts derive different keys from the same exchange
+        let (dialed, accepted) = tokio::join!(
+            async move { write_handshake(&mut a, alice, scheme, CONTEXT, peer_id, None).await },
+            async move { read_handshake(&mut b, bob, scheme, b"other-context", None).await },
+        );
+        let err = accepted.err().expect("responder accepted a different key");
+        assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        assert!(
+            err.to_string().contains("key confirmation failed"),
+            "{}",
+            err
+        );
+        // the responder hangs up without its tag
+        assert!(dialed.is_err());
+    }
+
+    #[test]
+    fn confirmation_tags_are_bound_to_key_role_and_transcript() {
+        let (_, confirm_key, _) = derive_keys(&[1u8; 32], CONTEXT).unwrap();
+        let (_, other_key, _) = derive_keys(&[1u8; 32], b"other-context").unwrap();
+        let transcript = [2u8; 64];
+        let tag = confirmation_tag(&confirm_key, INITIATOR_ROLE, &transcript).unwrap();
+        verify_confirmation(&confirm_key, INITIATOR_ROLE, &transcript, &tag).unwrap();
+        // another key, a reflected tag or another transcript don't verify
+        assert!(verify_confirmation(&other_key, INITIATOR_ROLE, &transcript, &tag).is_err());
+        assert!(verify_confirmation(&confirm_key, RESPONDER_ROLE, &transcript, &tag).is_err());
+        assert!(verify_confirmation(&confirm_key, INITIATOR_ROLE, &[3u8; 64], &tag).is_err());
+    }
+
+    /// A transport whose peer goes away after `limit` bytes were read from it,
+    /// reads then see the end of the stream and writes a broken pipe.
+    struct CutAfter {
+        inner: DuplexStream,
+        limit: usize,
+        read: usize,
+    }
+
+    impl tokio::io::AsyncRead for CutAfter {
+        fn poll_read(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            let left = self.limit - self.read;
+            if left == 0 {
+                return std::task::Poll::Ready(Ok(()));
+            }
+            let mut limited = buf.take(left);
+            let res = std::pin::Pin::new(&mut self.inner).poll_read(cx, &mut limited);
+            let n = limited.filled().len();
+            buf.advance(n);
+            self.read += n;
+            res
+        }
+    }
+
+    impl tokio::io::AsyncWrite for CutAfter {
+        fn poll_write(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+            buf: &[u8],
+        ) -> std::task::Poll<io::Result<usize>> {
+            if self.read == self.limit {
+                return std::task::Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            std::pin::Pin::new(&mut self.inner).poll_write(cx, buf)
+        }
+
+        fn poll_flush(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(
+            mut self: std::pin::Pin<&mut Self>,
+            cx: &mut std::task::Context<'_>,
+        ) -> std::task::Poll<io::Result<()>> {
+            std::pin::Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// Runs a full handshake where one side, the initiator or the responder, loses its
+    /// peer after reading `limit` bytes. Returns that side's result and how much it read.
+    async fn handshake_cut_after(initiator: bool, limit: usize) -> (io::Result<Handshake>, usize) {
+        let alice = SigningKey::generate(&mut OsRng);
+        let bob = SigningKey::generate(&mut OsRng);
+        let (a, b) = tokio::io::duplex(4096);
+        let scheme = &HandshakeScheme::default();
+        let peer_id = &id(&bob);
+        let (alice, bob) = (&alice, &bob);
+        let (ours, theirs) = if initiator { (a, b) } else { (b, a) };
+        let mut cut = CutAfter {
+            inner: ours,
+            limit,
+            read: 0,
+        };
+        let mut theirs = theirs;
+        // each side owns its end, so the side that fails hangs up on the other
+        let (res, _) = tokio::join!(
+            async move {
+                let res = if initiator {
+                    write_handshake(&mut cut, alice, scheme, CONTEXT, peer_id, None).await
+                } else {
+                    read_handshake(&mut cut, bob, scheme, CONTEXT, None).await
+                };
+                (res, cut.read)
+            },
+            async move {
+                if initiator {
+                    read_handshake(&mut theirs, bob, scheme, CONTEXT, None).await
+                } else {
+                    write_handshake(&mut theirs, alice, scheme, CONTEXT, peer_id, None).await
+                }
+            },
+        );
+        res
+    }
+
+    #[tokio::test]
+    async fn peer_closing_at_any_phase_is_reported_as_closed() {
+        for initiator in [true, false] {
+            let (res, total) = handshake_cut_after(initiator, usize::MAX).await;
+            res.unwrap();
+            let mut fields = HashSet::new();
+            for limit in 0..total {
+                let (res, _) = handshake_cut_after(initiator, limit).await;
+                let err = res.err().expect("handshake finished without its peer");
+                assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof, "{}", err);
+                let message = err.to_string();
+                let field = message
+                    .strip_prefix("peer closed the connection while ")
+                    .unwrap_or_else(|| panic!("no phase in {:?}", message));
+                fields.insert(field.to_owned());
+            }
+            for field in [
+                "reading the ephemeral public key",
+                "reading the public key",
+                "reading the signature",
+                "reading the confirmation tag",
+            ] {
+                assert!(fields.contains(field), "{} in {:?}", field, fields);
+            }
+        }
+    }
 }
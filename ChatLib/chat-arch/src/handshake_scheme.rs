@@ -0,0 +1,134 @@
+use std::fmt::Debug;
+use std::io;
+use std::sync::Arc;
+
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::{CryptoRng, RngCore};
+
+/// Our long-term key, it signs the handshake transcript and the peer id is derived
+/// from its public key.
+pub trait HandshakeIdentity: Send + Sync {
+    fn public_key(&self) -> Vec<u8>;
+
+    fn sign(&self, message: &[u8]) -> Vec<u8>;
+}
+
+impl HandshakeIdentity for SigningKey {
+    fn public_key(&self) -> Vec<u8> {
+        self.verifying_key().to_bytes().to_vec()
+    }
+
+    fn sign(&self, message: &[u8]) -> Vec<u8> {
+        Signer::sign(self, message).to_bytes().to_vec()
+    }
+}
+
+/// Checks the other side's signature over the transcript. Must match the scheme of
+/// the `HandshakeIdentity` both sides use.
+pub trait SignatureVerifier: Send + Sync + Debug {
+    fn public_key_len(&self) -> usize;
+
+    fn signature_len(&self) -> usize;
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> io::Result<()>;
+}
+
+/// Randomness for ephemeral keys, any cryptographically secure rng.
+pub trait HandshakeRng: RngCore + CryptoRng {}
+
+impl<R: RngCore + CryptoRng> HandshakeRng for R {}
+
+/// Secret half of an ephemeral key pair, consumed by the agreement.
+pub trait AgreementSecret: Send {
+    fn agree(self: Box<Self>, their_public: &[u8]) -> io::Result<Vec<u8>>;
+}
+
+/// Ephemeral key agreement giving both sides the same shared secret.
+pub trait KeyAgreement: Send + Sync + Debug {
+    fn public_key_len(&self) -> usize;
+
+    /// A fresh key pair, the public half is sent to the other side.
+    fn generate(&self, rng: &mut dyn HandshakeRng) -> (Box<dyn AgreementSecret>, Vec<u8>);
+}
+
+/// Identity signatures and key agreement of a handshake, evolving independently.
+/// Both sides must use the same scheme, a mismatch fails the handshake.
+#[derive(Clone, Debug)]
+pub struct HandshakeScheme {
+    /// Sent during the handshake to detect a mismatch. 0 is the default
+    /// ed25519/x25519 scheme, its id is left out so peers from before schemes existed
+    /// still understand it. It is also the only scheme of releases without handshake
+    /// versioning.
+    pub id: u8,
+    pub verifier: Arc<dyn SignatureVerifier>,
+    pub key_agreement: Arc<dyn KeyAgreement>,
+}
+
+impl HandshakeScheme {
+    pub const DEFAULT_ID: u8 = 0;
+}
+
+impl Default for HandshakeScheme {
+    fn default() -> Self {
+        Self {
+            id: Self::DEFAULT_ID,
+            verifier: Arc::new(Ed25519),
+            key_agreement: Arc::new(X25519),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Ed25519;
+
+impl SignatureVerifier for Ed25519 {
+    fn public_key_len(&self) -> usize {
+        32
+    }
+
+    fn signature_len(&self) -> usize {
+        64
+    }
+
+    fn verify(&self, public_key: &[u8], message: &[u8], signature: &[u8]) -> io::Result<()> {
+        let public_key: [u8; 32] = public_key
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad verifying key"))?;
+        let signature: [u8; 64] = signature
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad signature"))?;
+        VerifyingKey::from_bytes(&public_key)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad verifying key"))?
+            .verify(message, &Signature::from_bytes(&signature))
+            .map_err(|_| io::Error::new(io::ErrorKind::PermissionDenied, "signature verify fail"))
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct X25519;
+
+struct X25519Secret(x25519_dalek::StaticSecret);
+
+impl AgreementSecret for X25519Secret {
+    fn agree(self: Box<Self>, their_public: &[u8]) -> io::Result<Vec<u8>> {
+        let their_public: [u8; 32] = their_public
+            .try_into()
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad ephemeral key"))?;
+        let shared = self
+            .0
+            .diffie_hellman(&x25519_dalek::PublicKey::from(their_public));
+        Ok(shared.to_bytes().to_vec())
+    }
+}
+
+impl KeyAgreement for X25519 {
+    fn public_key_len(&self) -> usize {
+        32
+    }
+
+    fn generate(&self, rng: &mut dyn HandshakeRng) -> (Box<dyn AgreementSecret>, Vec<u8>) {
+        let secret = x25519_dalek::StaticSecret::random_from_rng(rng);
+        let public = x25519_dalek::PublicKey::from(&secret);
+        (Box::new(X25519Secret(secret)), public.as_bytes().to_vec())
+    }
+}
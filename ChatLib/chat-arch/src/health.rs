@@ -0,0 +1,92 @@
+use std::time::{Duration, Instant};
+
+use tokio::time::timeout;
+
+use crate::{app_context::AppContext, sync_engine::SYNC_INTERVAL};
+
+/// The scheduler counts as stalled once it missed this many sweeps in a row.
+const MISSED_SWEEPS: u32 = 3;
+/// A database that takes longer to answer a trivial query counts as wedged.
+const DB_PING_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Liveness of a running node, for a supervisor that restarts a wedged one.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Health {
+    /// The server has a bound socket and accepts connections.
+    pub listening: bool,
+    /// The database answered a query within a few seconds.
+    pub db_responsive: bool,
+    /// Time since the sync scheduler last started a sweep, `None` if it never ran.
+    pub since_last_sweep: Option<Duration>,
+}
+
+impl Health {
+    /// Whether the node is serving, storing and syncing.
+    pub fn is_healthy(&self) -> bool {
+        self.listening && self.db_responsive && !self.scheduler_stalled()
+    }
+
+    pub fn scheduler_stalled(&self) -> bool {
+        self.since_last_sweep
+            .is_none_or(|since| since > SYNC_INTERVAL * MISSED_SWEEPS)
+    }
+}
+
+pub async fn check(ctx: &AppContext) -> Health {
+    let db_responsive = matches!(
+        timeout(DB_PING_TIMEOUT, ctx.message_db.ping()).await,
+        Ok(Ok(()))
+    );
+    Health {
+        listening: ctx.server.is_listening(),
+        db_responsive,
+        since_last_sweep: ctx
+            .sync_engine
+            .last_sweep()
+            .map(|tick| Instant::now().saturating_duration_since(tick)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn health(since_last_sweep: Option<Duration>) -> Health {
+        Health {
+            listening: true,
+            db_responsive: true,
+            since_last_sweep,
+        }
+    }
+
+    #[test]
+    fn scheduler_that_never_ran_is_stalled() {
+        assert!(health(None).scheduler_stalled());
+        assert!(!health(None).is_healthy());
+    }
+
+    #[test]
+    fn scheduler_is_stalled_after_missing_sweeps() {
+        let limit = SYNC_INTERVAL * MISSED_SWEEPS;
+        assert!(!health(Some(Duration::ZERO)).scheduler_stalled());
+        assert!(!health(Some(limit)).scheduler_stalled());
+        assert!(health(Some(limit + Duration::from_millis(1))).scheduler_stalled());
+        assert!(!health(Some(limit + Duration::from_millis(1))).is_healthy());
+    }
+
+    #[test]
+    fn healthy_needs_every_check() {
+        let recent = Some(Duration::ZERO);
+        assert!(health(recent).is_healthy());
+        assert!(!Health {
+            listening: false,
+            ..health(recent)
+        }
+        .is_healthy());
+        assert!(!Health {
+            db_responsive: false,
+            ..health(recent)
+        }
+        .is_healthy());
+    }
+}
@@ -0,0 +1,418 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::sync::Mutex;
+
+use crate::{
+    clock::Clock,
+    events::Events,
+    peer_database::PeerDatabase,
+    peer_pool::{EncryptedPool, EncryptedSession},
+};
+
+/// Decides what happens to an inbound session once the handshake verified the peer's key.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum InboundPolicy {
+    /// Every peer with a valid handshake can sync.
+    #[default]
+    AcceptAll,
+    /// Only peers already known to the peer database can sync, others are dropped.
+    AllowlistOnly,
+    /// Known peers are accepted, unknown ones are held until the embedder answers
+    /// `ChatEvent::ConnectionRequest` with `approve` or `reject`. Up to
+    /// `MAX_PENDING_REQUESTS` are held, each for `PENDING_REQUEST_TIMEOUT`.
+    PromptViaEvent,
+}
+
+/// Unknown peers held for approval at once, further ones are dropped until the
+/// embedder answered some or they expired.
+pub const MAX_PENDING_REQUESTS: usize = 32;
+/// How long a held session waits for `approve` or `reject` before it is dropped.
+pub const PENDING_REQUEST_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+struct PendingRequest {
+    addr: SocketAddr,
+    session: EncryptedSession,
+    since: Instant,
+}
+
+pub struct InboundGate {
+    policy: InboundPolicy,
+    peer_pool: Arc<EncryptedPool>,
+    peer_db: Arc<PeerDatabase>,
+    events: Arc<Events>,
+    clock: Arc<dyn Clock>,
+    pending: Mutex<HashMap<String, PendingRequest>>,
+}
+
+impl InboundGate {
+    pub fn new(
+        policy: InboundPolicy,
+        peer_pool: Arc<EncryptedPool>,
+        peer_db: Arc<PeerDatabase>,
+        events: Arc<Events>,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            policy,
+            peer_pool,
+            peer_db,
+            events,
+            clock,
+            pending: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub async fn admit(
+        &self,
+        peer_id: &str,
+        addr: SocketAddr,
+        session: EncryptedSession,
+    ) -> Result<()> {
+        if self.policy == InboundPolicy::AcceptAll
+            || self.peer_db.get_peer_by_id(peer_id).await?.is_some()
+        {
+            return self.peer_pool.insert(peer_id, addr, session).await;
+        }
+        match self.policy {
+            InboundPolicy::PromptViaEvent => {
+                let mut pending = self.pending.lock().await;
+                self.expire(&mut pending);
+                // a newer connection replaces the one still waiting
+                if !pending.contains_key(peer_id) && pending.len() >= MAX_PENDING_REQUESTS {
+                    warn!(
+                        "too many connection requests, dropping session of {}",
+                        peer_id
+                    );
+                    return Ok(());
+                }
+                info!("holding session of unknown peer {} for approval", peer_id);
+                let since = self.clock.instant();
+                pending.insert(
+                    peer_id.to_owned(),
+                    PendingRequest {
+                        addr,
+                        session,
+                        since,
+                    },
+                );
+                drop(pending);
+                self.events
+                    .send_connection_request(peer_id.to_owned())
+                    .await
+            }
+            _ => {
+                info!("rejecting session of unknown peer {}", peer_id);
+                Ok(())
+            }
+        }
+    }
+
+    /// Lets a held peer sync, returns false if no session of the peer is waiting
+    /// or it expired.
+    pub async fn approve(&self, peer_id: &str) -> Result<bool> {
+        match self.take(peer_id).await {
+            Some(request) => {
+                self.peer_pool
+                    .insert(peer_id, request.addr, request.session)
+                    .await?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Drops a held session, returns false if no session of the peer is waiting
+    /// or it expired.
+    pub async fn reject(&self, peer_id: &str) -> bool {
+        self.take(peer_id).await.is_some()
+    }
+
+    async fn take(&self, peer_id: &str) -> Option<PendingRequest> {
+        let mut pending = self.pending.lock().await;
+        self.expire(&mut pending);
+        pending.remove(peer_id)
+    }
+
+    fn expire(&self, pending: &mut HashMap<String, PendingRequest>) {
+        let now = self.clock.instant();
+        pending.retain(|peer_id, request| {
+            let waiting = now.saturating_duration_since(request.since) < PENDING_REQUEST_TIMEOUT;
+            if !waiting {
+                info!("connection request of {} expired", peer_id);
+            }
+            waiting
+        });
+    }
+}
+
+#[cfg(test)]
+pub(crate) async fn memory_gate(
+    policy: InboundPolicy,
+    pool: sqlx::SqlitePool,
+    events: Arc<Events>,
+    delegate: std::sync::Weak<dyn crate::peer::PeerDelegate + Send + Sync>,
+    clock: Arc<dyn Clock>,
+    runtime: Arc<tokio::runtime::Runtime>,
+) -> InboundGate {
+    use crate::dialer::Dialer;
+    use crate::file_database::FileDatabase;
+    use crate::peer_pool::PeerPool;
+    use crate::sanitize::TextPolicy;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    // peers are read joined with their avatar files
+    FileDatabase::new(pool.clone(), events.clone())
+        .init()
+        .await
+        .unwrap();
+    let peer_db = Arc::new(PeerDatabase::new(
+        pool,
+        events.clone(),
+        TextPolicy::default(),
+        clock.clone(),
+    ));
+    peer_db.init().await.unwrap();
+    let key = SigningKey::generate(&mut OsRng);
+    let peer_pool = Arc::new(PeerPool::new(
+        crate::peer_database::peer_id(&key.verifying_key()),
+        Arc::new(Dialer::new(
+            key,
+            Vec::new(),
+            Default::default(),
+            None,
+            Default::default(),
+            Default::default(),
+        )),
+        delegate,
+        events.clone(),
+        Default::default(),
+        4,
+        None,
+        None,
+        clock.clone(),
+        runtime,
+    ));
+    InboundGate::new(policy, peer_pool, peer_db, events, clock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::conn::{EncryptedStream, StreamOptions};
+    use crate::events::ChatEvent;
+    use crate::message_database::memory_pool;
+    use crate::peer::PeerDelegate;
+    use crate::peer_pool::{PeerConnState, SessionOptions};
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::future::Future;
+    use tokio::net::{TcpListener, TcpStream};
+    use tokio::runtime::Runtime;
+    use tokio_yamux::{Session, StreamHandle};
+
+    struct NoopDelegate;
+
+    impl PeerDelegate for NoopDelegate {
+        fn handle_inbound_stream(
+            self: Arc<Self>,
+            _stream: StreamHandle,
+            _peer_id: String,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn peer_connected(self: Arc<Self>, _peer_id: String) {}
+
+        fn session_corrupted(self: Arc<Self>, _peer_id: String) {}
+    }
+
+    /// An accepted session, the dialing end is returned so the socket stays open.
+    async fn inbound_session() -> (SocketAddr, EncryptedSession, TcpStream) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let dialing = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (socket, addr) = listener.accept().await.unwrap();
+        let stream = EncryptedStream::with_options(socket, &[7; 32], StreamOptions::default());
+        let session = Session::new_server(stream, SessionOptions::default().yamux_config());
+        (addr, Arc::new(Mutex::new(session)), dialing)
+    }
+
+    fn new_peer_id() -> String {
+        crate::peer_database::peer_id(&SigningKey::generate(&mut OsRng).verifying_key())
+    }
+
+    fn connection_requests(events: &Events) -> Vec<String> {
+        events
+            .get_rx()
+            .try_iter()
+            .filter_map(|event| match event {
+                ChatEvent::ConnectionRequest(peer_id) => Some(peer_id),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn with_gate<F, Fut>(policy: InboundPolicy, clock: Arc<dyn Clock>, test: F)
+    where
+        F: FnOnce(InboundGate, Arc<Events>) -> Fut,
+        Fut: Future<Output = ()>,
+    {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let delegate: Arc<dyn PeerDelegate + Send + Sync> = Arc::new(NoopDelegate);
+            let events = Arc::new(Events::new());
+            let gate = memory_gate(
+                policy,
+                memory_pool().await,
+                events.clone(),
+                Arc::downgrade(&delegate),
+                clock,
+                runtime.clone(),
+            )
+            .await;
+            test(gate, events).await;
+        });
+    }
+
+    #[test]
+    fn allowlist_only_admits_known_peers() {
+        with_gate(
+            InboundPolicy::AllowlistOnly,
+            Arc::new(SystemClock),
+            |gate, events| async move {
+                let key = SigningKey::generate(&mut OsRng);
+                let known = gate
+                    .peer_db
+                    .new_peer(
+                        "known".to_owned(),
+                        hex::encode(key.verifying_key().to_bytes()),
+                    )
+                    .unwrap();
+                gate.peer_db.save_peer(&known).await.unwrap();
+                let (addr, session, _known_socket) = inbound_session().await;
+                gate.admit(&known.id, addr, session).await.unwrap();
+                assert_eq!(
+                    gate.peer_pool.peer_state(&known.id).await,
+                    PeerConnState::Connected
+                );
+
+                let unknown = new_peer_id();
+                let (addr, session, _unknown_socket) = inbound_session().await;
+                gate.admit(&unknown, addr, session).await.unwrap();
+                assert_eq!(
+                    gate.peer_pool.peer_state(&unknown).await,
+                    PeerConnState::Disconnected
+                );
+                assert!(connection_requests(&events).is_empty());
+                assert!(!gate.approve(&unknown).await.unwrap());
+            },
+        );
+    }
+
+    #[test]
+    fn prompt_holds_unknown_peers_until_answered() {
+        with_gate(
+            InboundPolicy::PromptViaEvent,
+            Arc::new(SystemClock),
+            |gate, events| async move {
+                let approved = new_peer_id();
+                let (addr, session, _approved_socket) = inbound_session().await;
+                gate.admit(&approved, addr, session).await.unwrap();
+                assert_eq!(
+                    gate.peer_pool.peer_state(&approved).await,
+                    PeerConnState::Disconnected
+                );
+                assert_eq!(connection_requests(&events), vec![approved.clone()]);
+
+                assert!(gate.approve(&approved).await.unwrap());
+                assert_eq!(
+                    gate.peer_pool.peer_state(&approved).await,
+                    PeerConnState::Connected
+                );
+                assert!(!gate.approve(&approved).await.unwrap());
+
+                let rejected = new_peer_id();
+                let (addr, session, _rejected_socket) = inbound_session().await;
+                gate.admit(&rejected, addr, session).await.unwrap();
+                assert!(gate.reject(&rejected).await);
+                assert!(!gate.reject(&rejected).await);
+                assert!(!gate.approve(&rejected).await.unwrap());
+                assert_eq!(
+                    gate.peer_pool.peer_state(&rejected).await,
+                    PeerConnState::Disconnected
+                );
+            },
+        );
+    }
+
+    #[test]
+    fn held_sessions_are_capped() {
+        with_gate(
+            InboundPolicy::PromptViaEvent,
+            Arc::new(SystemClock),
+            |gate, events| async move {
+                let mut sockets = Vec::new();
+                let mut held = Vec::new();
+                for _ in 0..MAX_PENDING_REQUESTS {
+                    let peer_id = new_peer_id();
+                    let (addr, session, socket) = inbound_session().await;
+                    gate.admit(&peer_id, addr, session).await.unwrap();
+                    sockets.push(socket);
+                    held.push(peer_id);
+                }
+                assert_eq!(connection_requests(&events), held);
+
+                let overflow = new_peer_id();
+                let (addr, session, _overflow_socket) = inbound_session().await;
+                gate.admit(&overflow, addr, session).await.unwrap();
+                assert!(connection_requests(&events).is_empty());
+                assert!(!gate.approve(&overflow).await.unwrap());
+
+                // a held peer reconnecting replaces its own session
+                let (addr, session, _reconnected_socket) = inbound_session().await;
+                gate.admit(&held[0], addr, session).await.unwrap();
+                assert_eq!(connection_requests(&events), vec![held[0].clone()]);
+                assert!(gate.approve(&held[0]).await.unwrap());
+            },
+        );
+    }
+
+    #[test]
+    fn unanswered_sessions_expire() {
+        let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+        with_gate(
+            InboundPolicy::PromptViaEvent,
+            clock.clone(),
+            |gate, events| async move {
+                let mut sockets = Vec::new();
+                let mut held = Vec::new();
+                for _ in 0..MAX_PENDING_REQUESTS {
+                    let peer_id = new_peer_id();
+                    let (addr, session, socket) = inbound_session().await;
+                    gate.admit(&peer_id, addr, session).await.unwrap();
+                    sockets.push(socket);
+                    held.push(peer_id);
+                }
+                clock.advance(PENDING_REQUEST_TIMEOUT);
+
+                // the expired requests free their slots
+                let late = new_peer_id();
+                let (addr, session, _late_socket) = inbound_session().await;
+                gate.admit(&late, addr, session).await.unwrap();
+                assert_eq!(connection_requests(&events).last(), Some(&late));
+                assert!(!gate.approve(&held[0]).await.unwrap());
+                assert!(gate.approve(&late).await.unwrap());
+            },
+        );
+    }
+}
@@ -1,6 +1,12 @@
-use crate::models::IndexedMessage;
+use crate::message_database::add_column_if_missing;
+use crate::models::{
+    group_creator, ConversationSummary, Expiry, ExpiryTrigger, IndexedMessage, MessageStatus,
+    NotificationImportance, NotificationPref, Poll, SystemInfo, SystemKind,
+};
+use crate::proto::chat::GroupChange;
 use anyhow::Result;
 use sqlx::{Row, SqlitePool};
+use std::collections::{BTreeSet, HashMap};
 
 pub struct IndexedMessageDatabase {
     pool: SqlitePool,
@@ -22,22 +28,506 @@ impl IndexedMessageDatabase {
                 text TEXT NOT NULL,
                 file_id TEXT,
                 file_path TEXT,
-                peer_id TEXT NOT NULL
+                peer_id TEXT NOT NULL,
+                system_kind INTEGER,
+                system_value TEXT,
+                unsupported INTEGER NOT NULL DEFAULT 0,
+                timestamp INTEGER NOT NULL DEFAULT 0,
+                received_at INTEGER NOT NULL DEFAULT 0,
+                recipient TEXT,
+                status INTEGER,
+                metadata TEXT,
+                poll INTEGER NOT NULL DEFAULT 0,
+                link_preview TEXT,
+                expiry TEXT,
+                group_id TEXT
             )
             "#,
         )
         .execute(&self.pool)
         .await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "system_kind", "INTEGER").await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "system_value", "TEXT").await?;
+        add_column_if_missing(
+            &self.pool,
+            "indexed_messages",
+            "unsupported",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &self.pool,
+            "indexed_messages",
+            "timestamp",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(
+            &self.pool,
+            "indexed_messages",
+            "received_at",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "recipient", "TEXT").await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "status", "INTEGER").await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "metadata", "TEXT").await?;
+        add_column_if_missing(
+            &self.pool,
+            "indexed_messages",
+            "poll",
+            "INTEGER NOT NULL DEFAULT 0",
+        )
+        .await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "link_preview", "TEXT").await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "expiry", "TEXT").await?;
+        add_column_if_missing(&self.pool, "indexed_messages", "group_id", "TEXT").await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS indexed_messages_peer_order ON indexed_messages (peer_id, order_id)",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS indexed_messages_group_order ON indexed_messages (group_id, order_id)",
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS read_state (
+                peer_id TEXT PRIMARY KEY NOT NULL,
+                last_read_order_id TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // countdowns of self-deleting messages, `expires_at` stays empty until a
+        // read-triggered message is read here or a tombstone for it arrives.
+        // `tombstone_by` holds the author of a tombstone that came before its
+        // message, the row is only acted on once the message shows it may delete it
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS message_expiries (
+                id TEXT PRIMARY KEY NOT NULL,
+                seconds INTEGER NOT NULL,
+                from_read INTEGER NOT NULL,
+                expires_at INTEGER,
+                read_here INTEGER NOT NULL DEFAULT 0,
+                tombstone_by TEXT
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        add_column_if_missing(&self.pool, "message_expiries", "tombstone_by", "TEXT").await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS group_members (
+                group_id TEXT NOT NULL,
+                member TEXT NOT NULL,
+                removed INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                change_id TEXT NOT NULL,
+                PRIMARY KEY (group_id, member)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // every membership change seen, `group_members` is rebuilt from them
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS group_changes (
+                change_id TEXT PRIMARY KEY NOT NULL,
+                group_id TEXT NOT NULL,
+                author TEXT NOT NULL,
+                member TEXT NOT NULL,
+                removed INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // lifetime of the messages between two peers, `peer_a` sorts first. A peer
+        // with itself stands for its messages to everyone
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS conversation_ttls (
+                peer_a TEXT NOT NULL,
+                peer_b TEXT NOT NULL,
+                seconds INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                entry_id TEXT NOT NULL,
+                PRIMARY KEY (peer_a, peer_b)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS polls (
+                id TEXT PRIMARY KEY NOT NULL,
+                question TEXT NOT NULL,
+                options TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS poll_votes (
+                poll_id TEXT NOT NULL,
+                voter TEXT NOT NULL,
+                option INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                vote_id TEXT NOT NULL,
+                PRIMARY KEY (poll_id, voter)
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS notification_prefs (
+                peer_id TEXT PRIMARY KEY NOT NULL,
+                muted_until INTEGER,
+                mentions_only INTEGER NOT NULL,
+                importance INTEGER NOT NULL
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn set_notification_pref(&self, peer_id: &str, pref: &NotificationPref) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO notification_prefs (peer_id, muted_until, mentions_only, importance)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(peer_id) DO UPDATE SET
+                muted_until = excluded.muted_until,
+                mentions_only = excluded.mentions_only,
+                importance = excluded.importance
+            "#,
+        )
+        .bind(peer_id)
+        .bind(pref.muted_until)
+        .bind(pref.mentions_only)
+        .bind(pref.importance.to_i32())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Conversations without stored preferences get the defaults.
+    pub async fn get_notification_pref(&self, peer_id: &str) -> Result<NotificationPref> {
+        let row = sqlx::query(
+            "SELECT muted_until, mentions_only, importance FROM notification_prefs WHERE peer_id = ?",
+        )
+        .bind(peer_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row
+            .map(|row| NotificationPref {
+                muted_until: row.get("muted_until"),
+                mentions_only: row.get("mentions_only"),
+                importance: NotificationImportance::from_i32(row.get("importance")),
+            })
+            .unwrap_or_default())
+    }
+
+    /// Records a membership change by `author` and rebuilds the group's roster from
+    /// every change seen so far. They are replayed oldest first, the message id breaks
+    /// ties, and a change only counts if its author is the group's creator or a member
+    /// at that point. Every peer ends up with the same roster whatever order the
+    /// changes arrive in. Returns whether the roster changed.
+    pub async fn apply_group_change(
+        &self,
+        change: &GroupChange,
+        author: &str,
+        timestamp: i64,
+        change_id: &str,
+    ) -> Result<bool> {
+        let mut tx = self.pool.begin().await?;
+        let res = sqlx::query(
+            r#"
+            INSERT INTO group_changes (change_id, group_id, author, member, removed, timestamp)
+            VALUES (?, ?, ?, ?, ?, ?)
+            ON CONFLICT(change_id) DO NOTHING
+            "#,
+        )
+        .bind(change_id)
+        .bind(&change.group_id)
+        .bind(author)
+        .bind(&change.member)
+        .bind(change.removed)
+        .bind(timestamp)
+        .execute(&mut *tx)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+
+        let rows = sqlx::query(
+            r#"
+            SELECT change_id, author, member, removed, timestamp FROM group_changes
+            WHERE group_id = ?
+            ORDER BY timestamp, change_id
+            "#,
+        )
+        .bind(&change.group_id)
+        .fetch_all(&mut *tx)
+        .await?;
+        let creator = group_creator(&change.group_id);
+        let mut roster: HashMap<String, (bool, i64, String)> = HashMap::new();
+        for row in rows {
+            let author: String = row.get("author");
+            let is_member = roster.get(&author).is_some_and(|(removed, _, _)| !removed);
+            if creator != Some(author.as_str()) && !is_member {
+                continue;
+            }
+            roster.insert(
+                row.get("member"),
+                (row.get("removed"), row.get("timestamp"), row.get("change_id")),
+            );
+        }
+
+        let before: BTreeSet<String> =
+            sqlx::query("SELECT member FROM group_members WHERE group_id = ? AND removed = 0")
+                .bind(&change.group_id)
+                .fetch_all(&mut *tx)
+                .await?
+                .into_iter()
+                .map(|row| row.get("member"))
+                .collect();
+        sqlx::query("DELETE FROM group_members WHERE group_id = ?")
+            .bind(&change.group_id)
+            .execute(&mut *tx)
+            .await?;
+        for (member, (removed, timestamp, change_id)) in &roster {
+            sqlx::query(
+                r#"
+                INSERT INTO group_members (group_id, member, removed, timestamp, change_id)
+                VALUES (?, ?, ?, ?, ?)
+                "#,
+            )
+            .bind(&change.group_id)
+            .bind(member)
+            .bind(removed)
+            .bind(timestamp)
+            .bind(change_id)
+            .execute(&mut *tx)
+            .await?;
+        }
+        tx.commit().await?;
+
+        let after: BTreeSet<&String> = roster
+            .iter()
+            .filter(|(_, (removed, _, _))| !removed)
+            .map(|(member, _)| member)
+            .collect();
+        Ok(before.iter().collect::<BTreeSet<_>>() != after)
+    }
+
+    /// Applies a lifetime `author` set for its messages with `peer_id`, 0 seconds for
+    /// none. Either of the two can change it, the latest entry wins like with
+    /// `apply_vote`. Returns whether it changed.
+    pub async fn apply_conversation_ttl(
+        &self,
+        author: &str,
+        peer_id: &str,
+        seconds: u64,
+        timestamp: i64,
+        entry_id: &str,
+    ) -> Result<bool> {
+        let (peer_a, peer_b) = conversation_key(author, peer_id);
+        let res = sqlx::query(
+            r#"
+            INSERT INTO conversation_ttls (peer_a, peer_b, seconds, timestamp, entry_id)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(peer_a, peer_b) DO UPDATE SET
+                seconds = excluded.seconds,
+                timestamp = excluded.timestamp,
+                entry_id = excluded.entry_id
+            WHERE (excluded.timestamp, excluded.entry_id) > (conversation_ttls.timestamp, conversation_ttls.entry_id)
+            "#,
+        )
+        .bind(peer_a)
+        .bind(peer_b)
+        .bind(seconds as i64)
+        .bind(timestamp)
+        .bind(entry_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Lifetime of the messages between two peers, of a peer's messages to everyone
+    /// when both are the same.
+    pub async fn get_conversation_ttl(&self, peer_a: &str, peer_b: &str) -> Result<Option<u64>> {
+        let (peer_a, peer_b) = conversation_key(peer_a, peer_b);
+        let row = sqlx::query(
+            "SELECT seconds FROM conversation_ttls WHERE peer_a = ? AND peer_b = ? AND seconds > 0",
+        )
+        .bind(peer_a)
+        .bind(peer_b)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|row| row.get::<i64, _>("seconds") as u64))
+    }
+
+    /// Every lifetime in effect as (peer, peer, seconds).
+    pub async fn get_conversation_ttls(&self) -> Result<Vec<(String, String, u64)>> {
+        let rows = sqlx::query("SELECT peer_a, peer_b, seconds FROM conversation_ttls WHERE seconds > 0")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| {
+                (
+                    row.get("peer_a"),
+                    row.get("peer_b"),
+                    row.get::<i64, _>("seconds") as u64,
+                )
+            })
+            .collect())
+    }
+
+    pub async fn save_poll(&self, id: &str, question: &str, options: &[String]) -> Result<()> {
+        sqlx::query("INSERT OR REPLACE INTO polls (id, question, options) VALUES (?, ?, ?)")
+            .bind(id)
+            .bind(question)
+            .bind(serde_json::to_string(options)?)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
+    /// Applies a vote. Each (poll, voter) pair keeps the vote with the highest
+    /// timestamp, the message id breaks ties, so concurrent votes converge like
+    /// group changes do. Votes may arrive before their poll. Returns whether the
+    /// tally changed.
+    pub async fn apply_vote(
+        &self,
+        poll_id: &str,
+        voter: &str,
+        option: u32,
+        timestamp: i64,
+        vote_id: &str,
+    ) -> Result<bool> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO poll_votes (poll_id, voter, option, timestamp, vote_id)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(poll_id, voter) DO UPDATE SET
+                option = excluded.option,
+                timestamp = excluded.timestamp,
+                vote_id = excluded.vote_id
+            WHERE (excluded.timestamp, excluded.vote_id) > (poll_votes.timestamp, poll_votes.vote_id)
+            "#,
+        )
+        .bind(poll_id)
+        .bind(voter)
+        .bind(option)
+        .bind(timestamp)
+        .bind(vote_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// The poll with its tally, votes for options it doesn't have are ignored.
+    pub async fn get_poll(&self, id: &str, own_id: &str) -> Result<Option<Poll>> {
+        let row = sqlx::query("SELECT question, options FROM polls WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let Some(row) = row else {
+            return Ok(None);
+        };
+        let options: Vec<String> = serde_json::from_str(row.get("options"))?;
+        let mut votes = vec![0; options.len()];
+        let mut own_vote = None;
+        let rows = sqlx::query("SELECT voter, option FROM poll_votes WHERE poll_id = ?")
+            .bind(id)
+            .fetch_all(&self.pool)
+            .await?;
+        for row in rows {
+            let option: u32 = row.get("option");
+            let Some(count) = votes.get_mut(option as usize) else {
+                continue;
+            };
+            *count += 1;
+            if row.get::<&str, _>("voter") == own_id {
+                own_vote = Some(option);
+            }
+        }
+        Ok(Some(Poll {
+            id: id.to_owned(),
+            question: row.get("question"),
+            options,
+            votes,
+            own_vote,
+        }))
+    }
+
+    pub async fn get_group_members(&self, group_id: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT member FROM group_members WHERE group_id = ? AND removed = 0 ORDER BY member",
+        )
+        .bind(group_id)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("member")).collect())
+    }
+
+    pub async fn is_group_member(&self, group_id: &str, peer_id: &str) -> Result<bool> {
+        let row = sqlx::query(
+            "SELECT 1 FROM group_members WHERE group_id = ? AND member = ? AND removed = 0",
+        )
+        .bind(group_id)
+        .bind(peer_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.is_some())
+    }
+
+    /// Groups `member` currently belongs to.
+    pub async fn get_groups_of(&self, member: &str) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            "SELECT group_id FROM group_members WHERE member = ? AND removed = 0 ORDER BY group_id",
+        )
+        .bind(member)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("group_id")).collect())
+    }
+
     pub async fn save(&self, msg: &IndexedMessage) -> Result<()> {
         let mentions = msg.mentions.join(",");
+        let metadata = if msg.metadata.is_empty() {
+            None
+        } else {
+            Some(serde_json::to_string(&msg.metadata)?)
+        };
+        let link_preview = msg
+            .link_preview
+            .as_ref()
+            .map(serde_json::to_string)
+            .transpose()?;
+        let expiry = msg.expiry.as_ref().map(serde_json::to_string).transpose()?;
 
         sqlx::query(
             r#"
-            INSERT INTO indexed_messages (id, order_id, mentions, reply, text, file_id, file_path, peer_id)
-            VALUES (?, ?, ?, ?, ?, ?, ?, ?)
+            INSERT INTO indexed_messages (id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id)
+            VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
             "#,
         )
         .bind(&msg.id)
@@ -48,6 +538,18 @@ impl IndexedMessageDatabase {
         .bind(&msg.file_id)
         .bind(&msg.file_path)
         .bind(&msg.peer_id)
+        .bind(msg.system.as_ref().map(|system| system.kind.to_proto()))
+        .bind(msg.system.as_ref().map(|system| system.value.clone()))
+        .bind(msg.unsupported)
+        .bind(msg.timestamp)
+        .bind(msg.received_at)
+        .bind(&msg.recipient)
+        .bind(msg.status.map(MessageStatus::to_i32))
+        .bind(metadata)
+        .bind(msg.poll)
+        .bind(link_preview)
+        .bind(expiry)
+        .bind(&msg.group_id)
         .execute(&self.pool)
         .await?;
 
@@ -64,7 +566,7 @@ impl IndexedMessageDatabase {
             UPDATE indexed_messages
             SET file_path = ?
             WHERE file_id = ?
-            RETURNING id, order_id, mentions, reply, text, file_id, file_path, peer_id
+            RETURNING id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
             "#,
         )
         .bind(file_path)
@@ -79,10 +581,217 @@ impl IndexedMessageDatabase {
         Ok(messages)
     }
 
+    /// Unsets the path of a file that is gone, returns the messages referencing it.
+    pub async fn clear_file_path(&self, file_id: &str) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            UPDATE indexed_messages
+            SET file_path = NULL
+            WHERE file_id = ?
+            RETURNING id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            "#,
+        )
+        .bind(file_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    /// Moves a message to `status` unless it is already there or further, returns
+    /// whether the status changed.
+    pub async fn advance_status(&self, id: &str, status: MessageStatus) -> Result<bool> {
+        let res = sqlx::query(
+            "UPDATE indexed_messages SET status = ? WHERE id = ? AND status IS NOT NULL AND status < ?",
+        )
+        .bind(status.to_i32())
+        .bind(id)
+        .bind(status.to_i32())
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Drops every indexed message, read watermarks and group rosters are kept.
+    /// Local receive time of every indexed message, kept across `clear`.
+    pub async fn get_received_times(&self) -> Result<HashMap<String, i64>> {
+        let rows = sqlx::query("SELECT id, received_at FROM indexed_messages")
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("id"), row.get("received_at")))
+            .collect())
+    }
+
+    pub async fn clear(&self) -> Result<()> {
+        sqlx::query("DELETE FROM indexed_messages")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Starts tracking a self-deleting message of `sender`. A tombstone that arrived
+    /// first is honored when the message is read-triggered and the tombstone came
+    /// from its sender or `recipient`, any other is dropped. Returns when the message
+    /// expires, `None` while it waits to be read.
+    pub async fn track_expiry(
+        &self,
+        id: &str,
+        sender: &str,
+        recipient: Option<&str>,
+        expiry: &Expiry,
+        expires_at: Option<i64>,
+    ) -> Result<Option<i64>> {
+        sqlx::query(
+            r#"
+            INSERT INTO message_expiries (id, seconds, from_read, expires_at)
+            VALUES (?, ?, ?, ?)
+            ON CONFLICT(id) DO UPDATE SET
+                seconds = excluded.seconds,
+                from_read = excluded.from_read,
+                expires_at = CASE
+                    WHEN excluded.from_read = 1 AND message_expiries.tombstone_by IN (?, ?)
+                    THEN message_expiries.expires_at
+                    ELSE excluded.expires_at
+                END,
+                tombstone_by = NULL
+            WHERE message_expiries.tombstone_by IS NOT NULL
+            "#,
+        )
+        .bind(id)
+        .bind(expiry.seconds as i64)
+        .bind(expiry.trigger == ExpiryTrigger::FromRead)
+        .bind(expires_at)
+        .bind(sender)
+        .bind(recipient)
+        .execute(&self.pool)
+        .await?;
+        let row = sqlx::query("SELECT expires_at FROM message_expiries WHERE id = ?")
+            .bind(id)
+            .fetch_one(&self.pool)
+            .await?;
+        Ok(row.get("expires_at"))
+    }
+
+    /// Drops the countdown of `id`, e.g. a tombstone naming a message that turned out
+    /// not to delete itself.
+    pub async fn forget_expiry(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM message_expiries WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Starts the countdown of the unread read-triggered messages of `peer_id` up to
+    /// `order_id`. This node then deletes them, and syncs the tombstone for those
+    /// addressed to `own_id`, the only ones other nodes accept it for.
+    pub async fn start_read_expiries(
+        &self,
+        peer_id: &str,
+        order_id: &str,
+        own_id: &str,
+        now: i64,
+    ) -> Result<()> {
+        sqlx::query(
+            r#"
+            UPDATE message_expiries
+            SET expires_at = ? + seconds, read_here = EXISTS (
+                SELECT 1 FROM indexed_messages m
+                WHERE m.id = message_expiries.id AND m.recipient = ?
+            )
+            WHERE from_read = 1 AND expires_at IS NULL AND tombstone_by IS NULL AND id IN (
+                SELECT id FROM indexed_messages WHERE peer_id = ? AND order_id <= ?
+            )
+            "#,
+        )
+        .bind(now)
+        .bind(own_id)
+        .bind(peer_id)
+        .bind(order_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Applies a tombstone `author` wrote for a read-triggered message, the message
+    /// goes with the next sweep. Only the sender or the recipient of the message may
+    /// delete it, and only when it deletes itself on read. A tombstone ahead of its
+    /// message waits for it, see `track_expiry`. Returns false for a tombstone that
+    /// is ignored.
+    pub async fn apply_tombstone(&self, id: &str, author: &str, now: i64) -> Result<bool> {
+        let row = sqlx::query("SELECT peer_id, recipient FROM indexed_messages WHERE id = ?")
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
+        let Some(row) = row else {
+            let res = sqlx::query(
+                r#"
+                INSERT INTO message_expiries (id, seconds, from_read, expires_at, tombstone_by)
+                VALUES (?, 0, 1, ?, ?)
+                ON CONFLICT(id) DO NOTHING
+                "#,
+            )
+            .bind(id)
+            .bind(now)
+            .bind(author)
+            .execute(&self.pool)
+            .await?;
+            return Ok(res.rows_affected() > 0);
+        };
+        let sender: String = row.get("peer_id");
+        let recipient: Option<String> = row.get("recipient");
+        if author != sender && recipient.as_deref() != Some(author) {
+            return Ok(false);
+        }
+        let res = sqlx::query(
+            r#"
+            UPDATE message_expiries SET expires_at = ?, read_here = 0
+            WHERE id = ? AND from_read = 1 AND tombstone_by IS NULL
+                AND (expires_at IS NULL OR expires_at > ?)
+            "#,
+        )
+        .bind(now)
+        .bind(id)
+        .bind(now)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Self-deleting messages whose time is up, with whether this node read them and
+    /// owes the other nodes a tombstone. Tombstones still waiting for their message
+    /// are left out.
+    pub async fn due_expiries(&self, now: i64) -> Result<Vec<(String, bool)>> {
+        let rows = sqlx::query(
+            "SELECT id, read_here FROM message_expiries WHERE expires_at <= ? AND tombstone_by IS NULL",
+        )
+        .bind(now)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|row| (row.get("id"), row.get("read_here")))
+            .collect())
+    }
+
+    pub async fn delete(&self, id: &str) -> Result<()> {
+        sqlx::query("DELETE FROM indexed_messages WHERE id = ?")
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
     pub async fn get_by_id(&self, id: &str) -> Result<Option<IndexedMessage>> {
         let row = sqlx::query(
             r#"
-            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
             FROM indexed_messages
             WHERE id = ?
             "#,
@@ -94,10 +803,35 @@ impl IndexedMessageDatabase {
         row.map(|row| self.row_to_indexed_message(row)).transpose()
     }
 
+    pub async fn get_peer_after_order_id(
+        &self,
+        peer_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE peer_id = ? AND order_id >= ?
+            ORDER BY order_id
+            "#,
+        )
+        .bind(peer_id)
+        .bind(order_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
     pub async fn get_all_after_order_id(&self, order_id: &str) -> Result<Vec<IndexedMessage>> {
         let rows = sqlx::query(
             r#"
-            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
             FROM indexed_messages
             WHERE order_id >= ?
             ORDER BY order_id
@@ -114,6 +848,335 @@ impl IndexedMessageDatabase {
         Ok(messages)
     }
 
+    pub async fn get_before_order_id(
+        &self,
+        order_id: &str,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE order_id < ?
+            ORDER BY order_id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(order_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows.into_iter().rev() {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    /// Up to `limit` messages of `peer_id` following `after_order_id` (from the start
+    /// without a cursor), oldest first.
+    pub async fn get_for_peer(
+        &self,
+        peer_id: &str,
+        after_order_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE peer_id = ? AND (? IS NULL OR order_id > ?)
+            ORDER BY order_id
+            LIMIT ?
+            "#,
+        )
+        .bind(peer_id)
+        .bind(after_order_id)
+        .bind(after_order_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    /// Up to `limit` messages sent to `group_id` following `after_order_id` (from the
+    /// start without a cursor), oldest first.
+    pub async fn get_for_group(
+        &self,
+        group_id: &str,
+        after_order_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE group_id = ? AND (? IS NULL OR order_id > ?)
+            ORDER BY order_id
+            LIMIT ?
+            "#,
+        )
+        .bind(group_id)
+        .bind(after_order_id)
+        .bind(after_order_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    /// Messages of `peer_id` older than `order_id` (or the newest ones without a cursor),
+    /// newest first. Served by the `(peer_id, order_id)` index.
+    pub async fn get_peer_before_order_id(
+        &self,
+        peer_id: &str,
+        order_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE peer_id = ? AND (? IS NULL OR order_id < ?)
+            ORDER BY order_id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(peer_id)
+        .bind(order_id)
+        .bind(order_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    pub async fn get_from_order_id(
+        &self,
+        order_id: &str,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE order_id >= ?
+            ORDER BY order_id
+            LIMIT ?
+            "#,
+        )
+        .bind(order_id)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    pub async fn count_after_order_id(&self, order_id: &str) -> Result<u64> {
+        let row = sqlx::query(
+            r#"
+            SELECT COUNT(*) as count
+            FROM indexed_messages
+            WHERE order_id > ?
+            "#,
+        )
+        .bind(order_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.get::<i64, _>("count") as u64)
+    }
+
+    /// Moves the last-read watermark of a conversation forward, older order ids are
+    /// ignored. Returns whether it moved.
+    pub async fn set_read_watermark(&self, peer_id: &str, order_id: &str) -> Result<bool> {
+        let res = sqlx::query(
+            r#"
+            INSERT INTO read_state (peer_id, last_read_order_id)
+            VALUES (?, ?)
+            ON CONFLICT(peer_id) DO UPDATE
+            SET last_read_order_id = excluded.last_read_order_id
+            WHERE excluded.last_read_order_id > read_state.last_read_order_id
+            "#,
+        )
+        .bind(peer_id)
+        .bind(order_id)
+        .execute(&self.pool)
+        .await?;
+        Ok(res.rows_affected() > 0)
+    }
+
+    /// Moves the messages of `own_id` up to `order_id` that `reader` got, those to
+    /// everyone, to it or to a group it is in, to `MessageStatus::Read`. Returns the
+    /// ids that changed.
+    pub async fn mark_read_by(
+        &self,
+        own_id: &str,
+        reader: &str,
+        order_id: &str,
+    ) -> Result<Vec<String>> {
+        let rows = sqlx::query(
+            r#"
+            UPDATE indexed_messages SET status = ?
+            WHERE peer_id = ? AND order_id <= ? AND status IS NOT NULL AND status < ?
+                AND (recipient IS NULL OR recipient = ?)
+                AND (group_id IS NULL OR group_id IN (
+                    SELECT group_id FROM group_members WHERE member = ? AND removed = 0
+                ))
+            RETURNING id
+            "#,
+        )
+        .bind(MessageStatus::Read.to_i32())
+        .bind(own_id)
+        .bind(order_id)
+        .bind(MessageStatus::Read.to_i32())
+        .bind(reader)
+        .bind(reader)
+        .fetch_all(&self.pool)
+        .await?;
+        Ok(rows.into_iter().map(|row| row.get("id")).collect())
+    }
+
+    pub async fn get_read_watermark(&self, peer_id: &str) -> Result<Option<String>> {
+        let row = sqlx::query(
+            r#"
+            SELECT last_read_order_id
+            FROM read_state
+            WHERE peer_id = ?
+            "#,
+        )
+        .bind(peer_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| row.get("last_read_order_id")))
+    }
+
+    pub async fn count_unread(&self, peer_id: &str) -> Result<u64> {
+        let row = sqlx::query(
+            r#"
+            SELECT COUNT(*) as count
+            FROM indexed_messages
+            WHERE peer_id = ? AND order_id > COALESCE(
+                (SELECT last_read_order_id FROM read_state WHERE peer_id = ?),
+                ''
+            )
+            "#,
+        )
+        .bind(peer_id)
+        .bind(peer_id)
+        .fetch_one(&self.pool)
+        .await?;
+
+        Ok(row.get::<i64, _>("count") as u64)
+    }
+
+    /// Messages whose text contains `query`, newest first. Optionally limited to one
+    /// peer and to a `[start, end]` range of unix timestamps.
+    pub async fn search(
+        &self,
+        query: &str,
+        peer_id: Option<&str>,
+        start: Option<i64>,
+        end: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let pattern = format!(
+            "%{}%",
+            query
+                .replace('\\', "\\\\")
+                .replace('%', "\\%")
+                .replace('_', "\\_")
+        );
+        let rows = sqlx::query(
+            r#"
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id
+            FROM indexed_messages
+            WHERE text LIKE ? ESCAPE '\'
+                AND (? IS NULL OR peer_id = ?)
+                AND (? IS NULL OR timestamp >= ?)
+                AND (? IS NULL OR timestamp <= ?)
+            ORDER BY order_id DESC
+            LIMIT ?
+            "#,
+        )
+        .bind(pattern)
+        .bind(peer_id)
+        .bind(peer_id)
+        .bind(start)
+        .bind(start)
+        .bind(end)
+        .bind(end)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut messages = Vec::new();
+        for row in rows {
+            messages.push(self.row_to_indexed_message(row)?);
+        }
+        Ok(messages)
+    }
+
+    /// The most recent message of every peer that has any, newest conversation first.
+    /// The newest message of every peer but `own_id` together with the peer's unread
+    /// count, newest first, read in a single query.
+    pub async fn latest_per_peer(&self, own_id: &str) -> Result<Vec<ConversationSummary>> {
+        let rows = sqlx::query(
+            r#"
+            WITH unread AS (
+                SELECT u.peer_id, COUNT(*) AS unread_count
+                FROM indexed_messages AS u
+                LEFT JOIN read_state AS r ON r.peer_id = u.peer_id
+                WHERE u.order_id > COALESCE(r.last_read_order_id, '')
+                GROUP BY u.peer_id
+            )
+            SELECT id, order_id, mentions, reply, text, file_id, file_path, m.peer_id, system_kind, system_value, unsupported, timestamp, received_at, recipient, status, metadata, poll, link_preview, expiry, group_id,
+                COALESCE(unread.unread_count, 0) AS unread_count
+            FROM indexed_messages AS m
+            LEFT JOIN unread ON unread.peer_id = m.peer_id
+            WHERE m.peer_id != ? AND order_id = (
+                SELECT MAX(order_id) FROM indexed_messages WHERE peer_id = m.peer_id
+            )
+            ORDER BY order_id DESC
+            "#,
+        )
+        .bind(own_id)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut summaries = Vec::new();
+        for row in rows {
+            let unread_count = row.get::<i64, _>("unread_count") as u64;
+            summaries.push(ConversationSummary {
+                last_message: self.row_to_indexed_message(row)?,
+                unread_count,
+            });
+        }
+        Ok(summaries)
+    }
+
     fn row_to_indexed_message(&self, row: sqlx::sqlite::SqliteRow) -> Result<IndexedMessage> {
         let mentions: String = row.get("mentions");
         let mentions: Vec<String> = mentions.split(',').map(|s| s.to_string()).collect();
@@ -127,6 +1190,455 @@ impl IndexedMessageDatabase {
             file_id: row.get("file_id"),
             file_path: row.get("file_path"),
             peer_id: row.get("peer_id"),
+            system: row
+                .get::<Option<i32>, _>("system_kind")
+                .and_then(SystemKind::from_proto)
+                .map(|kind| SystemInfo {
+                    kind,
+                    value: row
+                        .get::<Option<String>, _>("system_value")
+                        .unwrap_or_default(),
+                }),
+            unsupported: row.get("unsupported"),
+            timestamp: row.get("timestamp"),
+            received_at: row.get("received_at"),
+            recipient: row.get("recipient"),
+            status: row
+                .get::<Option<i32>, _>("status")
+                .and_then(MessageStatus::from_i32),
+            metadata: match row.get::<Option<String>, _>("metadata") {
+                Some(metadata) => serde_json::from_str(&metadata)?,
+                None => HashMap::new(),
+            },
+            poll: row.get("poll"),
+            link_preview: row
+                .get::<Option<String>, _>("link_preview")
+                .map(|preview| serde_json::from_str(&preview))
+                .transpose()?,
+            expiry: row
+                .get::<Option<String>, _>("expiry")
+                .map(|expiry| serde_json::from_str(&expiry))
+                .transpose()?,
+            group_id: row.get("group_id"),
         })
     }
 }
+
+/// The two peers of a conversation in the order `conversation_ttls` keeps them.
+fn conversation_key<'a>(peer_a: &'a str, peer_b: &'a str) -> (&'a str, &'a str) {
+    if peer_a <= peer_b {
+        (peer_a, peer_b)
+    } else {
+        (peer_b, peer_a)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::message_database::memory_pool;
+
+    async fn database() -> IndexedMessageDatabase {
+        let db = IndexedMessageDatabase::new(memory_pool().await);
+        db.init().await.unwrap();
+        db
+    }
+
+    fn message(id: &str, peer_id: &str, order_id: &str, recipient: Option<&str>) -> IndexedMessage {
+        IndexedMessage {
+            id: id.to_owned(),
+            order_id: order_id.to_owned(),
+            mentions: Vec::new(),
+            reply: None,
+            text: "hi".to_owned(),
+            file_id: None,
+            file_path: None,
+            peer_id: peer_id.to_owned(),
+            system: None,
+            unsupported: false,
+            timestamp: 0,
+            received_at: 0,
+            recipient: recipient.map(str::to_owned),
+            status: None,
+            metadata: HashMap::new(),
+            poll: false,
+            link_preview: None,
+            expiry: None,
+            group_id: None,
+        }
+    }
+
+    const FROM_SEND: Expiry = Expiry {
+        seconds: 60,
+        trigger: ExpiryTrigger::FromSend,
+    };
+    const FROM_READ: Expiry = Expiry {
+        seconds: 60,
+        trigger: ExpiryTrigger::FromRead,
+    };
+
+    #[tokio::test]
+    async fn send_trigger_expires_after_the_timestamp() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", None)).await.unwrap();
+        let expires_at = db
+            .track_expiry("m1", "alice", None, &FROM_SEND, Some(1000 + 60))
+            .await
+            .unwrap();
+        assert_eq!(expires_at, Some(1060));
+        assert!(db.due_expiries(1059).await.unwrap().is_empty());
+        assert_eq!(db.due_expiries(1060).await.unwrap(), vec![("m1".to_owned(), false)]);
+    }
+
+    #[tokio::test]
+    async fn read_trigger_waits_for_the_read() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", Some("bob"))).await.unwrap();
+        let expires_at = db
+            .track_expiry("m1", "alice", Some("bob"), &FROM_READ, None)
+            .await
+            .unwrap();
+        assert_eq!(expires_at, None);
+        assert!(db.due_expiries(i64::MAX).await.unwrap().is_empty());
+
+        db.start_read_expiries("alice", "1", "bob", 1000).await.unwrap();
+        assert!(db.due_expiries(1059).await.unwrap().is_empty());
+        // read by its recipient, so the other nodes are owed a tombstone
+        assert_eq!(db.due_expiries(1060).await.unwrap(), vec![("m1".to_owned(), true)]);
+    }
+
+    #[tokio::test]
+    async fn reading_a_broadcast_deletes_only_the_local_copy() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", None)).await.unwrap();
+        db.track_expiry("m1", "alice", None, &FROM_READ, None).await.unwrap();
+        db.start_read_expiries("alice", "1", "bob", 1000).await.unwrap();
+        assert_eq!(db.due_expiries(1060).await.unwrap(), vec![("m1".to_owned(), false)]);
+    }
+
+    #[tokio::test]
+    async fn tombstone_of_the_recipient_deletes_a_read_message() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", Some("bob"))).await.unwrap();
+        db.track_expiry("m1", "alice", Some("bob"), &FROM_READ, None).await.unwrap();
+        assert!(db.apply_tombstone("m1", "bob", 1000).await.unwrap());
+        assert_eq!(db.due_expiries(1000).await.unwrap(), vec![("m1".to_owned(), false)]);
+    }
+
+    #[tokio::test]
+    async fn tombstone_of_a_stranger_is_ignored() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", Some("bob"))).await.unwrap();
+        db.track_expiry("m1", "alice", Some("bob"), &FROM_READ, None).await.unwrap();
+        assert!(!db.apply_tombstone("m1", "mallory", 1000).await.unwrap());
+        assert!(db.due_expiries(i64::MAX).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tombstone_cannot_delete_a_message_that_does_not_expire() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", None)).await.unwrap();
+        assert!(!db.apply_tombstone("m1", "alice", 1000).await.unwrap());
+        assert!(db.due_expiries(i64::MAX).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn tombstone_cannot_bring_a_send_triggered_expiry_forward() {
+        let db = database().await;
+        db.save(&message("m1", "alice", "1", None)).await.unwrap();
+        db.track_expiry("m1", "alice", None, &FROM_SEND, Some(5000)).await.unwrap();
+        assert!(!db.apply_tombstone("m1", "alice", 1000).await.unwrap());
+        assert!(db.due_expiries(4999).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn early_tombstone_waits_for_its_message() {
+        let db = database().await;
+        assert!(db.apply_tombstone("m1", "bob", 1000).await.unwrap());
+        // not acted on before the message shows who may delete it
+        assert!(db.due_expiries(i64::MAX).await.unwrap().is_empty());
+
+        db.save(&message("m1", "alice", "1", Some("bob"))).await.unwrap();
+        let expires_at = db
+            .track_expiry("m1", "alice", Some("bob"), &FROM_READ, None)
+            .await
+            .unwrap();
+        assert_eq!(expires_at, Some(1000));
+        assert_eq!(db.due_expiries(1000).await.unwrap(), vec![("m1".to_owned(), false)]);
+    }
+
+    #[tokio::test]
+    async fn early_tombstone_of_a_stranger_is_dropped_with_its_message() {
+        let db = database().await;
+        assert!(db.apply_tombstone("m1", "mallory", 1000).await.unwrap());
+        db.save(&message("m1", "alice", "1", Some("bob"))).await.unwrap();
+        let expires_at = db
+            .track_expiry("m1", "alice", Some("bob"), &FROM_READ, None)
+            .await
+            .unwrap();
+        assert_eq!(expires_at, None);
+
+        // a send-triggered message keeps its own time
+        assert!(db.apply_tombstone("m2", "alice", 1000).await.unwrap());
+        db.save(&message("m2", "alice", "2", None)).await.unwrap();
+        let expires_at = db
+            .track_expiry("m2", "alice", None, &FROM_SEND, Some(5000))
+            .await
+            .unwrap();
+        assert_eq!(expires_at, Some(5000));
+    }
+
+    const GROUP: &str = "alice:g1";
+
+    fn change(member: &str, removed: bool) -> GroupChange {
+        GroupChange {
+            group_id: GROUP.to_owned(),
+            member: member.to_owned(),
+            removed,
+        }
+    }
+
+    /// (author, member, removed, timestamp, change id)
+    async fn apply(db: &IndexedMessageDatabase, entry: &(&str, &str, bool, i64, &str)) -> bool {
+        let (author, member, removed, timestamp, id) = *entry;
+        db.apply_group_change(&change(member, removed), author, timestamp, id)
+            .await
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn group_change_by_a_stranger_is_ignored() {
+        let db = database().await;
+        assert!(apply(&db, &("alice", "alice", false, 1, "c1")).await);
+        assert!(apply(&db, &("alice", "bob", false, 2, "c2")).await);
+        assert!(!apply(&db, &("mallory", "mallory", false, 3, "c3")).await);
+        assert!(!apply(&db, &("mallory", "bob", true, 4, "c4")).await);
+        assert_eq!(db.get_group_members(GROUP).await.unwrap(), vec!["alice", "bob"]);
+        assert!(db.is_group_member(GROUP, "bob").await.unwrap());
+        assert!(!db.is_group_member(GROUP, "mallory").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn removed_member_can_no_longer_change_the_group() {
+        let db = database().await;
+        apply(&db, &("alice", "bob", false, 1, "c1")).await;
+        apply(&db, &("alice", "bob", true, 2, "c2")).await;
+        assert!(!apply(&db, &("bob", "bob", false, 3, "c3")).await);
+        assert!(db.get_group_members(GROUP).await.unwrap().is_empty());
+    }
+
+    #[tokio::test]
+    async fn change_waits_for_its_authors_membership() {
+        let db = database().await;
+        // bob's change arrives before the one that made him a member
+        assert!(!apply(&db, &("bob", "carol", false, 3, "c3")).await);
+        assert!(db.get_group_members(GROUP).await.unwrap().is_empty());
+        assert!(apply(&db, &("alice", "bob", false, 2, "c2")).await);
+        assert_eq!(db.get_group_members(GROUP).await.unwrap(), vec!["bob", "carol"]);
+    }
+
+    #[tokio::test]
+    async fn concurrent_group_changes_converge_in_any_order() {
+        // bob adds carol while alice removes bob, both at the same time, the change
+        // id decides that bob's change came first. Carol then adds dave, bob tries
+        // to add erin too late, and alice and carol concurrently add and remove her
+        let changes = [
+            ("alice", "alice", false, 1, "c1"),
+            ("alice", "bob", false, 2, "c2"),
+            ("bob", "carol", false, 3, "c3"),
+            ("alice", "bob", true, 3, "c4"),
+            ("carol", "dave", false, 4, "c5"),
+            ("bob", "erin", false, 4, "c6"),
+            ("alice", "erin", false, 5, "c7"),
+            ("carol", "erin", true, 5, "c8"),
+        ];
+        let mut orders = vec![changes.to_vec()];
+        orders.push(changes.iter().rev().cloned().collect());
+        for shift in 1..changes.len() {
+            let mut order = changes.to_vec();
+            order.rotate_left(shift);
+            orders.push(order);
+        }
+        let mut interleaved: Vec<_> = changes.iter().step_by(2).cloned().collect();
+        interleaved.extend(changes.iter().skip(1).step_by(2).cloned());
+        orders.push(interleaved);
+
+        for order in orders {
+            let db = database().await;
+            for entry in &order {
+                apply(&db, entry).await;
+            }
+            // bob's late change doesn't count, he was removed before it
+            assert_eq!(
+                db.get_group_members(GROUP).await.unwrap(),
+                vec!["alice", "carol", "dave"],
+                "order {:?}",
+                order.iter().map(|entry| entry.4).collect::<Vec<_>>()
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn group_messages_are_listed_by_group() {
+        let db = database().await;
+        let mut in_group = message("m1", "bob", "1", None);
+        in_group.group_id = Some(GROUP.to_owned());
+        db.save(&in_group).await.unwrap();
+        db.save(&message("m2", "bob", "2", None)).await.unwrap();
+        let messages = db.get_for_group(GROUP, None, 10).await.unwrap();
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].id, "m1");
+        assert_eq!(messages[0].group_id.as_deref(), Some(GROUP));
+    }
+
+    #[tokio::test]
+    async fn latest_conversation_lifetime_of_either_side_wins() {
+        let db = database().await;
+        assert!(db.apply_conversation_ttl("bob", "alice", 60, 2, "t2").await.unwrap());
+        assert!(!db.apply_conversation_ttl("alice", "bob", 30, 1, "t1").await.unwrap());
+        assert_eq!(db.get_conversation_ttl("alice", "bob").await.unwrap(), Some(60));
+        assert!(db.apply_conversation_ttl("alice", "bob", 0, 3, "t3").await.unwrap());
+        assert_eq!(db.get_conversation_ttl("bob", "alice").await.unwrap(), None);
+        assert!(db.get_conversation_ttls().await.unwrap().is_empty());
+    }
+
+    fn ids(messages: &[IndexedMessage]) -> Vec<&str> {
+        messages.iter().map(|message| message.id.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn peer_pages_backwards_between_the_boundaries() {
+        let db = database().await;
+        for (id, peer_id, order_id) in [
+            ("a1", "alice", "1"),
+            ("b2", "bob", "2"),
+            ("a3", "alice", "3"),
+            ("a4", "alice", "4"),
+            ("b5", "bob", "5"),
+        ] {
+            db.save(&message(id, peer_id, order_id, None)).await.unwrap();
+        }
+        let page = |cursor: Option<&'static str>, limit| {
+            let db = &db;
+            async move {
+                db.get_peer_before_order_id("alice", cursor, limit)
+                    .await
+                    .unwrap()
+            }
+        };
+
+        assert_eq!(ids(&page(None, 2).await), vec!["a4", "a3"]);
+        assert_eq!(ids(&page(None, 10).await), vec!["a4", "a3", "a1"]);
+        assert_eq!(ids(&page(None, 0).await), Vec::<&str>::new());
+        // the cursor itself is never part of the page
+        assert_eq!(ids(&page(Some("3"), 10).await), vec!["a1"]);
+        // a cursor on another peer's message still splits the conversation
+        assert_eq!(ids(&page(Some("2"), 10).await), vec!["a1"]);
+        assert!(page(Some("1"), 10).await.is_empty());
+        assert!(page(Some("0"), 10).await.is_empty());
+        assert_eq!(ids(&page(Some("9"), 10).await), vec!["a4", "a3", "a1"]);
+        assert!(db
+            .get_peer_before_order_id("carol", None, 10)
+            .await
+            .unwrap()
+            .is_empty());
+    }
+
+    #[tokio::test]
+    async fn conversation_holds_only_its_peers_messages_in_order() {
+        let db = database().await;
+        // saved out of order, alice's and bob's messages interleaved
+        for (id, peer_id, order_id) in [
+            ("a5", "alice", "5"),
+            ("b2", "bob", "2"),
+            ("a1", "alice", "1"),
+            ("b4", "bob", "4"),
+            ("a3", "alice", "3"),
+            ("b6", "bob", "6"),
+        ] {
+            db.save(&message(id, peer_id, order_id, None)).await.unwrap();
+        }
+
+        let alice = db.get_for_peer("alice", None, 10).await.unwrap();
+        assert_eq!(ids(&alice), vec!["a1", "a3", "a5"]);
+        let bob = db.get_for_peer("bob", None, 2).await.unwrap();
+        assert_eq!(ids(&bob), vec!["b2", "b4"]);
+        let bob = db.get_for_peer("bob", Some("4"), 10).await.unwrap();
+        assert_eq!(ids(&bob), vec!["b6"]);
+        let alice = db.get_for_peer("alice", Some("2"), 10).await.unwrap();
+        assert_eq!(ids(&alice), vec!["a3", "a5"]);
+        let alice = db.get_for_peer("alice", Some("5"), 10).await.unwrap();
+        assert!(alice.is_empty());
+
+        let plan: Vec<(i64, i64, i64, String)> = sqlx::query_as(
+            "EXPLAIN QUERY PLAN SELECT id FROM indexed_messages WHERE peer_id = ? AND order_id > ? ORDER BY order_id",
+        )
+        .bind("alice")
+        .bind("2")
+        .fetch_all(&db.pool)
+        .await
+        .unwrap();
+        assert!(plan
+            .iter()
+            .any(|(_, _, _, detail)| detail.contains("indexed_messages_peer_order")));
+    }
+
+    #[tokio::test]
+    async fn search_is_scoped_by_peer_and_date() {
+        let db = database().await;
+        for (id, peer_id, order_id, timestamp, text) in [
+            ("a1", "alice", "1", 100, "lunch on monday"),
+            ("b2", "bob", "2", 200, "Lunch tomorrow?"),
+            ("a3", "alice", "3", 300, "dinner instead"),
+            ("a4", "alice", "4", 400, "lunch at noon"),
+            ("b5", "bob", "5", 500, "100% lunch"),
+        ] {
+            let mut message = message(id, peer_id, order_id, None);
+            message.timestamp = timestamp;
+            message.text = text.to_owned();
+            db.save(&message).await.unwrap();
+        }
+        let search = |peer_id: Option<&'static str>, start, end| {
+            let db = &db;
+            async move { ids(&db.search("lunch", peer_id, start, end, 10).await.unwrap()).join(" ") }
+        };
+
+        assert_eq!(search(None, None, None).await, "b5 a4 b2 a1");
+        assert_eq!(search(Some("alice"), None, None).await, "a4 a1");
+        assert_eq!(search(None, Some(200), Some(400)).await, "a4 b2");
+        assert_eq!(search(None, Some(400), None).await, "b5 a4");
+        assert_eq!(search(None, None, Some(100)).await, "a1");
+        assert_eq!(search(Some("bob"), Some(300), None).await, "b5");
+        assert_eq!(search(Some("carol"), None, None).await, "");
+        // the order id is what the message is located by
+        let found = db.search("noon", None, None, None, 10).await.unwrap();
+        assert_eq!(found[0].order_id, "4");
+        // wildcards of the query are matched literally
+        let found = db.search("0%", None, None, None, 10).await.unwrap();
+        assert_eq!(ids(&found), vec!["b5"]);
+        let found = db.search("_", None, None, None, 10).await.unwrap();
+        assert!(found.is_empty());
+    }
+
+    #[tokio::test]
+    async fn latest_per_peer_counts_unread_and_leaves_us_out() {
+        let db = database().await;
+        for (id, peer_id, order_id) in [
+            ("m1", "alice", "1"),
+            ("m2", "bob", "2"),
+            ("m3", "alice", "3"),
+            ("m4", "me", "4"),
+            ("m5", "alice", "5"),
+        ] {
+            db.save(&message(id, peer_id, order_id, None)).await.unwrap();
+        }
+        db.set_read_watermark("alice", "3").await.unwrap();
+
+        let summaries = db.latest_per_peer("me").await.unwrap();
+        let summaries: Vec<_> = summaries
+            .iter()
+            .map(|summary| (summary.last_message.id.as_str(), summary.unread_count))
+            .collect();
+        assert_eq!(summaries, vec![("m5", 1), ("m2", 1)]);
+    }
+}
@@ -1,37 +1,292 @@
+use std::collections::HashMap;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::Duration;
 
 use crate::{
+    clock::Clock,
+    direct_message::DirectCipher,
     events::Events,
-    file_database::FileDatabase,
+    file_database::{FileDatabase, FileDescription},
     index_database::IndexedMessageDatabase,
-    models::{DbMessage, IndexedMessage},
+    message_database::MessageDatabase,
+    peer_database::PeerDatabase,
+    models::{
+        metadata_size, split_group_repo, ConversationSummary, DbMessage, Expiry, ExpiryTrigger,
+        IndexedMessage, LinkPreview, MessageStatus, NotificationPref, Poll, SystemInfo, SystemKind,
+        MAX_METADATA_SIZE, PAYLOAD_VERSION,
+    },
     proto::chat::MessagePayload,
+    sanitize::TextPolicy,
 };
 use anyhow::Result;
-use log::info;
+use log::{info, warn};
 use prost::Message;
 
 pub struct Indexer {
+    peer_id: String,
     db: IndexedMessageDatabase,
+    message_db: Arc<MessageDatabase>,
     file_db: Arc<FileDatabase>,
+    peer_db: Arc<PeerDatabase>,
     events: Arc<Events>,
+    direct_cipher: Arc<DirectCipher>,
+    text_policy: TextPolicy,
+    coalesce_batch_events: bool,
+    max_clock_skew: Option<Duration>,
+    root_path: String,
+    clock: Arc<dyn Clock>,
 }
 
 impl Indexer {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        peer_id: String,
         db: IndexedMessageDatabase,
+        message_db: Arc<MessageDatabase>,
         file_db: Arc<FileDatabase>,
+        peer_db: Arc<PeerDatabase>,
         events: Arc<Events>,
+        direct_cipher: Arc<DirectCipher>,
+        text_policy: TextPolicy,
+        coalesce_batch_events: bool,
+        max_clock_skew: Option<Duration>,
+        root_path: String,
+        clock: Arc<dyn Clock>,
     ) -> Self {
         Self {
+            peer_id,
             db,
+            message_db,
             file_db,
+            peer_db,
             events,
+            direct_cipher,
+            text_policy,
+            coalesce_batch_events,
+            max_clock_skew,
+            root_path,
+            clock,
         }
     }
 
-    async fn process_message(&self, msg: &DbMessage) -> Result<IndexedMessage> {
-        let payload = MessagePayload::decode(&*msg.payload)?;
+    /// Events are best effort, the index is already updated when nobody is listening.
+    fn notify(&self, res: Result<()>) {
+        if let Err(e) = res {
+            warn!("failed to send indexer event: {:?}", e);
+        }
+    }
+
+    /// The timestamp a message is shown and searched with. A peer with a clock far
+    /// ahead would otherwise pin its messages to the end of every conversation, old
+    /// timestamps are fine since history arrives late by design.
+    fn effective_timestamp(&self, msg: &DbMessage, received_at: i64) -> i64 {
+        match self.max_clock_skew {
+            Some(skew) if msg.timestamp > received_at.saturating_add(skew.as_secs() as i64) => {
+                warn!(
+                    "message {} from {} is {}s in the future, using the receive time",
+                    msg.id,
+                    msg.peer_id,
+                    msg.timestamp - received_at
+                );
+                received_at
+            }
+            _ => msg.timestamp,
+        }
+    }
+
+    /// Returns `None` for direct messages between two other peers, those are stored
+    /// and relayed but never shown, and for group membership entries, votes and
+    /// profiles, which only update the roster, tally or peer record, and for read
+    /// receipts, which update the status of our messages. A profile or
+    /// membership entry with a system message is shown as that system message.
+    async fn process_message(
+        &self,
+        msg: &DbMessage,
+        received_at: i64,
+    ) -> Result<Option<IndexedMessage>> {
+        let mut payload = MessagePayload::decode(&*msg.payload)?;
+        let recipient = if payload.recipient.is_empty() {
+            None
+        } else {
+            Some(payload.recipient.clone())
+        };
+        // messages to a group are stored in the author's repository of the group
+        let (group_id, author) = match split_group_repo(&msg.peer_id) {
+            Some((group_id, author)) => (Some(group_id.to_owned()), author),
+            None => (None, msg.peer_id.as_str()),
+        };
+        // indexing happens once the message is stored
+        let status = (author == self.peer_id).then_some(MessageStatus::Sent);
+        let timestamp = self.effective_timestamp(msg, received_at);
+        if payload.version > PAYLOAD_VERSION {
+            // fields may have changed meaning, don't interpret anything beyond the envelope
+            return Ok(Some(IndexedMessage {
+                id: msg.id.clone(),
+                order_id: order_id(msg.order, &msg.peer_id),
+                mentions: Vec::new(),
+                reply: None,
+                text: String::new(),
+                file_id: None,
+                file_path: None,
+                peer_id: author.to_owned(),
+                system: None,
+                unsupported: true,
+                timestamp,
+                received_at,
+                recipient,
+                status,
+                metadata: HashMap::new(),
+                poll: false,
+                link_preview: None,
+                expiry: None,
+                group_id,
+            }));
+        }
+        if recipient.is_some() {
+            payload = match self.direct_cipher.open(&msg.id, author, &payload)? {
+                Some(payload) => payload,
+                None => return Ok(None),
+            };
+        }
+        // profiles and group changes are only shown when they carry a system message
+        let is_system = SystemKind::from_proto(payload.system_kind).is_some();
+        if let Some(change) = &payload.group_change {
+            // a change only counts in the group's own repositories
+            let changed = if group_id.as_deref() != Some(change.group_id.as_str()) {
+                warn!(
+                    "ignoring change of group {} outside of it",
+                    change.group_id
+                );
+                false
+            } else {
+                self.db
+                    .apply_group_change(change, author, msg.timestamp, &msg.id)
+                    .await?
+            };
+            if changed {
+                self.notify(
+                    self.events
+                        .send_group_changed(change.group_id.clone())
+                        .await,
+                );
+            }
+            // e.g. `Left` of a removal, unless the author wasn't allowed to make it
+            if !(changed && is_system) {
+                return Ok(None);
+            }
+        }
+        if let Some(vote) = &payload.poll_vote {
+            if !vote.poll_id.is_empty()
+                && self
+                    .db
+                    .apply_vote(&vote.poll_id, author, vote.option, msg.timestamp, &msg.id)
+                    .await?
+            {
+                self.notify(self.events.send_poll_changed(vote.poll_id.clone()).await);
+            }
+            return Ok(None);
+        }
+        if !payload.tombstone_id.is_empty() {
+            // only read-triggered messages can be deleted this way, see `apply_tombstone`
+            if !self
+                .db
+                .apply_tombstone(&payload.tombstone_id, author, self.clock.timestamp())
+                .await?
+            {
+                warn!("ignoring tombstone of {} from {}", payload.tombstone_id, author);
+            }
+            return Ok(None);
+        }
+        if !payload.read_up_to.is_empty() {
+            // only the peer whose messages were read can open it
+            if recipient.as_deref() == Some(self.peer_id.as_str()) {
+                let read = self
+                    .db
+                    .mark_read_by(&self.peer_id, author, &payload.read_up_to)
+                    .await?;
+                for id in read {
+                    self.notify(
+                        self.events
+                            .send_message_status(id, MessageStatus::Read)
+                            .await,
+                    );
+                }
+            }
+            return Ok(None);
+        }
+        // the messages between the author and the recipient, or to everyone without one
+        let conversation_peer = recipient.as_deref().unwrap_or(author);
+        if let Some(ttl) = &payload.conversation_ttl {
+            // only set by one of the two, the entry is sent to the other
+            if ttl.peer_id != conversation_peer {
+                warn!("ignoring lifetime of {} set by {}", ttl.peer_id, author);
+            } else {
+                self.db
+                    .apply_conversation_ttl(author, &ttl.peer_id, ttl.seconds, msg.timestamp, &msg.id)
+                    .await?;
+            }
+            return Ok(None);
+        }
+        let expiry = payload.expiry.and_then(|expiry| {
+            ExpiryTrigger::from_proto(expiry.trigger).map(|trigger| Expiry {
+                seconds: expiry.seconds,
+                trigger,
+            })
+        });
+        match &expiry {
+            Some(expiry) => {
+                let expires_at = match expiry.trigger {
+                    ExpiryTrigger::FromSend => Some(timestamp.saturating_add(expiry.seconds as i64)),
+                    ExpiryTrigger::FromRead => None,
+                };
+                let expires_at = self
+                    .db
+                    .track_expiry(&msg.id, author, recipient.as_deref(), expiry, expires_at)
+                    .await?;
+                // synced after its time, it's deleted by the next sweep without being shown
+                if expires_at.is_some_and(|expires_at| expires_at <= self.clock.timestamp()) {
+                    return Ok(None);
+                }
+            }
+            // drops a tombstone that named this message before it arrived
+            None => self.db.forget_expiry(&msg.id).await?,
+        }
+        if let Some(profile) = &payload.profile {
+            // events are sent by the peer database
+            self.peer_db
+                .apply_profile(author, profile, msg.timestamp, &msg.id)
+                .await?;
+            // a `Renamed` stays in the history even once a newer profile replaced it
+            if !is_system {
+                return Ok(None);
+            }
+        }
+        if let Some(ttl) = self.db.get_conversation_ttl(author, conversation_peer).await? {
+            // synced after its time, the next sweep clears it without it being shown
+            if msg.timestamp.saturating_add(ttl as i64) <= self.clock.timestamp() {
+                return Ok(None);
+            }
+        }
+        let poll = match payload.poll.take() {
+            Some(poll) => {
+                let options: Vec<String> = poll
+                    .options
+                    .iter()
+                    .map(|option| self.text_policy.text(option))
+                    .collect();
+                self.db
+                    .save_poll(&msg.id, &self.text_policy.text(&poll.question), &options)
+                    .await?;
+                // the question is shown as the message text
+                payload.text = poll.question;
+                true
+            }
+            None => false,
+        };
+        if !payload.file_data.is_empty() {
+            self.store_inline_file(&payload).await?;
+        }
         let file_path = if !payload.file_id.is_empty() {
             let file = self.file_db.get_by_id(&payload.file_id).await?;
             if let Some(descr) = file {
@@ -42,6 +297,26 @@ impl Indexer {
         } else {
             None
         };
+        let metadata = if metadata_size(&payload.metadata) <= MAX_METADATA_SIZE {
+            std::mem::take(&mut payload.metadata)
+        } else {
+            warn!("dropping oversized metadata of message {}", msg.id);
+            HashMap::new()
+        };
+        let link_preview = payload
+            .link_preview
+            .take()
+            .filter(|preview| !preview.url.is_empty())
+            .map(|preview| LinkPreview {
+                url: preview.url,
+                title: self.text_policy.text(&preview.title),
+                description: self.text_policy.text(&preview.description),
+                image_file_id: Some(preview.image_file_id).filter(|id| !id.is_empty()),
+            });
+        let system = SystemKind::from_proto(payload.system_kind).map(|kind| SystemInfo {
+            kind,
+            value: self.text_policy.name(&payload.system_value),
+        });
         let indexed_message = IndexedMessage {
             id: msg.id.clone(),
             order_id: order_id(msg.order, &msg.peer_id),
@@ -51,32 +326,147 @@ impl Indexer {
             } else {
                 Some(payload.reply_id)
             },
-            text: payload.text,
+            text: self.text_policy.text(&payload.text),
             file_id: if payload.file_id.is_empty() {
                 None
             } else {
                 Some(payload.file_id)
             },
             file_path,
-            peer_id: msg.peer_id.clone(),
+            peer_id: author.to_owned(),
+            system,
+            unsupported: false,
+            timestamp,
+            received_at,
+            recipient,
+            status,
+            metadata,
+            poll,
+            link_preview,
+            expiry,
+            group_id,
         };
 
-        Ok(indexed_message)
+        Ok(Some(indexed_message))
+    }
+
+    /// Writes a file sent inline with its message into the file store, unless we have it already.
+    async fn store_inline_file(&self, payload: &MessagePayload) -> Result<()> {
+        if payload.file_id.is_empty() || self.file_db.contains(&payload.file_id).await? {
+            return Ok(());
+        }
+        // the id comes from a peer, keep it from pointing outside the root folder
+        if payload.file_id.contains(['/', '\\', '.']) || payload.file_format.contains(['/', '\\']) {
+            return Err(anyhow::anyhow!("malformed file id {}", payload.file_id));
+        }
+        let local_path = format!("{}.{}", payload.file_id, payload.file_format);
+        tokio::fs::write(Path::new(&self.root_path).join(&local_path), &payload.file_data).await?;
+        self.file_db
+            .save_owned(&FileDescription {
+                id: payload.file_id.clone(),
+                format: payload.file_format.clone(),
+                local_path,
+                timestamp: self.clock.timestamp(),
+            })
+            .await
+    }
+
+    pub async fn get_poll(&self, poll_id: &str) -> Result<Option<Poll>> {
+        self.db.get_poll(poll_id, &self.peer_id).await
+    }
+
+    pub async fn get_group_members(&self, group_id: &str) -> Result<Vec<String>> {
+        self.db.get_group_members(group_id).await
+    }
+
+    pub async fn is_group_member(&self, group_id: &str, peer_id: &str) -> Result<bool> {
+        self.db.is_group_member(group_id, peer_id).await
+    }
+
+    /// Messages sent to a group, see `IndexedMessageDatabase::get_for_group`.
+    pub async fn get_for_group(
+        &self,
+        group_id: &str,
+        after_order_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        self.db.get_for_group(group_id, after_order_id, limit).await
+    }
+
+    /// See `IndexedMessageDatabase::get_conversation_ttls`.
+    pub async fn get_conversation_ttls(&self) -> Result<Vec<(String, String, u64)>> {
+        self.db.get_conversation_ttls().await
+    }
+
+    pub async fn get_groups_of(&self, member: &str) -> Result<Vec<String>> {
+        self.db.get_groups_of(member).await
     }
 
     pub async fn index_file_path(&self, file_id: String, file_path: String) -> Result<()> {
-        info!("updating file path {}, {}", &file_id, &file_path);
+        info!("updating file path {}, {}", file_id, file_path);
         let messages = self.db.update_file_id(&file_id, &file_path).await?;
         for msg in messages {
-            self.events.send_message(msg).await?;
+            self.notify(self.events.send_message(msg).await);
+        }
+        // the file may be an avatar, those peers show it from now on
+        for peer in self.peer_db.get_peers_with_avatar(&file_id).await? {
+            self.notify(self.events.send_peer_profile_updated(peer).await);
         }
         Ok(())
     }
 
+    /// Marks a file as unavailable in every message referencing it, returns whether
+    /// any message does.
+    pub async fn clear_file_path(&self, file_id: &str) -> Result<bool> {
+        let messages = self.db.clear_file_path(file_id).await?;
+        let referenced = !messages.is_empty();
+        for msg in messages {
+            self.notify(self.events.send_message(msg).await);
+        }
+        Ok(referenced)
+    }
+
     pub async fn index_message(&self, msg: &DbMessage) -> Result<()> {
-        let indexed_message = self.process_message(msg).await?;
+        if let Some(indexed_message) = self.save_message(msg).await? {
+            self.notify(self.events.send_message(indexed_message).await);
+        }
+        Ok(())
+    }
+
+    /// Indexes a message without announcing it, returns what was saved.
+    async fn save_message(&self, msg: &DbMessage) -> Result<Option<IndexedMessage>> {
+        if msg.payload.is_empty() {
+            // expired messages are synced without payload and are never shown
+            return Ok(None);
+        }
+        let indexed_message = match self.process_message(msg, self.clock.timestamp()).await? {
+            Some(indexed_message) => indexed_message,
+            None => return Ok(None),
+        };
         self.db.save(&indexed_message).await?;
-        self.events.send_message(indexed_message).await?;
+        Ok(Some(indexed_message))
+    }
+
+    /// Reports an own message that is about to be stored, it has no row to update yet.
+    pub async fn notify_sending(&self, id: &str) {
+        self.notify(
+            self.events
+                .send_message_status(id.to_owned(), MessageStatus::Sending)
+                .await,
+        );
+    }
+
+    /// Moves an own message forward to `status`, a status never goes back.
+    pub async fn advance_status(&self, id: &str, status: MessageStatus) -> Result<()> {
+        if self.db.advance_status(id, status).await? {
+            self.notify(self.events.send_message_status(id.to_owned(), status).await);
+        }
+        Ok(())
+    }
+
+    pub async fn remove_message(&self, id: &str) -> Result<()> {
+        self.db.delete(id).await?;
+        self.notify(self.events.send_message_removed(id.to_owned()).await);
         Ok(())
     }
 
@@ -84,15 +474,179 @@ impl Indexer {
     where
         I: IntoIterator<Item = &'a DbMessage>,
     {
+        if !self.coalesce_batch_events {
+            for msg in messages {
+                self.index_message(msg).await?;
+            }
+            return Ok(());
+        }
+        let mut indexed = Vec::new();
         for msg in messages {
-            self.index_message(msg).await?;
+            if let Some(indexed_message) = self.save_message(msg).await? {
+                indexed.push(indexed_message);
+            }
+        }
+        if indexed.len() == 1 {
+            self.notify(self.events.send_message(indexed.remove(0)).await);
+        } else if !indexed.is_empty() {
+            self.notify(self.events.send_messages_batch(indexed).await);
         }
         Ok(())
     }
 
+    /// Rebuilds the index from the message store, e.g. after the index was lost or
+    /// the indexing logic changed. Running it again gives the same index. No
+    /// per-message events are sent, reload everything once it returns.
+    pub async fn reindex_all(&self) -> Result<()> {
+        let received_times = self.db.get_received_times().await?;
+        self.db.clear().await?;
+        let mut count = 0;
+        for peer_id in self.message_db.get_peers().await? {
+            for msg in self.message_db.get_after(&peer_id, 0).await? {
+                if msg.payload.is_empty() {
+                    continue;
+                }
+                let received_at = received_times
+                    .get(&msg.id)
+                    .copied()
+                    .unwrap_or_else(|| self.clock.timestamp());
+                let mut indexed_message = match self.process_message(&msg, received_at).await? {
+                    Some(indexed_message) => indexed_message,
+                    None => continue,
+                };
+                // delivery is only known from the acknowledgements, which cover our own repository
+                if indexed_message.status.is_some()
+                    && msg.peer_id == self.peer_id
+                    && !self
+                        .message_db
+                        .get_peers_acked_since(msg.counter)
+                        .await?
+                        .is_empty()
+                {
+                    indexed_message.status = Some(MessageStatus::Delivered);
+                }
+                self.db.save(&indexed_message).await?;
+                count += 1;
+            }
+        }
+        info!("reindexed {} messages", count);
+        Ok(())
+    }
+
     pub async fn get_all_after_order_id(&self, order_id: &str) -> Result<Vec<IndexedMessage>> {
         self.db.get_all_after_order_id(order_id).await
     }
+
+    /// Messages of a single peer, the only ordering that holds when global ordering is disabled.
+    pub async fn get_peer_after_order_id(
+        &self,
+        peer_id: &str,
+        order_id: &str,
+    ) -> Result<Vec<IndexedMessage>> {
+        self.db.get_peer_after_order_id(peer_id, order_id).await
+    }
+
+    pub async fn get_for_peer(
+        &self,
+        peer_id: &str,
+        after_order_id: Option<&str>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        self.db.get_for_peer(peer_id, after_order_id, limit).await
+    }
+
+    /// The latest `limit` messages of a peer, newest first.
+    pub async fn get_recent(&self, peer_id: &str, limit: u32) -> Result<Vec<IndexedMessage>> {
+        self.db.get_peer_before_order_id(peer_id, None, limit).await
+    }
+
+    /// Up to `limit` messages preceding `order_id` in the same conversation, newest first.
+    pub async fn get_older(&self, order_id: &str, limit: u32) -> Result<Vec<IndexedMessage>> {
+        let peer_id = peer_of_order_id(order_id)
+            .ok_or_else(|| anyhow::anyhow!("malformed order id {}", order_id))?;
+        self.db
+            .get_peer_before_order_id(peer_id, Some(order_id), limit)
+            .await
+    }
+
+    pub async fn search(
+        &self,
+        query: &str,
+        peer_id: Option<&str>,
+        start: Option<i64>,
+        end: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        self.db.search(query, peer_id, start, end, limit).await
+    }
+
+    pub async fn get_by_id(&self, id: &str) -> Result<Option<IndexedMessage>> {
+        self.db.get_by_id(id).await
+    }
+
+    pub async fn count_after_order_id(&self, order_id: &str) -> Result<u64> {
+        self.db.count_after_order_id(order_id).await
+    }
+
+    /// Also starts the countdown of the read-triggered messages it covers. Returns
+    /// whether the watermark moved, the peer is then sent a read receipt.
+    pub async fn mark_read(&self, peer_id: &str, up_to_order_id: &str) -> Result<bool> {
+        let moved = self.db.set_read_watermark(peer_id, up_to_order_id).await?;
+        // our own messages count down once the recipient reads them
+        if peer_id != self.peer_id {
+            self.db
+                .start_read_expiries(peer_id, up_to_order_id, &self.peer_id, self.clock.timestamp())
+                .await?;
+        }
+        Ok(moved)
+    }
+
+    /// Self-deleting messages whose time is up, see `IndexedMessageDatabase::due_expiries`.
+    pub async fn due_expiries(&self, now: i64) -> Result<Vec<(String, bool)>> {
+        self.db.due_expiries(now).await
+    }
+
+    pub async fn forget_expiry(&self, id: &str) -> Result<()> {
+        self.db.forget_expiry(id).await
+    }
+
+    pub async fn set_notification_pref(&self, peer_id: &str, pref: &NotificationPref) -> Result<()> {
+        self.db.set_notification_pref(peer_id, pref).await
+    }
+
+    pub async fn get_notification_pref(&self, peer_id: &str) -> Result<NotificationPref> {
+        self.db.get_notification_pref(peer_id).await
+    }
+
+    pub async fn get_read_watermark(&self, peer_id: &str) -> Result<Option<String>> {
+        self.db.get_read_watermark(peer_id).await
+    }
+
+    pub async fn count_unread(&self, peer_id: &str) -> Result<u64> {
+        self.db.count_unread(peer_id).await
+    }
+
+    /// Conversations with other peers, see `IndexedMessageDatabase::latest_per_peer`.
+    pub async fn latest_per_peer(&self) -> Result<Vec<ConversationSummary>> {
+        self.db.latest_per_peer(&self.peer_id).await
+    }
+
+    /// Returns up to `before` messages preceding `order_id`, the message at `order_id`
+    /// itself and up to `after` messages following it, in ascending order.
+    pub async fn get_around_order_id(
+        &self,
+        order_id: &str,
+        before: u32,
+        after: u32,
+    ) -> Result<Vec<IndexedMessage>> {
+        let mut messages = self.db.get_before_order_id(order_id, before).await?;
+        messages.extend(
+            self.db
+                .get_from_order_id(order_id, after.saturating_add(1))
+                .await?,
+        );
+        Ok(messages)
+    }
 }
 
 fn order_id(order: u64, peer_id: &str) -> String {
@@ -104,3 +658,725 @@ fn order_id(order: u64, peer_id: &str) -> String {
         .join("");
     format!("{}-{}", bytes, peer_id)
 }
+
+fn peer_of_order_id(order_id: &str) -> Option<&str> {
+    order_id.split_once('-').map(|(_, peer_id)| peer_id)
+}
+
+/// An indexer of the peer with `signing_key` over `pool`, see `memory_pool`.
+#[cfg(test)]
+pub(crate) async fn memory_indexer(
+    pool: sqlx::SqlitePool,
+    signing_key: ed25519_dalek::SigningKey,
+) -> Indexer {
+    memory_indexer_with_events(pool, signing_key, Arc::new(Events::new())).await
+}
+
+/// Like `memory_indexer`, sending its events to `events`.
+#[cfg(test)]
+pub(crate) async fn memory_indexer_with_events(
+    pool: sqlx::SqlitePool,
+    signing_key: ed25519_dalek::SigningKey,
+    events: Arc<Events>,
+) -> Indexer {
+    use crate::clock::SystemClock;
+
+    let clock: Arc<dyn Clock> = Arc::new(SystemClock);
+    let file_db = Arc::new(FileDatabase::new(pool.clone(), events.clone()));
+    file_db.init().await.unwrap();
+    let peer_db = Arc::new(PeerDatabase::new(
+        pool.clone(),
+        events.clone(),
+        TextPolicy::default(),
+        clock.clone(),
+    ));
+    peer_db.init().await.unwrap();
+    let message_db = Arc::new(MessageDatabase::new(pool.clone(), events.clone(), false));
+    message_db.init().await.unwrap();
+    let db = IndexedMessageDatabase::new(pool);
+    db.init().await.unwrap();
+    Indexer::new(
+        crate::peer_database::peer_id(&signing_key.verifying_key()),
+        db,
+        message_db,
+        file_db,
+        peer_db,
+        events,
+        Arc::new(DirectCipher::new(signing_key)),
+        TextPolicy::default(),
+        false,
+        None,
+        std::env::temp_dir().to_string_lossy().into_owned(),
+        clock,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::events::ChatEvent;
+    use crate::message_database::memory_pool;
+    use crate::models::MessageBuilder;
+    use crate::peer_database::peer_id;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+
+    fn key() -> SigningKey {
+        SigningKey::generate(&mut OsRng)
+    }
+
+    fn group_change(id: &str, author: &str, group_id: &str, member: &str) -> MessageBuilder {
+        MessageBuilder::new(id.to_owned(), 1, author.to_owned()).group_change(
+            group_id.to_owned(),
+            member.to_owned(),
+            false,
+        )
+    }
+
+    #[tokio::test]
+    async fn rename_is_shown_as_a_system_message() {
+        let alice = key();
+        let indexer = memory_indexer(memory_pool().await, key()).await;
+        let alice_id = peer_id(&alice.verifying_key());
+
+        let quiet = MessageBuilder::new("p1".to_owned(), 1, alice_id.clone())
+            .profile("Alice".to_owned(), None)
+            .build();
+        let renamed = MessageBuilder::new("p2".to_owned(), 2, alice_id.clone())
+            .profile("Ally".to_owned(), None)
+            .system(SystemKind::Renamed, "Ally".to_owned())
+            .build();
+        indexer.index_messages([&quiet, &renamed]).await.unwrap();
+
+        assert!(indexer.get_by_id("p1").await.unwrap().is_none());
+        let renamed = indexer.get_by_id("p2").await.unwrap().unwrap();
+        let system = renamed.system.unwrap();
+        assert_eq!(system.kind, SystemKind::Renamed);
+        assert_eq!(system.value, "Ally");
+    }
+
+    #[tokio::test]
+    async fn removal_is_shown_as_left_in_the_group() {
+        let (alice, bob, carol) = (key(), key(), key());
+        let (alice_id, bob_id, carol_id) = (
+            peer_id(&alice.verifying_key()),
+            peer_id(&bob.verifying_key()),
+            peer_id(&carol.verifying_key()),
+        );
+        let indexer = memory_indexer(memory_pool().await, bob).await;
+        let group_id = format!("{}:g1", alice_id);
+
+        let created = group_change("c1", &alice_id, &group_id, &alice_id).build();
+        let added = group_change("c2", &alice_id, &group_id, &bob_id).build();
+        let removed = MessageBuilder::new("c3".to_owned(), 2, alice_id.clone())
+            .group_change(group_id.clone(), bob_id.clone(), true)
+            .system(SystemKind::Left, "Bob".to_owned())
+            .build();
+        // by somebody who was never a member
+        let forged = MessageBuilder::new("c4".to_owned(), 3, carol_id.clone())
+            .group_change(group_id.clone(), alice_id.clone(), true)
+            .system(SystemKind::Left, "Alice".to_owned())
+            .build();
+        indexer
+            .index_messages([&created, &added, &removed, &forged])
+            .await
+            .unwrap();
+
+        for id in ["c1", "c2", "c4"] {
+            assert!(indexer.get_by_id(id).await.unwrap().is_none());
+        }
+        let left = indexer.get_by_id("c3").await.unwrap().unwrap();
+        assert_eq!(left.group_id.as_deref(), Some(group_id.as_str()));
+        assert_eq!(left.peer_id, alice_id);
+        let system = left.system.unwrap();
+        assert_eq!(system.kind, SystemKind::Left);
+        assert_eq!(system.value, "Bob");
+        let members = indexer.get_group_members(&group_id).await.unwrap();
+        assert_eq!(members, vec![alice_id]);
+    }
+
+    #[tokio::test]
+    async fn read_receipt_marks_what_the_reader_got() {
+        let (alice, bob, carol) = (key(), key(), key());
+        let (alice_id, bob_id, carol_id) = (
+            peer_id(&alice.verifying_key()),
+            peer_id(&bob.verifying_key()),
+            peer_id(&carol.verifying_key()),
+        );
+        let alice_cipher = DirectCipher::new(alice.clone());
+        let indexer = memory_indexer(memory_pool().await, alice).await;
+        let own = |id: &str, order: u64, recipient: Option<&str>| {
+            let builder =
+                MessageBuilder::new(id.to_owned(), 1, alice_id.clone()).text("hi".to_owned());
+            let mut msg = match recipient {
+                Some(recipient) => builder.build_direct(recipient, &alice_cipher).unwrap(),
+                None => builder.build(),
+            };
+            msg.order = order;
+            msg
+        };
+        let messages = [
+            own("m1", 1, None),
+            own("m2", 2, Some(&carol_id)),
+            own("m3", 3, Some(&bob_id)),
+            own("m4", 5, None),
+        ];
+        indexer.index_messages(&messages).await.unwrap();
+
+        let receipt = MessageBuilder::new("r1".to_owned(), 2, bob_id.clone())
+            .read_receipt(order_id(4, &alice_id))
+            .build_direct(&alice_id, &DirectCipher::new(bob))
+            .unwrap();
+        indexer.index_message(&receipt).await.unwrap();
+
+        assert!(indexer.get_by_id("r1").await.unwrap().is_none());
+        for (id, status) in [
+            ("m1", MessageStatus::Read),
+            ("m2", MessageStatus::Sent),
+            ("m3", MessageStatus::Read),
+            ("m4", MessageStatus::Sent),
+        ] {
+            let msg = indexer.get_by_id(id).await.unwrap().unwrap();
+            assert_eq!(msg.status, Some(status), "{}", id);
+        }
+    }
+
+    #[tokio::test]
+    async fn only_a_newer_mark_read_moves_the_watermark() {
+        let indexer = memory_indexer(memory_pool().await, key()).await;
+        let bob_id = peer_id(&key().verifying_key());
+
+        let (newer, older) = (order_id(5, &bob_id), order_id(3, &bob_id));
+        assert!(indexer.mark_read(&bob_id, &newer).await.unwrap());
+        assert!(!indexer.mark_read(&bob_id, &older).await.unwrap());
+        assert!(!indexer.mark_read(&bob_id, &newer).await.unwrap());
+        let watermark = indexer.get_read_watermark(&bob_id).await.unwrap();
+        assert_eq!(watermark, Some(newer));
+    }
+
+    #[tokio::test]
+    async fn messages_are_indexed_without_a_receiver() {
+        let alice_id = peer_id(&key().verifying_key());
+        let events = Arc::new(Events::disconnected());
+        let indexer = memory_indexer_with_events(memory_pool().await, key(), events).await;
+        let message = |id: &str| {
+            MessageBuilder::new(id.to_owned(), 1, alice_id.clone())
+                .text("hello".to_owned())
+                .build()
+        };
+
+        indexer.index_message(&message("m1")).await.unwrap();
+        indexer
+            .index_messages([&message("m2"), &message("m3")])
+            .await
+            .unwrap();
+        for id in ["m1", "m2", "m3"] {
+            let indexed = indexer.get_by_id(id).await.unwrap().unwrap();
+            assert_eq!(indexed.text, "hello");
+        }
+        indexer.remove_message("m1").await.unwrap();
+        assert!(indexer.get_by_id("m1").await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn payload_of_a_later_version_is_unsupported() {
+        let alice_id = peer_id(&key().verifying_key());
+        let indexer = memory_indexer(memory_pool().await, key()).await;
+        let with_version = |id: &str, version: u32| {
+            let mut msg = MessageBuilder::new(id.to_owned(), 1, alice_id.clone())
+                .text("hi".to_owned())
+                .build();
+            let mut payload = MessagePayload::decode(&*msg.payload).unwrap();
+            payload.version = version;
+            msg.payload = payload.encode_to_vec();
+            msg
+        };
+        let messages = [
+            with_version("legacy", 0),
+            with_version("current", PAYLOAD_VERSION),
+            with_version("future", PAYLOAD_VERSION + 1),
+        ];
+        indexer.index_messages(&messages).await.unwrap();
+
+        for id in ["legacy", "current"] {
+            let indexed = indexer.get_by_id(id).await.unwrap().unwrap();
+            assert!(!indexed.unsupported);
+            assert_eq!(indexed.text, "hi");
+        }
+        // shown in its place in the conversation, but nothing is read from it
+        let future = indexer.get_by_id("future").await.unwrap().unwrap();
+        assert!(future.unsupported);
+        assert!(future.text.is_empty());
+        assert_eq!(future.peer_id, alice_id);
+        assert_eq!(future.order_id, order_id(messages[2].order, &alice_id));
+    }
+
+    #[tokio::test]
+    async fn message_from_the_future_is_shown_at_its_receive_time() {
+        use crate::clock::SystemClock;
+
+        let alice_id = peer_id(&key().verifying_key());
+        let mut indexer = memory_indexer(memory_pool().await, key()).await;
+        indexer.max_clock_skew = Some(Duration::from_secs(60));
+        let now = SystemClock.timestamp();
+        let years_ahead = now + 5 * 365 * 24 * 60 * 60;
+        let message = |id: &str, timestamp: i64, order: u64| {
+            let mut msg = MessageBuilder::new(id.to_owned(), timestamp, alice_id.clone())
+                .text("hi".to_owned())
+                .build();
+            (msg.counter, msg.order) = (order, order);
+            msg
+        };
+        let messages = [
+            message("future", years_ahead, 1),
+            message("skewed", now + 30, 2),
+        ];
+        for msg in &messages {
+            indexer.message_db.save(msg).await.unwrap();
+        }
+        indexer.index_messages(&messages).await.unwrap();
+
+        let future = indexer.get_by_id("future").await.unwrap().unwrap();
+        assert!(future.received_at >= now && future.received_at < now + 60);
+        assert_eq!(future.timestamp, future.received_at);
+        // within the allowed skew the claimed time is kept
+        let skewed = indexer.get_by_id("skewed").await.unwrap().unwrap();
+        assert_eq!(skewed.timestamp, now + 30);
+
+        // the claimed timestamp stays in the store, the index keeps the receive time
+        let stored = indexer.message_db.get_by_id("future").await.unwrap();
+        assert_eq!(stored.unwrap().timestamp, years_ahead);
+        indexer.reindex_all().await.unwrap();
+        let reindexed = indexer.get_by_id("future").await.unwrap().unwrap();
+        assert_eq!(reindexed.received_at, future.received_at);
+        assert_eq!(reindexed.timestamp, future.received_at);
+    }
+
+    #[tokio::test]
+    async fn concurrent_votes_converge_in_any_order() {
+        let (alice, bob, carol) = (key(), key(), key());
+        let [alice_id, bob_id, carol_id] =
+            [&alice, &bob, &carol].map(|key| peer_id(&key.verifying_key()));
+        let options = vec!["tea".to_owned(), "coffee".to_owned()];
+        let vote = |id: &str, voter: &str, timestamp: i64, option: u32| {
+            MessageBuilder::new(id.to_owned(), timestamp, voter.to_owned())
+                .vote("poll".to_owned(), option)
+                .build()
+        };
+        let messages = [
+            MessageBuilder::new("poll".to_owned(), 1, alice_id.clone())
+                .poll("Drink?".to_owned(), options.clone())
+                .build(),
+            vote("a1", &alice_id, 5, 0),
+            // two votes at the same time, the larger id wins everywhere
+            vote("b1", &bob_id, 10, 0),
+            vote("b2", &bob_id, 10, 1),
+            // a changed vote, the later one counts however they arrive
+            vote("c2", &carol_id, 12, 0),
+            vote("c1", &carol_id, 11, 1),
+        ];
+
+        let mut polls = Vec::new();
+        for (owner, reversed) in [(alice, false), (bob, true)] {
+            let indexer = memory_indexer(memory_pool().await, owner).await;
+            let mut arrival: Vec<&DbMessage> = messages.iter().collect();
+            if reversed {
+                // votes before their poll
+                arrival.reverse();
+            }
+            for msg in arrival {
+                indexer.index_messages([msg]).await.unwrap();
+            }
+            polls.push(indexer.get_poll("poll").await.unwrap().unwrap());
+        }
+
+        for poll in &polls {
+            assert_eq!(poll.question, "Drink?");
+            assert_eq!(poll.options, options);
+            assert_eq!(poll.votes, [2, 1]);
+        }
+        assert_eq!(polls[0].own_vote, Some(0));
+        assert_eq!(polls[1].own_vote, Some(1));
+    }
+
+    #[tokio::test]
+    async fn link_preview_round_trips() {
+        let alice_id = peer_id(&key().verifying_key());
+        let indexer = memory_indexer(memory_pool().await, key()).await;
+        let with_preview = |id: &str, url: &str, image_file_id: Option<String>| {
+            MessageBuilder::new(id.to_owned(), 1, alice_id.clone())
+                .text(format!("see {}", url))
+                .link_preview(
+                    url.to_owned(),
+                    "Example".to_owned(),
+                    "An example page".to_owned(),
+                    image_file_id,
+                )
+                .build()
+        };
+        let messages = [
+            with_preview("thumb", "https://example.com", Some("image".to_owned())),
+            with_preview("plain", "https://example.org", None),
+            // a card without a link is dropped, the message is kept
+            with_preview("empty", "", Some("image".to_owned())),
+        ];
+        indexer.index_messages(&messages).await.unwrap();
+
+        let preview = |id: &str| {
+            let indexer = &indexer;
+            let id = id.to_owned();
+            async move { indexer.get_by_id(&id).await.unwrap().unwrap().link_preview }
+        };
+        let expected = LinkPreview {
+            url: "https://example.com".to_owned(),
+            title: "Example".to_owned(),
+            description: "An example page".to_owned(),
+            image_file_id: Some("image".to_owned()),
+        };
+        assert_eq!(preview("thumb").await, Some(expected));
+        let plain = preview("plain").await.unwrap();
+        assert_eq!(plain.url, "https://example.org");
+        assert_eq!(plain.image_file_id, None);
+        assert_eq!(preview("empty").await, None);
+    }
+
+    #[tokio::test]
+    async fn large_batch_is_reported_in_one_event() {
+        let alice_id = peer_id(&key().verifying_key());
+        let batch = |from: u64, count: u64| -> Vec<DbMessage> {
+            (from..from + count)
+                .map(|order| {
+                    let mut msg = MessageBuilder::new(format!("m{}", order), 1, alice_id.clone())
+                        .text("hi".to_owned())
+                        .build();
+                    (msg.counter, msg.order) = (order, order);
+                    msg
+                })
+                .collect()
+        };
+        let events = Arc::new(Events::new());
+        let pool = memory_pool().await;
+        let mut indexer = memory_indexer_with_events(pool, key(), events.clone()).await;
+        indexer.coalesce_batch_events = true;
+
+        indexer.index_messages(&batch(1, 200)).await.unwrap();
+        let received: Vec<ChatEvent> = events.get_rx().try_iter().collect();
+        let [ChatEvent::MessagesBatch(messages)] = &received[..] else {
+            panic!("expected a single batch");
+        };
+        let ids: Vec<&str> = messages.iter().map(|msg| msg.id.as_str()).collect();
+        let expected: Vec<String> = (1..=200).map(|order| format!("m{}", order)).collect();
+        assert_eq!(ids, expected);
+        // a batch of one is reported like any message
+        indexer.index_messages(&batch(201, 1)).await.unwrap();
+        let received: Vec<ChatEvent> = events.get_rx().try_iter().collect();
+        assert!(matches!(&received[..], [ChatEvent::Message(msg)] if msg.id == "m201"));
+
+        // without coalescing every message is its own event
+        indexer.coalesce_batch_events = false;
+        indexer.index_messages(&batch(202, 50)).await.unwrap();
+        let received: Vec<ChatEvent> = events.get_rx().try_iter().collect();
+        assert_eq!(received.len(), 50);
+        let single = |event: &ChatEvent| matches!(event, ChatEvent::Message(_));
+        assert!(received.iter().all(single));
+    }
+
+    #[tokio::test]
+    async fn mentions_only_conversation_notifies_about_mentions() {
+        let own = key();
+        let own_id = peer_id(&own.verifying_key());
+        let bob_id = peer_id(&key().verifying_key());
+        let indexer = memory_indexer(memory_pool().await, own).await;
+        let pref = NotificationPref {
+            mentions_only: true,
+            ..NotificationPref::default()
+        };
+        indexer.set_notification_pref(&bob_id, &pref).await.unwrap();
+
+        let mentioning = |id: &str, mentions: &[&str]| {
+            let mut msg = MessageBuilder::new(id.to_owned(), 1, bob_id.clone())
+                .text("hi".to_owned())
+                .build();
+            let mut payload = MessagePayload::decode(&*msg.payload).unwrap();
+            payload.mentions = mentions.iter().map(|id| id.to_string()).collect();
+            msg.payload = payload.encode_to_vec();
+            msg
+        };
+        let messages = [
+            mentioning("us", &[&own_id]),
+            mentioning("us-and-carol", &["carol", &own_id]),
+            mentioning("carol", &["carol"]),
+            mentioning("nobody", &[]),
+        ];
+        indexer.index_messages(&messages).await.unwrap();
+
+        let pref = indexer.get_notification_pref(&bob_id).await.unwrap();
+        let mut notified = Vec::new();
+        for msg in &messages {
+            let indexed = indexer.get_by_id(&msg.id).await.unwrap().unwrap();
+            if pref.allows(&indexed, &own_id, 0) {
+                notified.push(indexed.id);
+            }
+        }
+        assert_eq!(notified, ["us", "us-and-carol"]);
+        // other conversations keep notifying about everything
+        let other = indexer.get_notification_pref("carol").await.unwrap();
+        let indexed = indexer.get_by_id("nobody").await.unwrap().unwrap();
+        assert!(other.allows(&indexed, &own_id, 0));
+    }
+
+    #[tokio::test]
+    async fn reindex_rebuilds_the_lost_index() {
+        let (alice, bob) = (key(), key());
+        let (alice_id, bob_id) = (
+            peer_id(&alice.verifying_key()),
+            peer_id(&bob.verifying_key()),
+        );
+        let bob_cipher = DirectCipher::new(bob);
+        let indexer = memory_indexer(memory_pool().await, alice).await;
+        let text = |id: &str, author: &str, text: &str| {
+            MessageBuilder::new(id.to_owned(), 1, author.to_owned()).text(text.to_owned())
+        };
+        let mut messages = vec![
+            text("a1", &alice_id, "hi bob").build(),
+            text("b1", &bob_id, "hi all").build(),
+            text("b2", &bob_id, "just you")
+                .build_direct(&alice_id, &bob_cipher)
+                .unwrap(),
+            text("a2", &alice_id, "bye").build(),
+        ];
+        for (order, msg) in messages.iter_mut().enumerate() {
+            msg.counter = if msg.id.ends_with('1') { 1 } else { 2 };
+            msg.order = order as u64 + 1;
+        }
+        indexer.message_db.save_many(&messages).await.unwrap();
+        indexer.index_messages(&messages).await.unwrap();
+        // when a message was received is only kept in the index
+        let snapshot = |indexed: Vec<IndexedMessage>, received: bool| {
+            indexed
+                .into_iter()
+                .map(|mut msg| {
+                    if !received {
+                        msg.received_at = 0;
+                    }
+                    format!("{:?}", msg)
+                })
+                .collect::<Vec<_>>()
+        };
+        let before = indexer.get_all_after_order_id("").await.unwrap();
+        assert_eq!(before.len(), messages.len());
+
+        indexer.db.clear().await.unwrap();
+        assert!(indexer.get_all_after_order_id("").await.unwrap().is_empty());
+        indexer.reindex_all().await.unwrap();
+        let after = indexer.get_all_after_order_id("").await.unwrap();
+        // the same messages in the same order
+        assert_eq!(snapshot(after.clone(), false), snapshot(before, false));
+        let direct = indexer.get_by_id("b2").await.unwrap().unwrap();
+        assert_eq!(direct.text, "just you");
+
+        // running it on an intact index changes nothing
+        indexer.reindex_all().await.unwrap();
+        let again = indexer.get_all_after_order_id("").await.unwrap();
+        assert_eq!(snapshot(again, true), snapshot(after, true));
+    }
+
+    #[test]
+    fn metadata_survives_sync() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.learn(&alice).await;
+            alice.start().await;
+            bob.start().await;
+            bob.wait_for_counter(&alice.id(), 1).await;
+
+            let manager = alice.ctx.sync_engine.get_manager();
+            let quiz = MessageBuilder::new("quiz".to_owned(), 1, alice.id())
+                .text("6 x 7?".to_owned())
+                .metadata("type".to_owned(), "quiz".to_owned())
+                .metadata("answer".to_owned(), "42".to_owned())
+                .build();
+            // the binding refuses these, an older or modified client may not
+            let oversized = MessageBuilder::new("oversized".to_owned(), 1, alice.id())
+                .text("big".to_owned())
+                .metadata("blob".to_owned(), "x".repeat(MAX_METADATA_SIZE))
+                .build();
+            for msg in [quiz, oversized] {
+                manager.clone().add_own_message(msg).await.unwrap();
+            }
+            let engine = &bob.ctx.sync_engine;
+            engine.sync_now(Some(alice.id())).await.unwrap();
+            bob.wait_for_counter(&alice.id(), 3).await;
+
+            let indexed = |id: &str| {
+                let (indexer, id) = (bob.ctx.indexer.clone(), id.to_owned());
+                async move { indexer.get_by_id(&id).await.unwrap() }
+            };
+            wait_until("bob shows both messages", || async {
+                indexed("quiz").await.is_some() && indexed("oversized").await.is_some()
+            })
+            .await;
+            let expected = HashMap::from([
+                ("type".to_owned(), "quiz".to_owned()),
+                ("answer".to_owned(), "42".to_owned()),
+            ]);
+            assert_eq!(indexed("quiz").await.unwrap().metadata, expected);
+            let own = alice.ctx.indexer.get_by_id("quiz").await.unwrap();
+            assert_eq!(own.unwrap().metadata, expected);
+            // the message itself is kept, only its metadata is dropped
+            let oversized = indexed("oversized").await.unwrap();
+            assert_eq!(oversized.text, "big");
+            assert!(oversized.metadata.is_empty());
+        });
+    }
+
+    #[test]
+    fn avatar_is_synced_like_a_file() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.learn(&alice).await;
+            alice.start().await;
+            bob.start().await;
+            bob.wait_for_counter(&alice.id(), 1).await;
+
+            let image = b"not really a png".to_vec();
+            tokio::fs::write(Path::new(&alice.root).join("me.png"), &image)
+                .await
+                .unwrap();
+            let file = FileDescription {
+                id: "avatar".to_owned(),
+                format: "png".to_owned(),
+                local_path: "me.png".to_owned(),
+                timestamp: 0,
+            };
+            alice.ctx.file_db.save_owned(&file).await.unwrap();
+            let profile = MessageBuilder::new("profile".to_owned(), 1, alice.id())
+                .profile("alice".to_owned(), Some(file.id.clone()))
+                .build();
+            let manager = alice.ctx.sync_engine.get_manager();
+            manager.add_own_message(profile).await.unwrap();
+            let engine = &bob.ctx.sync_engine;
+            engine.sync_now(Some(alice.id())).await.unwrap();
+            bob.wait_for_counter(&alice.id(), 2).await;
+
+            // the profile only names the file, it is downloaded when asked for
+            let peer_db = bob.ctx.peer_db.clone();
+            let stored = || async { peer_db.get_peer_by_id(&alice.id()).await.unwrap().unwrap() };
+            wait_until("bob knows alice's avatar", || async {
+                stored().await.avatar_file_id.as_deref() == Some("avatar")
+            })
+            .await;
+            assert!(stored().await.avatar_path.is_none());
+            let events = bob.ctx.events.get_rx();
+            events.drain();
+
+            let resolver = &bob.ctx.file_resolver;
+            resolver.add_need_resolve("avatar", Some(alice.id())).await;
+            wait_until("bob downloaded the avatar", || async {
+                stored().await.avatar_path.is_some()
+            })
+            .await;
+            let path = stored().await.avatar_path.unwrap();
+            let downloaded = tokio::fs::read(Path::new(&bob.root).join(path)).await;
+            assert_eq!(downloaded.unwrap(), image);
+            // the app is told to show it
+            wait_until("the profile update is sent", || async {
+                events.try_iter().any(|event| match event {
+                    ChatEvent::PeerProfileUpdated(peer) => {
+                        peer.id == alice.id() && peer.avatar_path.is_some()
+                    }
+                    _ => false,
+                })
+            })
+            .await;
+        });
+    }
+
+    #[test]
+    fn inline_file_arrives_with_its_message_and_others_are_downloaded() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.learn(&alice).await;
+            alice.start().await;
+            bob.start().await;
+            bob.wait_for_counter(&alice.id(), 1).await;
+
+            let manager = alice.ctx.sync_engine.get_manager();
+            let mut sent = Vec::new();
+            for (file_id, inline) in [("small", true), ("large", false)] {
+                let content = format!("contents of {}", file_id).into_bytes();
+                let local_path = format!("{}.bin", file_id);
+                tokio::fs::write(Path::new(&alice.root).join(&local_path), &content)
+                    .await
+                    .unwrap();
+                let file = FileDescription {
+                    id: file_id.to_owned(),
+                    format: "bin".to_owned(),
+                    local_path,
+                    timestamp: 0,
+                };
+                alice.ctx.file_db.save_owned(&file).await.unwrap();
+                let builder = MessageBuilder::new(uuid::Uuid::new_v4().to_string(), 1, alice.id())
+                    .file_id(file_id.to_owned());
+                let builder = if inline {
+                    builder.inline_file(content.clone(), "bin".to_owned())
+                } else {
+                    builder
+                };
+                let msg = builder.build();
+                manager.clone().add_own_message(msg.clone()).await.unwrap();
+                sent.push((msg.id, file_id, content));
+            }
+            // pushes may overtake each other, a pull gets whatever they missed
+            let engine = &bob.ctx.sync_engine;
+            engine.sync_now(Some(alice.id())).await.unwrap();
+            bob.wait_for_counter(&alice.id(), 3).await;
+
+            let indexed = |id: &str| {
+                let (indexer, id) = (bob.ctx.indexer.clone(), id.to_owned());
+                async move { indexer.get_by_id(&id).await.unwrap() }
+            };
+            let (small_id, _, small) = &sent[0];
+            let (large_id, large_file, large) = &sent[1];
+            wait_until("bob shows both messages", || async {
+                indexed(small_id).await.is_some() && indexed(large_id).await.is_some()
+            })
+            .await;
+            // the inline file is there as soon as its message
+            let path = indexed(small_id).await.unwrap().file_path.unwrap();
+            let stored = tokio::fs::read(Path::new(&bob.root).join(path)).await;
+            assert_eq!(&stored.unwrap(), small);
+            // the other one waits for a download
+            assert!(indexed(large_id).await.unwrap().file_path.is_none());
+            bob.ctx
+                .file_resolver
+                .add_need_resolve(large_file, Some(alice.id()))
+                .await;
+            wait_until("bob downloaded the file", || async {
+                indexed(large_id).await.unwrap().file_path.is_some()
+            })
+            .await;
+            let path = indexed(large_id).await.unwrap().file_path.unwrap();
+            let stored = tokio::fs::read(Path::new(&bob.root).join(path)).await;
+            assert_eq!(&stored.unwrap(), large);
+        });
+    }
+}
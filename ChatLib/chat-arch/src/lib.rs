@@ -1,22 +1,37 @@
 pub mod app_context;
+pub mod bandwidth;
+mod capabilities;
 mod chat_msg;
-mod conn;
+pub mod clock;
+pub mod config;
+pub mod conn;
 pub mod dialer;
+mod direct_message;
 pub mod events;
 pub mod file_database;
+pub mod fingerprint;
 mod file_resolver;
 mod handshake;
+pub mod handshake_scheme;
+pub mod health;
 pub mod index_database;
+mod inbound_policy;
 mod indexer;
 mod message_database;
+mod message_expiry;
+mod outbox;
 pub mod models;
 mod peer;
 pub mod peer_database;
 pub mod peer_pool;
+mod peer_pruner;
 mod proto;
+pub mod protocol_recorder;
 mod repository;
 mod repository_manager;
 mod request_queue;
+mod sanitize;
 mod server;
+pub mod storage_error;
 mod stream_protocol;
 mod sync_engine;
@@ -8,19 +8,30 @@ use tokio::io::{self, AsyncBufReadExt, BufReader};
 
 fn main() {
     env_logger::init();
-    if std::env::args().nth(1) == Some("server".to_string()) {
-        info!("Starting server ......");
-        run_server("Alice", "127.0.0.1:6262", "server");
-    } else {
-        info!("Starting client ......");
-        run_server("Bob", "127.0.0.1:6363", "client");
+    match std::env::args().nth(1).as_deref() {
+        Some("server") => {
+            info!("Starting server ......");
+            run_server("Alice", "127.0.0.1:6262", "server", false);
+        }
+        Some("observer") => {
+            info!("Starting observer ......");
+            run_server("Backup", "127.0.0.1:6464", "observer", true);
+        }
+        _ => {
+            info!("Starting client ......");
+            run_server("Bob", "127.0.0.1:6363", "client", false);
+        }
     }
 }
 
-fn run_server(name: &str, addr: &str, folder: &str) {
-    let rt = Arc::new(tokio::runtime::Runtime::new().unwrap());
+fn run_server(name: &str, addr: &str, folder: &str, observer: bool) {
+    let config = chat_arch::config::Config {
+        observer,
+        ..chat_arch::config::Config::default()
+    };
+    let rt = Arc::new(config.runtime.build().unwrap());
     rt.clone().block_on(async move {
-        if let Err(e) = server(name, addr, folder, rt).await {
+        if let Err(e) = server(name, addr, folder, config, rt).await {
             warn!("Error: {:?}", e);
         }
     });
@@ -30,10 +41,11 @@ async fn server(
     name: &str,
     addr: &str,
     folder: &str,
+    config: chat_arch::config::Config,
     rt: Arc<tokio::runtime::Runtime>,
 ) -> anyhow::Result<()> {
-    let deps = chat_arch::app_context::prepare_deps(name, addr, folder, rt.clone()).await?;
-    println!("My peer id is {}", &deps.peer.id);
+    let deps = chat_arch::app_context::prepare_deps(name, addr, folder, config, rt.clone()).await?;
+    println!("My peer id is {}", deps.peer.id);
     let cloned_deps = deps.clone();
     let event_deps = deps.clone();
     let events_handle = rt.spawn(async move {
@@ -51,6 +63,8 @@ async fn server(
     });
     deps.sync_engine.run();
     deps.file_resolver.clone().run();
+    deps.message_expiry.clone().run();
+    deps.peer_pruner.clone().run();
     read_loop(deps).await;
     events_handle.await?;
     server_handle.await?;
@@ -86,7 +100,7 @@ async fn read_loop(deps: AppContext) {
                 let manager = deps.sync_engine.get_manager();
                 let msg = models::MessageBuilder::new(
                     uuid::Uuid::new_v4().to_string(),
-                    chrono::Utc::now().timestamp(),
+                    deps.clock.timestamp(),
                     deps.peer.id.clone(),
                 )
                 .text(parts[1..].join(" "))
@@ -108,7 +122,7 @@ async fn read_loop(deps: AppContext) {
                     id: file_id.to_owned(),
                     local_path: path.to_owned(),
                     format: "txt".to_owned(),
-                    timestamp: chrono::Utc::now().timestamp(),
+                    timestamp: deps.clock.timestamp(),
                 };
                 if let Err(e) = deps.file_db.save(&description).await {
                     println!("Failed to save file: {:?}", e);
@@ -139,7 +153,7 @@ async fn read_loop(deps: AppContext) {
                 let manager = deps.sync_engine.get_manager();
                 let msg = models::MessageBuilder::new(
                     uuid::Uuid::new_v4().to_string(),
-                    chrono::Utc::now().timestamp(),
+                    deps.clock.timestamp(),
                     deps.peer.id.clone(),
                 )
                 .file_id(parts[1].to_owned())
@@ -158,6 +172,15 @@ async fn read_loop(deps: AppContext) {
                 let dialer = deps.dialer.clone();
                 dialer.add(parts[1].to_owned(), parts[2].to_owned()).await;
             }
+            "health" => {
+                let health = chat_arch::health::check(&deps).await;
+                println!("{:?}, healthy: {}", health, health.is_healthy());
+            }
+            "traffic" => {
+                for (peer_id, traffic) in deps.sync_engine.peer_pool.meter().all() {
+                    println!("{}: {} in, {} out", peer_id, traffic.bytes_in, traffic.bytes_out);
+                }
+            }
             "read_all" => {
                 if parts.len() != 1 {
                     println!("read_all command should be empty");
@@ -1,16 +1,67 @@
+use std::borrow::Cow;
 use std::path::Path;
 
+use crate::events::Events;
 use crate::models::DbMessage;
 use anyhow::Result;
+use sqlx::sqlite::{SqliteConnectOptions, SqliteJournalMode, SqliteRow, SqliteSynchronous};
 use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long a connection waits for a write lock held by another one before
+/// failing with `SQLITE_BUSY`.
+const BUSY_TIMEOUT: Duration = Duration::from_secs(5);
 
 pub struct MessageDatabase {
     pool: SqlitePool,
+    events: Arc<Events>,
+    compress_payloads: bool,
 }
 
 impl MessageDatabase {
-    pub fn new(pool: SqlitePool) -> Self {
-        Self { pool }
+    pub fn new(pool: SqlitePool, events: Arc<Events>, compress_payloads: bool) -> Self {
+        Self {
+            pool,
+            events,
+            compress_payloads,
+        }
+    }
+
+    /// The payload as stored and whether it is compressed. Payloads that don't
+    /// shrink, like short texts and encrypted direct messages, are stored as they are.
+    fn stored_payload<'a>(&self, payload: &'a [u8]) -> (Cow<'a, [u8]>, bool) {
+        if self.compress_payloads && !payload.is_empty() {
+            let compressed = lz4_flex::compress_prepend_size(payload);
+            if compressed.len() < payload.len() {
+                return (Cow::Owned(compressed), true);
+            }
+        }
+        (Cow::Borrowed(payload), false)
+    }
+
+    /// Reports a failed write that was caused by the disk, see `ChatEvent::StorageError`.
+    async fn checked<T>(&self, res: Result<T>) -> Result<T> {
+        if let Err(e) = &res {
+            self.events.report_storage_error(e).await;
+        }
+        res
+    }
+
+    /// Moves the WAL into the main database file and truncates it, so everything
+    /// written so far is synced to disk. Meant for when the app goes to background.
+    /// A trivial query, fails or hangs if the database is wedged.
+    pub async fn ping(&self) -> Result<()> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+
+    pub async fn checkpoint(&self) -> Result<()> {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(&self.pool)
+            .await?;
+        Ok(())
     }
 
     pub async fn init(&self) -> Result<Option<u64>> {
@@ -22,7 +73,21 @@ impl MessageDatabase {
                 timestamp INTEGER NOT NULL,
                 order_counter INTEGER NOT NULL,
                 payload BLOB NOT NULL,
-                peer_id TEXT NOT NULL
+                peer_id TEXT NOT NULL,
+                compressed INTEGER NOT NULL DEFAULT 0
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        // rows written before compression existed read as uncompressed
+        add_column_if_missing(&self.pool, "messages", "compressed", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS deliveries (
+                peer_id TEXT PRIMARY KEY NOT NULL,
+                acked_counter INTEGER NOT NULL
             )
             "#,
         )
@@ -41,24 +106,33 @@ impl MessageDatabase {
     }
 
     pub async fn save(&self, msg: &DbMessage) -> Result<()> {
-        sqlx::query(
+        let (payload, compressed) = self.stored_payload(&msg.payload);
+        let res = sqlx::query(
             r#"
-            INSERT INTO messages (id, timestamp, counter, order_counter, payload, peer_id)
-            VALUES (?, ?, ?, ?, ?, ?)"#,
+            INSERT INTO messages (id, timestamp, counter, order_counter, payload, peer_id, compressed)
+            VALUES (?, ?, ?, ?, ?, ?, ?)"#,
         )
         .bind(&msg.id)
-        .bind(&msg.timestamp)
-        .bind(&(msg.counter as i64))
-        .bind(&(msg.order as i64))
-        .bind(&msg.payload)
+        .bind(msg.timestamp)
+        .bind(msg.counter as i64)
+        .bind(msg.order as i64)
+        .bind(payload.as_ref())
         .bind(&msg.peer_id)
+        .bind(compressed)
         .execute(&self.pool)
-        .await?;
-
-        Ok(())
+        .await;
+        self.checked(res.map(|_| ()).map_err(|e| e.into())).await
     }
 
     pub async fn save_many<'a, I>(&self, messages: I) -> Result<()>
+    where
+        I: IntoIterator<Item = &'a DbMessage>,
+    {
+        let res = self.insert_many(messages).await;
+        self.checked(res).await
+    }
+
+    async fn insert_many<'a, I>(&self, messages: I) -> Result<()>
     where
         I: IntoIterator<Item = &'a DbMessage>,
     {
@@ -67,18 +141,20 @@ impl MessageDatabase {
         for msg in messages {
             let counter = msg.counter as i64;
             let order = msg.order as i64;
+            let (payload, compressed) = self.stored_payload(&msg.payload);
             sqlx::query(
                 r#"
-                    INSERT INTO messages (id, timestamp, counter, order_counter, payload, peer_id)
-                    VALUES ($1, $2, $3, $4, $5, $6)
+                    INSERT INTO messages (id, timestamp, counter, order_counter, payload, peer_id, compressed)
+                    VALUES ($1, $2, $3, $4, $5, $6, $7)
                     "#,
             )
             .bind(msg.id.clone())
             .bind(msg.timestamp)
-            .bind(&counter)
-            .bind(&order)
-            .bind(msg.payload.clone())
+            .bind(counter)
+            .bind(order)
+            .bind(payload.into_owned())
             .bind(msg.peer_id.clone())
+            .bind(compressed)
             .execute(&mut *tx)
             .await?;
         }
@@ -90,7 +166,7 @@ impl MessageDatabase {
     pub async fn get_by_id(&self, id: &str) -> Result<Option<DbMessage>> {
         let row = sqlx::query(
             r#"
-            SELECT counter, id, timestamp, payload, peer_id, order_counter
+            SELECT counter, id, timestamp, payload, peer_id, order_counter, compressed
             FROM messages
             WHERE id = ?
             "#,
@@ -99,14 +175,7 @@ impl MessageDatabase {
         .fetch_optional(&self.pool)
         .await?;
 
-        Ok(row.map(|row| DbMessage {
-            counter: row.get("counter"),
-            id: row.get("id"),
-            timestamp: row.get("timestamp"),
-            payload: row.get("payload"),
-            order: row.get("order_counter"),
-            peer_id: row.get("peer_id"),
-        }))
+        row.map(row_to_message).transpose()
     }
 
     pub async fn get_highest_counter(&self, peer_id: &str) -> Result<u64> {
@@ -127,7 +196,7 @@ impl MessageDatabase {
     pub async fn get_after(&self, peer_id: &str, counter: u64) -> Result<Vec<DbMessage>> {
         let rows = sqlx::query(
             r#"
-            SELECT counter, id, timestamp, order_counter, payload, peer_id
+            SELECT counter, id, timestamp, order_counter, payload, peer_id, compressed
             FROM messages
             WHERE peer_id = ? AND counter >= ?
             ORDER BY counter
@@ -138,19 +207,116 @@ impl MessageDatabase {
         .fetch_all(&self.pool)
         .await?;
 
-        let messages = rows
-            .into_iter()
-            .map(|row| DbMessage {
-                counter: row.get("counter"),
-                id: row.get("id"),
-                timestamp: row.get("timestamp"),
-                payload: row.get("payload"),
-                order: row.get("order_counter"),
-                peer_id: row.get("peer_id"),
-            })
-            .collect();
+        rows.into_iter().map(row_to_message).collect()
+    }
+
+    /// Like `get_after`, at most `limit` messages.
+    pub async fn get_page(&self, peer_id: &str, counter: u64, limit: u32) -> Result<Vec<DbMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT counter, id, timestamp, order_counter, payload, peer_id, compressed
+            FROM messages
+            WHERE peer_id = ? AND counter >= ?
+            ORDER BY counter
+            LIMIT ?
+            "#,
+        )
+        .bind(peer_id)
+        .bind(counter as i64)
+        .bind(limit as i64)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(row_to_message).collect()
+    }
+
+    /// Messages of `peer_id` older than `timestamp` that still have their payload.
+    pub async fn get_unexpired_before(&self, peer_id: &str, timestamp: i64) -> Result<Vec<DbMessage>> {
+        let rows = sqlx::query(
+            r#"
+            SELECT counter, id, timestamp, order_counter, payload, peer_id, compressed
+            FROM messages
+            WHERE peer_id = ? AND timestamp < ? AND length(payload) > 0
+            ORDER BY counter
+            "#,
+        )
+        .bind(peer_id)
+        .bind(timestamp)
+        .fetch_all(&self.pool)
+        .await?;
+
+        rows.into_iter().map(row_to_message).collect()
+    }
+
+    /// Clears the payload of a message. The row is kept so that the counter chain of
+    /// the repository stays contiguous.
+    pub async fn clear_payload(&self, id: &str) -> Result<()> {
+        sqlx::query("UPDATE messages SET payload = ?, compressed = 0 WHERE id = ?")
+            .bind(Vec::<u8>::new())
+            .bind(id)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    /// Moves the highest own counter acknowledged by `peer_id` forward, lower values are ignored.
+    pub async fn set_acked_counter(&self, peer_id: &str, counter: u64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO deliveries (peer_id, acked_counter)
+            VALUES (?, ?)
+            ON CONFLICT(peer_id) DO UPDATE SET acked_counter = excluded.acked_counter
+            WHERE excluded.acked_counter > deliveries.acked_counter
+            "#,
+        )
+        .bind(peer_id)
+        .bind(counter as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    /// Records that `peer_id` still needs own messages after `acked_counter`, unless
+    /// its delivery state is known already.
+    pub async fn track_delivery(&self, peer_id: &str, acked_counter: u64) -> Result<()> {
+        sqlx::query(
+            r#"
+            INSERT INTO deliveries (peer_id, acked_counter)
+            VALUES (?, ?)
+            ON CONFLICT(peer_id) DO NOTHING
+            "#,
+        )
+        .bind(peer_id)
+        .bind(acked_counter as i64)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    pub async fn get_peers_acked_before(&self, counter: u64) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT peer_id FROM deliveries WHERE acked_counter < ?")
+            .bind(counter as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("peer_id")).collect())
+    }
+
+    pub async fn get_acked_counter(&self, peer_id: &str) -> Result<u64> {
+        let row = sqlx::query("SELECT acked_counter FROM deliveries WHERE peer_id = ?")
+            .bind(peer_id)
+            .fetch_optional(&self.pool)
+            .await?;
+        Ok(row
+            .map(|row| row.get::<i64, _>("acked_counter") as u64)
+            .unwrap_or(0))
+    }
 
-        Ok(messages)
+    pub async fn get_peers_acked_since(&self, counter: u64) -> Result<Vec<String>> {
+        let rows = sqlx::query("SELECT peer_id FROM deliveries WHERE acked_counter >= ?")
+            .bind(counter as i64)
+            .fetch_all(&self.pool)
+            .await?;
+        Ok(rows.into_iter().map(|row| row.get("peer_id")).collect())
     }
 
     pub async fn get_peers(&self) -> Result<Vec<String>> {
@@ -169,12 +335,305 @@ impl MessageDatabase {
     }
 }
 
-pub async fn create_pool(db_folder: &str) -> Result<SqlitePool> {
+/// Adds a column to an existing table, used to migrate databases created before the column existed.
+fn row_to_message(row: SqliteRow) -> Result<DbMessage> {
+    let payload: Vec<u8> = row.get("payload");
+    let payload = if row.get::<bool, _>("compressed") {
+        lz4_flex::decompress_size_prepended(&payload)?
+    } else {
+        payload
+    };
+    Ok(DbMessage {
+        counter: row.get("counter"),
+        id: row.get("id"),
+        timestamp: row.get("timestamp"),
+        payload,
+        order: row.get("order_counter"),
+        peer_id: row.get("peer_id"),
+    })
+}
+
+pub async fn add_column_if_missing(
+    pool: &SqlitePool,
+    table: &str,
+    column: &str,
+    definition: &str,
+) -> Result<()> {
+    let row = sqlx::query(
+        r#"
+        SELECT COUNT(*) as count
+        FROM pragma_table_info(?)
+        WHERE name = ?
+        "#,
+    )
+    .bind(table)
+    .bind(column)
+    .fetch_one(pool)
+    .await?;
+    if row.get::<i64, _>("count") == 0 {
+        let query = format!("ALTER TABLE {} ADD COLUMN {} {}", table, column, definition);
+        sqlx::query(&query).execute(pool).await?;
+    }
+    Ok(())
+}
+
+/// Opens the database in WAL mode with `synchronous=NORMAL`, a busy timeout and
+/// foreign keys enforced. A committed write survives the app being killed, it
+/// can only be lost if the OS itself crashes before the WAL is synced,
+/// `checkpoint` narrows that window further.
+pub async fn create_pool(db_folder: &str, max_connections: u32) -> Result<SqlitePool> {
     let path = Path::new(db_folder).join("message.db");
     let database_url = format!("sqlite:{}?mode=rwc", path.display());
     println!("database url {}", database_url);
+    let options = SqliteConnectOptions::from_str(&database_url)?
+        .journal_mode(SqliteJournalMode::Wal)
+        .synchronous(SqliteSynchronous::Normal)
+        .busy_timeout(BUSY_TIMEOUT)
+        .foreign_keys(true);
     let pool = sqlx::sqlite::SqlitePoolOptions::new()
-        .connect(&database_url)
+        .max_connections(max_connections.max(1))
+        .connect_with(options)
         .await?;
     Ok(pool)
 }
+
+/// A private in-memory database, one connection so every query sees the same data.
+#[cfg(test)]
+pub(crate) async fn memory_pool() -> SqlitePool {
+    sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::events::ChatEvent;
+    use crate::storage_error::StorageErrorKind;
+    use crate::sync_engine::SYNC_WORKERS;
+
+    fn temp_folder() -> String {
+        let folder = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&folder).unwrap();
+        folder.to_string_lossy().into_owned()
+    }
+
+    #[tokio::test]
+    async fn pool_holds_at_most_max_connections() {
+        let folder = temp_folder();
+        let pool = create_pool(&folder, 3).await.unwrap();
+        assert_eq!(pool.options().get_max_connections(), 3);
+        let mut held = Vec::new();
+        for _ in 0..3 {
+            held.push(pool.acquire().await.unwrap());
+        }
+        // a fourth query waits for one of them
+        let waiting = tokio::time::timeout(Duration::from_millis(200), pool.acquire()).await;
+        assert!(waiting.is_err());
+        held.pop();
+        pool.acquire().await.unwrap();
+        drop(held);
+        pool.close().await;
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn pool_has_at_least_one_connection() {
+        let folder = temp_folder();
+        let pool = create_pool(&folder, 0).await.unwrap();
+        assert_eq!(pool.options().get_max_connections(), 1);
+        sqlx::query("SELECT 1").execute(&pool).await.unwrap();
+        pool.close().await;
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    #[tokio::test]
+    async fn reads_run_alongside_writes() {
+        const WRITES: u64 = 200;
+        let folder = temp_folder();
+        let pool = create_pool(&folder, 4).await.unwrap();
+        let mode: String = sqlx::query_scalar("PRAGMA journal_mode")
+            .fetch_one(&pool)
+            .await
+            .unwrap();
+        assert_eq!(mode, "wal");
+        let db = Arc::new(database(pool.clone(), false).await);
+
+        let writer = tokio::spawn({
+            let db = db.clone();
+            async move {
+                for counter in 1..=WRITES {
+                    let id = format!("m{}", counter);
+                    db.save(&message(&id, counter, b"hi".to_vec())).await?;
+                }
+                anyhow::Ok(())
+            }
+        });
+        let readers: Vec<_> = (0..3)
+            .map(|_| {
+                let db = db.clone();
+                tokio::spawn(async move {
+                    // every read succeeds and never sees the log go backwards
+                    let mut seen = 0;
+                    while seen < WRITES {
+                        let highest = db.get_highest_counter("alice").await?;
+                        assert!(highest >= seen);
+                        let after = db.get_after("alice", highest).await?;
+                        assert!(after.iter().all(|message| message.counter >= highest));
+                        seen = highest;
+                    }
+                    anyhow::Ok(())
+                })
+            })
+            .collect();
+
+        writer.await.unwrap().unwrap();
+        for reader in readers {
+            reader.await.unwrap().unwrap();
+        }
+        assert_eq!(db.get_highest_counter("alice").await.unwrap(), WRITES);
+        pool.close().await;
+        std::fs::remove_dir_all(&folder).unwrap();
+    }
+
+    async fn database(pool: SqlitePool, compress_payloads: bool) -> MessageDatabase {
+        let db = MessageDatabase::new(pool, Arc::new(Events::new()), compress_payloads);
+        db.init().await.unwrap();
+        db
+    }
+
+    fn message(id: &str, counter: u64, payload: Vec<u8>) -> DbMessage {
+        DbMessage {
+            counter,
+            id: id.to_owned(),
+            order: counter,
+            timestamp: 1,
+            payload,
+            peer_id: "alice".to_owned(),
+        }
+    }
+
+    /// The payload as it is in the table and whether it is marked compressed.
+    async fn stored(pool: &SqlitePool, id: &str) -> (Vec<u8>, bool) {
+        let row = sqlx::query("SELECT payload, compressed FROM messages WHERE id = ?")
+            .bind(id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        (row.get("payload"), row.get("compressed"))
+    }
+
+    #[tokio::test]
+    async fn compressed_rows_round_trip() {
+        let pool = memory_pool().await;
+        let db = database(pool.clone(), true).await;
+        let long = b"hello ".repeat(200);
+        db.save(&message("m1", 1, long.clone())).await.unwrap();
+        db.save_many([&message("m2", 2, long.clone())])
+            .await
+            .unwrap();
+        for id in ["m1", "m2"] {
+            let (payload, compressed) = stored(&pool, id).await;
+            assert!(compressed);
+            assert!(payload.len() < long.len());
+            assert_eq!(db.get_by_id(id).await.unwrap().unwrap().payload, long);
+        }
+        let page = db.get_after("alice", 1).await.unwrap();
+        assert!(page.iter().all(|message| message.payload == long));
+
+        // payloads that don't shrink are stored as they are
+        db.save(&message("m3", 3, b"hi".to_vec())).await.unwrap();
+        assert_eq!(stored(&pool, "m3").await, (b"hi".to_vec(), false));
+        assert_eq!(db.get_by_id("m3").await.unwrap().unwrap().payload, b"hi");
+
+        // turning compression off still reads what was compressed
+        let db = database(pool, false).await;
+        assert_eq!(db.get_by_id("m1").await.unwrap().unwrap().payload, long);
+    }
+
+    #[tokio::test]
+    async fn full_database_reports_a_storage_error() {
+        let pool = memory_pool().await;
+        let events = Arc::new(Events::new());
+        let db = MessageDatabase::new(pool.clone(), events.clone(), false);
+        db.init().await.unwrap();
+        db.save(&message("m1", 1, b"hi".to_vec())).await.unwrap();
+        let storage_errors = || {
+            events
+                .get_rx()
+                .try_iter()
+                .filter_map(|event| match event {
+                    ChatEvent::StorageError { kind } => Some(kind),
+                    _ => None,
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // a failure that has nothing to do with the disk is not reported
+        assert!(db.save(&message("m1", 1, b"hi".to_vec())).await.is_err());
+        assert!(storage_errors().is_empty());
+
+        // the database can't grow any more, as on a full disk
+        sqlx::query("PRAGMA max_page_count = 1")
+            .execute(&pool)
+            .await
+            .unwrap();
+        let big = message("m2", 2, vec![7u8; 64 * 1024]);
+        assert!(db.save(&big).await.is_err());
+        assert!(db.save_many([&big]).await.is_err());
+        let full = [StorageErrorKind::Full, StorageErrorKind::Full];
+        assert_eq!(storage_errors(), full);
+    }
+
+    #[tokio::test]
+    async fn legacy_rows_read_as_uncompressed() {
+        let pool = memory_pool().await;
+        // the table as it was before payloads were compressed
+        sqlx::query(
+            r#"
+            CREATE TABLE messages (
+                id TEXT PRIMARY KEY NOT NULL,
+                counter INTEGER NOT NULL,
+                timestamp INTEGER NOT NULL,
+                order_counter INTEGER NOT NULL,
+                payload BLOB NOT NULL,
+                peer_id TEXT NOT NULL
+            )
+            "#,
+        )
+        .execute(&pool)
+        .await
+        .unwrap();
+        let long = b"hello ".repeat(200);
+        sqlx::query(
+            r#"
+            INSERT INTO messages (id, timestamp, counter, order_counter, payload, peer_id)
+            VALUES ('m1', 1, 1, 1, ?, 'alice')"#,
+        )
+        .bind(&long)
+        .execute(&pool)
+        .await
+        .unwrap();
+
+        let db = database(pool.clone(), true).await;
+        assert_eq!(db.get_by_id("m1").await.unwrap().unwrap().payload, long);
+        assert_eq!(stored(&pool, "m1").await, (long.clone(), false));
+        // new rows next to it are compressed
+        db.save(&message("m2", 2, long.clone())).await.unwrap();
+        assert!(stored(&pool, "m2").await.1);
+        let messages = db.get_after("alice", 1).await.unwrap();
+        assert_eq!(messages.len(), 2);
+        assert!(messages.iter().all(|message| message.payload == long));
+    }
+
+    #[test]
+    fn default_pool_fits_the_sync_workers() {
+        assert_eq!(
+            Config::default().db_max_connections,
+            SYNC_WORKERS as u32 + 2
+        );
+    }
+}
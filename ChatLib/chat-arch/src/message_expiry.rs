@@ -0,0 +1,369 @@
+use std::{path::Path, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use log::{info, warn};
+use prost::Message;
+use tokio::runtime::Runtime;
+
+use crate::{
+    clock::Clock,
+    direct_message::DirectCipher,
+    file_database::FileDatabase,
+    indexer::Indexer,
+    message_database::MessageDatabase,
+    models::{repo_author, DbMessage, MessageBuilder},
+    proto::chat::MessagePayload,
+    repository_manager::RepositoryManager,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+pub struct MessageExpiry {
+    peer_id: String,
+    message_db: Arc<MessageDatabase>,
+    file_db: Arc<FileDatabase>,
+    indexer: Arc<Indexer>,
+    manager: Arc<RepositoryManager>,
+    direct_cipher: Arc<DirectCipher>,
+    root_path: String,
+    clock: Arc<dyn Clock>,
+    runtime: Arc<Runtime>,
+}
+
+impl MessageExpiry {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        peer_id: String,
+        message_db: Arc<MessageDatabase>,
+        file_db: Arc<FileDatabase>,
+        indexer: Arc<Indexer>,
+        manager: Arc<RepositoryManager>,
+        direct_cipher: Arc<DirectCipher>,
+        root_path: String,
+        clock: Arc<dyn Clock>,
+        runtime: Arc<Runtime>,
+    ) -> Self {
+        Self {
+            peer_id,
+            message_db,
+            file_db,
+            indexer,
+            manager,
+            direct_cipher,
+            root_path,
+            clock,
+            runtime,
+        }
+    }
+
+    pub fn run(self: Arc<Self>) {
+        let runtime = self.runtime.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.sweep().await {
+                    warn!("message expiry sweep failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Sets how long the messages between us and `peer_id` live, our own messages to
+    /// everyone when it is us. The setting is synced, so both sides and every node
+    /// keeping them expire the same messages. It goes to `peer_id` alone, anyone
+    /// else only sees that we sent it a message.
+    pub async fn set_ttl(&self, peer_id: &str, ttl_seconds: Option<u64>) -> Result<()> {
+        let builder = MessageBuilder::new(
+            uuid::Uuid::new_v4().to_string(),
+            self.clock.timestamp(),
+            self.peer_id.clone(),
+        )
+        .conversation_ttl(peer_id.to_owned(), ttl_seconds);
+        let entry = if peer_id == self.peer_id {
+            builder.build()
+        } else {
+            builder.build_direct(peer_id, &self.direct_cipher)?
+        };
+        self.manager.clone().add_own_message(entry).await?;
+        self.sweep().await
+    }
+
+    pub async fn sweep(&self) -> Result<()> {
+        let now = self.clock.timestamp();
+        for (peer_a, peer_b, ttl_seconds) in self.indexer.get_conversation_ttls().await? {
+            let before = now - ttl_seconds as i64;
+            self.expire_conversation(&peer_a, &peer_b, before).await?;
+            if peer_a != peer_b {
+                self.expire_conversation(&peer_b, &peer_a, before).await?;
+            }
+        }
+        self.sweep_messages(now).await
+    }
+
+    /// Clears the messages `author` sent to `peer_id` before `before`, or to everyone
+    /// when both are the same. Entries like profiles and votes stay, they aren't
+    /// shown and later state builds on them.
+    async fn expire_conversation(&self, author: &str, peer_id: &str, before: i64) -> Result<()> {
+        for msg in self.message_db.get_unexpired_before(author, before).await? {
+            let envelope = MessagePayload::decode(&*msg.payload)?;
+            let to_conversation = if author == peer_id {
+                envelope.recipient.is_empty()
+            } else {
+                envelope.recipient == peer_id
+            };
+            if !to_conversation {
+                continue;
+            }
+            // sealed unless we are one of the two, those are kept as they are
+            let Some(payload) = self.open(&msg)? else {
+                continue;
+            };
+            if is_state_entry(&payload) {
+                continue;
+            }
+            info!("expiring message {} of {}", msg.id, author);
+            self.message_db.clear_payload(&msg.id).await?;
+            if let Err(e) = self.remove_file(&payload).await {
+                warn!("failed to remove file of expired message {}: {:?}", msg.id, e);
+            }
+            self.indexer.remove_message(&msg.id).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the self-deleting messages whose time is up. Direct messages this node
+    /// read as their recipient are deleted on the others with a tombstone.
+    async fn sweep_messages(&self, now: i64) -> Result<()> {
+        for (id, read_here) in self.indexer.due_expiries(now).await? {
+            let Some(msg) = self.message_db.get_by_id(&id).await? else {
+                // a tombstone ahead of its message, the message goes once it is synced
+                continue;
+            };
+            if !msg.payload.is_empty() {
+                info!("deleting expired message {}", id);
+                self.message_db.clear_payload(&id).await?;
+                let removed = match self.open(&msg) {
+                    Ok(Some(payload)) => self.remove_file(&payload).await,
+                    Ok(None) => Ok(()),
+                    Err(e) => Err(e),
+                };
+                if let Err(e) = removed {
+                    warn!("failed to remove file of expired message {}: {:?}", id, e);
+                }
+                self.indexer.remove_message(&id).await?;
+            }
+            if read_here {
+                let tombstone = MessageBuilder::new(
+                    uuid::Uuid::new_v4().to_string(),
+                    now,
+                    self.peer_id.clone(),
+                )
+                .tombstone(id.clone())
+                .build();
+                if let Err(e) = self.manager.clone().add_own_message(tombstone).await {
+                    warn!("failed to send tombstone of {}: {:?}", id, e);
+                }
+            }
+            // after the tombstone, which we index as well
+            self.indexer.forget_expiry(&id).await?;
+        }
+        Ok(())
+    }
+
+    /// The payload of a message, opened if it is a direct message to or from us.
+    /// `None` for direct messages between two other peers.
+    fn open(&self, msg: &DbMessage) -> Result<Option<MessagePayload>> {
+        let payload = MessagePayload::decode(&*msg.payload)?;
+        if payload.recipient.is_empty() {
+            return Ok(Some(payload));
+        }
+        self.direct_cipher
+            .open(&msg.id, repo_author(&msg.peer_id), &payload)
+    }
+
+    /// Deletes the message's file, unless the app registered it from a path of its
+    /// own, see `FileDatabase::save_owned`.
+    async fn remove_file(&self, payload: &MessagePayload) -> Result<()> {
+        if payload.file_id.is_empty() || !self.file_db.is_owned(&payload.file_id).await? {
+            return Ok(());
+        }
+        if let Some(descr) = self.file_db.get_by_id(&payload.file_id).await? {
+            let path = Path::new(&self.root_path).join(&descr.local_path);
+            tokio::fs::remove_file(&path).await?;
+            self.file_db.delete(&payload.file_id).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Entries that update state, like a profile or a vote, rather than being shown.
+fn is_state_entry(payload: &MessagePayload) -> bool {
+    payload.group_change.is_some()
+        || payload.poll_vote.is_some()
+        || payload.profile.is_some()
+        || payload.conversation_ttl.is_some()
+        || !payload.tombstone_id.is_empty()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::events::Events;
+    use crate::file_database::FileDescription;
+    use crate::indexer::memory_indexer;
+    use crate::message_database::memory_pool;
+    use crate::peer_database::peer_id;
+    use crate::repository_manager::UnknownPeerPolicy;
+    use crate::sync_engine::SyncEngine;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::sync::Weak;
+
+    struct Fixture {
+        expiry: MessageExpiry,
+        message_db: Arc<MessageDatabase>,
+        root: std::path::PathBuf,
+    }
+
+    async fn fixture(key: &SigningKey, runtime: Arc<Runtime>) -> Fixture {
+        let pool = memory_pool().await;
+        let events = Arc::new(Events::new());
+        let indexer = Arc::new(memory_indexer(pool.clone(), key.clone()).await);
+        let message_db = Arc::new(MessageDatabase::new(pool.clone(), events.clone(), false));
+        let file_db = Arc::new(FileDatabase::new(pool, events));
+        let manager = Arc::new(RepositoryManager::new(
+            message_db.clone(),
+            0,
+            indexer.clone(),
+            Weak::<SyncEngine>::new(),
+            true,
+            UnknownPeerPolicy::Accept,
+            false,
+        ));
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        tokio::fs::create_dir_all(&root).await.unwrap();
+        Fixture {
+            expiry: MessageExpiry::new(
+                peer_id(&key.verifying_key()),
+                message_db.clone(),
+                file_db,
+                indexer,
+                manager,
+                Arc::new(DirectCipher::new(key.clone())),
+                root.to_string_lossy().into_owned(),
+                Arc::new(SystemClock),
+                runtime,
+            ),
+            message_db,
+            root,
+        }
+    }
+
+    impl Fixture {
+        async fn store(&self, msg: &DbMessage) {
+            self.message_db.save(msg).await.unwrap();
+            self.expiry.indexer.index_message(msg).await.unwrap();
+        }
+
+        async fn add_file(&self, id: &str, owned: bool) {
+            let descr = FileDescription {
+                id: id.to_owned(),
+                format: "txt".to_owned(),
+                local_path: format!("{}.txt", id),
+                timestamp: 0,
+            };
+            tokio::fs::write(self.root.join(&descr.local_path), b"hi").await.unwrap();
+            if owned {
+                self.expiry.file_db.save_owned(&descr).await.unwrap();
+            } else {
+                self.expiry.file_db.save(&descr).await.unwrap();
+            }
+        }
+
+        async fn expired(&self, id: &str) -> bool {
+            let msg = self.message_db.get_by_id(id).await.unwrap().unwrap();
+            let indexed = self.expiry.indexer.get_by_id(id).await.unwrap();
+            msg.payload.is_empty() && indexed.is_none()
+        }
+    }
+
+    fn builder(id: &str, timestamp: i64, author: &SigningKey) -> MessageBuilder {
+        MessageBuilder::new(id.to_owned(), timestamp, peer_id(&author.verifying_key()))
+    }
+
+    #[test]
+    fn conversation_lifetime_expires_both_sides_only() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = SigningKey::generate(&mut OsRng);
+            let bob = SigningKey::generate(&mut OsRng);
+            let carol = SigningKey::generate(&mut OsRng);
+            let (alice_id, bob_id) = (peer_id(&alice.verifying_key()), peer_id(&bob.verifying_key()));
+            let f = fixture(&alice, runtime).await;
+            let alice_cipher = DirectCipher::new(alice.clone());
+            let bob_cipher = DirectCipher::new(bob.clone());
+            let now = f.expiry.clock.timestamp();
+
+            f.add_file("f1", true).await;
+            f.add_file("f2", false).await;
+            f.store(
+                &builder("ttl", 100, &alice)
+                    .conversation_ttl(bob_id.clone(), Some(60))
+                    .build_direct(&bob_id, &alice_cipher)
+                    .unwrap(),
+            )
+            .await;
+            let to_bob = builder("m1", 100, &alice)
+                .file_id("f1".to_owned())
+                .build_direct(&bob_id, &alice_cipher)
+                .unwrap();
+            let to_alice = builder("m2", 100, &bob)
+                .file_id("f2".to_owned())
+                .build_direct(&alice_id, &bob_cipher)
+                .unwrap();
+            let recent = builder("m3", now, &bob)
+                .text("hi".to_owned())
+                .build_direct(&alice_id, &bob_cipher)
+                .unwrap();
+            let to_everyone = builder("m4", 100, &alice).text("hi".to_owned()).build();
+            let from_carol = builder("m5", 100, &carol).text("hi".to_owned()).build();
+            for msg in [&to_bob, &to_alice, &recent, &to_everyone, &from_carol] {
+                f.store(msg).await;
+            }
+
+            f.expiry.sweep().await.unwrap();
+
+            assert!(f.expired("m1").await);
+            assert!(f.expired("m2").await);
+            for id in ["ttl", "m3", "m4", "m5"] {
+                assert!(!f.expired(id).await, "{} expired", id);
+            }
+            // the file we downloaded goes, the one the app registered stays
+            assert!(!f.root.join("f1.txt").exists());
+            assert!(f.root.join("f2.txt").exists());
+        });
+    }
+
+    #[test]
+    fn late_message_of_an_expiring_conversation_is_not_shown() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = SigningKey::generate(&mut OsRng);
+            let f = fixture(&alice, runtime).await;
+            let alice_id = peer_id(&alice.verifying_key());
+            f.store(
+                &builder("ttl", 100, &alice)
+                    .conversation_ttl(alice_id, Some(60))
+                    .build(),
+            )
+            .await;
+            let old = builder("m1", 100, &alice).text("hi".to_owned()).build();
+            f.store(&old).await;
+            assert!(f.expiry.indexer.get_by_id("m1").await.unwrap().is_none());
+            f.expiry.sweep().await.unwrap();
+            assert!(f.expired("m1").await);
+        });
+    }
+}
@@ -1,6 +1,45 @@
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 
-use crate::proto::chat::{Message, MessagePayload};
+use crate::direct_message::DirectCipher;
+use crate::proto::chat::{self, Message, MessagePayload};
+
+/// Version of `MessagePayload` written by this build. Payloads without a version
+/// predate the field and are read as version 1. Version 2 added direct messages,
+/// older readers show them as unsupported instead of as an empty message.
+pub const PAYLOAD_VERSION: u32 = 2;
+
+/// Starts the repositories of groups, see `group_repo_id`.
+const GROUP_REPO_PREFIX: &str = "group/";
+
+/// Id of a new group created by `creator`. The creator is part of the id, so every
+/// peer knows who may change the roster of a group before anyone else has joined.
+pub fn new_group_id(creator: &str) -> String {
+    format!("{}:{}", creator, uuid::Uuid::new_v4())
+}
+
+/// The peer that created a group, `None` for ids not made by `new_group_id`.
+pub fn group_creator(group_id: &str) -> Option<&str> {
+    group_id.split_once(':').map(|(creator, _)| creator)
+}
+
+/// Repository holding what `author` wrote to a group, its membership changes and
+/// messages. A group's entries are split by author so each repository keeps a
+/// single writer like a peer's own one. They are only synced with members of the
+/// group, a new member gets them from whoever added it.
+pub fn group_repo_id(group_id: &str, author: &str) -> String {
+    format!("{}{}/{}", GROUP_REPO_PREFIX, group_id, author)
+}
+
+/// Group and author of a group repository, `None` for the repository of a peer.
+pub fn split_group_repo(repo_id: &str) -> Option<(&str, &str)> {
+    repo_id.strip_prefix(GROUP_REPO_PREFIX)?.rsplit_once('/')
+}
+
+/// The peer whose messages a repository holds.
+pub fn repo_author(repo_id: &str) -> &str {
+    split_group_repo(repo_id).map_or(repo_id, |(_, author)| author)
+}
 
 #[derive(Debug, Clone, sqlx::FromRow, Serialize, Deserialize)]
 pub struct DbMessage {
@@ -22,6 +61,210 @@ pub struct IndexedMessage {
     pub file_id: Option<String>,
     pub file_path: Option<String>,
     pub peer_id: String,
+    pub system: Option<SystemInfo>,
+    /// Set when the payload was written by a newer version, only the envelope
+    /// fields are meaningful then and the raw payload stays in the message store.
+    pub unsupported: bool,
+    /// Unix timestamp claimed by the author. Pulled back to `received_at` when it lies
+    /// further in the future than `Config::max_clock_skew`, the message store keeps
+    /// the claimed value.
+    pub timestamp: i64,
+    /// Unix timestamp at which this device indexed the message.
+    pub received_at: i64,
+    /// The only peer besides the sender able to read a direct message, `None` for
+    /// messages to everyone.
+    pub recipient: Option<String>,
+    /// Progress of our own messages, `None` for messages of other peers.
+    pub status: Option<MessageStatus>,
+    /// Entries the sending app attached, see `MessageBuilder::metadata`.
+    pub metadata: HashMap<String, String>,
+    /// The message is a poll, `text` holds the question and `Indexer::get_poll` the tally.
+    pub poll: bool,
+    /// Preview card of a link in `text`, see `MessageBuilder::link_preview`.
+    pub link_preview: Option<LinkPreview>,
+    /// Set on messages that delete themselves, see `MessageBuilder::expires`.
+    pub expiry: Option<Expiry>,
+    /// The group the message was sent to, `None` for messages to everyone. `peer_id`
+    /// is the sender either way.
+    pub group_id: Option<String>,
+}
+
+/// When a self-deleting message starts counting down.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ExpiryTrigger {
+    /// From the message timestamp, on every node alike.
+    FromSend,
+    /// From when a recipient marked it read, "view once" with a short time. Every
+    /// reader deletes its copy. The recipient of a direct message also syncs a
+    /// tombstone that deletes it on every other node, a message to everyone is only
+    /// deleted everywhere by a tombstone of its sender.
+    FromRead,
+}
+
+impl ExpiryTrigger {
+    pub fn from_proto(value: i32) -> Option<Self> {
+        match chat::ExpiryTrigger::try_from(value) {
+            Ok(chat::ExpiryTrigger::FromSend) => Some(ExpiryTrigger::FromSend),
+            Ok(chat::ExpiryTrigger::FromRead) => Some(ExpiryTrigger::FromRead),
+            _ => None,
+        }
+    }
+
+    pub fn to_proto(self) -> i32 {
+        match self {
+            ExpiryTrigger::FromSend => chat::ExpiryTrigger::FromSend as i32,
+            ExpiryTrigger::FromRead => chat::ExpiryTrigger::FromRead as i32,
+        }
+    }
+}
+
+/// Lifetime of a self-deleting message, independent of the conversation TTL.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Expiry {
+    pub seconds: u64,
+    pub trigger: ExpiryTrigger,
+}
+
+/// Preview card of a link. The crate never fetches URLs, the sender's app fills it in
+/// and peers show it as sent.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    /// Thumbnail, resolved and downloaded like any other file.
+    pub image_file_id: Option<String>,
+}
+
+/// A poll with the current votes, see `IndexedMessageDatabase::apply_vote`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Poll {
+    pub id: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Number of votes of each option, in the order of `options`.
+    pub votes: Vec<u64>,
+    /// The option we voted for last.
+    pub own_vote: Option<u32>,
+}
+
+/// Progress of one of our messages, it only ever moves forward.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum MessageStatus {
+    /// Handed to `add_own_message`, not stored yet.
+    Sending,
+    /// Stored in our repository, peers pick it up from there.
+    Sent,
+    /// Accepted by at least one peer.
+    Delivered,
+    /// Seen by a peer, reported by its read receipt, see `MessageBuilder::read_receipt`.
+    Read,
+}
+
+impl MessageStatus {
+    pub fn from_i32(value: i32) -> Option<Self> {
+        match value {
+            0 => Some(MessageStatus::Sending),
+            1 => Some(MessageStatus::Sent),
+            2 => Some(MessageStatus::Delivered),
+            3 => Some(MessageStatus::Read),
+            _ => None,
+        }
+    }
+
+    pub fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SystemKind {
+    /// The sender joined, the value is its name.
+    Joined,
+    /// A member left a group or was removed from it, the value is its name.
+    Left,
+    /// The sender changed its name, the value is the new name.
+    Renamed,
+}
+
+impl SystemKind {
+    pub fn from_proto(value: i32) -> Option<Self> {
+        match chat::SystemKind::try_from(value) {
+            Ok(chat::SystemKind::Joined) => Some(SystemKind::Joined),
+            Ok(chat::SystemKind::Left) => Some(SystemKind::Left),
+            Ok(chat::SystemKind::Renamed) => Some(SystemKind::Renamed),
+            _ => None,
+        }
+    }
+
+    pub fn to_proto(self) -> i32 {
+        match self {
+            SystemKind::Joined => chat::SystemKind::Joined as i32,
+            SystemKind::Left => chat::SystemKind::Left as i32,
+            SystemKind::Renamed => chat::SystemKind::Renamed as i32,
+        }
+    }
+}
+
+/// Describes a system message, e.g. a peer joining or changing its name.
+/// `value` carries the kind specific data, like the new name.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SystemInfo {
+    pub kind: SystemKind,
+    pub value: String,
+}
+
+/// How loudly new messages of a conversation should be announced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub enum NotificationImportance {
+    Low,
+    #[default]
+    Normal,
+    High,
+}
+
+impl NotificationImportance {
+    pub fn from_i32(value: i32) -> Self {
+        match value {
+            0 => NotificationImportance::Low,
+            2 => NotificationImportance::High,
+            _ => NotificationImportance::Normal,
+        }
+    }
+
+    pub fn to_i32(self) -> i32 {
+        self as i32
+    }
+}
+
+/// The newest message of a conversation and how many of its messages are unread.
+#[derive(Debug, Clone)]
+pub struct ConversationSummary {
+    pub last_message: IndexedMessage,
+    pub unread_count: u64,
+}
+
+/// Local notification settings of a conversation, never synced to peers.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+pub struct NotificationPref {
+    /// No notifications until this unix timestamp.
+    pub muted_until: Option<i64>,
+    /// Only notify about messages mentioning us.
+    pub mentions_only: bool,
+    pub importance: NotificationImportance,
+}
+
+impl NotificationPref {
+    /// Whether `msg` should be announced to `own_id` at `now`. Our own messages never are.
+    pub fn allows(&self, msg: &IndexedMessage, own_id: &str, now: i64) -> bool {
+        if msg.peer_id == own_id {
+            return false;
+        }
+        if self.muted_until.is_some_and(|until| now < until) {
+            return false;
+        }
+        !self.mentions_only || msg.mentions.iter().any(|id| id == own_id)
+    }
 }
 
 impl From<Message> for DbMessage {
@@ -37,25 +280,45 @@ impl From<Message> for DbMessage {
     }
 }
 
-impl Into<Message> for DbMessage {
-    fn into(self) -> Message {
+impl From<DbMessage> for Message {
+    fn from(msg: DbMessage) -> Self {
         Message {
-            counter: self.counter as i32,
-            timestamp: self.timestamp,
-            global_counter: self.order as i64,
-            payload: self.payload,
-            peer_id: self.peer_id,
-            id: self.id,
+            counter: msg.counter as i32,
+            timestamp: msg.timestamp,
+            global_counter: msg.order as i64,
+            payload: msg.payload,
+            peer_id: msg.peer_id,
+            id: msg.id,
         }
     }
 }
 
+/// Bytes of keys and values a message's metadata may take, metadata of received
+/// messages beyond that is dropped.
+pub const MAX_METADATA_SIZE: usize = 4 * 1024;
+
+pub fn metadata_size(metadata: &HashMap<String, String>) -> usize {
+    metadata.iter().map(|(key, value)| key.len() + value.len()).sum()
+}
+
 pub struct MessageBuilder {
     id: String,
     timestamp: i64,
     peer_id: String,
     text: Option<String>,
     file_id: Option<String>,
+    inline_file: Option<(Vec<u8>, String)>,
+    system: Option<SystemInfo>,
+    group_change: Option<chat::GroupChange>,
+    metadata: HashMap<String, String>,
+    poll: Option<chat::Poll>,
+    poll_vote: Option<chat::PollVote>,
+    profile: Option<chat::Profile>,
+    link_preview: Option<chat::LinkPreview>,
+    expiry: Option<chat::Expiry>,
+    tombstone_id: Option<String>,
+    conversation_ttl: Option<chat::ConversationTtl>,
+    read_up_to: Option<String>,
 }
 
 impl MessageBuilder {
@@ -66,6 +329,18 @@ impl MessageBuilder {
             peer_id,
             text: None,
             file_id: None,
+            inline_file: None,
+            system: None,
+            group_change: None,
+            metadata: HashMap::new(),
+            poll: None,
+            poll_vote: None,
+            profile: None,
+            link_preview: None,
+            expiry: None,
+            tombstone_id: None,
+            conversation_ttl: None,
+            read_up_to: None,
         }
     }
 
@@ -79,15 +354,161 @@ impl MessageBuilder {
         self
     }
 
+    /// Sends the contents of the `file_id` file with the message, so peers don't
+    /// have to download it. Meant for files below `Config::inline_file_limit`.
+    pub fn inline_file(mut self, data: Vec<u8>, format: String) -> Self {
+        self.inline_file = Some((data, format));
+        self
+    }
+
+    pub fn system(mut self, kind: SystemKind, value: String) -> Self {
+        self.system = Some(SystemInfo { kind, value });
+        self
+    }
+
+    /// Sends the message to a group instead of to everyone, it goes into our
+    /// repository of the group, see `group_repo_id`.
+    pub fn group(mut self, group_id: &str) -> Self {
+        self.peer_id = group_repo_id(group_id, &self.peer_id);
+        self
+    }
+
+    /// Adds `member` to or removes it from the group's roster, see `IndexedMessageDatabase::apply_group_change`.
+    /// Like any entry of the group it goes into our repository of the group.
+    pub fn group_change(self, group_id: String, member: String, removed: bool) -> Self {
+        let mut builder = self.group(&group_id);
+        builder.group_change = Some(chat::GroupChange {
+            group_id,
+            member,
+            removed,
+        });
+        builder
+    }
+
+    /// Makes the message a poll, its id becomes the poll id.
+    pub fn poll(mut self, question: String, options: Vec<String>) -> Self {
+        self.poll = Some(chat::Poll { question, options });
+        self
+    }
+
+    /// Votes for `option` of a poll, replacing our earlier vote on it.
+    pub fn vote(mut self, poll_id: String, option: u32) -> Self {
+        self.poll_vote = Some(chat::PollVote { poll_id, option });
+        self
+    }
+
+    /// Publishes our profile, replacing the one peers have if this message is newer,
+    /// see `PeerDatabase::apply_profile`.
+    pub fn profile(mut self, name: String, avatar_file_id: Option<String>) -> Self {
+        self.profile = Some(chat::Profile {
+            name,
+            avatar_file_id: avatar_file_id.unwrap_or_default(),
+        });
+        self
+    }
+
+    /// Attaches a preview card for a link in the text. The app fetches it, the
+    /// thumbnail is a file registered beforehand.
+    pub fn link_preview(
+        mut self,
+        url: String,
+        title: String,
+        description: String,
+        image_file_id: Option<String>,
+    ) -> Self {
+        self.link_preview = Some(chat::LinkPreview {
+            url,
+            title,
+            description,
+            image_file_id: image_file_id.unwrap_or_default(),
+        });
+        self
+    }
+
+    /// Makes the message delete itself on every node `seconds` after `trigger`.
+    pub fn expires(mut self, seconds: u64, trigger: ExpiryTrigger) -> Self {
+        self.expiry = Some(chat::Expiry {
+            seconds,
+            trigger: trigger.to_proto(),
+        });
+        self
+    }
+
+    /// Deletes the expired read-once message `message_id` on every node.
+    pub fn tombstone(mut self, message_id: String) -> Self {
+        self.tombstone_id = Some(message_id);
+        self
+    }
+
+    /// Sets how long the messages between us and `peer_id` live on every node, our
+    /// own messages to everyone when it is us. `None` keeps them. Send it to `peer_id`
+    /// with `build_direct` unless it is us, see `IndexedMessageDatabase::apply_conversation_ttl`.
+    pub fn conversation_ttl(mut self, peer_id: String, seconds: Option<u64>) -> Self {
+        self.conversation_ttl = Some(chat::ConversationTtl {
+            peer_id,
+            seconds: seconds.unwrap_or(0),
+        });
+        self
+    }
+
+    /// Tells a peer we read its messages up to `order_id`, they move to
+    /// `MessageStatus::Read` on its side. Send it to the peer with `build_direct`.
+    pub fn read_receipt(mut self, order_id: String) -> Self {
+        self.read_up_to = Some(order_id);
+        self
+    }
+
+    /// Attaches an app specific entry, the crate stores and syncs it without looking
+    /// at it. Keep all entries below `MAX_METADATA_SIZE`.
+    pub fn metadata(mut self, key: String, value: String) -> Self {
+        self.metadata.insert(key, value);
+        self
+    }
+
     pub fn build(self) -> DbMessage {
-        let payload = MessagePayload {
-            text: self.text.unwrap_or_default(),
-            file_id: self.file_id.unwrap_or_default(),
+        let payload = self.payload();
+        self.into_db_message(&payload)
+    }
+
+    /// Builds a message readable only by us and `recipient`, see `DirectCipher`.
+    pub fn build_direct(self, recipient: &str, cipher: &DirectCipher) -> anyhow::Result<DbMessage> {
+        let payload = cipher.seal(&self.id, recipient, &self.payload())?;
+        Ok(self.into_db_message(&payload))
+    }
+
+    fn payload(&self) -> MessagePayload {
+        let (file_data, file_format) = self.inline_file.clone().unwrap_or_default();
+        let (system_kind, system_value) = match &self.system {
+            Some(system) => (system.kind.to_proto(), system.value.clone()),
+            None => (chat::SystemKind::None as i32, String::new()),
+        };
+        MessagePayload {
+            text: self.text.clone().unwrap_or_default(),
+            file_id: self.file_id.clone().unwrap_or_default(),
             reply_id: String::new(),
             mentions: Vec::new(),
-        };
+            system_kind,
+            system_value,
+            version: PAYLOAD_VERSION,
+            recipient: String::new(),
+            sealed: Vec::new(),
+            file_data,
+            file_format,
+            group_change: self.group_change.clone(),
+            metadata: self.metadata.clone(),
+            poll: self.poll.clone(),
+            poll_vote: self.poll_vote.clone(),
+            profile: self.profile.clone(),
+            link_preview: self.link_preview.clone(),
+            expiry: self.expiry,
+            tombstone_id: self.tombstone_id.clone().unwrap_or_default(),
+            conversation_ttl: self.conversation_ttl.clone(),
+            read_up_to: self.read_up_to.clone().unwrap_or_default(),
+        }
+    }
 
-        let payload_bytes = prost::Message::encode_to_vec(&payload);
+    fn into_db_message(self, payload: &MessagePayload) -> DbMessage {
+        let payload_bytes = prost::Message::encode_to_vec(payload);
 
         DbMessage {
             id: self.id,
@@ -99,3 +520,27 @@ impl MessageBuilder {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_repo_id_round_trips() {
+        let group_id = new_group_id("alice");
+        assert_eq!(group_creator(&group_id), Some("alice"));
+        let repo_id = group_repo_id(&group_id, "bob");
+        assert_eq!(split_group_repo(&repo_id), Some((group_id.as_str(), "bob")));
+        assert_eq!(repo_author(&repo_id), "bob");
+        assert_eq!(split_group_repo("bob"), None);
+        assert_eq!(repo_author("bob"), "bob");
+    }
+
+    #[test]
+    fn group_entries_go_to_the_authors_group_repository() {
+        let message = MessageBuilder::new("m1".to_owned(), 1, "bob".to_owned())
+            .group_change("alice:g1".to_owned(), "carol".to_owned(), false)
+            .build();
+        assert_eq!(message.peer_id, group_repo_id("alice:g1", "bob"));
+    }
+}
@@ -0,0 +1,342 @@
+use std::sync::Arc;
+
+use anyhow::Result;
+use log::info;
+use tokio::sync::Mutex;
+
+use crate::{
+    events::Events,
+    indexer::Indexer,
+    message_database::MessageDatabase,
+    models::{DbMessage, MessageStatus},
+};
+
+/// Tracks which of our own messages each peer has accepted. A peer acknowledges
+/// by returning its counter of our repository in `MessageAccept`, everything up
+/// to that counter is delivered. The state lives in the message database, so
+/// pushes that didn't get through before a restart are resumed afterwards.
+pub struct Outbox {
+    peer_id: String,
+    message_db: Arc<MessageDatabase>,
+    indexer: Arc<Indexer>,
+    events: Arc<Events>,
+    // pushes to a peer can be answered concurrently, each delivery is reported once
+    acknowledging: Mutex<()>,
+}
+
+impl Outbox {
+    pub fn new(
+        peer_id: String,
+        message_db: Arc<MessageDatabase>,
+        indexer: Arc<Indexer>,
+        events: Arc<Events>,
+    ) -> Self {
+        Self {
+            peer_id,
+            message_db,
+            indexer,
+            events,
+            acknowledging: Mutex::new(()),
+        }
+    }
+
+    pub async fn acknowledge(&self, peer_id: &str, counter: u64) -> Result<()> {
+        let _guard = self.acknowledging.lock().await;
+        let previous = self.message_db.get_acked_counter(peer_id).await?;
+        if counter <= previous {
+            return Ok(());
+        }
+        self.message_db.set_acked_counter(peer_id, counter).await?;
+        let delivered = self.message_db.get_after(&self.peer_id, previous + 1).await?;
+        for msg in delivered.into_iter().filter(|msg| msg.counter <= counter) {
+            self.indexer
+                .advance_status(&msg.id, MessageStatus::Delivered)
+                .await?;
+            self.events
+                .send_message_delivered(msg.id, peer_id.to_owned())
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Persists that `peer_ids` are due the broadcast `messages`, before they are pushed.
+    pub async fn track(&self, peer_ids: &[String], messages: &[DbMessage]) -> Result<()> {
+        let first = match messages.iter().map(|msg| msg.counter).min() {
+            Some(counter) => counter,
+            None => return Ok(()),
+        };
+        for peer_id in peer_ids {
+            self.message_db
+                .track_delivery(peer_id, first.saturating_sub(1))
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Peers that haven't acknowledged our latest message.
+    pub async fn peers_with_pending(&self) -> Result<Vec<String>> {
+        let latest = self.message_db.get_highest_counter(&self.peer_id).await?;
+        self.message_db.get_peers_acked_before(latest).await
+    }
+
+    /// Own messages the peer has not acknowledged yet, in counter order.
+    pub async fn pending_for(&self, peer_id: &str) -> Result<Vec<DbMessage>> {
+        let acked = self.message_db.get_acked_counter(peer_id).await?;
+        let pending = self.message_db.get_after(&self.peer_id, acked + 1).await?;
+        if !pending.is_empty() {
+            info!("{} own messages pending for {}", pending.len(), peer_id);
+        }
+        Ok(pending)
+    }
+
+    /// Peers that have accepted the message, empty for messages of other peers.
+    pub async fn delivered_to(&self, message_id: &str) -> Result<Vec<String>> {
+        match self.message_db.get_by_id(message_id).await? {
+            Some(msg) if msg.peer_id == self.peer_id => {
+                self.message_db.get_peers_acked_since(msg.counter).await
+            }
+            _ => Ok(Vec::new()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::{wait_until, TestNode};
+    use crate::config::Config;
+    use crate::events::ChatEvent;
+    use crate::models::MessageBuilder;
+    use tokio::runtime::Runtime;
+
+    /// Peers `node` reports accepted `message_id`, waits until `count` are reported.
+    async fn delivered_events(node: &TestNode, message_id: &str, count: usize) -> Vec<String> {
+        let rx = node.ctx.events.get_rx();
+        let mut delivered = Vec::new();
+        wait_until("the deliveries are reported", || {
+            delivered.extend(rx.try_iter().filter_map(|event| match event {
+                ChatEvent::MessageDelivered {
+                    message_id: id,
+                    peer_id,
+                } if id == message_id => Some(peer_id),
+                _ => None,
+            }));
+            let reported = delivered.len() >= count;
+            async move { reported }
+        })
+        .await;
+        delivered.sort();
+        delivered
+    }
+
+    async fn wait_for_delivery(node: &TestNode, message_id: &str, expected: &[String]) {
+        let mut expected = expected.to_vec();
+        expected.sort();
+        let outbox = node.ctx.sync_engine.get_outbox();
+        wait_until("the message is acknowledged", || {
+            let (outbox, expected) = (outbox.clone(), expected.clone());
+            async move {
+                let mut delivered = outbox.delivered_to(message_id).await.unwrap();
+                delivered.sort();
+                delivered == expected
+            }
+        })
+        .await;
+    }
+
+    /// Stops `node` and opens its folder again, as after the app restarted.
+    async fn restart(node: TestNode, name: &str, runtime: &Arc<Runtime>) -> TestNode {
+        node.stop().await;
+        let (addr, root) = (node.addr.clone(), node.root.clone());
+        drop(node);
+        TestNode::open(name, addr, root, Config::default(), runtime.clone()).await
+    }
+
+    #[test]
+    fn acknowledgements_of_several_peers_survive_a_restart() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            let carol = TestNode::new("carol", Config::default(), runtime.clone()).await;
+            let dave = TestNode::new("dave", Config::default(), runtime.clone()).await;
+            for other in [&bob, &carol, &dave] {
+                alice.learn(other).await;
+                other.start().await;
+            }
+            alice.start().await;
+            // everyone has caught up with alice before she writes
+            for other in [&bob, &carol, &dave] {
+                other.wait_for_counter(&alice.id(), 1).await;
+            }
+            let dave = restart(dave, "dave", &runtime).await;
+            let pool = alice.ctx.sync_engine.peer_pool.clone();
+            wait_until("dave is gone", || async {
+                !pool.current_peers().await.contains(&dave.id())
+            })
+            .await;
+
+            let message = MessageBuilder::new(uuid::Uuid::new_v4().to_string(), 1, alice.id())
+                .text("hi".to_owned())
+                .build();
+            let message = alice
+                .ctx
+                .sync_engine
+                .get_manager()
+                .add_own_message(message)
+                .await
+                .unwrap();
+            let mut online = vec![bob.id(), carol.id()];
+            online.sort();
+            wait_for_delivery(&alice, &message.id, &online).await;
+            assert_eq!(delivered_events(&alice, &message.id, 2).await, online);
+            let outbox = alice.ctx.sync_engine.get_outbox();
+            assert_eq!(outbox.peers_with_pending().await.unwrap(), [dave.id()]);
+
+            let alice = restart(alice, "alice", &runtime).await;
+            // what was acknowledged before the restart is still known
+            let outbox = alice.ctx.sync_engine.get_outbox();
+            let mut delivered = outbox.delivered_to(&message.id).await.unwrap();
+            delivered.sort();
+            assert_eq!(delivered, online);
+
+            dave.start().await;
+            for other in [&bob, &carol, &dave] {
+                alice.learn(other).await;
+            }
+            alice.start().await;
+            wait_for_delivery(&alice, &message.id, &[bob.id(), carol.id(), dave.id()]).await;
+            // only the peer that was missing is reported again
+            assert_eq!(delivered_events(&alice, &message.id, 1).await, [dave.id()]);
+            assert!(outbox.peers_with_pending().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn push_cut_off_by_a_crash_is_delivered_after_the_restart() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            alice.start().await;
+            bob.wait_for_counter(&alice.id(), 1).await;
+            let bob = restart(bob, "bob", &runtime).await;
+            // opened but not started, the push waits in the queue when the app dies
+            let alice = restart(alice, "alice", &runtime).await;
+            alice.learn(&bob).await;
+            let message = MessageBuilder::new(uuid::Uuid::new_v4().to_string(), 1, alice.id())
+                .text("hi".to_owned())
+                .build();
+            let message = alice
+                .ctx
+                .sync_engine
+                .get_manager()
+                .add_own_message(message)
+                .await
+                .unwrap();
+            let outbox = alice.ctx.sync_engine.get_outbox();
+            assert_eq!(outbox.peers_with_pending().await.unwrap(), [bob.id()]);
+            let pending = outbox.pending_for(&bob.id()).await.unwrap();
+            assert_eq!(pending.len(), 1);
+            assert_eq!(pending[0].id, message.id);
+            let (addr, root) = (alice.addr.clone(), alice.root.clone());
+            drop(alice);
+
+            let config = Config::default();
+            let alice = TestNode::open("alice", addr, root, config, runtime.clone()).await;
+            bob.start().await;
+            alice.learn(&bob).await;
+            alice.start().await;
+            // bob doesn't know alice, only her push can bring him the message
+            bob.wait_for_counter(&alice.id(), pending[0].counter).await;
+            wait_for_delivery(&alice, &message.id, &[bob.id()]).await;
+            assert_eq!(delivered_events(&alice, &message.id, 1).await, [bob.id()]);
+            let outbox = alice.ctx.sync_engine.get_outbox();
+            assert!(outbox.peers_with_pending().await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn message_moves_from_sending_to_read() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.learn(&alice).await;
+            alice.start().await;
+            bob.start().await;
+            // each has the other's joined message, so the pushes below follow on
+            bob.wait_for_counter(&alice.id(), 1).await;
+            alice.wait_for_counter(&bob.id(), 1).await;
+
+            let rx = alice.ctx.events.get_rx();
+            let mut statuses = Vec::new();
+            let mut reached = |message_id: &str, status: MessageStatus| {
+                statuses.extend(rx.try_iter().filter_map(|event| match event {
+                    ChatEvent::MessageStatusChanged {
+                        message_id: id,
+                        status,
+                    } if id == message_id => Some(status),
+                    // stored, an own message is indexed as sent
+                    ChatEvent::Message(msg) if msg.id == message_id => msg.status,
+                    _ => None,
+                }));
+                statuses.contains(&status).then(|| statuses.clone())
+            };
+            let stored = |node: &TestNode, id: &str| {
+                let (indexer, id) = (node.ctx.indexer.clone(), id.to_owned());
+                async move { indexer.get_by_id(&id).await.unwrap() }
+            };
+
+            let id = uuid::Uuid::new_v4().to_string();
+            let msg = MessageBuilder::new(id.clone(), 1, alice.id())
+                .text("hi".to_owned())
+                .build_direct(&bob.id(), &alice.ctx.direct_cipher)
+                .unwrap();
+            let manager = alice.ctx.sync_engine.get_manager();
+            manager.add_own_message(msg).await.unwrap();
+            wait_until("bob accepts the message", || {
+                let delivered = reached(&id, MessageStatus::Delivered).is_some();
+                async move { delivered }
+            })
+            .await;
+            let status = stored(&alice, &id).await.unwrap().status;
+            assert_eq!(status, Some(MessageStatus::Delivered));
+
+            // bob reads it and tells alice
+            wait_until("bob shows the message", || async {
+                stored(&bob, &id).await.is_some()
+            })
+            .await;
+            let order_id = stored(&bob, &id).await.unwrap().order_id;
+            let indexer = &bob.ctx.indexer;
+            assert!(indexer.mark_read(&alice.id(), &order_id).await.unwrap());
+            let receipt = MessageBuilder::new(uuid::Uuid::new_v4().to_string(), 2, bob.id())
+                .read_receipt(order_id)
+                .build_direct(&alice.id(), &bob.ctx.direct_cipher)
+                .unwrap();
+            let manager = bob.ctx.sync_engine.get_manager();
+            manager.add_own_message(receipt).await.unwrap();
+            let mut walked = None;
+            wait_until("alice learns bob read it", || {
+                walked = reached(&id, MessageStatus::Read);
+                let read = walked.is_some();
+                async move { read }
+            })
+            .await;
+
+            let expected = [
+                MessageStatus::Sending,
+                MessageStatus::Sent,
+                MessageStatus::Delivered,
+                MessageStatus::Read,
+            ];
+            assert_eq!(walked.unwrap(), expected);
+            let status = stored(&alice, &id).await.unwrap().status;
+            assert_eq!(status, Some(MessageStatus::Read));
+        });
+    }
+}
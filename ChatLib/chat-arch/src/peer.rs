@@ -1,16 +1,22 @@
+use crate::clock::Clock;
+use crate::conn::is_decrypt_error;
 use anyhow::anyhow;
 use futures::StreamExt;
-use log::{debug, info, warn};
+use log::{debug, warn};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::{
     io::{AsyncRead, AsyncWrite},
     sync::{
         watch::{Receiver, Sender},
         Mutex,
     },
+    time::timeout,
 };
 use tokio_yamux::{Session, StreamHandle};
 
+const CLOSE_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Peer<T> {
     session: Arc<Mutex<Session<T>>>,
     pub peer_id: String,
@@ -19,6 +25,9 @@ pub struct Peer<T> {
     rx: Receiver<i32>,
     open_lock: Arc<Mutex<()>>,
     pub is_alive: Arc<Mutex<bool>>,
+    // when a stream was last opened in either direction
+    last_activity: std::sync::Mutex<Instant>,
+    clock: Arc<dyn Clock>,
     runtime: Arc<tokio::runtime::Runtime>,
 }
 
@@ -28,6 +37,12 @@ pub trait PeerDelegate: 'static {
         stream: StreamHandle,
         peer_id: String,
     ) -> anyhow::Result<()>;
+
+    /// Called whenever a session with the peer is established, in either direction.
+    fn peer_connected(self: Arc<Self>, peer_id: String);
+
+    /// Called when the session died because a frame from the peer failed to decrypt.
+    fn session_corrupted(self: Arc<Self>, peer_id: String);
 }
 
 impl<T> Peer<T>
@@ -38,6 +53,7 @@ where
         session: Arc<Mutex<Session<T>>>,
         peer_id: String,
         delegate: Arc<dyn PeerDelegate + Send + Sync>,
+        clock: Arc<dyn Clock>,
         runtime: Arc<tokio::runtime::Runtime>,
     ) -> Self {
         let (tx, rx) = tokio::sync::watch::channel(0);
@@ -50,16 +66,30 @@ where
             rx,
             open_lock: Arc::new(Mutex::new(())),
             is_alive,
+            last_activity: std::sync::Mutex::new(clock.instant()),
+            clock,
             runtime,
         }
     }
 
+    /// Time since a stream was last opened on the session. A single long transfer
+    /// counts as activity only when it starts.
+    pub fn idle_for(&self) -> Duration {
+        let last_activity = *self.last_activity.lock().unwrap();
+        self.clock.instant().saturating_duration_since(last_activity)
+    }
+
+    fn touch(&self) {
+        *self.last_activity.lock().unwrap() = self.clock.instant();
+    }
+
     pub async fn is_alive(&self) -> bool {
         *self.is_alive.lock().await
     }
 
     pub async fn open_stream(self: Arc<Self>) -> anyhow::Result<StreamHandle> {
         debug!("opening stream");
+        self.touch();
         let _guard = self.open_lock.lock().await;
         self.tx.send(1)?;
         let mut sess = self.session.lock().await;
@@ -70,6 +100,20 @@ where
         stream.map_err(|e| anyhow!("received error openeing stream: {:?}", e))
     }
 
+    /// Marks the peer dead and closes the session, so streams still open on it
+    /// fail instead of waiting on a socket that no longer works.
+    pub async fn close(&self) {
+        *self.is_alive.lock().await = false;
+        let guard = self.open_lock.lock().await;
+        // wakes the inbound loop so it releases the session
+        let _ = self.tx.send(1);
+        let mut control = self.session.lock().await.control();
+        drop(guard);
+        if timeout(CLOSE_TIMEOUT, control.close()).await.is_err() {
+            warn!("timed out closing session with {}", self.peer_id);
+        }
+    }
+
     pub fn start_inbound_loop(self: Arc<Self>) {
         let self_clone = self.clone();
         let mut rx = self.rx.clone();
@@ -90,6 +134,7 @@ where
                         match res {
                             Some(Ok(stream)) => {
                                 debug!("got stream");
+                                self_clone.touch();
                                 if let Err(res) = self_clone.delegate.clone().handle_inbound_stream(stream, self_clone.peer_id.clone()) {
                                     warn!("error handling stream: {:?}", res);
                                     continue;
@@ -97,6 +142,11 @@ where
                             },
                             Some(Err(e)) => {
                                 debug!("error reading from session: {:?}", e);
+                                if is_decrypt_error(&e) {
+                                    warn!("failed to decrypt frame from {}", self_clone.peer_id);
+                                    *self_clone.is_alive.lock().await = false;
+                                    self_clone.delegate.clone().session_corrupted(self_clone.peer_id.clone());
+                                }
                                 break;
                             },
                             None => {
@@ -112,3 +162,122 @@ where
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::conn::{encode_frame, EncryptedStream, FrameVersion, StreamOptions};
+    use aes_gcm::{Aes256Gcm, KeyInit};
+    use bytes::BytesMut;
+    use tokio::io::{duplex, AsyncWriteExt, DuplexStream};
+    use tokio::runtime::Runtime;
+    use tokio::time::sleep;
+
+    const STALL_TIMEOUT: Duration = Duration::from_millis(100);
+    const KEY: [u8; 32] = [7; 32];
+
+    /// Accepts nothing and remembers which sessions were reported corrupted.
+    #[derive(Default)]
+    struct TestDelegate {
+        corrupted: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl PeerDelegate for TestDelegate {
+        fn handle_inbound_stream(
+            self: Arc<Self>,
+            _stream: StreamHandle,
+            _peer_id: String,
+        ) -> anyhow::Result<()> {
+            Ok(())
+        }
+
+        fn peer_connected(self: Arc<Self>, _peer_id: String) {}
+
+        fn session_corrupted(self: Arc<Self>, peer_id: String) {
+            self.corrupted.lock().unwrap().push(peer_id);
+        }
+    }
+
+    type TestPeer = Peer<EncryptedStream<DuplexStream>>;
+
+    /// A peer with its inbound loop running, the returned socket is the remote end
+    /// and sends nothing until the test writes to it.
+    fn connected_peer(
+        runtime: &Arc<Runtime>,
+        delegate: Arc<TestDelegate>,
+    ) -> (Arc<TestPeer>, DuplexStream) {
+        let (ours, theirs) = duplex(1 << 20);
+        let options = StreamOptions {
+            stall_timeout: STALL_TIMEOUT,
+            ..StreamOptions::default()
+        };
+        let stream = EncryptedStream::with_options(ours, &KEY, options);
+        let session = Session::new_client(stream, tokio_yamux::Config::default());
+        let peer = Arc::new(Peer::new(
+            Arc::new(Mutex::new(session)),
+            "remote".to_owned(),
+            delegate,
+            Arc::new(SystemClock),
+            runtime.clone(),
+        ));
+        peer.clone().start_inbound_loop();
+        (peer, theirs)
+    }
+
+    #[test]
+    fn peer_that_sends_nothing_leaves_the_session_usable() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let (peer, _remote) = connected_peer(&runtime, Arc::default());
+            sleep(STALL_TIMEOUT * 3).await;
+            // silence between frames is not a stall
+            assert!(peer.is_alive().await);
+            // the inbound loop waiting on the socket doesn't hold up outbound streams
+            timeout(Duration::from_secs(5), peer.clone().open_stream())
+                .await
+                .expect("opening a stream wedged behind the inbound loop")
+                .unwrap();
+            assert!(peer.is_alive().await);
+        });
+    }
+
+    #[test]
+    fn peer_that_stops_mid_frame_ends_the_session() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let (peer, mut remote) = connected_peer(&runtime, Arc::default());
+            // a frame length and the first byte of the frame, then nothing
+            remote.write_all(&[0, 64, 1]).await.unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while peer.is_alive().await {
+                assert!(Instant::now() < deadline, "stalled session was kept");
+                sleep(Duration::from_millis(20)).await;
+            }
+            // the remote end is still open, it just went quiet
+            drop(remote);
+        });
+    }
+
+    #[test]
+    fn corrupted_frame_ends_the_session_as_corrupted() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let delegate = Arc::new(TestDelegate::default());
+            let (peer, mut remote) = connected_peer(&runtime, delegate.clone());
+            let mut frame = BytesMut::new();
+            let cipher = Aes256Gcm::new(&KEY.into());
+            encode_frame(&cipher, FrameVersion::V0, &[1; 12], b"hello", &mut frame).unwrap();
+            // a flipped ciphertext byte, the frame no longer authenticates
+            let last = frame.len() - 1;
+            frame[last] ^= 0x01;
+            remote.write_all(&frame).await.unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            while peer.is_alive().await {
+                assert!(Instant::now() < deadline, "corrupted session was kept");
+                sleep(Duration::from_millis(20)).await;
+            }
+            assert_eq!(*delegate.corrupted.lock().unwrap(), ["remote"]);
+        });
+    }
+}
@@ -1,14 +1,39 @@
+use std::collections::HashSet;
 use std::sync::Arc;
+use std::time::Duration;
 use anyhow::Result;
 use chrono::{DateTime, Utc};
 use ed25519_dalek::{SigningKey, VerifyingKey};
 use hex;
-use sqlx::{Row, SqlitePool};
+use log::info;
+use sqlx::{sqlite::SqliteRow, Row, SqlitePool};
+use crate::clock::Clock;
 use crate::events::Events;
+use crate::message_database::add_column_if_missing;
+use crate::sanitize::TextPolicy;
+
+/// Columns of other tables holding a peer id, moved along when duplicate records are
+/// merged. `conversation_ttls` keeps its pair sorted and is handled on its own.
+const PEER_ID_COLUMNS: &[(&str, &str)] = &[
+    ("peer_profiles", "peer_id"),
+    ("messages", "peer_id"),
+    ("deliveries", "peer_id"),
+    ("indexed_messages", "peer_id"),
+    ("indexed_messages", "recipient"),
+    ("read_state", "peer_id"),
+    ("message_expiries", "tombstone_by"),
+    ("group_members", "member"),
+    ("group_changes", "author"),
+    ("group_changes", "member"),
+    ("poll_votes", "voter"),
+    ("notification_prefs", "peer_id"),
+];
 
 pub struct PeerDatabase {
     pool: SqlitePool,
     events: Arc<Events>,
+    text_policy: TextPolicy,
+    clock: Arc<dyn Clock>,
 }
 
 #[derive(Debug, Clone)]
@@ -18,20 +43,82 @@ pub struct Peer {
     pub created_at: DateTime<Utc>,
     pub public_key: VerifyingKey,
     pub signing_key: Option<SigningKey>,
+    /// The user confirmed the peer's safety number. Only changed with
+    /// `PeerDatabase::set_verified`, a new key clears it.
+    pub verified: bool,
+    /// File id of the avatar from the peer's latest profile.
+    pub avatar_file_id: Option<String>,
+    /// Where the avatar is stored, `None` until the file was downloaded.
+    pub avatar_path: Option<String>,
+    /// Last completed handshake with the peer, `None` if it never connected.
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+/// Which peers `PeerDatabase::prune_stale` removes. Their messages stay, only the
+/// record and the dialer address go.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PeerPrunePolicy {
+    /// How long a peer may go without a handshake, counted from when it was added
+    /// if it never connected.
+    pub retention: Duration,
+    /// Also remove peers whose safety number the user confirmed.
+    pub prune_verified: bool,
+    /// Also remove peers that wrote messages.
+    pub prune_with_history: bool,
+}
+
+/// Columns of a peer record, the name and avatar of the latest profile take
+/// precedence over the name the record was saved with. The avatar is an ordinary
+/// file, its path comes from the file database.
+const PEER_COLUMNS: &str = r#"
+    peers.id, COALESCE(peer_profiles.name, peers.name) AS name, peers.created_at,
+    peers.public_key, peers.signing_key, peers.verified, peers.last_seen,
+    peer_profiles.avatar_file_id, files.local_path AS avatar_path
+    FROM peers
+    LEFT JOIN peer_profiles ON peer_profiles.peer_id = peers.id
+    LEFT JOIN files ON files.id = peer_profiles.avatar_file_id
+"#;
+
+/// Order of `PeerDatabase::get_all_peers_sorted`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PeerSort {
+    /// Case-insensitive by name, peers without a name last.
+    #[default]
+    Name,
+    /// Most recently added first.
+    Newest,
+}
+
+impl PeerSort {
+    fn order_by(self) -> &'static str {
+        match self {
+            PeerSort::Name => {
+                "COALESCE(peer_profiles.name, peers.name) IS NULL, \
+                 COALESCE(peer_profiles.name, peers.name) COLLATE NOCASE"
+            }
+            PeerSort::Newest => "peers.created_at DESC",
+        }
+    }
 }
 
-impl Into<crate::proto::chat::Peer> for Peer {
-    fn into(self) -> crate::proto::chat::Peer {
+impl From<Peer> for crate::proto::chat::Peer {
+    fn from(peer: Peer) -> Self {
         crate::proto::chat::Peer {
-            id: self.id.clone(),
-            name: self.name.unwrap_or("".to_string()),
-            pub_key: self.id,
+            id: peer.id.clone(),
+            name: peer.name.unwrap_or("".to_string()),
+            pub_key: peer.id,
         }
     }
 }
 
+/// A peer's id is the hex of its public key, on every path that creates a peer, so
+/// the same key never ends up in two records.
+pub fn peer_id(key: &VerifyingKey) -> String {
+    hex::encode(key.to_bytes())
+}
+
 impl Peer {
-    pub fn new(id: String, name: String, pub_key: String) -> Result<Peer> {
+    pub fn new(name: String, pub_key: String, created_at: DateTime<Utc>) -> Result<Peer> {
         let public_key = VerifyingKey::from_bytes(
             hex::decode(pub_key)
                 .map_err(|_| anyhow::anyhow!("Invalid public key hex"))?
@@ -39,22 +126,53 @@ impl Peer {
                 .try_into()?,
         )?;
         Ok(Peer {
-            id,
+            id: peer_id(&public_key),
             name: Some(name),
-            created_at: Utc::now(),
+            created_at,
             public_key,
             signing_key: None,
+            verified: false,
+            avatar_file_id: None,
+            avatar_path: None,
+            last_seen: None,
         })
     }
 
     pub fn get_name(&self) -> String {
         self.name.clone().unwrap_or("".to_string())
     }
+
+    /// Name to show in the UI, falls back to a shortened peer id when the peer has no name.
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) if !name.is_empty() => name.clone(),
+            _ => short_id(&self.id),
+        }
+    }
+}
+
+pub fn short_id(peer_id: &str) -> String {
+    peer_id.chars().take(8).collect()
 }
 
 impl PeerDatabase {
-    pub fn new(pool: SqlitePool, events: Arc<Events>) -> Self {
-        Self { pool, events }
+    pub fn new(
+        pool: SqlitePool,
+        events: Arc<Events>,
+        text_policy: TextPolicy,
+        clock: Arc<dyn Clock>,
+    ) -> Self {
+        Self {
+            pool,
+            events,
+            text_policy,
+            clock,
+        }
+    }
+
+    /// A remote peer first seen now, its id is derived from `pub_key`.
+    pub fn new_peer(&self, name: String, pub_key: String) -> Result<Peer> {
+        Peer::new(name, pub_key, self.clock.now())
     }
 
     pub async fn init(&self) -> Result<()> {
@@ -65,7 +183,26 @@ impl PeerDatabase {
                 name TEXT,
                 created_at INTEGER NOT NULL,
                 public_key BLOB NOT NULL,
-                signing_key BLOB
+                signing_key BLOB,
+                verified INTEGER NOT NULL DEFAULT 0,
+                last_seen INTEGER
+            )
+            "#,
+        )
+        .execute(&self.pool)
+        .await?;
+        add_column_if_missing(&self.pool, "peers", "verified", "INTEGER NOT NULL DEFAULT 0")
+            .await?;
+        add_column_if_missing(&self.pool, "peers", "last_seen", "INTEGER").await?;
+        // kept apart from the records so a profile that arrives before its peer isn't lost
+        sqlx::query(
+            r#"
+            CREATE TABLE IF NOT EXISTS peer_profiles (
+                peer_id TEXT PRIMARY KEY NOT NULL,
+                name TEXT,
+                avatar_file_id TEXT,
+                timestamp INTEGER NOT NULL,
+                profile_id TEXT NOT NULL
             )
             "#,
         )
@@ -74,38 +211,247 @@ impl PeerDatabase {
         Ok(())
     }
 
+    /// Folds records stored under an id other than the hex of their key, as older
+    /// versions saved peers added by hand, into the record of that key. Everything
+    /// stored under the old id moves with it, so it runs once all tables exist.
+    /// Returns how many were merged.
+    pub async fn merge_duplicates(&self) -> Result<usize> {
+        let rows = sqlx::query("SELECT id, name, public_key, verified FROM peers")
+            .fetch_all(&self.pool)
+            .await?;
+        let tables: HashSet<String> =
+            sqlx::query("SELECT name FROM sqlite_master WHERE type = 'table'")
+                .fetch_all(&self.pool)
+                .await?
+                .iter()
+                .map(|row| row.get("name"))
+                .collect();
+        let mut merged = 0;
+        for row in rows {
+            let id: String = row.get("id");
+            let canonical = hex::encode(row.get::<Vec<u8>, _>("public_key"));
+            if id == canonical {
+                continue;
+            }
+            let mut tx = self.pool.begin().await?;
+            // the canonical record wins, it only takes over a name or verification it lacks
+            let res = sqlx::query(
+                "UPDATE peers SET name = COALESCE(name, ?), verified = MAX(verified, ?) WHERE id = ?",
+            )
+            .bind(row.get::<Option<String>, _>("name"))
+            .bind(row.get::<bool, _>("verified"))
+            .bind(&canonical)
+            .execute(&mut *tx)
+            .await?;
+            if res.rows_affected() == 0 {
+                sqlx::query("UPDATE peers SET id = ? WHERE id = ?")
+                    .bind(&canonical)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            } else {
+                sqlx::query("DELETE FROM peers WHERE id = ?")
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            for (table, column) in PEER_ID_COLUMNS {
+                if !tables.contains(*table) {
+                    continue;
+                }
+                // where the id is part of the key the canonical row wins as well
+                let query = format!(
+                    "UPDATE OR IGNORE {} SET {} = ? WHERE {} = ?",
+                    table, column, column
+                );
+                sqlx::query(&query)
+                    .bind(&canonical)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+                let query = format!("DELETE FROM {} WHERE {} = ?", table, column);
+                sqlx::query(&query).bind(&id).execute(&mut *tx).await?;
+            }
+            if tables.contains("conversation_ttls") {
+                sqlx::query(
+                    r#"
+                    UPDATE OR IGNORE conversation_ttls
+                    SET peer_a = MIN(IIF(peer_a = ?1, ?2, peer_a), IIF(peer_b = ?1, ?2, peer_b)),
+                        peer_b = MAX(IIF(peer_a = ?1, ?2, peer_a), IIF(peer_b = ?1, ?2, peer_b))
+                    WHERE peer_a = ?1 OR peer_b = ?1
+                    "#,
+                )
+                .bind(&id)
+                .bind(&canonical)
+                .execute(&mut *tx)
+                .await?;
+                sqlx::query("DELETE FROM conversation_ttls WHERE peer_a = ? OR peer_b = ?")
+                    .bind(&id)
+                    .bind(&id)
+                    .execute(&mut *tx)
+                    .await?;
+            }
+            tx.commit().await?;
+            merged += 1;
+        }
+        if merged > 0 {
+            info!("merged {} duplicate peer records", merged);
+        }
+        Ok(merged)
+    }
+
     pub async fn save_peer(&self, peer: &Peer) -> Result<()> {
+        // names come from TXT records and other peers, clean them before they reach the UI
+        let mut peer = peer.clone();
+        peer.name = peer.name.map(|name| self.text_policy.name(&name));
+        // a different key for a known id is either a reinstall or someone in the middle
+        let previous = self.get_peer_by_id(&peer.id).await?;
+        let key_changed = previous
+            .as_ref()
+            .is_some_and(|previous| previous.public_key != peer.public_key);
+        let was_verified = previous.as_ref().is_some_and(|previous| previous.verified);
+        peer.verified = was_verified && !key_changed;
+        // records relayed by other peers don't know when we last saw the peer
+        peer.last_seen = peer.last_seen.max(previous.and_then(|previous| previous.last_seen));
         let public_key_bytes = peer.public_key.to_bytes();
         let signing_key_bytes = peer.signing_key.as_ref().map(|key| key.to_bytes());
 
         sqlx::query(
             r#"
-            INSERT OR REPLACE INTO peers (id, name, created_at, public_key, signing_key)
-            VALUES (?, ?, ?, ?, ?)
+            INSERT OR REPLACE INTO peers (id, name, created_at, public_key, signing_key, verified, last_seen)
+            VALUES (?, ?, ?, ?, ?, ?, ?)
             "#,
         )
         .bind(&peer.id)
         .bind(&peer.name)
         .bind(peer.created_at.timestamp())
-        .bind(&public_key_bytes.to_vec())
+        .bind(public_key_bytes.to_vec())
         .bind(signing_key_bytes.map(|bytes| bytes.to_vec()))
+        .bind(peer.verified)
+        .bind(peer.last_seen.map(|last_seen| last_seen.timestamp()))
         .execute(&self.pool)
         .await?;
-        self.events.send_peer(peer.clone()).await?;
+        if key_changed {
+            self.events
+                .send_peer_key_changed(peer.id.clone(), was_verified)
+                .await?;
+        }
+        // read back, the peer's own profile may override the name we were given
+        if let Some(peer) = self.get_peer_by_id(&peer.id).await? {
+            self.events.send_peer(peer).await?;
+        }
+        Ok(())
+    }
+
+    /// Applies a profile published by `peer_id`. Each peer keeps the profile with the
+    /// highest timestamp, the message id breaks ties, so every node shows the same
+    /// name and avatar whatever order the profiles arrive in. Profiles of peers
+    /// without a record are kept for when the record arrives. Returns whether the
+    /// profile changed.
+    pub async fn apply_profile(
+        &self,
+        peer_id: &str,
+        profile: &crate::proto::chat::Profile,
+        timestamp: i64,
+        profile_id: &str,
+    ) -> Result<bool> {
+        let name = Some(self.text_policy.name(&profile.name)).filter(|name| !name.is_empty());
+        let avatar_file_id = Some(profile.avatar_file_id.clone()).filter(|id| !id.is_empty());
+        let res = sqlx::query(
+            r#"
+            INSERT INTO peer_profiles (peer_id, name, avatar_file_id, timestamp, profile_id)
+            VALUES (?, ?, ?, ?, ?)
+            ON CONFLICT(peer_id) DO UPDATE SET
+                name = excluded.name,
+                avatar_file_id = excluded.avatar_file_id,
+                timestamp = excluded.timestamp,
+                profile_id = excluded.profile_id
+            WHERE (excluded.timestamp, excluded.profile_id) > (peer_profiles.timestamp, peer_profiles.profile_id)
+            "#,
+        )
+        .bind(peer_id)
+        .bind(name)
+        .bind(avatar_file_id)
+        .bind(timestamp)
+        .bind(profile_id)
+        .execute(&self.pool)
+        .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        if let Some(peer) = self.get_peer_by_id(peer_id).await? {
+            self.events.send_peer_profile_updated(peer).await?;
+        }
+        Ok(true)
+    }
+
+    /// Records whether the user confirmed the peer's safety number, returns false
+    /// for unknown peers.
+    pub async fn set_verified(&self, peer_id: &str, verified: bool) -> Result<bool> {
+        let res = sqlx::query("UPDATE peers SET verified = ? WHERE id = ?")
+            .bind(verified)
+            .bind(peer_id)
+            .execute(&self.pool)
+            .await?;
+        if res.rows_affected() == 0 {
+            return Ok(false);
+        }
+        if let Some(peer) = self.get_peer_by_id(peer_id).await? {
+            self.events.send_peer(peer).await?;
+        }
+        Ok(true)
+    }
+
+    /// Records a completed handshake with `peer_id`, a no-op for unknown peers.
+    pub async fn set_last_seen(&self, peer_id: &str) -> Result<()> {
+        sqlx::query("UPDATE peers SET last_seen = ? WHERE id = ?")
+            .bind(self.clock.timestamp())
+            .bind(peer_id)
+            .execute(&self.pool)
+            .await?;
         Ok(())
     }
 
+    /// Removes the peers without a handshake for `policy.retention`, returns their
+    /// ids. The local peer is never removed, neither are verified peers or peers
+    /// with messages unless the policy allows it.
+    pub async fn prune_stale(&self, policy: &PeerPrunePolicy) -> Result<Vec<String>> {
+        let cutoff = self.clock.timestamp() - policy.retention.as_secs() as i64;
+        let rows = sqlx::query(
+            r#"
+            DELETE FROM peers
+            WHERE signing_key IS NULL
+                AND COALESCE(last_seen, created_at) < ?
+                AND (? OR verified = 0)
+                AND (? OR NOT EXISTS (SELECT 1 FROM messages WHERE messages.peer_id = peers.id))
+            RETURNING id
+            "#,
+        )
+        .bind(cutoff)
+        .bind(policy.prune_verified)
+        .bind(policy.prune_with_history)
+        .fetch_all(&self.pool)
+        .await?;
+        let pruned: Vec<String> = rows.iter().map(|row| row.get("id")).collect();
+        if !pruned.is_empty() {
+            info!("pruned {} stale peers", pruned.len());
+        }
+        Ok(pruned)
+    }
+
     pub async fn create_local_peer(&self, name: Option<String>) -> Result<Peer> {
         let signing_key = SigningKey::generate(&mut rand::rngs::OsRng);
         let verifying_key = signing_key.verifying_key();
-        let peer_id = hex::encode(verifying_key.to_bytes());
-
         let peer = Peer {
-            id: peer_id,
+            id: peer_id(&verifying_key),
             name,
-            created_at: Utc::now(),
+            created_at: self.clock.now(),
             public_key: verifying_key,
             signing_key: Some(signing_key),
+            verified: false,
+            avatar_file_id: None,
+            avatar_path: None,
+            last_seen: None,
         };
 
         self.save_peer(&peer).await?;
@@ -113,127 +459,448 @@ impl PeerDatabase {
     }
 
     pub async fn get_peer_by_id(&self, id: &str) -> Result<Option<Peer>> {
-        let row = sqlx::query(
-            r#"
-            SELECT id, name, created_at, public_key, signing_key
-            FROM peers
-            WHERE id = ?
-            "#,
-        )
-        .bind(id)
-        .fetch_optional(&self.pool)
-        .await?;
+        let row = sqlx::query(&format!("SELECT {} WHERE peers.id = ?", PEER_COLUMNS))
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?;
 
-        match row {
-            Some(row) => {
-                let public_key_bytes: Vec<u8> = row.get("public_key");
-                let public_key = VerifyingKey::from_bytes(
-                    public_key_bytes[..]
-                        .try_into()
-                        .map_err(|_| anyhow::anyhow!("Invalid public key bytes"))?,
-                )?;
-
-                let signing_key = match row.get::<Option<Vec<u8>>, _>("signing_key") {
-                    Some(bytes) => Some(SigningKey::from_bytes(
-                        bytes[..]
-                            .try_into()
-                            .map_err(|_| anyhow::anyhow!("Invalid signing key bytes"))?,
-                    )),
-                    None => None,
-                };
-
-                Ok(Some(Peer {
-                    id: row.get("id"),
-                    name: row.get("name"),
-                    created_at: DateTime::from_timestamp(row.get::<i64, _>("created_at"), 0)
-                        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?,
-                    public_key,
-                    signing_key,
-                }))
-            }
-            None => Ok(None),
-        }
+        row.as_ref().map(peer_from_row).transpose()
+    }
+
+    pub async fn get_display_name(&self, peer_id: &str) -> Result<String> {
+        Ok(self
+            .get_peer_by_id(peer_id)
+            .await?
+            .map(|peer| peer.display_name())
+            .unwrap_or_else(|| short_id(peer_id)))
     }
 
+    /// All peers by name, see `get_all_peers_sorted`.
     pub async fn get_all_peers(&self) -> Result<Vec<Peer>> {
-        let rows = sqlx::query(
-            r#"
-            SELECT id, name, created_at, public_key, signing_key
-            FROM peers
-            "#,
-        )
+        self.get_all_peers_sorted(PeerSort::Name).await
+    }
+
+    /// All peers in a stable order, ties are broken by id so lists don't reshuffle
+    /// between calls.
+    pub async fn get_all_peers_sorted(&self, sort: PeerSort) -> Result<Vec<Peer>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} ORDER BY {}, peers.id",
+            PEER_COLUMNS,
+            sort.order_by()
+        ))
         .fetch_all(&self.pool)
         .await?;
 
-        let mut peers = Vec::with_capacity(rows.len());
-        for row in rows {
-            let public_key_bytes: Vec<u8> = row.get("public_key");
-            let public_key = VerifyingKey::from_bytes(
-                public_key_bytes[..]
-                    .try_into()
-                    .map_err(|_| anyhow::anyhow!("Invalid public key bytes"))?,
-            )?;
-
-            let signing_key = match row.get::<Option<Vec<u8>>, _>("signing_key") {
-                Some(bytes) => Some(SigningKey::from_bytes(
-                    bytes[..]
-                        .try_into()
-                        .map_err(|_| anyhow::anyhow!("Invalid signing key bytes"))?,
-                )),
-                None => None,
-            };
-
-            peers.push(Peer {
-                id: row.get("id"),
-                name: row.get("name"),
-                created_at: DateTime::from_timestamp(row.get::<i64, _>("created_at"), 0)
-                    .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?,
-                public_key,
-                signing_key,
-            });
-        }
-
-        Ok(peers)
+        rows.iter().map(peer_from_row).collect()
+    }
+
+    /// Peers whose current avatar is `file_id`.
+    pub async fn get_peers_with_avatar(&self, file_id: &str) -> Result<Vec<Peer>> {
+        let rows = sqlx::query(&format!(
+            "SELECT {} WHERE peer_profiles.avatar_file_id = ?",
+            PEER_COLUMNS
+        ))
+        .bind(file_id)
+        .fetch_all(&self.pool)
+        .await?;
+        rows.iter().map(peer_from_row).collect()
     }
 
     pub async fn get_local_peer(&self) -> Result<Option<Peer>> {
-        let row = sqlx::query(
+        let row = sqlx::query(&format!(
+            "SELECT {} WHERE peers.signing_key IS NOT NULL LIMIT 1",
+            PEER_COLUMNS
+        ))
+        .fetch_optional(&self.pool)
+        .await?;
+
+        row.as_ref().map(peer_from_row).transpose()
+    }
+}
+
+fn peer_from_row(row: &SqliteRow) -> Result<Peer> {
+    let public_key_bytes: Vec<u8> = row.get("public_key");
+    let public_key = VerifyingKey::from_bytes(
+        public_key_bytes[..]
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid public key bytes"))?,
+    )?;
+
+    let signing_key = match row.get::<Option<Vec<u8>>, _>("signing_key") {
+        Some(bytes) => Some(SigningKey::from_bytes(
+            bytes[..]
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("Invalid signing key bytes"))?,
+        )),
+        None => None,
+    };
+
+    Ok(Peer {
+        id: row.get("id"),
+        name: row.get("name"),
+        created_at: DateTime::from_timestamp(row.get::<i64, _>("created_at"), 0)
+            .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?,
+        public_key,
+        signing_key,
+        verified: row.get("verified"),
+        avatar_file_id: row.get("avatar_file_id"),
+        avatar_path: row.get("avatar_path"),
+        last_seen: row
+            .get::<Option<i64>, _>("last_seen")
+            .and_then(|last_seen| DateTime::from_timestamp(last_seen, 0)),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::{ManualClock, SystemClock};
+    use crate::events::ChatEvent;
+    use crate::file_database::FileDatabase;
+    use crate::index_database::IndexedMessageDatabase;
+    use crate::message_database::{memory_pool, MessageDatabase};
+    use rand::rngs::OsRng;
+
+    async fn databases() -> (PeerDatabase, SqlitePool) {
+        databases_at(Arc::new(SystemClock)).await
+    }
+
+    async fn databases_at(clock: Arc<dyn Clock>) -> (PeerDatabase, SqlitePool) {
+        let pool = memory_pool().await;
+        let events = Arc::new(Events::new());
+        FileDatabase::new(pool.clone(), events.clone())
+            .init()
+            .await
+            .unwrap();
+        let peer_db = PeerDatabase::new(pool.clone(), events.clone(), TextPolicy::default(), clock);
+        peer_db.init().await.unwrap();
+        MessageDatabase::new(pool.clone(), events, false)
+            .init()
+            .await
+            .unwrap();
+        IndexedMessageDatabase::new(pool.clone())
+            .init()
+            .await
+            .unwrap();
+        (peer_db, pool)
+    }
+
+    /// A peer record the way older versions stored it, under `id` instead of its key.
+    async fn save_legacy(pool: &SqlitePool, id: &str, key: &VerifyingKey, verified: bool) {
+        sqlx::query(
+            "INSERT INTO peers (id, name, created_at, public_key, verified) VALUES (?, 'old', 0, ?, ?)",
+        )
+        .bind(id)
+        .bind(key.to_bytes().to_vec())
+        .bind(verified)
+        .execute(pool)
+        .await
+        .unwrap();
+    }
+
+    /// A row under `peer_id` in the tables that refer to peers, `tag` keeps the rows
+    /// of different calls apart.
+    async fn add_rows(pool: &SqlitePool, peer_id: &str, other: &str, tag: &str) {
+        for query in [
+            "INSERT INTO read_state (peer_id, last_read_order_id) VALUES (?1, ?3)",
+            "INSERT INTO indexed_messages (id, order_id, mentions, text, peer_id) VALUES (?3, ?3, '', 'hi', ?1)",
+            "INSERT INTO messages (id, counter, timestamp, order_counter, payload, peer_id) VALUES (?3, 1, 0, 1, x'', ?1)",
+            "INSERT INTO group_members (group_id, member, removed, timestamp, change_id) VALUES ('group', ?1, 0, 0, ?3)",
+            "INSERT INTO conversation_ttls (peer_a, peer_b, seconds, timestamp, entry_id) VALUES (MIN(?1, ?2), MAX(?1, ?2), 60, 0, ?3)",
+        ] {
+            sqlx::query(query)
+                .bind(peer_id)
+                .bind(other)
+                .bind(tag)
+                .execute(pool)
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn rows_of(pool: &SqlitePool, peer_id: &str) -> i64 {
+        sqlx::query(
             r#"
-            SELECT id, name, created_at, public_key, signing_key
-            FROM peers
-            WHERE signing_key IS NOT NULL
-            LIMIT 1
+            SELECT (SELECT COUNT(*) FROM read_state WHERE peer_id = ?1)
+                + (SELECT COUNT(*) FROM indexed_messages WHERE peer_id = ?1)
+                + (SELECT COUNT(*) FROM messages WHERE peer_id = ?1)
+                + (SELECT COUNT(*) FROM group_members WHERE member = ?1)
+                + (SELECT COUNT(*) FROM conversation_ttls WHERE peer_a = ?1 OR peer_b = ?1)
+                AS count
             "#,
         )
-        .fetch_optional(&self.pool)
-        .await?;
+        .bind(peer_id)
+        .fetch_one(pool)
+        .await
+        .unwrap()
+        .get("count")
+    }
 
-        match row {
-            Some(row) => {
-                let public_key_bytes: Vec<u8> = row.get("public_key");
-                let public_key = VerifyingKey::from_bytes(
-                    public_key_bytes[..]
-                        .try_into()
-                        .map_err(|_| anyhow::anyhow!("Invalid public key bytes"))?,
-                )?;
-
-                let signing_key_bytes: Vec<u8> = row.get("signing_key");
-                let signing_key = SigningKey::from_bytes(
-                    signing_key_bytes[..]
-                        .try_into()
-                        .map_err(|_| anyhow::anyhow!("Invalid signing key bytes"))?,
-                );
+    async fn ttl_pair(pool: &SqlitePool, entry_id: &str) -> (String, String) {
+        let row = sqlx::query("SELECT peer_a, peer_b FROM conversation_ttls WHERE entry_id = ?")
+            .bind(entry_id)
+            .fetch_one(pool)
+            .await
+            .unwrap();
+        (row.get("peer_a"), row.get("peer_b"))
+    }
+
+    #[tokio::test]
+    async fn peer_added_by_hand_moves_with_its_rows() {
+        let (peer_db, pool) = databases().await;
+        let key = SigningKey::generate(&mut OsRng).verifying_key();
+        let canonical = peer_id(&key);
+        // `set_peer` used to keep the key as the caller typed it
+        let legacy = canonical.to_uppercase();
+        save_legacy(&pool, &legacy, &key, true).await;
+        add_rows(&pool, &legacy, "g-peer", "legacy").await;
+
+        assert_eq!(peer_db.merge_duplicates().await.unwrap(), 1);
+        let peer = peer_db.get_peer_by_id(&canonical).await.unwrap().unwrap();
+        assert!(peer.verified);
+        assert!(peer_db.get_peer_by_id(&legacy).await.unwrap().is_none());
+        assert_eq!(rows_of(&pool, &legacy).await, 0);
+        assert_eq!(rows_of(&pool, &canonical).await, 5);
+        assert_eq!(
+            ttl_pair(&pool, "legacy").await,
+            (canonical.clone(), "g-peer".to_owned())
+        );
+        assert_eq!(peer_db.merge_duplicates().await.unwrap(), 0);
+    }
+
+    #[tokio::test]
+    async fn peer_learned_in_a_handshake_merges_into_its_key() {
+        let (peer_db, pool) = databases().await;
+        let key = SigningKey::generate(&mut OsRng).verifying_key();
+        // synced peer records used to carry whatever id the remote sent
+        let legacy = "peer-1";
+        save_legacy(&pool, legacy, &key, true).await;
+        add_rows(&pool, legacy, "g-peer", "legacy").await;
+        let peer = peer_db
+            .new_peer("alice".to_owned(), hex::encode(key.to_bytes()))
+            .unwrap();
+        peer_db.save_peer(&peer).await.unwrap();
+        add_rows(&pool, &peer.id, "h-peer", "canonical").await;
+
+        assert_eq!(peer_db.merge_duplicates().await.unwrap(), 1);
+        let merged = peer_db.get_peer_by_id(&peer.id).await.unwrap().unwrap();
+        assert_eq!(merged.name.as_deref(), Some("alice"));
+        assert!(merged.verified);
+        assert_eq!(rows_of(&pool, legacy).await, 0);
+        // the read watermark and group membership of the canonical record win
+        assert_eq!(rows_of(&pool, &peer.id).await, 8);
+        let read: String =
+            sqlx::query("SELECT last_read_order_id FROM read_state WHERE peer_id = ?")
+                .bind(&peer.id)
+                .fetch_one(&pool)
+                .await
+                .unwrap()
+                .get("last_read_order_id");
+        assert_eq!(read, "canonical");
+        // the pair is sorted again, the old id sorted after the other peer
+        assert_eq!(
+            ttl_pair(&pool, "legacy").await,
+            (peer.id.clone(), "g-peer".to_owned())
+        );
+    }
+
+    /// Key changes reported since the last call.
+    fn key_changes(peer_db: &PeerDatabase) -> Vec<(String, bool)> {
+        peer_db
+            .events
+            .get_rx()
+            .try_iter()
+            .filter_map(|event| match event {
+                ChatEvent::PeerKeyChanged {
+                    peer_id,
+                    was_verified,
+                } => Some((peer_id, was_verified)),
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// A record of `name` under a key of its own.
+    fn new_peer(peer_db: &PeerDatabase, name: &str) -> Peer {
+        let key = SigningKey::generate(&mut OsRng).verifying_key();
+        peer_db
+            .new_peer(name.to_owned(), hex::encode(key.to_bytes()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn new_key_for_a_known_peer_clears_verification() {
+        let (peer_db, _) = databases().await;
+        let bob = new_peer(&peer_db, "bob");
+        let with_new_key = || Peer {
+            id: bob.id.clone(),
+            ..new_peer(&peer_db, "bob")
+        };
+        let verified = || async {
+            peer_db
+                .get_peer_by_id(&bob.id)
+                .await
+                .unwrap()
+                .unwrap()
+                .verified
+        };
+        peer_db.save_peer(&bob).await.unwrap();
+        assert!(peer_db.set_verified(&bob.id, true).await.unwrap());
+        // the same key again, e.g. from a TXT record, keeps the verification
+        peer_db.save_peer(&bob).await.unwrap();
+        assert!(verified().await);
+        assert!(key_changes(&peer_db).is_empty());
+
+        let reinstalled = with_new_key();
+        peer_db.save_peer(&reinstalled).await.unwrap();
+        let stored = peer_db.get_peer_by_id(&bob.id).await.unwrap().unwrap();
+        assert_eq!(stored.public_key, reinstalled.public_key);
+        assert!(!stored.verified);
+        assert_eq!(key_changes(&peer_db), [(bob.id.clone(), true)]);
+
+        // a key that changes again was never confirmed
+        peer_db.save_peer(&with_new_key()).await.unwrap();
+        assert_eq!(key_changes(&peer_db), [(bob.id.clone(), false)]);
+        // a record that says it is verified doesn't restore it
+        let claimed = Peer {
+            verified: true,
+            ..with_new_key()
+        };
+        peer_db.save_peer(&claimed).await.unwrap();
+        assert!(!verified().await);
+    }
 
-                Ok(Some(Peer {
-                    id: row.get("id"),
-                    name: row.get("name"),
-                    created_at: DateTime::from_timestamp(row.get::<i64, _>("created_at"), 0)
-                        .ok_or_else(|| anyhow::anyhow!("Invalid timestamp"))?,
-                    public_key,
-                    signing_key: Some(signing_key),
-                }))
+    #[tokio::test]
+    async fn profiles_converge_whatever_order_they_arrive_in() {
+        let profile = |name: &str, avatar: &str| crate::proto::chat::Profile {
+            name: name.to_owned(),
+            avatar_file_id: avatar.to_owned(),
+        };
+        // the last two tie on the timestamp, the higher message id wins
+        let profiles = [
+            (10, "p1", profile("Bob", "")),
+            (20, "p3", profile("Robert", "avatar-2")),
+            (20, "p2", profile("Bobby", "avatar-1")),
+        ];
+        let orders = [
+            [0, 1, 2],
+            [0, 2, 1],
+            [1, 0, 2],
+            [1, 2, 0],
+            [2, 0, 1],
+            [2, 1, 0],
+        ];
+        for order in orders {
+            let (peer_db, _) = databases().await;
+            let bob = new_peer(&peer_db, "bob");
+            let mut shown = (0, "");
+            for (n, &i) in order.iter().enumerate() {
+                // the first profile arrives before the record it belongs to
+                if n == 1 {
+                    peer_db.save_peer(&bob).await.unwrap();
+                }
+                let (timestamp, id, profile) = &profiles[i];
+                let applied = peer_db
+                    .apply_profile(&bob.id, profile, *timestamp, id)
+                    .await
+                    .unwrap();
+                assert_eq!(applied, (*timestamp, *id) > shown, "{:?}", order);
+                shown = shown.max((*timestamp, *id));
             }
-            None => Ok(None),
+            let stored = peer_db.get_peer_by_id(&bob.id).await.unwrap().unwrap();
+            assert_eq!(stored.name.as_deref(), Some("Robert"), "{:?}", order);
+            let avatar = stored.avatar_file_id.as_deref();
+            assert_eq!(avatar, Some("avatar-2"), "{:?}", order);
+        }
+    }
+
+    #[tokio::test]
+    async fn pruning_keeps_what_the_policy_protects() {
+        const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+        let clock = Arc::new(ManualClock::new(Utc::now()));
+        let (peer_db, pool) = databases_at(clock.clone()).await;
+        let me = Some("me".to_owned());
+        let local = peer_db.create_local_peer(me).await.unwrap();
+        let [stale, verified, with_history, seen] =
+            ["stale", "verified", "with history", "seen"].map(|name| new_peer(&peer_db, name));
+        for peer in [&stale, &verified, &with_history, &seen] {
+            peer_db.save_peer(peer).await.unwrap();
         }
+        peer_db.set_verified(&verified.id, true).await.unwrap();
+        sqlx::query(
+            "INSERT INTO messages (id, counter, timestamp, order_counter, payload, peer_id) VALUES ('m1', 1, 0, 1, x'', ?)",
+        )
+        .bind(&with_history.id)
+        .execute(&pool)
+        .await
+        .unwrap();
+        clock.advance(20 * DAY);
+        peer_db.set_last_seen(&seen.id).await.unwrap();
+        clock.advance(20 * DAY);
+
+        let policy = |prune_verified, prune_with_history| PeerPrunePolicy {
+            retention: 30 * DAY,
+            prune_verified,
+            prune_with_history,
+        };
+        // a handshake within the retention keeps a peer, an old record doesn't
+        let pruned = peer_db.prune_stale(&policy(false, false)).await.unwrap();
+        assert_eq!(pruned, [stale.id]);
+        assert!(peer_db.get_peer_by_id(&seen.id).await.unwrap().is_some());
+        let pruned = peer_db.prune_stale(&policy(true, false)).await.unwrap();
+        assert_eq!(pruned, [verified.id]);
+        let pruned = peer_db.prune_stale(&policy(true, true)).await.unwrap();
+        assert_eq!(pruned, [with_history.id]);
+
+        clock.advance(20 * DAY);
+        let pruned = peer_db.prune_stale(&policy(true, true)).await.unwrap();
+        assert_eq!(pruned, [seen.id]);
+        // our own record stays however long it has been
+        assert!(peer_db.get_peer_by_id(&local.id).await.unwrap().is_some());
+    }
+
+    #[tokio::test]
+    async fn peers_are_listed_in_the_same_order_every_time() {
+        let (peer_db, _pool) = databases().await;
+        let start = Utc::now();
+        let mut ids = std::collections::HashMap::new();
+        let names = ["bob", "Alice", "dave", "carol", "dave", ""];
+        for (i, name) in names.iter().enumerate() {
+            let key = SigningKey::generate(&mut OsRng).verifying_key();
+            let added = start + chrono::Duration::seconds(i as i64);
+            let mut peer = Peer::new(name.to_string(), peer_id(&key), added).unwrap();
+            if name.is_empty() {
+                peer.name = None;
+            }
+            ids.insert(peer.id.clone(), i);
+            peer_db.save_peer(&peer).await.unwrap();
+        }
+        let listed = |peers: Vec<Peer>| -> Vec<(Option<String>, String)> {
+            peers.into_iter().map(|peer| (peer.name, peer.id)).collect()
+        };
+
+        let by_name = listed(peer_db.get_all_peers().await.unwrap());
+        for _ in 0..5 {
+            assert_eq!(listed(peer_db.get_all_peers().await.unwrap()), by_name);
+        }
+        let names: Vec<Option<&str>> = by_name.iter().map(|(name, _)| name.as_deref()).collect();
+        let expected = [
+            Some("Alice"),
+            Some("bob"),
+            Some("carol"),
+            Some("dave"),
+            Some("dave"),
+            None,
+        ];
+        assert_eq!(names, expected);
+        // peers of the same name keep their places by id
+        assert!(by_name[3].1 < by_name[4].1);
+
+        let newest = peer_db
+            .get_all_peers_sorted(PeerSort::Newest)
+            .await
+            .unwrap();
+        let added: Vec<usize> = listed(newest).iter().map(|(_, id)| ids[id]).collect();
+        assert_eq!(added, [5, 4, 3, 2, 1, 0]);
     }
 }
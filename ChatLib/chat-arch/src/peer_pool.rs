@@ -1,19 +1,167 @@
-use crate::{conn::EncryptedStream, peer::Peer, peer::PeerDelegate};
+use crate::{bandwidth::BandwidthMeter, clock::Clock, conn::EncryptedStream, events::Events, peer::Peer, peer::PeerDelegate, protocol_recorder::ProtocolRecorder};
 use async_trait::async_trait;
-use log::info;
+use log::{info, warn};
 use std::{
-    collections::HashMap, net::SocketAddr, sync::{Arc, Weak}, time::Duration
+    collections::HashMap, fmt, io, net::SocketAddr, sync::{Arc, Weak}, time::{Duration, Instant}
 };
-use tokio::{runtime::Runtime, sync::Mutex, time::timeout};
-use tokio_yamux::Session;
+use tokio::{
+    runtime::Runtime,
+    sync::{Mutex, Semaphore},
+    time::{sleep, timeout},
+};
+use tokio_util::sync::CancellationToken;
+use tokio_yamux::{Config, Session};
 
 pub type EncryptedSession = Arc<Mutex<Session<EncryptedStream<tokio::net::TcpStream>>>>;
 
+/// Yamux settings of every session, dialed or accepted. Defaults match `tokio_yamux`.
+#[derive(Clone, Copy, Debug)]
+pub struct SessionOptions {
+    /// Bytes a stream may have in flight before the sender waits for the receiver.
+    /// A file is sent as `UPLOAD_CHUNK_SIZE` chunks and flushed every
+    /// `UPLOAD_FLUSH_EVERY_CHUNKS` chunks, so a window below that (128 KiB) stalls
+    /// uploads on every flush and a larger one lets high latency links stay busy.
+    pub max_stream_window_size: u32,
+    /// Streams that may be open on a session at once.
+    pub max_stream_count: usize,
+    /// Sends pings on idle sessions so dead connections are noticed.
+    pub enable_keepalive: bool,
+    pub keepalive_interval: Duration,
+    /// Closes a session no stream was opened on for this long, the peer is dialed
+    /// again when it is needed. Keep it above the sync interval for peers that
+    /// should stay connected. `None` keeps sessions open until they fail.
+    pub idle_timeout: Option<Duration>,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        let config = Config::default();
+        Self {
+            max_stream_window_size: config.max_stream_window_size,
+            max_stream_count: config.max_stream_count,
+            enable_keepalive: config.enable_keepalive,
+            keepalive_interval: config.keepalive_interval,
+            idle_timeout: None,
+        }
+    }
+}
+
+impl SessionOptions {
+    pub fn yamux_config(&self) -> Config {
+        Config {
+            max_stream_window_size: self.max_stream_window_size,
+            max_stream_count: self.max_stream_count,
+            enable_keepalive: self.enable_keepalive,
+            keepalive_interval: self.keepalive_interval,
+            ..Config::default()
+        }
+    }
+}
+
+/// Why a connection to a peer could not be established.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ConnectionError {
+    /// The peer did not answer within the dial timeout.
+    Timeout,
+    /// The handshake broke off or the peer spoke an unexpected protocol.
+    HandshakeFailed(String),
+    /// The peer could not prove it owns its key.
+    AuthRejected,
+    /// No address is known for the peer.
+    NoAddress,
+    /// The socket could not be opened or failed mid-way.
+    Io(String),
+    /// The sync engine is shutting down.
+    Closed,
+    /// A frame from the peer failed to decrypt and the session was torn down.
+    DecryptFailed,
+    /// The peer closed the connection during the handshake, e.g. it shut down or
+    /// dropped us. The reason names the step that was cut off.
+    PeerClosed(String),
+}
+
+impl ConnectionError {
+    /// Maps an error returned by the handshake, auth failures are reported as `PermissionDenied`.
+    pub fn from_handshake(err: io::Error) -> Self {
+        match err.kind() {
+            io::ErrorKind::PermissionDenied => ConnectionError::AuthRejected,
+            io::ErrorKind::UnexpectedEof => ConnectionError::PeerClosed(err.to_string()),
+            io::ErrorKind::InvalidData | io::ErrorKind::InvalidInput => {
+                ConnectionError::HandshakeFailed(err.to_string())
+            }
+            _ => ConnectionError::Io(err.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for ConnectionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConnectionError::Timeout => write!(f, "connection timed out"),
+            ConnectionError::HandshakeFailed(reason) => write!(f, "handshake failed: {}", reason),
+            ConnectionError::AuthRejected => write!(f, "peer authentication failed"),
+            ConnectionError::NoAddress => write!(f, "no address known for peer"),
+            ConnectionError::Io(reason) => write!(f, "connection error: {}", reason),
+            ConnectionError::Closed => write!(f, "sync engine is gone"),
+            ConnectionError::DecryptFailed => write!(f, "failed to decrypt a frame from peer"),
+            ConnectionError::PeerClosed(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for ConnectionError {}
+
+impl From<io::Error> for ConnectionError {
+    fn from(err: io::Error) -> Self {
+        ConnectionError::Io(err.to_string())
+    }
+}
+
+/// Idle sessions are looked for this often at most.
+const MAX_IDLE_SWEEP_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Failures counted against `DecryptFailurePolicy::Rehandshake` are forgotten after this long.
+const DECRYPT_FAILURE_WINDOW: Duration = Duration::from_secs(600);
+
+/// What happens after a session dies because a frame failed to decrypt.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum DecryptFailurePolicy {
+    /// Tear the session down and only reconnect when the peer is needed again.
+    #[default]
+    Strict,
+    /// Redial the peer right away with a fresh handshake, at most `max_attempts`
+    /// times within `DECRYPT_FAILURE_WINDOW`.
+    Rehandshake { max_attempts: u32 },
+}
+
+/// Connection status of a single peer, see `PeerPool::peer_state`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum PeerConnState {
+    /// A live session exists, dialed by either side.
+    Connected,
+    /// We are dialing the peer or waiting for a dial slot.
+    Connecting,
+    /// The last dial failed, the peer is tried again on the next sync round or
+    /// when a session is needed.
+    BackingOff { last_error: ConnectionError },
+    /// No session and no failed dial, e.g. never dialed or the session died.
+    Disconnected,
+}
+
+/// Outcome of the latest dial of a peer, cleared once a session exists.
+#[derive(Clone, Debug)]
+enum DialState {
+    Dialing,
+    Failed(ConnectionError),
+}
+
 #[async_trait]
 pub trait Dialer: Send + Sync {
-    async fn dial(&self, peer_id: &str) -> anyhow::Result<EncryptedSession>;
+    async fn dial(&self, peer_id: &str) -> Result<EncryptedSession, ConnectionError>;
     async fn add(&self, peer_id: String, addr: String);
     async fn all_peers(&self) -> Vec<String>;
+    /// Forgets the address of `peer_id`, it is no longer synced until added again.
+    async fn remove(&self, peer_id: &str);
 }
 
 pub type EncryptedPool = PeerPool;
@@ -21,26 +169,57 @@ pub type EncryptedPeer = Peer<EncryptedStream<tokio::net::TcpStream>>;
 
 #[derive(Clone)]
 pub struct PeerPool {
+    local_peer_id: String,
     outgoing: Arc<Mutex<HashMap<String, Arc<EncryptedPeer>>>>,
     incoming: Arc<Mutex<HashMap<String, Arc<EncryptedPeer>>>>,
     delegate: Weak<dyn PeerDelegate + Send + Sync>,
     locks: Arc<Mutex<HashMap<String, Arc<Mutex<()>>>>>,
     dialer: Arc<dyn Dialer>,
+    events: Arc<Events>,
+    decrypt_failure_policy: DecryptFailurePolicy,
+    decrypt_failures: Arc<Mutex<HashMap<String, (u32, Instant)>>>,
+    // only touched for quick inserts and lookups, so a std mutex keeps `peer_state` cheap
+    dial_states: Arc<std::sync::Mutex<HashMap<String, DialState>>>,
+    // bounds dials in flight, handing out live sessions never waits on it
+    dial_permits: Arc<Semaphore>,
+    idle_timeout: Option<Duration>,
+    // frames of the streams opened on our sessions, set when tracing is enabled
+    recorder: Option<Arc<ProtocolRecorder>>,
+    meter: Arc<BandwidthMeter>,
+    clock: Arc<dyn Clock>,
     runtime: Arc<Runtime>,
 }
 
 impl PeerPool {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
+        local_peer_id: String,
         dialer: Arc<dyn Dialer>,
         delegate: Weak<dyn PeerDelegate + Send + Sync>,
+        events: Arc<Events>,
+        decrypt_failure_policy: DecryptFailurePolicy,
+        max_concurrent_dials: usize,
+        idle_timeout: Option<Duration>,
+        recorder: Option<Arc<ProtocolRecorder>>,
+        clock: Arc<dyn Clock>,
         runtime: Arc<Runtime>,
     ) -> Self {
         Self {
+            local_peer_id,
             outgoing: Arc::new(Mutex::new(HashMap::new())),
             incoming: Arc::new(Mutex::new(HashMap::new())),
             locks: Arc::new(Mutex::new(HashMap::new())),
             delegate,
             dialer,
+            events,
+            decrypt_failure_policy,
+            decrypt_failures: Arc::new(Mutex::new(HashMap::new())),
+            dial_states: Arc::new(std::sync::Mutex::new(HashMap::new())),
+            dial_permits: Arc::new(Semaphore::new(max_concurrent_dials.max(1))),
+            idle_timeout,
+            recorder,
+            meter: Arc::new(BandwidthMeter::default()),
+            clock,
             runtime,
         }
     }
@@ -49,6 +228,24 @@ impl PeerPool {
         self.dialer.all_peers().await
     }
 
+    /// Drops the address and dial state of a peer that is gone for good, e.g. pruned
+    /// with `PeerPrunePolicy`. A live session is left alone.
+    pub async fn forget(&self, peer_id: &str) {
+        self.dialer.remove(peer_id).await;
+        self.set_dial_state(peer_id, None);
+        self.decrypt_failures.lock().await.remove(peer_id);
+    }
+
+    /// Bytes exchanged with each peer over the streams of our sessions.
+    pub fn meter(&self) -> Arc<BandwidthMeter> {
+        self.meter.clone()
+    }
+
+    /// Recorder for the protocol frames exchanged with peers, `None` unless tracing is enabled.
+    pub fn recorder(&self) -> Option<Arc<ProtocolRecorder>> {
+        self.recorder.clone()
+    }
+
     pub async fn current_peers(&self) -> Vec<String> {
         let mut peers = Vec::new();
         let guard = self.outgoing.lock().await;
@@ -60,13 +257,142 @@ impl PeerPool {
         drop(guard);
         let guard = self.incoming.lock().await;
         for (peer_id, peer) in guard.iter() {
+            if peer.is_alive().await && !peers.contains(peer_id) {
+                peers.push(peer_id.clone());
+            }
+        }
+        peers
+    }
+
+    /// Connection status of `peer_id`, for per-contact status in the UI.
+    pub async fn peer_state(&self, peer_id: &str) -> PeerConnState {
+        if self.is_connected(peer_id).await {
+            return PeerConnState::Connected;
+        }
+        match self.dial_states.lock().unwrap().get(peer_id) {
+            Some(DialState::Dialing) => PeerConnState::Connecting,
+            Some(DialState::Failed(err)) => PeerConnState::BackingOff {
+                last_error: err.clone(),
+            },
+            None => PeerConnState::Disconnected,
+        }
+    }
+
+    async fn is_connected(&self, peer_id: &str) -> bool {
+        let outgoing = self.outgoing.lock().await.get(peer_id).cloned();
+        if let Some(peer) = outgoing {
             if peer.is_alive().await {
-                if !peers.contains(&peer_id) {
-                    peers.push(peer_id.clone());
+                return true;
+            }
+        }
+        let incoming = self.incoming.lock().await.get(peer_id).cloned();
+        match incoming {
+            Some(peer) => peer.is_alive().await,
+            None => false,
+        }
+    }
+
+    fn set_dial_state(&self, peer_id: &str, state: Option<DialState>) {
+        let mut states = self.dial_states.lock().unwrap();
+        match state {
+            Some(state) => states.insert(peer_id.to_owned(), state),
+            None => states.remove(peer_id),
+        };
+    }
+
+    /// Closes idle sessions until `shutdown` is cancelled, see `SessionOptions::idle_timeout`.
+    pub fn start_idle_sweep(self: Arc<Self>, shutdown: CancellationToken) {
+        let Some(idle_timeout) = self.idle_timeout else {
+            return;
+        };
+        let interval = (idle_timeout / 2)
+            .clamp(Duration::from_secs(1), MAX_IDLE_SWEEP_INTERVAL);
+        self.runtime.clone().spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = shutdown.cancelled() => break,
+                    _ = sleep(interval) => self.close_idle(idle_timeout).await,
+                }
+            }
+        });
+    }
+
+    /// Closes the sessions no stream was opened on for `idle_timeout`. A peer left
+    /// without a session is reported with `ChatEvent::PeerOffline`.
+    pub async fn close_idle(&self, idle_timeout: Duration) {
+        let mut closed = Vec::new();
+        for sessions in [&self.outgoing, &self.incoming] {
+            let mut sessions = sessions.lock().await;
+            let mut idle = Vec::new();
+            for (peer_id, peer) in sessions.iter() {
+                if peer.is_alive().await && peer.idle_for() >= idle_timeout {
+                    idle.push(peer_id.clone());
+                }
+            }
+            for peer_id in idle {
+                if let Some(peer) = sessions.remove(&peer_id) {
+                    closed.push(peer);
                 }
             }
         }
-        peers
+        for peer in closed {
+            info!("closing idle session with {}", peer.peer_id);
+            peer.close().await;
+            if self.is_connected(&peer.peer_id).await {
+                continue;
+            }
+            if let Err(e) = self.events.send_peer_offline(peer.peer_id.clone()).await {
+                warn!("failed to send peer offline event: {:?}", e);
+            }
+        }
+    }
+
+    /// Closes every session, the next `get` dials the peer again.
+    pub async fn close_all(&self) {
+        let mut peers: Vec<Arc<EncryptedPeer>> =
+            self.outgoing.lock().await.drain().map(|(_, peer)| peer).collect();
+        peers.extend(self.incoming.lock().await.drain().map(|(_, peer)| peer));
+        for peer in peers {
+            info!("closing session with {}", peer.peer_id);
+            peer.close().await;
+        }
+    }
+
+    /// Reports a session that died on a frame that failed to decrypt and, depending on
+    /// the `DecryptFailurePolicy`, reconnects with a fresh handshake.
+    pub async fn handle_decrypt_failure(&self, peer_id: &str) {
+        if let Err(e) = self
+            .events
+            .send_connection_failed(peer_id.to_owned(), ConnectionError::DecryptFailed)
+            .await
+        {
+            warn!("failed to send connection failure event: {:?}", e);
+        }
+        self.set_dial_state(peer_id, Some(DialState::Failed(ConnectionError::DecryptFailed)));
+        let max_attempts = match self.decrypt_failure_policy {
+            DecryptFailurePolicy::Strict => return,
+            DecryptFailurePolicy::Rehandshake { max_attempts } => max_attempts,
+        };
+        let attempt = {
+            let now = self.clock.instant();
+            let mut failures = self.decrypt_failures.lock().await;
+            let entry = failures.entry(peer_id.to_owned()).or_insert((0, now));
+            if now.duration_since(entry.1) > DECRYPT_FAILURE_WINDOW {
+                *entry = (0, now);
+            }
+            entry.0 += 1;
+            entry.0
+        };
+        if attempt > max_attempts {
+            warn!(
+                "giving up on {} after {} decrypt failures",
+                peer_id, max_attempts
+            );
+            return;
+        }
+        info!("redialing {} after decrypt failure, attempt {}", peer_id, attempt);
+        // a failed redial is already reported by `get`
+        let _ = self.get(peer_id).await;
     }
 
     pub async fn insert(&self, peer_id: &str, addr: SocketAddr, session: EncryptedSession) -> anyhow::Result<()> {
@@ -77,16 +403,65 @@ impl PeerPool {
         let peer = Arc::new(Peer::new(
             session.clone(),
             peer_id.to_owned(),
-            delegate,
+            delegate.clone(),
+            self.clock.clone(),
             self.runtime.clone(),
         ));
         peer.clone().start_inbound_loop();
         self.dialer.add(peer_id.to_owned(), addr.to_string()).await;
         self.incoming.lock().await.insert(peer_id.to_owned(), peer);
+        self.set_dial_state(peer_id, None);
+        delegate.peer_connected(peer_id.to_owned());
+        self.drop_duplicate_session(peer_id).await;
         Ok(())
     }
 
-    pub async fn get(&self, peer_id: &str) -> anyhow::Result<Arc<EncryptedPeer>> {
+    /// Two peers dialing each other at once end up with two sessions. On both ends the
+    /// session dialed by the peer with the smaller id survives and the other is closed,
+    /// returns the survivor if there was a duplicate.
+    async fn drop_duplicate_session(&self, peer_id: &str) -> Option<Arc<EncryptedPeer>> {
+        let outgoing = self.outgoing.lock().await.get(peer_id).cloned()?;
+        let incoming = self.incoming.lock().await.get(peer_id).cloned()?;
+        if !outgoing.is_alive().await || !incoming.is_alive().await {
+            return None;
+        }
+        let (sessions, survivor, loser) = if self.local_peer_id.as_str() < peer_id {
+            (&self.incoming, outgoing, incoming)
+        } else {
+            (&self.outgoing, incoming, outgoing)
+        };
+        {
+            let mut sessions = sessions.lock().await;
+            if sessions
+                .get(peer_id)
+                .is_some_and(|current| Arc::ptr_eq(current, &loser))
+            {
+                sessions.remove(peer_id);
+            }
+        }
+        info!("closing duplicate session with {}", peer_id);
+        loser.close().await;
+        Some(survivor)
+    }
+
+    /// Returns a live session with the peer, dialing it if needed. Dial failures are
+    /// also reported through `ChatEvent::ConnectionFailed`.
+    pub async fn get(&self, peer_id: &str) -> Result<Arc<EncryptedPeer>, ConnectionError> {
+        let res = self.get_or_dial(peer_id).await;
+        if let Err(err) = &res {
+            warn!("failed to connect to {}: {}", peer_id, err);
+            if let Err(e) = self
+                .events
+                .send_connection_failed(peer_id.to_owned(), err.clone())
+                .await
+            {
+                warn!("failed to send connection failure event: {:?}", e);
+            }
+        }
+        res
+    }
+
+    async fn get_or_dial(&self, peer_id: &str) -> Result<Arc<EncryptedPeer>, ConnectionError> {
         let peer_id = peer_id.to_string();
         let mut guard = self.locks.lock().await;
         let lock_entry = guard
@@ -102,7 +477,7 @@ impl PeerPool {
                 if clone.is_alive().await {
                     return Ok(clone);
                 } else {
-                    info!("removing dead peer from outgoing {}", &peer_id);
+                    info!("removing dead peer from outgoing {}", peer_id);
                     guard.remove(&peer_id);
                 }
             }
@@ -114,23 +489,36 @@ impl PeerPool {
                 if clone.is_alive().await {
                     return Ok(clone);
                 } else {
-                    info!("removing dead peer from incoming {}", &peer_id);
+                    info!("removing dead peer from incoming {}", peer_id);
                     guard.remove(&peer_id);
                 }
             }
         }
-        info!("dialing {}", &peer_id);
+        self.set_dial_state(&peer_id, Some(DialState::Dialing));
+        let res = self.dial(&peer_id).await;
+        self.set_dial_state(&peer_id, res.as_ref().err().cloned().map(DialState::Failed));
+        res
+    }
+
+    /// Opens a new session with the peer, the caller holds the peer's dial lock.
+    async fn dial(&self, peer_id: &str) -> Result<Arc<EncryptedPeer>, ConnectionError> {
+        let _permit = self
+            .dial_permits
+            .acquire()
+            .await
+            .map_err(|_| ConnectionError::Closed)?;
+        info!("dialing {}", peer_id);
         let timeout_duration = Duration::from_secs(10);
         
-        let session = timeout(timeout_duration, self.dialer.dial(&peer_id)).await??;
-        let delegate = self
-            .delegate
-            .upgrade()
-            .ok_or_else(|| anyhow::anyhow!("Delegate is gone"))?;
+        let session = timeout(timeout_duration, self.dialer.dial(peer_id))
+            .await
+            .map_err(|_| ConnectionError::Timeout)??;
+        let delegate = self.delegate.upgrade().ok_or(ConnectionError::Closed)?;
         let peer = Arc::new(Peer::new(
             session,
             peer_id.to_owned(),
-            delegate,
+            delegate.clone(),
+            self.clock.clone(),
             self.runtime.clone(),
         ));
         self.outgoing
@@ -138,6 +526,370 @@ impl PeerPool {
             .await
             .insert(peer_id.to_string(), peer.clone());
         peer.clone().start_inbound_loop();
+        delegate.peer_connected(peer_id.to_owned());
+        if let Some(survivor) = self.drop_duplicate_session(peer_id).await {
+            return Ok(survivor);
+        }
         Ok(peer)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::ManualClock;
+    use crate::events::ChatEvent;
+    use crate::sync_engine::{SyncEngine, UPLOAD_CHUNK_SIZE, UPLOAD_FLUSH_EVERY_CHUNKS};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn default_session_options_keep_the_yamux_defaults() {
+        let config = SessionOptions::default().yamux_config();
+        let yamux = Config::default();
+        assert_eq!(config.max_stream_window_size, yamux.max_stream_window_size);
+        assert_eq!(config.max_stream_count, yamux.max_stream_count);
+        assert_eq!(config.enable_keepalive, yamux.enable_keepalive);
+        assert_eq!(config.keepalive_interval, yamux.keepalive_interval);
+    }
+
+    #[test]
+    fn session_options_reach_the_yamux_config() {
+        let options = SessionOptions {
+            max_stream_window_size: 1024 * 1024,
+            max_stream_count: 4,
+            enable_keepalive: false,
+            keepalive_interval: Duration::from_secs(5),
+            ..SessionOptions::default()
+        };
+        let config = options.yamux_config();
+        assert_eq!(config.max_stream_window_size, 1024 * 1024);
+        assert_eq!(config.max_stream_count, 4);
+        assert!(!config.enable_keepalive);
+        assert_eq!(config.keepalive_interval, Duration::from_secs(5));
+    }
+
+    #[test]
+    fn default_window_holds_a_batch_of_upload_chunks() {
+        let batch = UPLOAD_CHUNK_SIZE * UPLOAD_FLUSH_EVERY_CHUNKS;
+        assert!(SessionOptions::default().max_stream_window_size as usize >= batch);
+    }
+
+    /// Counts dials, none of which succeeds. Each takes `delay` to fail.
+    #[derive(Default)]
+    struct CountingDialer {
+        dials: AtomicUsize,
+        delay: Duration,
+        in_flight: AtomicUsize,
+        most_in_flight: AtomicUsize,
+    }
+
+    #[async_trait]
+    impl Dialer for CountingDialer {
+        async fn dial(&self, _peer_id: &str) -> Result<EncryptedSession, ConnectionError> {
+            self.dials.fetch_add(1, Ordering::SeqCst);
+            let in_flight = self.in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            self.most_in_flight.fetch_max(in_flight, Ordering::SeqCst);
+            tokio::time::sleep(self.delay).await;
+            self.in_flight.fetch_sub(1, Ordering::SeqCst);
+            Err(ConnectionError::Io("connection refused".to_owned()))
+        }
+
+        async fn add(&self, _peer_id: String, _addr: String) {}
+
+        async fn all_peers(&self) -> Vec<String> {
+            Vec::new()
+        }
+
+        async fn remove(&self, _peer_id: &str) {}
+    }
+
+    fn pool_with(
+        policy: DecryptFailurePolicy,
+        dialer: Arc<CountingDialer>,
+        events: Arc<Events>,
+        clock: Arc<ManualClock>,
+        runtime: Arc<Runtime>,
+    ) -> PeerPool {
+        let delegate: Weak<dyn PeerDelegate + Send + Sync> = Weak::<SyncEngine>::new();
+        PeerPool::new(
+            "local".to_owned(),
+            dialer,
+            delegate,
+            events,
+            policy,
+            4,
+            None,
+            None,
+            clock,
+            runtime,
+        )
+    }
+
+    #[test]
+    fn simultaneous_dials_of_dead_peers_are_bounded() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let dialer = Arc::new(CountingDialer {
+                delay: Duration::from_millis(50),
+                ..CountingDialer::default()
+            });
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            // the pool allows 4 dials at once
+            let pool = pool_with(
+                DecryptFailurePolicy::Strict,
+                dialer.clone(),
+                Arc::new(Events::disconnected()),
+                clock,
+                runtime,
+            );
+            let peers: Vec<String> = (0..20).map(|i| format!("peer{}", i)).collect();
+            let dials = peers.iter().map(|peer_id| pool.get(peer_id));
+            let results = futures::future::join_all(dials).await;
+
+            assert!(results.iter().all(|res| res.is_err()));
+            assert_eq!(dialer.dials.load(Ordering::SeqCst), 20);
+            assert_eq!(dialer.most_in_flight.load(Ordering::SeqCst), 4);
+            for peer_id in &peers {
+                assert!(matches!(
+                    pool.peer_state(peer_id).await,
+                    PeerConnState::BackingOff { .. }
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn peer_is_connecting_while_dialed() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let dialer = Arc::new(CountingDialer {
+                delay: Duration::from_millis(500),
+                ..CountingDialer::default()
+            });
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            let pool = Arc::new(pool_with(
+                DecryptFailurePolicy::Strict,
+                dialer,
+                Arc::new(Events::disconnected()),
+                clock,
+                runtime,
+            ));
+            assert_eq!(pool.peer_state("bob").await, PeerConnState::Disconnected);
+
+            let dialing = pool.clone();
+            let dial = tokio::spawn(async move { dialing.get("bob").await.is_ok() });
+            crate::app_context::wait_until("bob is being dialed", || async {
+                pool.peer_state("bob").await == PeerConnState::Connecting
+            })
+            .await;
+            assert!(!dial.await.unwrap());
+            assert_eq!(
+                pool.peer_state("bob").await,
+                PeerConnState::BackingOff {
+                    last_error: ConnectionError::Io("connection refused".to_owned())
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn strict_policy_reports_a_corrupted_session_without_redialing() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let dialer = Arc::new(CountingDialer::default());
+            let events = Arc::new(Events::new());
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            let pool = pool_with(
+                DecryptFailurePolicy::Strict,
+                dialer.clone(),
+                events.clone(),
+                clock,
+                runtime,
+            );
+            pool.handle_decrypt_failure("bob").await;
+            let ChatEvent::ConnectionFailed { peer_id, error } =
+                events.get_rx().try_recv().unwrap()
+            else {
+                panic!("expected a connection failure");
+            };
+            assert_eq!(peer_id, "bob");
+            assert_eq!(error, ConnectionError::DecryptFailed);
+            assert_eq!(dialer.dials.load(Ordering::SeqCst), 0);
+            assert_eq!(
+                pool.peer_state("bob").await,
+                PeerConnState::BackingOff {
+                    last_error: ConnectionError::DecryptFailed
+                }
+            );
+        });
+    }
+
+    #[test]
+    fn rehandshake_policy_redials_a_bounded_number_of_times() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let dialer = Arc::new(CountingDialer::default());
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            let pool = pool_with(
+                DecryptFailurePolicy::Rehandshake { max_attempts: 2 },
+                dialer.clone(),
+                Arc::new(Events::disconnected()),
+                clock.clone(),
+                runtime,
+            );
+            for _ in 0..3 {
+                pool.handle_decrypt_failure("bob").await;
+            }
+            assert_eq!(dialer.dials.load(Ordering::SeqCst), 2);
+            // failures outside the window no longer count against the peer
+            clock.advance(DECRYPT_FAILURE_WINDOW + Duration::from_secs(1));
+            pool.handle_decrypt_failure("bob").await;
+            assert_eq!(dialer.dials.load(Ordering::SeqCst), 3);
+        });
+    }
+
+    #[test]
+    fn idle_session_closes_after_the_timeout() {
+        use crate::app_context::TestNode;
+        use crate::config::Config;
+
+        const IDLE: Duration = Duration::from_secs(60);
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let clock = Arc::new(ManualClock::new(chrono::Utc::now()));
+            let config = Config {
+                clock: clock.clone(),
+                ..Config::default()
+            };
+            let alice = TestNode::new("alice", config, runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let peer = pool.get(&bob.id()).await.unwrap();
+
+            clock.advance(IDLE / 2);
+            // opening a stream is activity
+            drop(peer.clone().open_stream().await.unwrap());
+            clock.advance(IDLE * 2 / 3);
+            pool.close_idle(IDLE).await;
+            assert!(pool.current_peers().await.contains(&bob.id()));
+
+            clock.advance(IDLE / 2);
+            pool.close_idle(IDLE).await;
+            assert!(!pool.current_peers().await.contains(&bob.id()));
+            assert!(!peer.is_alive().await);
+            let offline: Vec<String> = alice
+                .ctx
+                .events
+                .get_rx()
+                .try_iter()
+                .filter_map(|event| match event {
+                    ChatEvent::PeerOffline(peer_id) => Some(peer_id),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(offline, [bob.id()]);
+
+            // the next request dials again
+            let redialed = pool.get(&bob.id()).await.unwrap();
+            assert!(!Arc::ptr_eq(&peer, &redialed));
+            assert!(redialed.is_alive().await);
+        });
+    }
+
+    /// Live sessions with `peer_id`, dialed by us and by the peer.
+    async fn live_sessions(pool: &PeerPool, peer_id: &str) -> (usize, usize) {
+        let mut live = [0, 0];
+        for (count, sessions) in live.iter_mut().zip([&pool.outgoing, &pool.incoming]) {
+            let session = sessions.lock().await.get(peer_id).cloned();
+            if let Some(session) = session {
+                if session.is_alive().await {
+                    *count += 1;
+                }
+            }
+        }
+        (live[0], live[1])
+    }
+
+    #[test]
+    fn peers_dialing_each_other_keep_a_single_session() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            alice.start().await;
+            bob.start().await;
+            let alice_pool = &alice.ctx.sync_engine.peer_pool;
+            let bob_pool = &bob.ctx.sync_engine.peer_pool;
+
+            let alice_dialed = alice_pool.get(&bob.id()).await.unwrap();
+            wait_until("bob accepted the session", || async {
+                live_sessions(bob_pool, &alice.id()).await == (0, 1)
+            })
+            .await;
+            // bob dials at the same time, before learning of alice's session. Accepting
+            // noted the port alice dialed from, bob dials her listening port instead.
+            bob.learn(&alice).await;
+            let bob_dialed = bob_pool.dial(&alice.id()).await.unwrap();
+
+            wait_until("one session is left", || async {
+                let alice_live = live_sessions(alice_pool, &bob.id()).await;
+                let bob_live = live_sessions(bob_pool, &alice.id()).await;
+                alice_live.0 + alice_live.1 == 1 && bob_live.0 + bob_live.1 == 1
+            })
+            .await;
+            // the session dialed by the smaller id survives on both ends
+            let alice_first = alice.id() < bob.id();
+            let expected = if alice_first { (1, 0) } else { (0, 1) };
+            assert_eq!(live_sessions(alice_pool, &bob.id()).await, expected);
+            let expected = if alice_first { (0, 1) } else { (1, 0) };
+            assert_eq!(live_sessions(bob_pool, &alice.id()).await, expected);
+            assert_eq!(alice_dialed.is_alive().await, alice_first);
+            assert!(bob_dialed.is_alive().await);
+            let survivor = alice_pool.get(&bob.id()).await.unwrap();
+            assert_eq!(Arc::ptr_eq(&survivor, &alice_dialed), alice_first);
+        });
+    }
+
+    #[test]
+    fn peer_state_follows_the_session() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            // alice only dials, without a sync engine running nothing dials behind our back
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let bob_id = bob.id();
+            let state = || pool.peer_state(&bob_id);
+            assert_eq!(state().await, PeerConnState::Disconnected);
+
+            pool.get(&bob_id).await.unwrap();
+            assert_eq!(state().await, PeerConnState::Connected);
+
+            // the session dies with bob
+            bob.stop().await;
+            wait_until("the session is gone", || async {
+                state().await == PeerConnState::Disconnected
+            })
+            .await;
+
+            assert!(pool.get(&bob_id).await.is_err());
+            assert!(matches!(
+                state().await,
+                PeerConnState::BackingOff {
+                    last_error: ConnectionError::Io(_)
+                }
+            ));
+        });
+    }
+}
@@ -0,0 +1,72 @@
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use log::{info, warn};
+use tokio::runtime::Runtime;
+
+use crate::{
+    events::Events,
+    peer_database::{PeerDatabase, PeerPrunePolicy},
+    peer_pool::EncryptedPool,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Removes peers that went without a handshake for longer than the policy allows,
+/// so the sync cycle stops dialing devices that are gone for good.
+pub struct PeerPruner {
+    policy: Option<PeerPrunePolicy>,
+    peer_db: Arc<PeerDatabase>,
+    peer_pool: Arc<EncryptedPool>,
+    events: Arc<Events>,
+    runtime: Arc<Runtime>,
+}
+
+impl PeerPruner {
+    pub fn new(
+        policy: Option<PeerPrunePolicy>,
+        peer_db: Arc<PeerDatabase>,
+        peer_pool: Arc<EncryptedPool>,
+        events: Arc<Events>,
+        runtime: Arc<Runtime>,
+    ) -> Self {
+        Self {
+            policy,
+            peer_db,
+            peer_pool,
+            events,
+            runtime,
+        }
+    }
+
+    /// Sweeps periodically, a no-op without a policy.
+    pub fn run(self: Arc<Self>) {
+        if self.policy.is_none() {
+            return;
+        }
+        let runtime = self.runtime.clone();
+        runtime.spawn(async move {
+            let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+            loop {
+                interval.tick().await;
+                if let Err(e) = self.sweep().await {
+                    warn!("peer pruning sweep failed: {:?}", e);
+                }
+            }
+        });
+    }
+
+    /// Removes the stale peers now, returns their ids.
+    pub async fn sweep(&self) -> Result<Vec<String>> {
+        let Some(policy) = &self.policy else {
+            return Ok(Vec::new());
+        };
+        let pruned = self.peer_db.prune_stale(policy).await?;
+        for peer_id in &pruned {
+            info!("pruning stale peer {}", peer_id);
+            self.peer_pool.forget(peer_id).await;
+            self.events.send_peer_removed(peer_id.clone()).await?;
+        }
+        Ok(pruned)
+    }
+}
@@ -5,6 +5,9 @@ pub struct FileDownloadRequest {
     pub file_id: ::prost::alloc::string::String,
     #[prost(string, tag = "2")]
     pub peer_id: ::prost::alloc::string::String,
+    /// the downloader acknowledges chunks with FileDownloadAck when asked to
+    #[prost(bool, tag = "3")]
+    pub flow_control: bool,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct FileDownloadResponse {
@@ -14,6 +17,22 @@ pub struct FileDownloadResponse {
     pub last_chunk: bool,
     #[prost(string, tag = "3")]
     pub ext: ::prost::alloc::string::String,
+    /// total size of the file, 0 from peers that predate it
+    #[prost(uint64, tag = "4")]
+    pub size: u64,
+    /// the uploader won't serve the file, e.g. it is above its upload limit
+    #[prost(bool, tag = "5")]
+    pub refused: bool,
+    /// unacknowledged bytes the uploader sends before waiting for a FileDownloadAck,
+    /// 0 when it doesn't wait
+    #[prost(uint64, tag = "6")]
+    pub window: u64,
+}
+/// bytes of the file the downloader has written so far
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct FileDownloadAck {
+    #[prost(uint64, tag = "1")]
+    pub received: u64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct Message {
@@ -49,6 +68,114 @@ pub struct MessagePayload {
     pub reply_id: ::prost::alloc::string::String,
     #[prost(string, repeated, tag = "4")]
     pub mentions: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(enumeration = "SystemKind", tag = "5")]
+    pub system_kind: i32,
+    #[prost(string, tag = "6")]
+    pub system_value: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "7")]
+    pub version: u32,
+    /// set on direct messages, every other field but version is then inside sealed
+    #[prost(string, tag = "8")]
+    pub recipient: ::prost::alloc::string::String,
+    #[prost(bytes = "vec", tag = "9")]
+    pub sealed: ::prost::alloc::vec::Vec<u8>,
+    /// contents of file_id for small files, sent along instead of downloaded
+    #[prost(bytes = "vec", tag = "10")]
+    pub file_data: ::prost::alloc::vec::Vec<u8>,
+    #[prost(string, tag = "11")]
+    pub file_format: ::prost::alloc::string::String,
+    /// membership entry of a group, such messages update the roster and aren't shown
+    #[prost(message, optional, tag = "12")]
+    pub group_change: ::core::option::Option<GroupChange>,
+    /// app specific entries the crate passes along without interpreting them
+    #[prost(map = "string, string", tag = "13")]
+    pub metadata: ::std::collections::HashMap<
+        ::prost::alloc::string::String,
+        ::prost::alloc::string::String,
+    >,
+    /// a question shown with its options, the message id is the poll id
+    #[prost(message, optional, tag = "14")]
+    pub poll: ::core::option::Option<Poll>,
+    /// a vote on a poll, such messages update the tally and aren't shown
+    #[prost(message, optional, tag = "15")]
+    pub poll_vote: ::core::option::Option<PollVote>,
+    /// the author's current profile, such messages update the peer record and aren't shown
+    #[prost(message, optional, tag = "16")]
+    pub profile: ::core::option::Option<Profile>,
+    /// preview of a link in the text, fetched by the sender's app
+    #[prost(message, optional, tag = "17")]
+    pub link_preview: ::core::option::Option<LinkPreview>,
+    /// the message deletes itself on every node, see Expiry
+    #[prost(message, optional, tag = "18")]
+    pub expiry: ::core::option::Option<Expiry>,
+    /// id of an expired read-once message, such messages delete it everywhere and aren't shown
+    #[prost(string, tag = "19")]
+    pub tombstone_id: ::prost::alloc::string::String,
+    /// how long the messages between the author and a peer live, such messages
+    /// update it on every node and aren't shown
+    #[prost(message, optional, tag = "20")]
+    pub conversation_ttl: ::core::option::Option<ConversationTtl>,
+    /// order id up to which the author read the recipient's messages, such messages
+    /// move them to read on the recipient and aren't shown
+    #[prost(string, tag = "21")]
+    pub read_up_to: ::prost::alloc::string::String,
+}
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Expiry {
+    #[prost(uint64, tag = "1")]
+    pub seconds: u64,
+    #[prost(enumeration = "ExpiryTrigger", tag = "2")]
+    pub trigger: i32,
+}
+/// the author's own messages to everyone when peer_id is the author, 0 seconds turns it off
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct ConversationTtl {
+    #[prost(string, tag = "1")]
+    pub peer_id: ::prost::alloc::string::String,
+    #[prost(uint64, tag = "2")]
+    pub seconds: u64,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct GroupChange {
+    #[prost(string, tag = "1")]
+    pub group_id: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub member: ::prost::alloc::string::String,
+    #[prost(bool, tag = "3")]
+    pub removed: bool,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Poll {
+    #[prost(string, tag = "1")]
+    pub question: ::prost::alloc::string::String,
+    #[prost(string, repeated, tag = "2")]
+    pub options: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct PollVote {
+    #[prost(string, tag = "1")]
+    pub poll_id: ::prost::alloc::string::String,
+    #[prost(uint32, tag = "2")]
+    pub option: u32,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct Profile {
+    #[prost(string, tag = "1")]
+    pub name: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub avatar_file_id: ::prost::alloc::string::String,
+}
+#[derive(Clone, PartialEq, ::prost::Message)]
+pub struct LinkPreview {
+    #[prost(string, tag = "1")]
+    pub url: ::prost::alloc::string::String,
+    #[prost(string, tag = "2")]
+    pub title: ::prost::alloc::string::String,
+    #[prost(string, tag = "3")]
+    pub description: ::prost::alloc::string::String,
+    /// thumbnail, an ordinary file resolved like any other
+    #[prost(string, tag = "4")]
+    pub image_file_id: ::prost::alloc::string::String,
 }
 #[derive(Clone, Copy, PartialEq, ::prost::Message)]
 pub struct MessageAccept {
@@ -69,11 +196,19 @@ pub struct FileWantResponse {
 pub struct CompareRequest {
     #[prost(message, repeated, tag = "1")]
     pub compare_payload: ::prost::alloc::vec::Vec<ComparePayload>,
+    /// bitmask of the sender's protocol features, 0 from peers that predate it
+    #[prost(uint64, tag = "2")]
+    pub capabilities: u64,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct CompareResponse {
     #[prost(string, repeated, tag = "1")]
     pub peer_ids: ::prost::alloc::vec::Vec<::prost::alloc::string::String>,
+    #[prost(uint64, tag = "2")]
+    pub capabilities: u64,
+    /// our counter of each repository in peer_ids, used to estimate sync progress
+    #[prost(message, repeated, tag = "3")]
+    pub counters: ::prost::alloc::vec::Vec<ComparePayload>,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ComparePayload {
@@ -88,6 +223,12 @@ pub struct BatchMessageRequest {
     pub my_counter: i32,
     #[prost(string, tag = "2")]
     pub peer_id: ::prost::alloc::string::String,
+    /// asks for the author's peer record even when my_counter isn't 0
+    #[prost(bool, tag = "3")]
+    pub want_peer: bool,
+    /// the most messages to send back, 0 asks for all of them
+    #[prost(uint32, tag = "4")]
+    pub limit: u32,
 }
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct BatchMessageResponse {
@@ -105,10 +246,16 @@ pub struct Peer {
     #[prost(string, tag = "3")]
     pub pub_key: ::prost::alloc::string::String,
 }
+/// answer to a request variant the receiver doesn't know
+#[derive(Clone, Copy, PartialEq, ::prost::Message)]
+pub struct Unsupported {}
 #[derive(Clone, PartialEq, ::prost::Message)]
 pub struct ChatMessage {
-    #[prost(oneof = "chat_message::Variant", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10")]
+    #[prost(oneof = "chat_message::Variant", tags = "1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 13")]
     pub variant: ::core::option::Option<chat_message::Variant>,
+    /// wire format of the sender, 0 from peers that predate it
+    #[prost(uint32, tag = "12")]
+    pub protocol_version: u32,
 }
 /// Nested message and enum types in `ChatMessage`.
 pub mod chat_message {
@@ -134,5 +281,69 @@ pub mod chat_message {
         FileWantRequest(super::FileWantRequest),
         #[prost(message, tag = "10")]
         FileWantResponse(super::FileWantResponse),
+        #[prost(message, tag = "11")]
+        Unsupported(super::Unsupported),
+        #[prost(message, tag = "13")]
+        FileDownloadAck(super::FileDownloadAck),
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum SystemKind {
+    None = 0,
+    Joined = 1,
+    Left = 2,
+    Renamed = 3,
+}
+impl SystemKind {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::None => "SYSTEM_KIND_NONE",
+            Self::Joined => "SYSTEM_KIND_JOINED",
+            Self::Left => "SYSTEM_KIND_LEFT",
+            Self::Renamed => "SYSTEM_KIND_RENAMED",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "SYSTEM_KIND_NONE" => Some(Self::None),
+            "SYSTEM_KIND_JOINED" => Some(Self::Joined),
+            "SYSTEM_KIND_LEFT" => Some(Self::Left),
+            "SYSTEM_KIND_RENAMED" => Some(Self::Renamed),
+            _ => None,
+        }
+    }
+}
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, ::prost::Enumeration)]
+#[repr(i32)]
+pub enum ExpiryTrigger {
+    /// counted from the message timestamp, every node deletes it on its own
+    FromSend = 0,
+    /// counted from when a recipient read it, the reader's tombstone deletes it elsewhere
+    FromRead = 1,
+}
+impl ExpiryTrigger {
+    /// String value of the enum field names used in the ProtoBuf definition.
+    ///
+    /// The values are not transformed in any way and thus are considered stable
+    /// (if the ProtoBuf definition does not change) and safe for programmatic use.
+    pub fn as_str_name(&self) -> &'static str {
+        match self {
+            Self::FromSend => "EXPIRY_TRIGGER_FROM_SEND",
+            Self::FromRead => "EXPIRY_TRIGGER_FROM_READ",
+        }
+    }
+    /// Creates an enum from field names used in the ProtoBuf definition.
+    pub fn from_str_name(value: &str) -> ::core::option::Option<Self> {
+        match value {
+            "EXPIRY_TRIGGER_FROM_SEND" => Some(Self::FromSend),
+            "EXPIRY_TRIGGER_FROM_READ" => Some(Self::FromRead),
+            _ => None,
+        }
     }
 }
@@ -0,0 +1,82 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use crate::clock::Clock;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameDirection {
+    Sent,
+    Received,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FrameKind {
+    Request,
+    Response,
+}
+
+/// One frame exchanged with a peer. Only metadata is kept, never the payload.
+#[derive(Clone, Debug)]
+pub struct ProtocolEvent {
+    /// Unix timestamp in milliseconds.
+    pub timestamp_ms: i64,
+    pub peer_id: String,
+    pub direction: FrameDirection,
+    pub kind: FrameKind,
+    /// Name of the message variant, "eof" for the end of a response stream.
+    pub variant: String,
+    /// Encoded payload size in bytes.
+    pub size: u64,
+    /// The error if writing or reading the frame failed.
+    pub error: Option<String>,
+}
+
+/// Keeps the last frames exchanged with peers so a "sync isn't working" report can
+/// come with a trace. Only created when `Config::protocol_trace_size` is non zero.
+pub struct ProtocolRecorder {
+    capacity: usize,
+    events: Mutex<VecDeque<ProtocolEvent>>,
+    clock: Arc<dyn Clock>,
+}
+
+impl ProtocolRecorder {
+    pub fn new(capacity: usize, clock: Arc<dyn Clock>) -> Self {
+        Self {
+            capacity,
+            events: Mutex::new(VecDeque::with_capacity(capacity)),
+            clock,
+        }
+    }
+
+    pub fn record(
+        &self,
+        peer_id: &str,
+        direction: FrameDirection,
+        kind: FrameKind,
+        variant: &str,
+        size: u64,
+        error: Option<String>,
+    ) {
+        let event = ProtocolEvent {
+            timestamp_ms: self.clock.now().timestamp_millis(),
+            peer_id: peer_id.to_owned(),
+            direction,
+            kind,
+            variant: variant.to_owned(),
+            size,
+            error,
+        };
+        let mut events = self.events.lock().unwrap();
+        if events.len() == self.capacity {
+            events.pop_front();
+        }
+        events.push_back(event);
+    }
+
+    /// Recorded frames, oldest first.
+    pub fn recent(&self) -> Vec<ProtocolEvent> {
+        self.events.lock().unwrap().iter().cloned().collect()
+    }
+}
@@ -23,7 +23,7 @@ impl Repository {
         sync_engine: Weak<dyn MessageBroadcaster>,
         manager: Weak<RepositoryManager>,
     ) -> anyhow::Result<Self> {
-        let res = db.get_highest_counter(&id).await.unwrap_or_else(|_| 0);
+        let res = db.get_highest_counter(&id).await.unwrap_or(0);
         let repo = Self {
             id,
             db,
@@ -46,23 +46,17 @@ impl Repository {
                 "Message peer_id does not match repository id"
             ));
         } else {
-            let mut add = false;
+            // callers hold the repository lock, the counter only moves once the
+            // message is stored, so a failed save doesn't leave a gap
+            let next_counter = self.cur_counter.load(std::sync::atomic::Ordering::SeqCst) + 1;
             if message.counter == 0 {
-                add = true;
-                message.counter = self
-                    .cur_counter
-                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst)
-                    + 1;
-            } else if message.counter
-                != self.cur_counter.load(std::sync::atomic::Ordering::SeqCst) + 1
-            {
+                message.counter = next_counter;
+            } else if message.counter != next_counter {
                 return Err(anyhow::anyhow!("Message counter is invalid"));
             }
             self.db.save(&message).await?;
-            if !add {
-                self.cur_counter
-                    .fetch_add(1, std::sync::atomic::Ordering::SeqCst);
-            }
+            self.cur_counter
+                .store(next_counter, std::sync::atomic::Ordering::SeqCst);
             if let Some(upgrade) = self.manager.upgrade() {
                 upgrade.update_counter(&message).await?;
             }
@@ -77,8 +71,12 @@ impl Repository {
             .await
     }
 
-    pub async fn get_messages(&self, start_counter: u64) -> anyhow::Result<Vec<DbMessage>> {
-        self.db.get_after(&self.id, start_counter).await
+    /// Messages from `start_counter` on, at most `limit` of them unless it is 0.
+    pub async fn get_messages(&self, start_counter: u64, limit: u32) -> anyhow::Result<Vec<DbMessage>> {
+        if limit == 0 {
+            return self.db.get_after(&self.id, start_counter).await;
+        }
+        self.db.get_page(&self.id, start_counter, limit).await
     }
 
     pub async fn insert_message_batch(&self, messages: &[DbMessage]) -> anyhow::Result<()> {
@@ -116,10 +114,9 @@ impl Repository {
             .upgrade()
             .ok_or_else(|| anyhow::anyhow!("SyncEngine is gone"))?
             .message_broadcast(SyncMessage {
-                stored_messages: filtered.into_iter().map(|msg| msg.clone()).collect(),
+                stored_messages: filtered.into_iter().cloned().collect(),
             })
-            .await;
-        Ok(())
+            .await
     }
 
     pub async fn get_state(&self) -> anyhow::Result<u64> {
@@ -1,19 +1,50 @@
 use crate::indexer::Indexer;
 use crate::message_database::MessageDatabase;
-use crate::models::DbMessage;
+use crate::models::{split_group_repo, DbMessage};
 use crate::repository::Repository;
 use crate::sync_engine::MessageBroadcaster;
 use anyhow::Result;
 use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::Arc;
 use tokio::sync::Mutex;
 
+/// What happens to messages of a repository whose author we have no peer record
+/// for, so neither a name nor a key to check them against.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum UnknownPeerPolicy {
+    /// Store them, the record may arrive later.
+    #[default]
+    Accept,
+    /// Drop them until the record arrives some other way, e.g. through discovery.
+    Reject,
+    /// Ask for the record together with the messages and store them once it is
+    /// there. Pushed messages wait for the next pull.
+    FetchFirst,
+}
+
+/// Returned by `RepositoryManager::add_own_message` on an observer node, see `Config::observer`.
+#[derive(Debug)]
+pub struct ObserverError;
+
+impl std::fmt::Display for ObserverError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Observer nodes don't send messages")
+    }
+}
+
+impl std::error::Error for ObserverError {}
+
 pub struct RepositoryManager {
     repositories: Arc<Mutex<HashMap<String, Arc<Mutex<Repository>>>>>,
     db: Arc<MessageDatabase>,
     indexer: Arc<Indexer>,
     sync_engine: std::sync::Weak<dyn MessageBroadcaster>,
-    counter_lock: Arc<Mutex<u64>>,
+    /// Global order of the latest known message, only ever moves forward.
+    counter: AtomicU64,
+    global_ordering: bool,
+    unknown_peer_policy: UnknownPeerPolicy,
+    observer: bool,
 }
 
 #[derive(Clone, Debug)]
@@ -28,45 +59,65 @@ impl RepositoryManager {
         counter: u64,
         indexer: Arc<Indexer>,
         sync_engine: std::sync::Weak<dyn MessageBroadcaster>,
+        global_ordering: bool,
+        unknown_peer_policy: UnknownPeerPolicy,
+        observer: bool,
     ) -> Self {
         Self {
             repositories: Arc::new(Mutex::new(HashMap::new())),
             db,
             indexer,
             sync_engine,
-            counter_lock: Arc::new(Mutex::new(counter)),
+            counter: AtomicU64::new(counter),
+            global_ordering,
+            unknown_peer_policy,
+            observer,
         }
     }
 
+    pub fn unknown_peer_policy(&self) -> UnknownPeerPolicy {
+        self.unknown_peer_policy
+    }
+
     pub async fn update_counter_many<'a, I>(&self, messages: I) -> Result<()>
     where
         I: IntoIterator<Item = &'a DbMessage>,
     {
-        let mut cnt = self.counter_lock.lock().await;
-        for message in messages {
-            if message.order > *cnt {
-                *cnt = message.order;
-            }
+        if !self.global_ordering {
+            return Ok(());
+        }
+        if let Some(max_order) = messages.into_iter().map(|message| message.order).max() {
+            self.counter.fetch_max(max_order, Ordering::SeqCst);
         }
         Ok(())
     }
 
     pub async fn update_counter(&self, message: &DbMessage) -> Result<()> {
-        let mut cnt = self.counter_lock.lock().await;
-        if message.order > *cnt {
-            *cnt = message.order;
+        if !self.global_ordering {
+            return Ok(());
         }
+        self.counter.fetch_max(message.order, Ordering::SeqCst);
         Ok(())
     }
 
+    /// Stores a message written by us. The order is assigned while the repository is
+    /// locked, so within a repository a later `counter` always has a larger `order`
+    /// and concurrent calls never produce duplicate or skipped counters.
+    /// Fails with `ObserverError` on an observer node.
     pub async fn add_own_message(self: Arc<Self>, mut message: DbMessage) -> Result<DbMessage> {
-        let self_clone = self.clone();
-        let mut cnt = self_clone.counter_lock.lock().await;
-        *cnt += 1;
-        message.order = *cnt;
-        drop(cnt);
-        let repository = self.get_or_create_repository(&message.peer_id).await?;
-        repository.lock().await.save_message(&message).await?;
+        if self.observer {
+            return Err(ObserverError.into());
+        }
+        self.indexer.notify_sending(&message.id).await;
+        let repository = self.clone().get_or_create_repository(&message.peer_id).await?;
+        let repository = repository.lock().await;
+        message.order = if self.global_ordering {
+            self.counter.fetch_add(1, Ordering::SeqCst) + 1
+        } else {
+            // the repository lock is held, so the next counter is the one save_message assigns
+            repository.get_counter() + 1
+        };
+        repository.save_message(&message).await?;
         Ok(message)
     }
 
@@ -109,7 +160,214 @@ impl RepositoryManager {
         Ok(states)
     }
 
+    /// Whether the repository may be synced with `peer_id`. A peer's repository goes
+    /// to everyone, a group's only to its members.
+    pub async fn shared_with(&self, repo_id: &str, peer_id: &str) -> Result<bool> {
+        match split_group_repo(repo_id) {
+            Some((group_id, _)) => self.indexer.is_group_member(group_id, peer_id).await,
+            None => Ok(true),
+        }
+    }
+
     pub async fn get_repository(self: Arc<Self>, peer_id: &str) -> Result<Arc<Mutex<Repository>>> {
         self.get_or_create_repository(peer_id).await
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::indexer::memory_indexer;
+    use crate::message_database::memory_pool;
+    use crate::models::{group_repo_id, MessageBuilder};
+    use crate::sync_engine::{SyncEngine, SyncMessage};
+    use async_trait::async_trait;
+    use std::collections::HashSet;
+    use std::sync::Weak;
+
+    async fn manager(indexer: Arc<Indexer>) -> Arc<RepositoryManager> {
+        manager_with(indexer, Weak::<SyncEngine>::new()).await
+    }
+
+    async fn manager_with(
+        indexer: Arc<Indexer>,
+        sync_engine: Weak<dyn MessageBroadcaster>,
+    ) -> Arc<RepositoryManager> {
+        let events = Arc::new(crate::events::Events::new());
+        let db = Arc::new(MessageDatabase::new(memory_pool().await, events, false));
+        db.init().await.unwrap();
+        Arc::new(RepositoryManager::new(
+            db,
+            0,
+            indexer,
+            sync_engine,
+            true,
+            UnknownPeerPolicy::Accept,
+            false,
+        ))
+    }
+
+    fn signing_key() -> ed25519_dalek::SigningKey {
+        ed25519_dalek::SigningKey::generate(&mut rand::rngs::OsRng)
+    }
+
+    struct NoopBroadcaster;
+
+    #[async_trait]
+    impl MessageBroadcaster for NoopBroadcaster {
+        async fn message_broadcast(self: Arc<Self>, _: SyncMessage) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    fn change(id: &str, author: &str, group_id: &str, member: &str) -> DbMessage {
+        MessageBuilder::new(id.to_owned(), 1, author.to_owned())
+            .group_change(group_id.to_owned(), member.to_owned(), false)
+            .build()
+    }
+
+    #[tokio::test]
+    async fn group_repositories_are_shared_with_members_only() {
+        let indexer = Arc::new(memory_indexer(memory_pool().await, signing_key()).await);
+        let manager = manager(indexer.clone()).await;
+        let group_id = "alice:g1";
+        let repo_id = group_repo_id(group_id, "alice");
+        assert!(manager.shared_with("alice", "mallory").await.unwrap());
+        assert!(!manager.shared_with(&repo_id, "bob").await.unwrap());
+
+        // the inviter's repository of the group tells us bob is in
+        indexer.index_message(&change("c1", "alice", group_id, "alice")).await.unwrap();
+        indexer.index_message(&change("c2", "alice", group_id, "bob")).await.unwrap();
+        assert!(manager.shared_with(&repo_id, "bob").await.unwrap());
+        assert!(manager.shared_with(&group_repo_id(group_id, "bob"), "alice").await.unwrap());
+        assert!(!manager.shared_with(&repo_id, "mallory").await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn group_change_outside_the_group_is_ignored() {
+        let indexer = Arc::new(memory_indexer(memory_pool().await, signing_key()).await);
+        let manager = manager(indexer.clone()).await;
+        let group_id = "mallory:g1";
+        let mut message = change("c1", "mallory", "alice:g1", "mallory");
+        // written into mallory's repository of another group
+        message.peer_id = group_repo_id(group_id, "mallory");
+        indexer.index_message(&message).await.unwrap();
+        assert!(!manager
+            .shared_with(&group_repo_id("alice:g1", "alice"), "mallory")
+            .await
+            .unwrap());
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn concurrent_own_messages_get_contiguous_counters() {
+        const TASKS: usize = 50;
+        let broadcaster = Arc::new(NoopBroadcaster);
+        let indexer = Arc::new(memory_indexer(memory_pool().await, signing_key()).await);
+        let manager = manager_with(indexer, Arc::downgrade(&broadcaster) as Weak<_>).await;
+        let mut tasks = Vec::new();
+        for i in 0..TASKS {
+            // two repositories, so the global order is shared between them
+            let peer_id = if i % 2 == 0 { "alice" } else { "alice:g1" };
+            let message = MessageBuilder::new(format!("m{}", i), 1, peer_id.to_owned())
+                .text(format!("hello {}", i))
+                .build();
+            tasks.push(tokio::spawn(manager.clone().add_own_message(message)));
+        }
+        for task in tasks {
+            task.await.unwrap().unwrap();
+        }
+
+        let mut orders = HashSet::new();
+        for peer_id in ["alice", "alice:g1"] {
+            let stored = manager.db.get_after(peer_id, 0).await.unwrap();
+            let counters: Vec<u64> = stored.iter().map(|message| message.counter).collect();
+            assert_eq!(counters, (1..=(TASKS / 2) as u64).collect::<Vec<_>>());
+            // a later counter always has a later order
+            assert!(stored.windows(2).all(|pair| pair[0].order < pair[1].order));
+            orders.extend(stored.iter().map(|message| message.order));
+        }
+        assert_eq!(orders.len(), TASKS);
+        assert_eq!(orders.iter().max(), Some(&(TASKS as u64)));
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn known_orders_only_move_the_counter_forward() {
+        let broadcaster = Arc::new(NoopBroadcaster);
+        let indexer = Arc::new(memory_indexer(memory_pool().await, signing_key()).await);
+        let manager = manager_with(indexer, Arc::downgrade(&broadcaster) as Weak<_>).await;
+        let mut tasks = Vec::new();
+        for task in 0..8u64 {
+            let manager = manager.clone();
+            tasks.push(tokio::spawn(async move {
+                // interleaved, so smaller orders arrive after larger ones
+                for i in 0..100u64 {
+                    let mut message = MessageBuilder::new(format!("m{}", i), 1, "bob".to_owned())
+                        .text("hi".to_owned())
+                        .build();
+                    message.order = (i * 8 + task) % 500;
+                    if i % 2 == 0 {
+                        manager.update_counter(&message).await.unwrap();
+                    } else {
+                        manager.update_counter_many([&message]).await.unwrap();
+                    }
+                }
+            }));
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(manager.counter.load(Ordering::SeqCst), 499);
+
+        let message = MessageBuilder::new("own".to_owned(), 1, "alice".to_owned())
+            .text("hello".to_owned())
+            .build();
+        let message = manager.clone().add_own_message(message).await.unwrap();
+        assert_eq!(message.order, 500);
+    }
+
+    #[test]
+    fn observer_syncs_a_conversation_but_cant_send() {
+        use crate::app_context::{wait_until, TestNode};
+        use crate::config::Config;
+
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let config = Config {
+                observer: true,
+                ..Config::default()
+            };
+            let backup = TestNode::new("backup", config, runtime.clone()).await;
+            alice.learn(&backup).await;
+            backup.learn(&alice).await;
+            alice.start().await;
+            backup.start().await;
+
+            let manager = alice.ctx.sync_engine.get_manager();
+            let hello = MessageBuilder::new("hello".to_owned(), 1, alice.id())
+                .text("hello".to_owned())
+                .build();
+            manager.add_own_message(hello).await.unwrap();
+            let indexer = backup.ctx.indexer.clone();
+            let engine = &backup.ctx.sync_engine;
+            engine.sync_now(Some(alice.id())).await.unwrap();
+            backup.wait_for_counter(&alice.id(), 2).await;
+            wait_until("the backup shows the message", || async {
+                indexer.get_by_id("hello").await.unwrap().is_some()
+            })
+            .await;
+
+            let manager = backup.ctx.sync_engine.get_manager();
+            let reply = MessageBuilder::new("reply".to_owned(), 1, backup.id())
+                .text("hi".to_owned())
+                .build();
+            let err = manager.add_own_message(reply).await.unwrap_err();
+            assert!(err.downcast_ref::<ObserverError>().is_some());
+            // not even the joined message was written
+            let message_db = &backup.ctx.message_db;
+            let written = message_db.get_highest_counter(&backup.id()).await;
+            assert_eq!(written.unwrap(), 0);
+            assert!(indexer.get_by_id("reply").await.unwrap().is_none());
+        });
+    }
+}
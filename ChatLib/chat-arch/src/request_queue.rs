@@ -1,6 +1,11 @@
 use anyhow::Result;
 use log::{debug, warn};
-use std::{future::Future, pin::Pin, sync::Arc, time::Duration};
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
 use tokio::{
     runtime::Runtime,
     time::{self, timeout},
@@ -8,27 +13,52 @@ use tokio::{
 
 pub type BoxFuture<'a, T> = Pin<Box<dyn Future<Output = T> + Send + 'a>>;
 
+/// A task still running after this is abandoned, see `Task::timeout`.
+pub const TASK_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Whether a task may be dropped while the queue is full, see `RequestQueue::new`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TaskPriority {
+    /// Work the next sync sweep repeats anyway, e.g. comparing state with a peer.
+    Background,
+    /// Work the user waits for, e.g. sending a message. Never dropped.
+    Interactive,
+}
+
 pub trait Task: Send + Sync + 'static {
     fn run(self: Arc<Self>) -> BoxFuture<'static, Result<()>>;
+
+    fn priority(&self) -> TaskPriority {
+        TaskPriority::Interactive
+    }
+
+    /// How long the task may run, `None` for a task that bounds itself, e.g. a
+    /// throttled download that takes as long as its rate limit needs.
+    fn timeout(&self) -> Option<Duration> {
+        Some(TASK_TIMEOUT)
+    }
 }
 
 pub struct RequestQueue {
     sender: flume::Sender<Arc<dyn Task>>,
     receiver: Arc<flume::Receiver<Arc<dyn Task>>>,
     worker_count: usize,
+    max_pending: Option<usize>,
     runtime: Arc<Runtime>,
 }
 
 impl RequestQueue {
-    pub fn new(worker_count: usize, runtime: Arc<Runtime>) -> Self {
+    /// Once `max_pending` tasks wait for a worker, background tasks are dropped
+    /// instead of queued. `None` queues everything.
+    pub fn new(worker_count: usize, max_pending: Option<usize>, runtime: Arc<Runtime>) -> Self {
         let (tx, rx) = flume::unbounded();
-        let queue = RequestQueue {
+        RequestQueue {
             sender: tx,
             receiver: Arc::new(rx),
             worker_count,
+            max_pending,
             runtime,
-        };
-        queue
+        }
     }
 
     pub fn start(&self) {
@@ -41,15 +71,31 @@ impl RequestQueue {
         }
     }
 
+    /// Tasks waiting for a worker.
+    pub fn pending(&self) -> usize {
+        self.sender.len()
+    }
+
+    /// Returns `Ok` for a shed task too, the caller has nothing to undo.
     pub async fn enqueue(&self, req: Arc<dyn Task>) -> anyhow::Result<()> {
+        let pending = self.pending();
+        if req.priority() == TaskPriority::Background
+            && self.max_pending.is_some_and(|max| pending >= max)
+        {
+            warn!("{} tasks pending, shedding a background task", pending);
+            return Ok(());
+        }
         self.sender.send_async(req).await.map_err(|e| e.into())
     }
 }
 
 async fn worker_loop(rx: Arc<flume::Receiver<Arc<dyn Task>>>) {
     while let Ok(request) = rx.as_ref().recv_async().await {
-        let tm = timeout(Duration::from_secs(30), request.run());
-        match tm.await {
+        let res = match request.timeout() {
+            Some(limit) => timeout(limit, request.run()).await,
+            None => Ok(request.run().await),
+        };
+        match res {
             Ok(res) => {
                 if let Err(e) = res {
                     warn!("Error processing request: {:?}", e);
@@ -67,35 +113,205 @@ pub type AsyncFn =
     dyn Fn() -> Pin<Box<dyn Future<Output = anyhow::Result<()>> + Send>> + Send + Sync;
 
 pub struct PeriodicTaskScheduler {
-    interval_seconds: u64,
+    interval: Duration,
     task_fn: Arc<AsyncFn>,
     runtime: Arc<Runtime>,
+    last_tick: Arc<Mutex<Option<Instant>>>,
 }
 
 impl PeriodicTaskScheduler {
-    pub fn new(task_fn: Arc<AsyncFn>, interval_seconds: u64, runtime: Arc<Runtime>) -> Self {
+    pub fn new(task_fn: Arc<AsyncFn>, interval: Duration, runtime: Arc<Runtime>) -> Self {
         Self {
-            interval_seconds,
+            interval,
             task_fn: Arc::clone(&task_fn),
             runtime,
+            last_tick: Arc::new(Mutex::new(None)),
         }
     }
 
     pub fn signal_start(&self) {
         let task_fn = Arc::clone(&self.task_fn);
-        let seconds = self.interval_seconds;
+        let interval = self.interval;
+        let last_tick = self.last_tick.clone();
         self.runtime.spawn(async move {
-            PeriodicTaskScheduler::start(seconds, task_fn).await;
+            PeriodicTaskScheduler::start(interval, task_fn, last_tick).await;
         });
     }
 
-    async fn start(interval_seconds: u64, task_fn: Arc<AsyncFn>) {
-        let mut interval = time::interval(Duration::from_secs(interval_seconds));
+    /// When the task last started, `None` before the first run. Falls behind when
+    /// a run hangs, which is how a stalled scheduler is told apart.
+    pub fn last_tick(&self) -> Option<Instant> {
+        *self.last_tick.lock().unwrap()
+    }
+
+    async fn start(
+        interval: Duration,
+        task_fn: Arc<AsyncFn>,
+        last_tick: Arc<Mutex<Option<Instant>>>,
+    ) {
+        let mut interval = time::interval(interval);
         loop {
             interval.tick().await;
+            *last_tick.lock().unwrap() = Some(Instant::now());
             if let Err(e) = task_fn().await {
                 warn!("Periodic task failed: {e}");
             }
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    struct SleepTask {
+        duration: Duration,
+        limit: Option<Duration>,
+        priority: TaskPriority,
+        done: AtomicBool,
+    }
+
+    impl Task for SleepTask {
+        fn run(self: Arc<Self>) -> BoxFuture<'static, Result<()>> {
+            Box::pin(async move {
+                time::sleep(self.duration).await;
+                self.done.store(true, Ordering::SeqCst);
+                Ok(())
+            })
+        }
+
+        fn priority(&self) -> TaskPriority {
+            self.priority
+        }
+
+        fn timeout(&self) -> Option<Duration> {
+            self.limit
+        }
+    }
+
+    fn sleep_task(duration: Duration, limit: Option<Duration>) -> Arc<SleepTask> {
+        Arc::new(SleepTask {
+            duration,
+            limit,
+            priority: TaskPriority::Interactive,
+            done: AtomicBool::new(false),
+        })
+    }
+
+    fn task_with(priority: TaskPriority) -> Arc<SleepTask> {
+        Arc::new(SleepTask {
+            duration: Duration::from_millis(1),
+            limit: None,
+            priority,
+            done: AtomicBool::new(false),
+        })
+    }
+
+    fn done(tasks: &[Arc<SleepTask>]) -> usize {
+        tasks
+            .iter()
+            .filter(|task| task.done.load(Ordering::SeqCst))
+            .count()
+    }
+
+    #[test]
+    fn task_runs_until_its_own_timeout() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let queue = RequestQueue::new(2, None, runtime);
+            queue.start();
+            let cut_off = sleep_task(Duration::from_millis(300), Some(Duration::from_millis(50)));
+            let unbounded = sleep_task(Duration::from_millis(300), None);
+            queue.enqueue(cut_off.clone()).await.unwrap();
+            queue.enqueue(unbounded.clone()).await.unwrap();
+
+            time::sleep(Duration::from_millis(600)).await;
+            assert!(!cut_off.done.load(Ordering::SeqCst));
+            assert!(unbounded.done.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn flooded_queue_sheds_background_tasks() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            // not started yet, so nothing is taken off the queue while it floods
+            let queue = RequestQueue::new(2, Some(4), runtime);
+            let background: Vec<_> = (0..20)
+                .map(|_| task_with(TaskPriority::Background))
+                .collect();
+            let interactive: Vec<_> = (0..10)
+                .map(|_| task_with(TaskPriority::Interactive))
+                .collect();
+            for task in background.iter().chain(&interactive) {
+                queue.enqueue(task.clone()).await.unwrap();
+            }
+            assert_eq!(queue.sender.len(), 14);
+
+            // a full queue sheds background work even after interactive tasks filled it
+            let late = task_with(TaskPriority::Background);
+            queue.enqueue(late.clone()).await.unwrap();
+            assert_eq!(queue.sender.len(), 14);
+
+            queue.start();
+            time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(done(&background), 4);
+            assert_eq!(done(&interactive), 10);
+            assert!(!late.done.load(Ordering::SeqCst));
+        });
+    }
+
+    #[test]
+    fn unbounded_queue_keeps_background_tasks() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let queue = RequestQueue::new(2, None, runtime);
+            let background: Vec<_> = (0..20)
+                .map(|_| task_with(TaskPriority::Background))
+                .collect();
+            for task in &background {
+                queue.enqueue(task.clone()).await.unwrap();
+            }
+            assert_eq!(queue.sender.len(), 20);
+
+            queue.start();
+            time::sleep(Duration::from_millis(300)).await;
+            assert_eq!(done(&background), 20);
+        });
+    }
+
+    fn scheduler(runtime: &Arc<Runtime>, hang: bool) -> PeriodicTaskScheduler {
+        let task_fn: Arc<AsyncFn> = Arc::new(move || {
+            Box::pin(async move {
+                if hang {
+                    std::future::pending::<()>().await;
+                }
+                Ok(())
+            })
+        });
+        PeriodicTaskScheduler::new(task_fn, Duration::from_millis(50), runtime.clone())
+    }
+
+    #[test]
+    fn last_tick_advances_while_the_task_finishes() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let scheduler = scheduler(&runtime, false);
+        assert_eq!(scheduler.last_tick(), None);
+
+        scheduler.signal_start();
+        runtime.block_on(async { time::sleep(Duration::from_millis(300)).await });
+        let since = scheduler.last_tick().unwrap().elapsed();
+        assert!(since < Duration::from_millis(150), "{:?}", since);
+    }
+
+    #[test]
+    fn last_tick_falls_behind_while_the_task_hangs() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        let scheduler = scheduler(&runtime, true);
+        scheduler.signal_start();
+        runtime.block_on(async { time::sleep(Duration::from_millis(300)).await });
+        let since = scheduler.last_tick().unwrap().elapsed();
+        assert!(since >= Duration::from_millis(250), "{:?}", since);
+    }
+}
@@ -0,0 +1,139 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// Longest peer name kept under [`TextPolicy::Sanitize`], in characters.
+pub const MAX_NAME_CHARS: usize = 64;
+
+/// How text from other peers is cleaned before it is stored for display.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum TextPolicy {
+    /// Store names and message text as received.
+    Keep,
+    /// NFC normalize and drop control, bidi override and zero-width characters, so a
+    /// peer can't make its name or text render like someone else's. Message text
+    /// keeps line breaks and tabs, names are cut to [`MAX_NAME_CHARS`]. The raw
+    /// payload stays in the message store.
+    #[default]
+    Sanitize,
+}
+
+impl TextPolicy {
+    /// Cleans a peer name, which is always shown on a single line.
+    pub fn name(&self, name: &str) -> String {
+        match self {
+            TextPolicy::Keep => name.to_owned(),
+            TextPolicy::Sanitize => {
+                let name: String = clean(name, false)
+                    .trim()
+                    .chars()
+                    .take(MAX_NAME_CHARS)
+                    .collect();
+                name.trim_end().to_owned()
+            }
+        }
+    }
+
+    /// Cleans message text.
+    pub fn text(&self, text: &str) -> String {
+        match self {
+            TextPolicy::Keep => text.to_owned(),
+            TextPolicy::Sanitize => clean(text, true),
+        }
+    }
+}
+
+fn clean(value: &str, multiline: bool) -> String {
+    value
+        .nfc()
+        .filter(|c| match c {
+            '\n' | '\t' => multiline,
+            _ => !is_spoofing_char(*c),
+        })
+        .collect()
+}
+
+fn is_spoofing_char(c: char) -> bool {
+    c.is_control()
+        || matches!(
+            c,
+            // zero-width characters
+            '\u{200B}'..='\u{200D}' | '\u{2060}' | '\u{FEFF}'
+            // bidi marks, embeddings, overrides and isolates
+            | '\u{200E}' | '\u{200F}' | '\u{061C}'
+            | '\u{202A}'..='\u{202E}' | '\u{2066}'..='\u{2069}'
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bidi_overrides_are_stripped() {
+        // "alice" + RLO + "gpj.exe" renders as "aliceexe.jpg"
+        let spoofed = "alice\u{202E}gpj.exe";
+        assert_eq!(TextPolicy::Sanitize.name(spoofed), "alicegpj.exe");
+        assert_eq!(TextPolicy::Sanitize.text(spoofed), "alicegpj.exe");
+        let isolated = "\u{2067}bob\u{2069}\u{200F}\u{061C}";
+        assert_eq!(TextPolicy::Sanitize.name(isolated), "bob");
+        let embedded = "\u{202A}carol\u{202C}\u{200E}";
+        assert_eq!(TextPolicy::Sanitize.text(embedded), "carol");
+    }
+
+    #[test]
+    fn zero_width_characters_are_stripped() {
+        let hidden = "\u{FEFF}al\u{200B}i\u{200C}c\u{200D}e\u{2060}";
+        assert_eq!(TextPolicy::Sanitize.name(hidden), "alice");
+        assert_eq!(TextPolicy::Sanitize.text(hidden), "alice");
+        // made only of invisible characters, the name ends up empty
+        assert_eq!(TextPolicy::Sanitize.name("\u{200B}\u{FEFF}"), "");
+    }
+
+    #[test]
+    fn control_characters_are_stripped() {
+        let name = "alice\u{0}\u{7}\u{1B}[31m\r\nadmin\t";
+        assert_eq!(TextPolicy::Sanitize.name(name), "alice[31madmin");
+        // text keeps its line breaks and tabs, nothing else
+        let text = "line one\r\n\tline two\u{8}\u{85}\u{7F}";
+        assert_eq!(TextPolicy::Sanitize.text(text), "line one\n\tline two");
+    }
+
+    #[test]
+    fn lookalike_compositions_normalize_to_the_same_name() {
+        let composed = "Jos\u{E9}";
+        let decomposed = "Jose\u{301}";
+        assert_ne!(composed, decomposed);
+        assert_eq!(TextPolicy::Sanitize.name(decomposed), composed);
+        assert_eq!(TextPolicy::Sanitize.text(decomposed), composed);
+    }
+
+    #[test]
+    fn overlong_names_are_cut() {
+        let long = "a".repeat(10 * MAX_NAME_CHARS);
+        assert_eq!(TextPolicy::Sanitize.name(&long), "a".repeat(MAX_NAME_CHARS));
+        // the limit counts characters, a multi-byte name isn't split inside one
+        let wide = "\u{1F600}".repeat(MAX_NAME_CHARS + 1);
+        let name = TextPolicy::Sanitize.name(&wide);
+        assert_eq!(name.chars().count(), MAX_NAME_CHARS);
+        assert!(name.chars().all(|c| c == '\u{1F600}'));
+        // characters that are stripped don't count towards the limit
+        let padded = format!("{}bob", "\u{200B}".repeat(10 * MAX_NAME_CHARS));
+        assert_eq!(TextPolicy::Sanitize.name(&padded), "bob");
+        // a cut doesn't leave trailing whitespace behind
+        let spaced = format!("{} tail", "a".repeat(MAX_NAME_CHARS - 1));
+        assert_eq!(
+            TextPolicy::Sanitize.name(&spaced),
+            "a".repeat(MAX_NAME_CHARS - 1)
+        );
+        // message text has no such limit
+        assert_eq!(TextPolicy::Sanitize.text(&long), long);
+    }
+
+    #[test]
+    fn keep_stores_strings_as_received() {
+        let raw = "\u{202E}al\u{200B}ice\u{7}";
+        assert_eq!(TextPolicy::Keep.name(raw), raw);
+        assert_eq!(TextPolicy::Keep.text(raw), raw);
+        let long = "a".repeat(10 * MAX_NAME_CHARS);
+        assert_eq!(TextPolicy::Keep.name(&long), long);
+    }
+}
@@ -1,79 +1,166 @@
-use crate::{handshake::read_handshake, peer_pool::EncryptedPool};
+use crate::{
+    conn::{EncryptedStream, StreamOptions},
+    handshake::{read_handshake, ResumptionCache},
+    handshake_scheme::HandshakeScheme,
+    inbound_policy::InboundGate,
+    peer_pool::SessionOptions,
+};
 use anyhow::Result;
 use ed25519_dalek::SigningKey;
 use log::{info, warn};
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use tokio::sync::watch;
-use tokio::{runtime::Runtime, select, sync::Mutex};
-use tokio_yamux::{Config, Session};
+use tokio::net::{lookup_host, TcpListener, TcpSocket};
+use tokio::sync::{watch, Notify};
+use std::time::Duration;
+use tokio::{runtime::Runtime, select, sync::Mutex, time::timeout};
+use tokio_yamux::Session;
+
+/// A client that connects and never completes the handshake is dropped after this.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// Wait before binding again after a rebind failed, doubled on every further
+/// attempt up to `MAX_REBIND_DELAY`.
+const REBIND_INTERVAL: Duration = Duration::from_millis(500);
+const MAX_REBIND_DELAY: Duration = Duration::from_secs(30);
 
 pub struct Server {
     addr: String,
     signing_key: SigningKey,
-    peer_pool: Arc<EncryptedPool>,
+    handshake_context: Arc<Vec<u8>>,
+    handshake_scheme: HandshakeScheme,
+    resumption: Option<Arc<ResumptionCache>>,
+    listen_backlog: u32,
+    stream_options: StreamOptions,
+    session_options: SessionOptions,
+    inbound_gate: Arc<InboundGate>,
     runtime: Arc<Runtime>,
     stop_tx: Arc<watch::Sender<bool>>,
+    rebind: Notify,
+    listening: AtomicBool,
 }
 
 impl Server {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         addr: String,
         signing_key: SigningKey,
-        peer_pool: Arc<EncryptedPool>,
+        handshake_context: Vec<u8>,
+        handshake_scheme: HandshakeScheme,
+        resumption: Option<Arc<ResumptionCache>>,
+        listen_backlog: u32,
+        stream_options: StreamOptions,
+        session_options: SessionOptions,
+        inbound_gate: Arc<InboundGate>,
         runtime: Arc<Runtime>,
     ) -> Self {
         let (stop_tx, _) = watch::channel(false);
         Server {
             addr,
-            peer_pool,
+            inbound_gate,
             signing_key,
+            handshake_context: Arc::new(stream_options.handshake_context(&handshake_context)),
+            handshake_scheme,
+            resumption,
+            listen_backlog,
+            stream_options,
+            session_options,
             runtime,
             stop_tx: Arc::new(stop_tx),
+            rebind: Notify::new(),
+            listening: AtomicBool::new(false),
         }
     }
 
     pub async fn run(&self) -> Result<()> {
-        info!("Listening on: {}", &self.addr);
+        let res = self.serve().await;
+        self.listening.store(false, Ordering::SeqCst);
+        res
+    }
+
+    /// Whether `run` holds a bound socket and accepts connections.
+    pub fn is_listening(&self) -> bool {
+        self.listening.load(Ordering::SeqCst)
+    }
+
+    async fn serve(&self) -> Result<()> {
         let _ = self.stop_tx.send(false);
-        let listener = tokio::net::TcpListener::bind(&self.addr).await?;
         let mut stop_rx = self.stop_tx.subscribe();
-        loop {
-            select! {
-                _ = stop_rx.changed() => {
-                    if *stop_rx.borrow() {
-                        info!("Stop signal received. Stopping server.");
-                        return Ok(());
+        info!("Listening on: {}", self.addr);
+        // only the first bind fails `run`, a rebind is retried until it succeeds
+        let mut first = Some(bind_listener(&self.addr, self.listen_backlog).await?);
+        'bind: loop {
+            let listener = match first.take() {
+                Some(listener) => listener,
+                None => {
+                    match bind_with_backoff(&self.addr, self.listen_backlog, &mut stop_rx).await {
+                        Some(listener) => listener,
+                        None => {
+                            info!("Stop signal received. Stopping server.");
+                            return Ok(());
+                        }
                     }
                 }
-                accept_result = listener.accept() => {
-                    let (mut socket, _) = accept_result?;
-                    let key = self.signing_key.clone();
-                    let peer_pool = self.peer_pool.clone();
-                    self.runtime.spawn(async move {
-                        let res = match read_handshake(&mut socket, &key).await {
-                            Ok(result) => result,
-                            Err(err) => {
-                                warn!("failed to read handshake: {:?}", err);
-                                return;
-                            }
-                        };
-                        let addr = match socket.peer_addr() {
-                            Ok(addr) => addr,
-                            Err(err) => {
-                                warn!("failed to get peer address: {:?}", err);
-                                return;
-                            }
-                        };
-                        let socket = crate::conn::EncryptedStream::new(socket, &res.symmetric_key);
-                        let session = Arc::new(Mutex::new(Session::new_server(socket, Config::default())));
-                        if let Err(e) = peer_pool.insert(&res.hex_key(), addr, session).await {
-                            warn!(
-                                "Failed to open a session with {}, error {:?}",
-                                &res.hex_key(),
-                                e
-                            );
+            };
+            self.listening.store(true, Ordering::SeqCst);
+            loop {
+                select! {
+                    _ = stop_rx.changed() => {
+                        if *stop_rx.borrow() {
+                            info!("Stop signal received. Stopping server.");
+                            return Ok(());
                         }
-                    });
+                    }
+                    _ = self.rebind.notified() => {
+                        info!("Rebinding listener.");
+                        // the old socket is closed before binding its port again
+                        self.listening.store(false, Ordering::SeqCst);
+                        continue 'bind;
+                    }
+                    accept_result = listener.accept() => {
+                        let (mut socket, _) = accept_result?;
+                        let key = self.signing_key.clone();
+                        let context = self.handshake_context.clone();
+                        let scheme = self.handshake_scheme.clone();
+                        let resumption = self.resumption.clone();
+                        let inbound_gate = self.inbound_gate.clone();
+                        let stream_options = self.stream_options;
+                        let yamux_config = self.session_options.yamux_config();
+                        self.runtime.spawn(async move {
+                            let handshake = read_handshake(&mut socket, &key, &scheme, &context, resumption.as_deref());
+                            let res = match timeout(HANDSHAKE_TIMEOUT, handshake).await {
+                                Ok(Ok(result)) => result,
+                                Ok(Err(err)) if err.kind() == std::io::ErrorKind::UnexpectedEof => {
+                                    info!("handshake cut off: {}", err);
+                                    return;
+                                }
+                                Ok(Err(err)) => {
+                                    warn!("failed to read handshake: {:?}", err);
+                                    return;
+                                }
+                                Err(_) => {
+                                    warn!("handshake timed out");
+                                    return;
+                                }
+                            };
+                            let addr = match socket.peer_addr() {
+                                Ok(addr) => addr,
+                                Err(err) => {
+                                    warn!("failed to get peer address: {:?}", err);
+                                    return;
+                                }
+                            };
+                            let socket = EncryptedStream::with_options(socket, &res.symmetric_key, stream_options);
+                            let session = Arc::new(Mutex::new(Session::new_server(socket, yamux_config)));
+                            if let Err(e) = inbound_gate.admit(&res.hex_key(), addr, session).await {
+                                warn!(
+                                    "Failed to open a session with {}, error {:?}",
+                                    res.hex_key(),
+                                    e
+                                );
+                            }
+                        });
+                    }
                 }
             }
         }
@@ -83,4 +170,187 @@ impl Server {
         info!("Stopping server.");
         let _ = self.stop_tx.send(true);
     }
+
+    /// Drops the listening socket and binds a new one, used after the local network changed.
+    pub fn rebind(&self) {
+        self.rebind.notify_one();
+    }
+}
+
+/// Binds `addr` again after a rebind, retrying while it can't be bound, e.g. until
+/// the new network is up. Returns `None` once the server is stopped.
+async fn bind_with_backoff(
+    addr: &str,
+    backlog: u32,
+    stop_rx: &mut watch::Receiver<bool>,
+) -> Option<TcpListener> {
+    let mut delay = REBIND_INTERVAL;
+    loop {
+        if *stop_rx.borrow() {
+            return None;
+        }
+        info!("Listening on: {}", addr);
+        match bind_listener(addr, backlog).await {
+            Ok(listener) => return Some(listener),
+            Err(e) => warn!("Failed to bind {}, retrying in {:?}: {:?}", addr, delay, e),
+        }
+        select! {
+            _ = stop_rx.changed() => {}
+            _ = tokio::time::sleep(delay) => {}
+        }
+        delay = (delay * 2).min(MAX_REBIND_DELAY);
+    }
+}
+
+/// Binds the first address `addr` resolves to that can be bound, so it may name a
+/// host as well as an IP.
+async fn bind_listener(addr: &str, backlog: u32) -> Result<TcpListener> {
+    let mut last_err = None;
+    for resolved in lookup_host(addr).await? {
+        match bind_socket(resolved, backlog) {
+            Ok(listener) => return Ok(listener),
+            Err(e) => last_err = Some(e.into()),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| anyhow::anyhow!("{} resolved to no address", addr)))
+}
+
+/// Binds with `SO_REUSEADDR`, so a restarted server can take over a port
+/// whose previous connections are still in TIME_WAIT.
+fn bind_socket(addr: SocketAddr, backlog: u32) -> std::io::Result<TcpListener> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    socket.set_reuseaddr(true)?;
+    socket.bind(addr)?;
+    socket.listen(backlog)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::SystemClock;
+    use crate::events::Events;
+    use crate::inbound_policy::{memory_gate, InboundPolicy};
+    use crate::message_database::memory_pool;
+    use crate::sync_engine::SyncEngine;
+    use rand::rngs::OsRng;
+    use std::sync::Weak;
+    use tokio::net::TcpStream;
+
+    /// A local address nobody listens on right now.
+    fn free_addr() -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.local_addr().unwrap().to_string()
+    }
+
+    async fn wait_listening(server: &Server) {
+        timeout(Duration::from_secs(5), async {
+            while !server.is_listening() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("server did not listen");
+    }
+
+    #[test]
+    fn rebind_drops_the_port_and_binds_it_again() {
+        let runtime = Arc::new(Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let addr = free_addr();
+            let gate = memory_gate(
+                InboundPolicy::AcceptAll,
+                memory_pool().await,
+                Arc::new(Events::new()),
+                Weak::<SyncEngine>::new(),
+                Arc::new(SystemClock),
+                runtime.clone(),
+            )
+            .await;
+            let server = Arc::new(Server::new(
+                addr.clone(),
+                SigningKey::generate(&mut OsRng),
+                Vec::new(),
+                HandshakeScheme::default(),
+                None,
+                16,
+                StreamOptions::default(),
+                SessionOptions::default(),
+                Arc::new(gate),
+                runtime.clone(),
+            ));
+            let running = runtime.spawn({
+                let server = server.clone();
+                async move { server.run().await }
+            });
+            wait_listening(&server).await;
+            // leaves a connection of the old socket behind
+            let old = TcpStream::connect(&addr).await.unwrap();
+
+            server.rebind();
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            wait_listening(&server).await;
+            TcpStream::connect(&addr).await.unwrap();
+            drop(old);
+
+            server.stop();
+            running.await.unwrap().unwrap();
+            assert!(!server.is_listening());
+        });
+    }
+
+    #[tokio::test]
+    async fn failed_rebind_is_retried_until_the_port_is_free() {
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = blocker.local_addr().unwrap().to_string();
+        let (_stop_tx, mut stop_rx) = watch::channel(false);
+        let binding = tokio::spawn({
+            let addr = addr.clone();
+            async move { bind_with_backoff(&addr, 16, &mut stop_rx).await }
+        });
+
+        tokio::time::sleep(REBIND_INTERVAL * 2).await;
+        assert!(!binding.is_finished());
+        drop(blocker);
+        let listener = timeout(MAX_REBIND_DELAY, binding)
+            .await
+            .unwrap()
+            .unwrap()
+            .expect("bound once the port was free");
+        assert_eq!(listener.local_addr().unwrap().to_string(), addr);
+    }
+
+    #[tokio::test]
+    async fn stopping_ends_the_rebind_retries() {
+        let blocker = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = blocker.local_addr().unwrap().to_string();
+        let (stop_tx, mut stop_rx) = watch::channel(false);
+        let binding = tokio::spawn(async move { bind_with_backoff(&addr, 16, &mut stop_rx).await });
+
+        tokio::time::sleep(REBIND_INTERVAL / 2).await;
+        stop_tx.send(true).unwrap();
+        let res = timeout(Duration::from_secs(5), binding)
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(res.is_none());
+    }
+
+    #[tokio::test]
+    async fn binds_a_host_name() {
+        let listener = bind_listener("localhost:0", 16).await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        assert!(addr.ip().is_loopback());
+        let (connected, accepted) = tokio::join!(TcpStream::connect(addr), listener.accept());
+        connected.unwrap();
+        accepted.unwrap();
+    }
+
+    #[tokio::test]
+    async fn bind_needs_a_port() {
+        assert!(bind_listener("localhost", 16).await.is_err());
+        assert!(bind_listener("127.0.0.1", 16).await.is_err());
+    }
 }
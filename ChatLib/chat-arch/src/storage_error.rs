@@ -0,0 +1,67 @@
+use std::io;
+
+/// Why the device refused to store data, reported with `ChatEvent::StorageError`
+/// so the app can tell the user instead of failing silently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    /// The disk or the database is full.
+    Full,
+    /// The app may not write to its files.
+    PermissionDenied,
+    /// The storage only allows reads.
+    ReadOnly,
+    /// SQLite failed to read or write its files.
+    Io,
+}
+
+// errno values, `io::ErrorKind::StorageFull` and `ReadOnlyFilesystem` need a newer toolchain
+const ENOSPC: i32 = 28;
+const EROFS: i32 = 30;
+const EDQUOT: i32 = 122;
+
+// primary SQLite result codes, extended codes keep them in the low byte
+const SQLITE_PERM: i32 = 3;
+const SQLITE_READONLY: i32 = 8;
+const SQLITE_IOERR: i32 = 10;
+const SQLITE_FULL: i32 = 13;
+const SQLITE_CANTOPEN: i32 = 14;
+
+/// Finds a storage failure anywhere in the chain of `err`, `None` for every other
+/// error, including network failures.
+pub fn classify(err: &anyhow::Error) -> Option<StorageErrorKind> {
+    err.chain().find_map(|cause| {
+        if let Some(err) = cause.downcast_ref::<io::Error>() {
+            return classify_io(err);
+        }
+        match cause.downcast_ref::<sqlx::Error>()? {
+            sqlx::Error::Io(err) => classify_io(err),
+            sqlx::Error::Database(err) => {
+                let code: i32 = err.code()?.parse().ok()?;
+                classify_sqlite(code)
+            }
+            _ => None,
+        }
+    })
+}
+
+fn classify_io(err: &io::Error) -> Option<StorageErrorKind> {
+    match err.raw_os_error() {
+        Some(ENOSPC) | Some(EDQUOT) => return Some(StorageErrorKind::Full),
+        Some(EROFS) => return Some(StorageErrorKind::ReadOnly),
+        _ => {}
+    }
+    match err.kind() {
+        io::ErrorKind::PermissionDenied => Some(StorageErrorKind::PermissionDenied),
+        _ => None,
+    }
+}
+
+fn classify_sqlite(code: i32) -> Option<StorageErrorKind> {
+    match code & 0xff {
+        SQLITE_FULL => Some(StorageErrorKind::Full),
+        SQLITE_READONLY => Some(StorageErrorKind::ReadOnly),
+        SQLITE_PERM => Some(StorageErrorKind::PermissionDenied),
+        SQLITE_IOERR | SQLITE_CANTOPEN => Some(StorageErrorKind::Io),
+        _ => None,
+    }
+}
@@ -1,14 +1,23 @@
+use std::sync::Arc;
+
 use anyhow::{anyhow, Result};
-use log::info;
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
 
+use crate::bandwidth::BandwidthMeter;
+use crate::protocol_recorder::{FrameDirection, FrameKind, ProtocolRecorder};
+
 const REQUEST_FRAME: u8 = 0x01;
 const RESPONSE_FRAME: u8 = 0x02;
+/// Frame type and payload length written ahead of every payload.
+const FRAME_HEADER_SIZE: usize = 5;
 
 pub trait MessageEncoding: Sized {
     fn encode_message(&self) -> Vec<u8>;
 
     fn decode_message(bytes: &[u8]) -> Result<Self>;
+
+    /// Name of the message kind, used when recording frames.
+    fn variant_name(&self) -> &'static str;
 }
 
 pub struct StreamProtocol<Stream>
@@ -16,6 +25,8 @@ where
     Stream: AsyncRead + AsyncWrite + Unpin + Send + 'static,
 {
     stream: Option<Stream>,
+    recorder: Option<(Arc<ProtocolRecorder>, String)>,
+    meter: Option<(Arc<BandwidthMeter>, String)>,
 }
 
 impl<Stream> StreamProtocol<Stream>
@@ -25,11 +36,56 @@ where
     pub fn new(stream: Stream) -> Self {
         StreamProtocol {
             stream: Some(stream),
+            recorder: None,
+            meter: None,
         }
     }
 
     pub fn default() -> Self {
-        StreamProtocol { stream: None }
+        StreamProtocol {
+            stream: None,
+            recorder: None,
+            meter: None,
+        }
+    }
+
+    /// Records the frames of this stream with `peer_id`, a no-op without a recorder.
+    pub fn recorded(mut self, recorder: Option<Arc<ProtocolRecorder>>, peer_id: &str) -> Self {
+        self.recorder = recorder.map(|recorder| (recorder, peer_id.to_owned()));
+        self
+    }
+
+    /// Counts the bytes of this stream's frames as traffic with `peer_id`.
+    pub fn metered(mut self, meter: Arc<BandwidthMeter>, peer_id: &str) -> Self {
+        self.meter = Some((meter, peer_id.to_owned()));
+        self
+    }
+
+    fn record(
+        &self,
+        direction: FrameDirection,
+        kind: FrameKind,
+        variant: &str,
+        size: usize,
+        error: Option<&anyhow::Error>,
+    ) {
+        if let (Some((meter, peer_id)), None) = (&self.meter, error) {
+            let bytes = (size + FRAME_HEADER_SIZE) as u64;
+            match direction {
+                FrameDirection::Sent => meter.record_sent(peer_id, bytes),
+                FrameDirection::Received => meter.record_received(peer_id, bytes),
+            }
+        }
+        if let Some((recorder, peer_id)) = &self.recorder {
+            recorder.record(
+                peer_id,
+                direction,
+                kind,
+                variant,
+                size as u64,
+                error.map(|e| e.to_string()),
+            );
+        }
     }
 
     fn get_stream(&mut self) -> &mut Stream {
@@ -41,17 +97,35 @@ where
         M: MessageEncoding,
     {
         let payload = message.encode_message();
-        let stream = self.get_stream();
-        stream.write_all(&[REQUEST_FRAME]).await?;
-
-        let length = payload.len() as u32;
-        stream.write_all(&length.to_be_bytes()).await?;
-        stream.write_all(&payload).await?;
-        stream.flush().await?;
-        Ok(())
+        let res = self.write_frame(REQUEST_FRAME, &payload, true).await;
+        self.record(
+            FrameDirection::Sent,
+            FrameKind::Request,
+            message.variant_name(),
+            payload.len(),
+            res.as_ref().err(),
+        );
+        res
     }
 
     pub async fn read_request<M>(&mut self) -> Result<M>
+    where
+        M: MessageEncoding,
+    {
+        let mut size = 0;
+        let res = self.read_request_frame::<M>(&mut size).await;
+        let variant = res.as_ref().map_or("", |message| message.variant_name());
+        self.record(
+            FrameDirection::Received,
+            FrameKind::Request,
+            variant,
+            size,
+            res.as_ref().err(),
+        );
+        res
+    }
+
+    async fn read_request_frame<M>(&mut self, size: &mut usize) -> Result<M>
     where
         M: MessageEncoding,
     {
@@ -68,6 +142,7 @@ where
         let mut len_buf = [0u8; 4];
         stream.read_exact(&mut len_buf).await?;
         let length = u32::from_be_bytes(len_buf);
+        *size = length as usize;
 
         let mut payload = vec![0u8; length as usize];
         stream.read_exact(&mut payload).await?;
@@ -77,22 +152,63 @@ where
     }
 
     pub async fn send_response<M>(&mut self, message: &M) -> Result<()>
+    where
+        M: MessageEncoding,
+    {
+        self.send_response_no_flush(message).await?;
+        self.flush().await
+    }
+
+    /// Writes a response without flushing the stream, the caller is responsible
+    /// for calling `flush` (or `send_eof`) once the batch is written.
+    pub async fn send_response_no_flush<M>(&mut self, message: &M) -> Result<()>
     where
         M: MessageEncoding,
     {
         let payload = message.encode_message();
+        let res = self.write_frame(RESPONSE_FRAME, &payload, false).await;
+        self.record(
+            FrameDirection::Sent,
+            FrameKind::Response,
+            message.variant_name(),
+            payload.len(),
+            res.as_ref().err(),
+        );
+        res
+    }
+
+    async fn write_frame(&mut self, frame: u8, payload: &[u8], flush: bool) -> Result<()> {
         let stream = self.get_stream();
-        stream.write_all(&[RESPONSE_FRAME]).await?;
+        stream.write_all(&[frame]).await?;
 
         let length = payload.len() as u32;
         stream.write_all(&length.to_be_bytes()).await?;
 
-        stream.write_all(&payload).await?;
-        stream.flush().await?;
+        stream.write_all(payload).await?;
+        if flush {
+            stream.flush().await?;
+        }
+        Ok(())
+    }
+
+    pub async fn flush(&mut self) -> Result<()> {
+        self.get_stream().flush().await?;
         Ok(())
     }
 
     pub async fn send_eof(&mut self) -> Result<()> {
+        let res = self.write_eof().await;
+        self.record(
+            FrameDirection::Sent,
+            FrameKind::Response,
+            "eof",
+            0,
+            res.as_ref().err(),
+        );
+        res
+    }
+
+    async fn write_eof(&mut self) -> Result<()> {
         let stream = self.get_stream();
         stream.write_all(&[RESPONSE_FRAME]).await?;
         let eof = 0xFFFF_FFFFu32.to_be_bytes();
@@ -102,6 +218,27 @@ where
     }
 
     pub async fn read_response<M>(&mut self) -> Result<Option<M>>
+    where
+        M: MessageEncoding,
+    {
+        let mut size = 0;
+        let res = self.read_response_frame::<M>(&mut size).await;
+        let variant = match &res {
+            Ok(Some(message)) => message.variant_name(),
+            Ok(None) => "eof",
+            Err(_) => "",
+        };
+        self.record(
+            FrameDirection::Received,
+            FrameKind::Response,
+            variant,
+            size,
+            res.as_ref().err(),
+        );
+        res
+    }
+
+    async fn read_response_frame<M>(&mut self, size: &mut usize) -> Result<Option<M>>
     where
         M: MessageEncoding,
     {
@@ -124,6 +261,7 @@ where
         if length == 0xFFFF_FFFF {
             return Ok(None);
         }
+        *size = length as usize;
 
         let mut chunk = vec![0u8; length as usize];
         stream.read_exact(&mut chunk).await?;
@@ -1,27 +1,40 @@
-use std::{collections::HashSet, sync::Arc};
+use std::{
+    collections::{HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    time::{Duration, Instant},
+};
 
 use async_trait::async_trait;
 use log::{debug, info, warn};
+use rand::seq::SliceRandom;
 use std::path::Path;
 use tokio::{
     fs,
-    io::{AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
 };
+use tokio_util::sync::CancellationToken;
 use tokio_yamux::StreamHandle;
 
 use crate::peer_database::{Peer, PeerDatabase};
 use crate::{
-    events::Events,
+    bandwidth::{BandwidthLimits, FileThrottle},
+    capabilities::{Capabilities, PeerCapabilities},
+    chat_msg::PROTOCOL_VERSION,
+    events::{Events, FileChunkListener},
     file_resolver::{FileResolverStorage, ResolveResult, ResolveWant},
-    models::DbMessage,
+    models::{repo_author, split_group_repo, DbMessage},
+    outbox::Outbox,
     peer::PeerDelegate,
     peer_pool::EncryptedPool,
     proto::{
         self,
         chat::{chat_message, ChatMessage, ComparePayload},
     },
-    repository_manager::{RepoState, RepositoryManager},
-    request_queue::{AsyncFn, BoxFuture, PeriodicTaskScheduler, RequestQueue, Task},
+    repository_manager::{RepoState, RepositoryManager, UnknownPeerPolicy},
+    request_queue::{AsyncFn, BoxFuture, PeriodicTaskScheduler, RequestQueue, Task, TaskPriority},
     stream_protocol::StreamProtocol,
 };
 
@@ -46,6 +59,14 @@ pub struct SyncMessage {
     pub stored_messages: Vec<DbMessage>,
 }
 
+/// Number of `RequestQueue` workers syncing with peers concurrently.
+pub const SYNC_WORKERS: usize = 10;
+/// Time between sync sweeps over all peers.
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(10);
+/// A download that receives nothing for this long is given up. It has no overall
+/// limit, a throttled file takes as long as the rate needs.
+const DOWNLOAD_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub struct SyncEngine {
     id: String,
     root_path: String,
@@ -56,9 +77,23 @@ pub struct SyncEngine {
     repos: Arc<RepositoryManager>,
     runtime: Arc<tokio::runtime::Runtime>,
     file_storage: Arc<FileResolverStorage>,
+    file_chunk_listener: RwLock<Option<Arc<dyn FileChunkListener>>>,
+    outbox: Arc<Outbox>,
+    events: Arc<Events>,
+    capabilities: Arc<PeerCapabilities>,
+    max_upload_size: Option<u64>,
+    max_upload_in_flight: Option<u64>,
+    // shared by uploads and downloads
+    throttle: Arc<FileThrottle>,
+    // uploads in flight by (peer, file), a repeated request replaces the running one
+    uploads: Mutex<HashMap<(String, String), (u64, CancellationToken)>>,
+    upload_seq: AtomicU64,
+    // cancelled on shutdown, stops downloads in flight
+    shutdown: CancellationToken,
 }
 
 impl SyncEngine {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         id: String,
         root_path: String,
@@ -67,9 +102,15 @@ impl SyncEngine {
         manager: Arc<RepositoryManager>,
         file_storage: Arc<FileResolverStorage>,
         events: Arc<Events>,
+        outbox: Arc<Outbox>,
+        max_upload_size: Option<u64>,
+        max_upload_in_flight: Option<u64>,
+        file_bandwidth: BandwidthLimits,
+        max_pending_tasks: Option<usize>,
         runtime: Arc<tokio::runtime::Runtime>,
     ) -> Self {
-        let rq = Arc::new(RequestQueue::new(10, runtime.clone()));
+        let rq = Arc::new(RequestQueue::new(SYNC_WORKERS, max_pending_tasks, runtime.clone()));
+        let capabilities = Arc::new(PeerCapabilities::default());
 
         let async_task: Arc<AsyncFn> = Arc::new({
             let manager = manager.clone();
@@ -77,6 +118,8 @@ impl SyncEngine {
             let peer_pool = peer_pool.clone();
             let file_storage = file_storage.clone();
             let peer_db = peer_db.clone();
+            let capabilities = capabilities.clone();
+            let events = events.clone();
 
             move || {
                 let manager = manager.clone();
@@ -84,41 +127,27 @@ impl SyncEngine {
                 let peer_pool = peer_pool.clone();
                 let file_storage = file_storage.clone();
                 let peer_db = peer_db.clone();
+                let capabilities = capabilities.clone();
+                let events = events.clone();
                 Box::pin(async move {
-                    let file_ids = file_storage.get_need_resolve().await;
-                    if let Ok(repo_states) = manager.clone().get_repo_states().await {
-                        info!("got repo states {:?}", &repo_states);
-                        let current_peers = peer_pool.all_peers().await;
-                        info!("current peers are {:?}", &current_peers);
-
-                        for peer in current_peers {
-                            let peer_id = peer.clone();
-
-                            let task = CompareStateTask {
-                                peer_id: peer_id.clone(),
-                                repo_states: repo_states.clone(),
-                                peer_db: peer_db.clone(),
-                                pool: peer_pool.clone(),
-                                rq: rq.clone(),
-                                manager: manager.clone(),
-                            };
-                            rq.enqueue(Arc::new(task)).await?;
-
-                            let task = FileWantTask {
-                                peer_id,
-                                file_ids: file_ids.clone(),
-                                pool: peer_pool.clone(),
-                                file_storage: file_storage.clone(),
-                            };
-                            rq.enqueue(Arc::new(task)).await?;
-                        }
-                    }
-                    Ok(())
+                    let current_peers = peer_pool.all_peers().await;
+                    info!("current peers are {:?}", current_peers);
+                    enqueue_sync_tasks(
+                        current_peers,
+                        &manager,
+                        &rq,
+                        &peer_pool,
+                        &file_storage,
+                        &peer_db,
+                        &capabilities,
+                        &events,
+                    )
+                    .await
                 })
             }
         });
 
-        let task_scheduler = PeriodicTaskScheduler::new(async_task, 10, runtime.clone());
+        let task_scheduler = PeriodicTaskScheduler::new(async_task, SYNC_INTERVAL, runtime.clone());
 
         SyncEngine {
             id,
@@ -130,16 +159,131 @@ impl SyncEngine {
             task_scheduler,
             file_storage,
             runtime,
+            file_chunk_listener: RwLock::new(None),
+            outbox,
+            events,
+            capabilities,
+            max_upload_size,
+            max_upload_in_flight,
+            throttle: Arc::new(FileThrottle::new(file_bandwidth)),
+            uploads: Mutex::new(HashMap::new()),
+            upload_seq: AtomicU64::new(0),
+            shutdown: CancellationToken::new(),
         }
     }
 
+    pub fn set_file_chunk_listener(&self, listener: Option<Arc<dyn FileChunkListener>>) {
+        *self.file_chunk_listener.write().unwrap() = listener;
+    }
+
+    pub fn get_outbox(&self) -> Arc<Outbox> {
+        self.outbox.clone()
+    }
+
+    /// When the last sync sweep started, see `health::check`.
+    pub fn last_sweep(&self) -> Option<Instant> {
+        self.task_scheduler.last_tick()
+    }
+
     pub fn get_manager(&self) -> Arc<RepositoryManager> {
         self.repos.clone()
     }
 
-    pub fn run(&self) {
+    /// Queues a state comparison and file want with the peer, or with every known
+    /// peer, without waiting for the periodic sweep. Returns once the tasks are queued.
+    pub async fn sync_now(&self, peer_id: Option<String>) -> anyhow::Result<()> {
+        let peers = match peer_id {
+            Some(peer_id) => vec![peer_id],
+            None => self.peer_pool.all_peers().await,
+        };
+        enqueue_sync_tasks(
+            peers,
+            &self.repos,
+            &self.request_queue,
+            &self.peer_pool,
+            &self.file_storage,
+            &self.peer_db,
+            &self.capabilities,
+            &self.events,
+        )
+        .await
+    }
+
+    pub fn run(self: &Arc<Self>) {
         self.task_scheduler.signal_start();
         self.request_queue.start();
+        self.peer_pool.clone().start_idle_sweep(self.shutdown.clone());
+        let self_clone = self.clone();
+        self.runtime.spawn(async move {
+            if let Err(e) = self_clone.resume_pending().await {
+                warn!("failed to resume pending messages: {:?}", e);
+            }
+        });
+    }
+
+    /// Pushes own messages left unacknowledged before a restart to every peer we can dial.
+    async fn resume_pending(&self) -> anyhow::Result<()> {
+        let reachable = self.peer_pool.all_peers().await;
+        for peer_id in self.outbox.peers_with_pending().await? {
+            if reachable.contains(&peer_id) {
+                self.resend_pending(peer_id).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Aborts downloads in flight, their partial files are removed.
+    pub fn shutdown(&self) {
+        self.shutdown.cancel();
+    }
+
+    /// Registers an upload. A peer asking for a file it is already downloading from
+    /// us is retrying, so the older stream is cancelled and at most one upload per
+    /// peer and file runs at a time.
+    fn start_upload(&self, peer_id: &str, file_id: &str) -> (u64, CancellationToken) {
+        let seq = self.upload_seq.fetch_add(1, Ordering::Relaxed);
+        let cancel = self.shutdown.child_token();
+        let previous = self
+            .uploads
+            .lock()
+            .unwrap()
+            .insert((peer_id.to_owned(), file_id.to_owned()), (seq, cancel.clone()));
+        if let Some((_, previous)) = previous {
+            previous.cancel();
+        }
+        (seq, cancel)
+    }
+
+    fn finish_upload(&self, peer_id: &str, file_id: &str, seq: u64) {
+        let mut uploads = self.uploads.lock().unwrap();
+        let key = (peer_id.to_owned(), file_id.to_owned());
+        if uploads.get(&key).is_some_and(|(current, _)| *current == seq) {
+            uploads.remove(&key);
+        }
+    }
+
+    /// Stores messages `peer_id` pushed to us, returns our counter of their repository.
+    async fn receive_messages(
+        &self,
+        peer_id: &str,
+        msg: crate::proto::chat::Messages,
+    ) -> anyhow::Result<u64> {
+        let policy = self.repos.unknown_peer_policy();
+        // decided before the record sent along is saved, which only counts from the next pull
+        let accept = accepts_author(policy, &self.peer_db, repo_author(&msg.peer_id)).await?;
+        if let Some(peer) = msg.peer {
+            save_author_record(policy, &self.peer_db, &msg.peer_id, peer).await?;
+        }
+        let repo = self.repos.clone().get_repository(&msg.peer_id).await?;
+        let guard = repo.lock().await;
+        let db_messages: Vec<DbMessage> = msg.messages.into_iter().map(|m| m.into()).collect();
+        if !accept {
+            // our counter doesn't move, the messages come again with a pull
+            info!("holding messages of unknown peer {}", msg.peer_id);
+        } else if let Err(err) = guard.insert_message_batch(&db_messages).await {
+            info!("failed to save messages: {} {:?}", peer_id, err);
+        }
+        Ok(guard.get_counter())
     }
 
     pub async fn handle_request(
@@ -147,9 +291,23 @@ impl SyncEngine {
         stream: StreamHandle,
         peer_id: String,
     ) -> anyhow::Result<()> {
-        let mut protocol = StreamProtocol::new(stream);
+        let mut protocol = StreamProtocol::new(stream)
+            .recorded(self.peer_pool.recorder(), &peer_id)
+            .metered(self.peer_pool.meter(), &peer_id);
         let req = protocol.read_request::<ChatMessage>().await?;
-        let req = req.variant.unwrap();
+        if let Err(err) = req.check_version() {
+            // our answer carries our version, so the peer can tell why it failed
+            warn!("rejecting request from {}: {}", peer_id, err);
+            return send_unsupported(&mut protocol).await;
+        }
+        let req = match req.variant {
+            Some(req) => req,
+            None => {
+                // a variant added after this version, prost leaves it out
+                info!("unknown request from {}", peer_id);
+                return send_unsupported(&mut protocol).await;
+            }
+        };
         match req {
             chat_message::Variant::FileDownloadRequest(req) => {
                 info!("receive download request: {:?}", req);
@@ -163,32 +321,38 @@ impl SyncEngine {
                     .join(&full_path.local_path)
                     .to_string_lossy()
                     .to_string();
-                return upload_file(&mut protocol, &full_path).await;
+                // only downloaders that acknowledge chunks can be held to a window
+                let window = self.max_upload_in_flight.filter(|_| req.flow_control);
+                let (seq, cancel) = self.start_upload(&peer_id, &req.file_id);
+                let res = tokio::select! {
+                    _ = cancel.cancelled() => {
+                        info!("upload of {} to {} replaced", req.file_id, peer_id);
+                        Ok(())
+                    }
+                    res = upload_file(
+                        &mut protocol,
+                        &full_path,
+                        self.max_upload_size,
+                        window,
+                        (self.throttle.as_ref(), &peer_id),
+                    ) => res,
+                };
+                self.finish_upload(&peer_id, &req.file_id, seq);
+                res
             }
             chat_message::Variant::Messages(msg) => {
-                if let Some(peer) = msg.peer {
-                    let peer = Peer::new(peer.id, peer.name, peer.pub_key)?;
-                    info!("saving peer {:?}", &peer);
-                    self.peer_db.save_peer(&peer).await?;
-                }
-                let repo = self.repos.clone().get_repository(&msg.peer_id).await?;
-                let guard = repo.lock().await;
-                let db_messages: Vec<DbMessage> =
-                    msg.messages.into_iter().map(|m| m.into()).collect();
-                if let Err(err) = guard.insert_message_batch(&db_messages).await {
-                    info!("failed to save messages: {} {:?}", &peer_id, err);
-                }
+                let counter = self.receive_messages(&peer_id, msg).await?;
                 let resp = ChatMessage {
+                    protocol_version: PROTOCOL_VERSION,
                     variant: Some(chat_message::Variant::MessageAccept(
                         crate::proto::chat::MessageAccept {
-                            counter: guard.get_counter() as i32,
+                            counter: counter as i32,
                         },
                     )),
                 };
-                drop(guard);
                 protocol.send_response::<ChatMessage>(&resp).await?;
                 protocol.send_eof().await?;
-                return Ok(());
+                Ok(())
             }
             chat_message::Variant::FileWantRequest(msg) => {
                 let all_file_ids = self.file_storage.file_db.all_file_ids().await?;
@@ -203,83 +367,100 @@ impl SyncEngine {
                     }
                 }
                 let resp = ChatMessage {
+                    protocol_version: PROTOCOL_VERSION,
                     variant: Some(chat_message::Variant::FileWantResponse(
                         crate::proto::chat::FileWantResponse { file_id: result },
                     )),
                 };
                 protocol.send_response(&resp).await?;
                 protocol.send_eof().await?;
-                return Ok(());
+                Ok(())
             }
             chat_message::Variant::BatchMessageRequest(msg) => {
                 let repo = self.repos.clone().get_repository(&msg.peer_id).await?;
                 let guard = repo.lock().await;
                 let my_counter = guard.get_counter();
                 let their_counter = msg.my_counter as u64;
-                let resp: ChatMessage;
-                if their_counter >= my_counter {
-                    resp = ChatMessage {
+                // a group's messages only go to its members, others are told there is nothing
+                let shared = self.repos.shared_with(&msg.peer_id, &peer_id).await?;
+                let resp: ChatMessage = if their_counter >= my_counter || !shared {
+                    ChatMessage {
+                        protocol_version: PROTOCOL_VERSION,
                         variant: Some(chat_message::Variant::BatchMessageResponse(
                             crate::proto::chat::BatchMessageResponse {
                                 messages: vec![],
                                 peer: None,
                             },
                         )),
-                    };
+                    }
                 } else {
                     let mut peer = None;
-                    if their_counter == 0 {
-                        peer = self.peer_db.get_peer_by_id(&msg.peer_id).await?;
+                    if their_counter == 0 || msg.want_peer {
+                        peer = self.peer_db.get_peer_by_id(repo_author(&msg.peer_id)).await?;
                     }
-                    let messages = guard.get_messages(their_counter).await?;
+                    let messages = guard.get_messages(their_counter, msg.limit).await?;
                     let resp_messages = messages.into_iter().map(|m| m.into()).collect();
-                    resp = ChatMessage {
+                    ChatMessage {
+                        protocol_version: PROTOCOL_VERSION,
                         variant: Some(chat_message::Variant::BatchMessageResponse(
                             crate::proto::chat::BatchMessageResponse {
                                 messages: resp_messages,
                                 peer: peer.map(|p| p.into()),
                             },
                         )),
-                    };
-                }
+                    }
+                };
                 drop(guard);
                 protocol.send_response(&resp).await?;
                 protocol.send_eof().await?;
-                return Ok(());
+                Ok(())
             }
             chat_message::Variant::CompareRequest(msg) => {
+                self.capabilities
+                    .set(&peer_id, Capabilities::from_bits(msg.capabilities));
                 let my_states = self.repos.clone().get_repo_states().await?;
                 let mut peer_ids = vec![];
+                let mut counters = vec![];
                 for state in my_states {
+                    if !self.repos.shared_with(&state.peer_id, &peer_id).await? {
+                        continue;
+                    }
                     let mut spotted = false;
-                    let state_id = state.peer_id.clone();
+                    let mut behind = false;
                     for other_state in &msg.compare_payload {
                         if other_state.peer_id == state.peer_id {
                             spotted = true;
-                            if other_state.counter < state.counter as i32 {
-                                peer_ids.push(state_id);
-                                break;
-                            }
+                            behind = other_state.counter < state.counter as i32;
+                            break;
                         }
                     }
-                    if !spotted {
+                    if behind || !spotted {
                         peer_ids.push(state.peer_id.clone());
+                        counters.push(ComparePayload {
+                            counter: state.counter as i32,
+                            peer_id: state.peer_id,
+                        });
                     }
                 }
                 let resp = ChatMessage {
+                    protocol_version: PROTOCOL_VERSION,
                     variant: Some(chat_message::Variant::CompareResponse(
-                        crate::proto::chat::CompareResponse { peer_ids },
+                        crate::proto::chat::CompareResponse {
+                            peer_ids,
+                            capabilities: Capabilities::LOCAL.bits(),
+                            counters,
+                        },
                     )),
                 };
                 protocol.send_response(&resp).await?;
                 protocol.send_eof().await?;
-                return Ok(());
+                Ok(())
             }
             _ => {
-                warn!("unknown message");
-                return Err(anyhow::anyhow!("something went wrong!"));
+                warn!("unexpected request from {}", peer_id);
+                return send_unsupported(&mut protocol).await;
             }
-        };
+        }
     }
 }
 
@@ -294,7 +475,7 @@ impl FileProvider for SyncEngine {
     ) -> anyhow::Result<()> {
         info!(
             "resolve: downloading file {} to {}, peer_ids {:?}",
-            file_id, &self.root_path, peer_ids
+            file_id, self.root_path, peer_ids
         );
         let task = FileTask {
             index_sender: to_index_send,
@@ -304,6 +485,10 @@ impl FileProvider for SyncEngine {
             folder: self.root_path.clone(),
             peer_ids,
             pool: self.peer_pool.clone(),
+            chunk_listener: self.file_chunk_listener.read().unwrap().clone(),
+            events: self.events.clone(),
+            throttle: self.throttle.clone(),
+            cancel: self.shutdown.child_token(),
         };
         self.request_queue.enqueue(Arc::new(task)).await?;
         Ok(())
@@ -313,19 +498,35 @@ impl FileProvider for SyncEngine {
 #[async_trait]
 impl MessageBroadcaster for SyncEngine {
     async fn message_broadcast(self: Arc<Self>, sync_message: SyncMessage) -> anyhow::Result<()> {
-        if self.id != sync_message.stored_messages[0].peer_id {
+        let repo_id = &sync_message.stored_messages[0].peer_id;
+        if self.id != repo_author(repo_id) {
             return Ok(());
         }
         let current_peers = self.peer_pool.current_peers().await;
         if sync_message.stored_messages.is_empty() {
             panic!("empty messages");
         }
+        if split_group_repo(repo_id).is_none() {
+            // offline peers are tracked too, they get the messages once they are back
+            self.outbox
+                .track(&self.peer_pool.all_peers().await, &sync_message.stored_messages)
+                .await?;
+        }
         for peer in current_peers {
+            // members that miss a group's push pick it up on the next compare
+            if !self.repos.shared_with(repo_id, &peer).await? {
+                continue;
+            }
+            // peers that can't take pushes pick the messages up on the next compare
+            if !self.capabilities.get(&peer).contains(Capabilities::MESSAGE_PUSH) {
+                continue;
+            }
             let task = MessageTask {
                 peer_id: peer.clone(),
                 peer_db: self.peer_db.clone(),
                 messages: sync_message.stored_messages.clone(),
                 pool: self.peer_pool.clone(),
+                outbox: self.outbox.clone(),
             };
             self.request_queue.enqueue(Arc::new(task)).await?;
         }
@@ -347,111 +548,430 @@ impl PeerDelegate for SyncEngine {
         });
         Ok(())
     }
+
+    fn session_corrupted(self: Arc<Self>, peer_id: String) {
+        let peer_pool = self.peer_pool.clone();
+        self.runtime.spawn(async move {
+            peer_pool.handle_decrypt_failure(&peer_id).await;
+        });
+    }
+
+    fn peer_connected(self: Arc<Self>, peer_id: String) {
+        let self_clone = self.clone();
+        self.runtime.spawn(async move {
+            if let Err(e) = self_clone.peer_db.set_last_seen(&peer_id).await {
+                warn!("failed to record last seen of {}: {:?}", peer_id, e);
+            }
+            if let Err(e) = self_clone.resend_pending(peer_id).await {
+                warn!("failed to resend pending messages: {:?}", e);
+            }
+        });
+    }
 }
 
-pub async fn upload_file(
-    protocol: &mut StreamProtocol<StreamHandle>,
-    filename: &str,
+impl SyncEngine {
+    /// Retries the own messages a peer hasn't acknowledged yet, e.g. ones written while
+    /// it was offline. Call when a peer's address becomes known.
+    pub async fn resend_pending(&self, peer_id: String) -> anyhow::Result<()> {
+        if !self.capabilities.get(&peer_id).contains(Capabilities::MESSAGE_PUSH) {
+            return Ok(());
+        }
+        let pending = self.outbox.pending_for(&peer_id).await?;
+        if pending.is_empty() {
+            return Ok(());
+        }
+        let task = MessageTask {
+            peer_id,
+            peer_db: self.peer_db.clone(),
+            messages: pending,
+            pool: self.peer_pool.clone(),
+            outbox: self.outbox.clone(),
+        };
+        self.request_queue.enqueue(Arc::new(task)).await
+    }
+}
+
+/// Whether messages written by `author` may be stored under `policy`.
+async fn accepts_author(
+    policy: UnknownPeerPolicy,
+    peer_db: &PeerDatabase,
+    author: &str,
+) -> anyhow::Result<bool> {
+    if policy == UnknownPeerPolicy::Accept {
+        return Ok(true);
+    }
+    Ok(peer_db.get_peer_by_id(author).await?.is_some())
+}
+
+/// Saves the record of a repository's author that came with its messages. Under
+/// `Reject` records only arrive some other way, and a record of anyone but the
+/// author is ignored, so a peer can't add or rename others along with messages.
+async fn save_author_record(
+    policy: UnknownPeerPolicy,
+    peer_db: &PeerDatabase,
+    repo_id: &str,
+    peer: crate::proto::chat::Peer,
 ) -> anyhow::Result<()> {
+    if policy == UnknownPeerPolicy::Reject {
+        return Ok(());
+    }
+    let peer = peer_db.new_peer(peer.name, peer.pub_key)?;
+    if peer.id != repo_author(repo_id) {
+        warn!("ignoring record of {} sent along with {}", peer.id, repo_id);
+        return Ok(());
+    }
+    info!("saving peer {:?}", peer);
+    peer_db.save_peer(&peer).await
+}
+
+/// Peers asked for missing files per sync cycle.
+const FILE_WANT_PEERS_PER_CYCLE: usize = 3;
+
+/// The files to ask each peer for this cycle. A random few peers are asked, so every
+/// peer gets asked over time without each cycle sending one request per peer, and
+/// only for the files they aren't already known to have.
+async fn file_wants(
+    peers: &[String],
+    file_storage: &FileResolverStorage,
+    capabilities: &PeerCapabilities,
+) -> HashMap<String, Vec<String>> {
+    let file_ids = file_storage.get_need_resolve().await;
+    let want_peers: Vec<&String> = peers
+        .choose_multiple(&mut rand::thread_rng(), FILE_WANT_PEERS_PER_CYCLE)
+        .collect();
+    let mut wants = HashMap::new();
+    for peer_id in want_peers {
+        if !capabilities.get(peer_id).contains(Capabilities::FILE_WANT) {
+            continue;
+        }
+        let file_ids = file_storage.unknown_to(peer_id, &file_ids).await;
+        if !file_ids.is_empty() {
+            wants.insert(peer_id.clone(), file_ids);
+        }
+    }
+    wants
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn enqueue_sync_tasks(
+    peers: Vec<String>,
+    manager: &Arc<RepositoryManager>,
+    rq: &Arc<RequestQueue>,
+    peer_pool: &Arc<EncryptedPool>,
+    file_storage: &Arc<FileResolverStorage>,
+    peer_db: &Arc<PeerDatabase>,
+    capabilities: &Arc<PeerCapabilities>,
+    events: &Arc<Events>,
+) -> anyhow::Result<()> {
+    let mut wants = file_wants(&peers, file_storage, capabilities).await;
+    if let Ok(repo_states) = manager.clone().get_repo_states().await {
+        info!("got repo states {:?}", repo_states);
+        for peer_id in peers {
+            let task = CompareStateTask {
+                peer_id: peer_id.clone(),
+                repo_states: repo_states.clone(),
+                peer_db: peer_db.clone(),
+                pool: peer_pool.clone(),
+                rq: rq.clone(),
+                manager: manager.clone(),
+                capabilities: capabilities.clone(),
+                events: events.clone(),
+            };
+            rq.enqueue(Arc::new(task)).await?;
+
+            let Some(file_ids) = wants.remove(&peer_id) else {
+                continue;
+            };
+            let task = FileWantTask {
+                peer_id,
+                file_ids,
+                pool: peer_pool.clone(),
+                file_storage: file_storage.clone(),
+            };
+            rq.enqueue(Arc::new(task)).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Answers a request this version can't handle. Only the stream is closed, the
+/// session and the peer's other streams carry on.
+async fn send_unsupported(protocol: &mut StreamProtocol<StreamHandle>) -> anyhow::Result<()> {
+    let resp = ChatMessage {
+        protocol_version: PROTOCOL_VERSION,
+        variant: Some(chat_message::Variant::Unsupported(
+            crate::proto::chat::Unsupported {},
+        )),
+    };
+    protocol.send_response(&resp).await?;
+    protocol.send_eof().await?;
+    Ok(())
+}
+
+/// Variant of a response, failing when the peer speaks another wire format.
+fn response_variant(resp: Option<ChatMessage>) -> anyhow::Result<Option<chat_message::Variant>> {
+    match resp {
+        Some(resp) => {
+            resp.check_version()?;
+            Ok(resp.variant)
+        }
+        None => Ok(None),
+    }
+}
+
+/// Bytes of a file sent in one `FileDownloadResponse`.
+pub const UPLOAD_CHUNK_SIZE: usize = 8192;
+/// Number of file chunks written before the stream is flushed.
+pub const UPLOAD_FLUSH_EVERY_CHUNKS: usize = 16;
+
+/// Streams a file in `UPLOAD_CHUNK_SIZE` chunks, or refuses it when it is larger than `max_size`.
+/// With a `window` at most that many bytes are sent ahead of the downloader's
+/// `FileDownloadAck`, so a slow downloader holds the upload back instead of letting
+/// it pile up in buffers. Every chunk first waits for the `throttle` of the downloader.
+pub async fn upload_file<S>(
+    protocol: &mut StreamProtocol<S>,
+    filename: &str,
+    max_size: Option<u64>,
+    window: Option<u64>,
+    throttle: (&FileThrottle, &str),
+) -> anyhow::Result<()>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
     let ext = Path::new(filename)
         .extension()
         .and_then(|e| e.to_str())
         .unwrap_or("");
     let mut file = tokio::fs::File::open(&filename).await?;
-    let mut buffer = [0u8; 8192];
+    let size = file.metadata().await?.len();
+    if max_size.is_some_and(|max| size > max) {
+        warn!("refusing to serve {}, {} bytes is above the upload limit", filename, size);
+        let refusal = ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
+            variant: Some(chat_message::Variant::FileDownloadResponse(
+                crate::proto::chat::FileDownloadResponse {
+                    ext: ext.to_string(),
+                    size,
+                    refused: true,
+                    last_chunk: true,
+                    ..Default::default()
+                },
+            )),
+        };
+        protocol.send_response(&refusal).await?;
+        protocol.send_eof().await?;
+        return Ok(());
+    }
+    // a window below a chunk could never be met
+    let window = window.map(|window| window.max(UPLOAD_CHUNK_SIZE as u64));
+    let mut buffer = [0u8; UPLOAD_CHUNK_SIZE];
+    let mut unflushed = 0;
+    let mut sent: u64 = 0;
+    let mut acked: u64 = 0;
     loop {
         let n = file.read(&mut buffer).await?;
         if n == 0 {
             let final_chunk = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
                 variant: Some(chat_message::Variant::FileDownloadResponse(
                     crate::proto::chat::FileDownloadResponse {
                         ext: ext.to_string(),
                         chunk: vec![],
                         last_chunk: true,
+                        size,
+                        refused: false,
+                        window: window.unwrap_or(0),
                     },
                 )),
             };
-            protocol.send_response(&final_chunk).await?;
+            protocol.send_response_no_flush(&final_chunk).await?;
             protocol.send_eof().await?;
             break;
         }
+        if let Some(window) = window {
+            while sent + n as u64 - acked > window {
+                // what is buffered must reach the downloader before it can ack it
+                protocol.flush().await?;
+                unflushed = 0;
+                acked = acked.max(read_download_ack(protocol).await?);
+            }
+        }
+        let (throttle, peer_id) = throttle;
+        throttle.acquire(peer_id, n as u64).await;
         let chunk_proto = ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
             variant: Some(chat_message::Variant::FileDownloadResponse(
                 crate::proto::chat::FileDownloadResponse {
                     ext: ext.to_string(),
                     chunk: buffer[..n].to_vec(),
                     last_chunk: false,
+                    size,
+                    refused: false,
+                    window: window.unwrap_or(0),
                 },
             )),
         };
-        protocol.send_response(&chunk_proto).await?;
+        protocol.send_response_no_flush(&chunk_proto).await?;
+        sent += n as u64;
+        unflushed += 1;
+        if unflushed == UPLOAD_FLUSH_EVERY_CHUNKS {
+            protocol.flush().await?;
+            unflushed = 0;
+        }
     }
     Ok(())
 }
 
+/// Waits for the downloader to confirm a part of the file, returns the bytes it has.
+async fn read_download_ack<S>(protocol: &mut StreamProtocol<S>) -> anyhow::Result<u64>
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let ack = protocol.read_request::<ChatMessage>().await?;
+    match ack.variant {
+        Some(chat_message::Variant::FileDownloadAck(ack)) => Ok(ack.received),
+        _ => Err(anyhow::anyhow!("expected a download ack")),
+    }
+}
+
+/// Fails a download the uploader refused or that streams past the size it announced
+/// first, returns the size the following chunks are held to.
+fn check_download_chunk(
+    file_id: &str,
+    advertised_size: Option<u64>,
+    offset: u64,
+    resp: &proto::chat::FileDownloadResponse,
+) -> anyhow::Result<Option<u64>> {
+    if resp.refused {
+        return Err(anyhow::anyhow!("peer refused to serve {}", file_id));
+    }
+    let advertised_size = advertised_size.or(Some(resp.size).filter(|size| *size > 0));
+    let end = offset + resp.chunk.len() as u64;
+    if let Some(size) = advertised_size.filter(|size| end > *size) {
+        return Err(anyhow::anyhow!(
+            "{} exceeds its advertised size of {} bytes",
+            file_id,
+            size
+        ));
+    }
+    Ok(advertised_size)
+}
+
+/// Messages asked for per `BatchMessageRequest`, a long history arrives in pages
+/// with a `ChatEvent::SyncProgress` after each.
+const BATCH_PAGE_SIZE: u32 = 500;
+
 pub struct BatchRequestTask {
     pub counter: u64,
+    /// The peer's counter of the repository from the compare response, `None` if
+    /// it is too old to send one.
+    pub target: Option<u64>,
     pub peer_id: String,
     pub repo_id: String,
     pub pool: Arc<EncryptedPool>,
     pub peer_db: Arc<PeerDatabase>,
     pub repo_manager: Arc<RepositoryManager>,
+    pub events: Arc<Events>,
+}
+
+impl BatchRequestTask {
+    /// Fetches and stores a page of the messages after `counter`, returns our counter
+    /// afterwards. It doesn't move if the peer had nothing new or the messages were dropped.
+    async fn fetch_page(&self, counter: u64) -> anyhow::Result<u64> {
+        let pool = self.pool.clone();
+        let peer = pool.get(&self.peer_id).await?;
+        let stream = peer.open_stream().await?;
+        let mut protocol = StreamProtocol::new(stream)
+            .recorded(pool.recorder(), &self.peer_id)
+            .metered(pool.meter(), &self.peer_id);
+        let policy = self.repo_manager.unknown_peer_policy();
+        let want_peer = policy == UnknownPeerPolicy::FetchFirst
+            && counter != 0
+            && self.peer_db.get_peer_by_id(repo_author(&self.repo_id)).await?.is_none();
+        let req = ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
+            variant: Some(chat_message::Variant::BatchMessageRequest(
+                crate::proto::chat::BatchMessageRequest {
+                    my_counter: counter as i32,
+                    peer_id: self.repo_id.clone(),
+                    want_peer,
+                    limit: BATCH_PAGE_SIZE,
+                },
+            )),
+        };
+        protocol.send_request(&req).await?;
+        debug!(
+            "sent request {:?}, peer {}, repo {}",
+            req, self.peer_id, self.repo_id
+        );
+        let resp = protocol
+            .read_response::<ChatMessage>()
+            .await
+            .and_then(response_variant)?;
+        if resp.is_none() {
+            return Err(anyhow::anyhow!("unexpected response"));
+        }
+        match resp.unwrap() {
+            chat_message::Variant::BatchMessageResponse(resp) => {
+                let messages: Vec<DbMessage> =
+                    resp.messages.into_iter().map(|m| m.into()).collect();
+                info!(
+                    "received response, peer {}, repo {}",
+                    self.peer_id, self.repo_id
+                );
+                if let Some(peer) = resp.peer {
+                    save_author_record(policy, &self.peer_db, &self.repo_id, peer).await?;
+                }
+                if !accepts_author(policy, &self.peer_db, repo_author(&self.repo_id)).await? {
+                    info!("dropping messages of unknown peer {}", self.repo_id);
+                    return Ok(counter);
+                }
+                let repo = self
+                    .repo_manager
+                    .clone()
+                    .get_repository(&self.repo_id)
+                    .await?;
+                let guard = repo.lock().await;
+                guard.insert_message_batch(&messages).await?;
+                Ok(guard.get_counter())
+            }
+            _ => Err(anyhow::anyhow!("unexpected response")),
+        }
+    }
 }
 
 impl Task for BatchRequestTask {
     fn run(self: Arc<Self>) -> BoxFuture<'static, anyhow::Result<()>> {
         let self_clone = self.clone();
         Box::pin(async move {
-            let pool = self_clone.pool.clone();
-            let peer = pool.get(&self_clone.peer_id).await?;
-            let stream = peer.open_stream().await?;
-            let mut protocol = StreamProtocol::new(stream);
-            let req = ChatMessage {
-                variant: Some(chat_message::Variant::BatchMessageRequest(
-                    crate::proto::chat::BatchMessageRequest {
-                        my_counter: self_clone.counter as i32,
-                        peer_id: self_clone.repo_id.clone(),
-                    },
-                )),
-            };
-            protocol.send_request(&req).await?;
-            debug!(
-                "sent request {:?}, peer {}, repo {}",
-                &req, &self_clone.peer_id, &self_clone.repo_id
-            );
-            let resp = protocol
-                .read_response::<ChatMessage>()
-                .await?
-                .and_then(|r| r.variant);
-            if resp.is_none() {
-                return Err(anyhow::anyhow!("unexpected response"));
-            }
-            match resp.unwrap() {
-                chat_message::Variant::BatchMessageResponse(resp) => {
-                    let messages: Vec<DbMessage> =
-                        resp.messages.into_iter().map(|m| m.into()).collect();
-                    info!(
-                        "received response, peer {}, repo {}",
-                        &self_clone.peer_id, &self_clone.repo_id
-                    );
-                    if let Some(peer) = resp.peer {
-                        let peer = Peer::new(peer.id, peer.name, peer.pub_key)?;
-                        info!("saving peer {:?}", &peer);
-                        self_clone.peer_db.save_peer(&peer).await?;
-                    }
-                    let repo = self_clone
-                        .repo_manager
-                        .clone()
-                        .get_repository(&self_clone.repo_id)
-                        .await?;
-                    let guard = repo.lock().await;
-                    guard.insert_message_batch(&messages).await?;
+            let mut counter = self_clone.counter;
+            loop {
+                let next = self_clone.fetch_page(counter).await?;
+                if next <= counter {
+                    return Ok(());
+                }
+                counter = next;
+                let received = counter - self_clone.counter;
+                let estimated_total = self_clone
+                    .target
+                    .map(|target| target.saturating_sub(self_clone.counter).max(received));
+                if let Err(e) = self_clone
+                    .events
+                    .send_sync_progress(self_clone.repo_id.clone(), received, estimated_total)
+                    .await
+                {
+                    warn!("failed to send sync progress: {:?}", e);
+                }
+                if self_clone.target.is_some_and(|target| counter >= target) {
+                    return Ok(());
                 }
-                _ => return Err(anyhow::anyhow!("unexpected response")),
             }
-            Ok(())
         })
     }
+
+    fn priority(&self) -> TaskPriority {
+        TaskPriority::Background
+    }
 }
 
 pub struct MessageTask {
@@ -459,6 +979,7 @@ pub struct MessageTask {
     pub peer_db: Arc<PeerDatabase>,
     pub messages: Vec<DbMessage>,
     pub pool: Arc<EncryptedPool>,
+    pub outbox: Arc<Outbox>,
 }
 
 impl Task for MessageTask {
@@ -471,17 +992,20 @@ impl Task for MessageTask {
                 Ok(peer) => peer,
                 Err(e) => {
                     warn!("Failed to get peer: {:?}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             };
             let stream = peer.open_stream().await?;
-            let mut protocol = StreamProtocol::new(stream);
+            let mut protocol = StreamProtocol::new(stream)
+                .recorded(pool.recorder(), &peer_id)
+                .metered(pool.meter(), &peer_id);
             let peer_id = self_clone.messages[0].peer_id.clone();
             let mut peer: Option<Peer> = None;
             if self_clone.messages[0].counter == 0 {
                 peer = self_clone.peer_db.get_peer_by_id(&peer_id).await?;
             }
             let req = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
                 variant: Some(chat_message::Variant::Messages(proto::chat::Messages {
                     messages: self_clone
                         .messages
@@ -495,8 +1019,8 @@ impl Task for MessageTask {
             protocol.send_request(&req).await?;
             let resp = protocol
                 .read_response::<ChatMessage>()
-                .await?
-                .and_then(|r| r.variant);
+                .await
+                .and_then(response_variant)?;
             if resp.is_none() {
                 return Err(anyhow::anyhow!("unexpected response"));
             }
@@ -504,11 +1028,18 @@ impl Task for MessageTask {
                 chat_message::Variant::MessageAccept(resp) => {
                     info!(
                         "received response, {:?}, peer {}",
-                        resp, &self_clone.peer_id
+                        resp, self_clone.peer_id
                     );
-                    return Ok(());
+                    // the outbox only follows our own repository
+                    if split_group_repo(&self_clone.messages[0].peer_id).is_none() {
+                        self_clone
+                            .outbox
+                            .acknowledge(&self_clone.peer_id, resp.counter as u64)
+                            .await?;
+                    }
+                    Ok(())
                 }
-                _ => return Err(anyhow::anyhow!("unexpected response")),
+                _ => Err(anyhow::anyhow!("unexpected response")),
             }
         })
     }
@@ -522,6 +1053,10 @@ pub struct FileTask {
     resolve_sender: Arc<flume::Sender<ResolveWant>>,
     file_storage: Arc<FileResolverStorage>,
     pool: Arc<EncryptedPool>,
+    chunk_listener: Option<Arc<dyn FileChunkListener>>,
+    events: Arc<Events>,
+    throttle: Arc<FileThrottle>,
+    cancel: CancellationToken,
 }
 
 impl FileTask {
@@ -529,45 +1064,86 @@ impl FileTask {
         let pool = self.pool.clone();
         let peer = pool.get(&peer_id).await?;
         let stream = peer.open_stream().await?;
-        let mut protocol = StreamProtocol::new(stream);
+        let mut protocol = StreamProtocol::new(stream)
+            .recorded(pool.recorder(), &peer_id)
+            .metered(pool.meter(), &peer_id);
         let req = ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
             variant: Some(chat_message::Variant::FileDownloadRequest(
                 crate::proto::chat::FileDownloadRequest {
                     file_id: self.file_id.clone(),
                     peer_id: peer_id.clone(),
+                    flow_control: true,
                 },
             )),
         };
         protocol.send_request(&req).await?;
         let mut file = tokio::fs::File::create(&path).await?;
         let mut ext: String = "".to_string();
+        let mut offset: u64 = 0;
+        let mut advertised_size: Option<u64> = None;
+        let mut acked: u64 = 0;
         loop {
-            let resp = protocol.read_response::<ChatMessage>().await?;
+            let resp = tokio::select! {
+                _ = self.cancel.cancelled() => {
+                    return Err(anyhow::anyhow!("download of {} cancelled", self.file_id));
+                }
+                resp = tokio::time::timeout(
+                    DOWNLOAD_IDLE_TIMEOUT,
+                    protocol.read_response::<ChatMessage>(),
+                ) => resp.map_err(|_| anyhow::anyhow!("download of {} stalled", self.file_id))??,
+            };
             if resp.is_none() {
                 break;
             }
-            match resp.and_then(|r| r.variant) {
-                Some(resp) => match resp {
-                    chat_message::Variant::FileDownloadResponse(resp) => {
-                        ext = resp.ext.clone();
-                        file.write_all(&resp.chunk).await?;
+            match response_variant(resp)? {
+                Some(chat_message::Variant::FileDownloadResponse(resp)) => {
+                    advertised_size =
+                        check_download_chunk(&self.file_id, advertised_size, offset, &resp)?;
+                    ext = resp.ext.clone();
+                    // not reading on slows the uploader down through the window
+                    self.throttle.acquire(&peer_id, resp.chunk.len() as u64).await;
+                    file.write_all(&resp.chunk).await?;
+                    if let Some(listener) = &self.chunk_listener {
+                        if !resp.chunk.is_empty() {
+                            listener.on_file_chunk(&self.file_id, offset, &resp.chunk);
+                        }
                     }
-                    _ => return Err(anyhow::anyhow!("unexpected response")),
-                },
+                    offset += resp.chunk.len() as u64;
+                    // acking at half the window keeps the uploader from ever
+                    // running dry while it waits
+                    if resp.window > 0
+                        && !resp.last_chunk
+                        && offset - acked >= resp.window / 2
+                    {
+                        let ack = ChatMessage {
+                            protocol_version: PROTOCOL_VERSION,
+                            variant: Some(chat_message::Variant::FileDownloadAck(
+                                crate::proto::chat::FileDownloadAck { received: offset },
+                            )),
+                        };
+                        // an uploader that already sent everything may have closed
+                        // the stream, the rest of the file is still readable
+                        if let Err(e) = protocol.send_request(&ack).await {
+                            debug!("failed to ack {} at {}: {:?}", self.file_id, offset, e);
+                        }
+                        acked = offset;
+                    }
+                }
                 _ => return Err(anyhow::anyhow!("unexpected response")),
             }
         }
-        let new_path = format!("{}.{}", &path, &ext);
+        let new_path = format!("{}.{}", path, ext);
         fs::rename(&path, &new_path).await?;
-        info!("renaming {} to {}", &path, &new_path);
+        info!("renaming {} to {}", path, new_path);
         let local_path = &new_path[self.folder.len() + 1..];
         self.file_storage
             .file_db
-            .save(&crate::file_database::FileDescription {
+            .save_owned(&crate::file_database::FileDescription {
                 id: self.file_id.clone(),
                 format: ext.clone(),
                 local_path: local_path.to_owned(),
-                timestamp: chrono::Utc::now().timestamp(),
+                timestamp: self.file_storage.clock.timestamp(),
             })
             .await?;
         Ok(local_path.to_string())
@@ -587,7 +1163,9 @@ impl Task for FileTask {
                     .await
                 {
                     Ok(res) => {
-                        self.index_sender
+                        // fails only once the resolver stopped on shutdown
+                        let _ = self
+                            .index_sender
                             .send_async(ResolveResult {
                                 file_id: self.file_id.clone(),
                                 file_path: res,
@@ -596,12 +1174,20 @@ impl Task for FileTask {
                         return Ok(());
                     }
                     Err(e) => {
-                        info!("failed to download file: {:?}, {}", e, &peer_id);
-                        tokio::fs::remove_file(path.clone()).await;
+                        info!("failed to download file: {:?}, {}", e, peer_id);
+                        // a download that failed on a full disk is reported to the UI
+                        self.events.report_storage_error(&e).await;
+                        if let Err(e) = tokio::fs::remove_file(path.clone()).await {
+                            debug!("failed to remove partial download {:?}: {:?}", path, e);
+                        }
+                        if self.cancel.is_cancelled() {
+                            return Ok(());
+                        }
                     }
                 };
             }
-            let res = self
+            // the resolver only stops on shutdown, nothing is left to retry then
+            let _ = self
                 .resolve_sender
                 .send_async(ResolveWant {
                     file_id: self.file_id.clone(),
@@ -611,6 +1197,11 @@ impl Task for FileTask {
             Ok(())
         })
     }
+
+    /// Bounded by `DOWNLOAD_IDLE_TIMEOUT` instead, see `FileTask::download_file`.
+    fn timeout(&self) -> Option<Duration> {
+        None
+    }
 }
 
 pub struct CompareStateTask {
@@ -620,6 +1211,8 @@ pub struct CompareStateTask {
     pool: Arc<EncryptedPool>,
     rq: Arc<RequestQueue>,
     manager: Arc<RepositoryManager>,
+    capabilities: Arc<PeerCapabilities>,
+    events: Arc<Events>,
 }
 
 impl Task for CompareStateTask {
@@ -632,40 +1225,55 @@ impl Task for CompareStateTask {
                 Ok(peer) => peer,
                 Err(e) => {
                     warn!("Failed to get peer: {:?}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             };
             let stream = peer.open_stream().await?;
-            let mut protocol = StreamProtocol::new(stream);
-            let payloads = self_clone
-                .repo_states
-                .iter()
-                .map(|s| ComparePayload {
-                    counter: s.counter as i32,
-                    peer_id: s.peer_id.clone(),
-                })
-                .collect::<Vec<crate::proto::chat::ComparePayload>>();
+            let mut protocol = StreamProtocol::new(stream)
+                .recorded(pool.recorder(), &peer_id)
+                .metered(pool.meter(), &peer_id);
+            // groups the peer isn't in stay unmentioned
+            let mut payloads = Vec::with_capacity(self_clone.repo_states.len());
+            for state in &self_clone.repo_states {
+                if self_clone.manager.shared_with(&state.peer_id, &peer_id).await? {
+                    payloads.push(ComparePayload {
+                        counter: state.counter as i32,
+                        peer_id: state.peer_id.clone(),
+                    });
+                }
+            }
             let req = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
                 variant: Some(chat_message::Variant::CompareRequest(
                     crate::proto::chat::CompareRequest {
                         compare_payload: payloads,
+                        capabilities: Capabilities::LOCAL.bits(),
                     },
                 )),
             };
             protocol.send_request(&req).await?;
             let resp = protocol
                 .read_response::<ChatMessage>()
-                .await?
-                .and_then(|r| r.variant);
+                .await
+                .and_then(response_variant)?;
             if resp.is_none() {
                 return Err(anyhow::anyhow!("unexpected response"));
             }
-            return match resp.unwrap() {
+            match resp.unwrap() {
                 chat_message::Variant::CompareResponse(resp) => {
                     info!(
                         "received response, {:?}, peer {}",
-                        resp, &self_clone.peer_id
+                        resp, self_clone.peer_id
                     );
+                    self_clone
+                        .capabilities
+                        .set(&self_clone.peer_id, Capabilities::from_bits(resp.capabilities));
+                    let target = |repo_id: &str| {
+                        resp.counters
+                            .iter()
+                            .find(|payload| payload.peer_id == repo_id)
+                            .map(|payload| payload.counter as u64)
+                    };
                     let repo_states_iter = self_clone
                         .repo_states
                         .iter()
@@ -674,10 +1282,12 @@ impl Task for CompareStateTask {
                         let task = BatchRequestTask {
                             repo_id: state.peer_id.clone(),
                             counter: state.counter,
+                            target: target(&state.peer_id),
                             peer_db: self_clone.peer_db.clone(),
                             peer_id: self_clone.peer_id.clone(),
                             pool: pool.clone(),
                             repo_manager: self_clone.manager.clone(),
+                            events: self_clone.events.clone(),
                         };
                         self_clone.rq.enqueue(Arc::new(task)).await?;
                     }
@@ -690,11 +1300,13 @@ impl Task for CompareStateTask {
                     for peer_id in peer_iter {
                         let task = BatchRequestTask {
                             counter: 0,
+                            target: target(peer_id),
                             repo_id: peer_id.clone(),
                             peer_db: self_clone.peer_db.clone(),
                             peer_id: self_clone.peer_id.clone(),
                             pool: pool.clone(),
                             repo_manager: self_clone.manager.clone(),
+                            events: self_clone.events.clone(),
                         };
                         self_clone.rq.enqueue(Arc::new(task)).await?;
                     }
@@ -704,6 +1316,10 @@ impl Task for CompareStateTask {
             }
         })
     }
+
+    fn priority(&self) -> TaskPriority {
+        TaskPriority::Background
+    }
 }
 
 struct FileWantTask {
@@ -718,18 +1334,21 @@ impl Task for FileWantTask {
         let self_clone = self.clone();
         Box::pin(async move {
             let peer_id = self_clone.peer_id.clone();
-            debug!("file want {} {:?}", &peer_id, &self.file_ids);
+            debug!("file want {} {:?}", peer_id, self.file_ids);
             let pool = self_clone.pool.clone();
             let peer = match pool.get(&peer_id).await {
                 Ok(peer) => peer,
                 Err(e) => {
                     warn!("Failed to get peer: {:?}", e);
-                    return Err(e);
+                    return Err(e.into());
                 }
             };
             let stream = peer.open_stream().await?;
-            let mut protocol = StreamProtocol::new(stream);
+            let mut protocol = StreamProtocol::new(stream)
+                .recorded(pool.recorder(), &peer_id)
+                .metered(pool.meter(), &peer_id);
             let req = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
                 variant: Some(chat_message::Variant::FileWantRequest(
                     crate::proto::chat::FileWantRequest {
                         file_id: self_clone.file_ids.clone(),
@@ -739,16 +1358,16 @@ impl Task for FileWantTask {
             protocol.send_request(&req).await?;
             let resp = protocol
                 .read_response::<ChatMessage>()
-                .await?
-                .and_then(|r| r.variant);
+                .await
+                .and_then(response_variant)?;
             if resp.is_none() {
                 return Err(anyhow::anyhow!("unexpected response"));
             }
-            return match resp.unwrap() {
+            match resp.unwrap() {
                 chat_message::Variant::FileWantResponse(resp) => {
                     info!(
                         "received response, {:?}, peer {}",
-                        resp, &self_clone.peer_id
+                        resp, self_clone.peer_id
                     );
                     self.file_storage
                         .add_peer_have_many(resp.file_id, &peer_id)
@@ -759,4 +1378,810 @@ impl Task for FileWantTask {
             }
         })
     }
+
+    fn priority(&self) -> TaskPriority {
+        TaskPriority::Background
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::app_context::{wait_until, TestNode};
+    use crate::config::Config;
+    use crate::models::MessageBuilder;
+    use crate::peer_database::peer_id;
+    use ed25519_dalek::SigningKey;
+    use rand::rngs::OsRng;
+    use std::io;
+    use std::pin::Pin;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::{Context, Poll};
+    use tokio::io::{duplex, DuplexStream, ReadBuf};
+
+    /// Flushes of a `Counting` stream and the bytes written to it.
+    #[derive(Default)]
+    struct Counters {
+        flushes: AtomicUsize,
+        written: AtomicUsize,
+    }
+
+    /// Counts the flushes and writes of the stream it wraps.
+    struct Counting {
+        inner: DuplexStream,
+        counters: Arc<Counters>,
+    }
+
+    impl AsyncRead for Counting {
+        fn poll_read(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_read(cx, buf)
+        }
+    }
+
+    impl AsyncWrite for Counting {
+        fn poll_write(
+            mut self: Pin<&mut Self>,
+            cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            let res = Pin::new(&mut self.inner).poll_write(cx, buf);
+            if let Poll::Ready(Ok(n)) = res {
+                self.counters.written.fetch_add(n, Ordering::SeqCst);
+            }
+            res
+        }
+
+        fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            self.counters.flushes.fetch_add(1, Ordering::SeqCst);
+            Pin::new(&mut self.inner).poll_flush(cx)
+        }
+
+        fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Pin::new(&mut self.inner).poll_shutdown(cx)
+        }
+    }
+
+    /// A temporary file holding `content`.
+    async fn file_with(content: &[u8]) -> String {
+        let path = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        fs::write(&path, content).await.unwrap();
+        path.to_string_lossy().into_owned()
+    }
+
+    /// Reads the responses of an upload until it ends, returns the bytes received.
+    async fn receive(downloader: &mut StreamProtocol<DuplexStream>) -> Vec<u8> {
+        let mut received = Vec::new();
+        while let Some(response) = downloader.read_response::<ChatMessage>().await.unwrap() {
+            match response.variant {
+                Some(chat_message::Variant::FileDownloadResponse(response)) => {
+                    received.extend(response.chunk)
+                }
+                other => panic!("unexpected response {:?}", other),
+            }
+        }
+        received
+    }
+
+    #[tokio::test]
+    async fn file_wants_are_bounded_per_cycle() {
+        const FILES: usize = 5;
+        let events = Arc::new(Events::disconnected());
+        let file_db = Arc::new(crate::file_database::FileDatabase::new(
+            crate::message_database::memory_pool().await,
+            events,
+        ));
+        let storage = FileResolverStorage::new(file_db, Arc::new(crate::clock::SystemClock));
+        let file_ids: Vec<String> = (0..FILES).map(|i| format!("file{}", i)).collect();
+        for file_id in &file_ids {
+            storage.add_need_resolve(file_id, None).await;
+        }
+        let peers: Vec<String> = (0..10).map(|i| format!("peer{}", i)).collect();
+        // peer0 is known to have file0, peer9 can't be asked for files
+        storage.add_peer_have("file0", "peer0").await;
+        let capabilities = PeerCapabilities::default();
+        capabilities.set("peer9", Capabilities::MESSAGE_PUSH);
+
+        let mut asked = HashSet::new();
+        for _ in 0..100 {
+            let wants = file_wants(&peers, &storage, &capabilities).await;
+            assert!(wants.len() <= FILE_WANT_PEERS_PER_CYCLE);
+            assert!(!wants.contains_key("peer9"));
+            for (peer_id, wanted) in &wants {
+                let mut wanted = wanted.clone();
+                wanted.sort();
+                let expected = if peer_id == "peer0" {
+                    &file_ids[1..]
+                } else {
+                    &file_ids[..]
+                };
+                assert_eq!(wanted, expected);
+            }
+            asked.extend(wants.into_keys());
+        }
+        // over the cycles every peer that can answer gets asked
+        assert_eq!(asked.len(), 9);
+
+        // nobody is asked for files every peer is known to have
+        storage.add_peer_have_many(file_ids.clone(), "peer1").await;
+        let wants = file_wants(&peers[1..2], &storage, &capabilities).await;
+        assert!(wants.is_empty());
+    }
+
+    #[tokio::test]
+    async fn upload_flushes_once_per_batch_of_chunks() {
+        const CHUNKS: usize = 2 * UPLOAD_FLUSH_EVERY_CHUNKS + 8;
+        let content: Vec<u8> = (0..CHUNKS * UPLOAD_CHUNK_SIZE).map(|i| i as u8).collect();
+        let filename = file_with(&content).await;
+
+        let (a, b) = duplex(64 * 1024);
+        let counters = Arc::new(Counters::default());
+        let mut uploader = StreamProtocol::new(Counting {
+            inner: a,
+            counters: counters.clone(),
+        });
+        let mut downloader = StreamProtocol::new(b);
+        let throttle = FileThrottle::new(BandwidthLimits::default());
+        let upload = upload_file(&mut uploader, &filename, None, None, (&throttle, "bob"));
+        let (uploaded, received) = tokio::join!(upload, receive(&mut downloader));
+        uploaded.unwrap();
+        assert_eq!(received, content);
+        // two full batches and the end of the file, instead of one per chunk
+        assert_eq!(counters.flushes.load(Ordering::SeqCst), 3);
+        fs::remove_file(&filename).await.unwrap();
+    }
+
+    /// Downloads slowly, acking like `FileTask`. Returns the bytes received and the
+    /// most the uploader had written beyond them.
+    async fn slow_download(
+        downloader: &mut StreamProtocol<DuplexStream>,
+        counters: &Counters,
+    ) -> (usize, usize) {
+        let (mut received, mut acked, mut ahead) = (0, 0, 0);
+        while let Some(response) = downloader.read_response::<ChatMessage>().await.unwrap() {
+            let Some(chat_message::Variant::FileDownloadResponse(response)) = response.variant
+            else {
+                panic!("expected a download response");
+            };
+            received += response.chunk.len();
+            let written = counters.written.load(Ordering::SeqCst);
+            ahead = ahead.max(written.saturating_sub(received));
+            if response.window > 0
+                && !response.last_chunk
+                && (received - acked) as u64 >= response.window / 2
+            {
+                let ack = ChatMessage {
+                    protocol_version: PROTOCOL_VERSION,
+                    variant: Some(chat_message::Variant::FileDownloadAck(
+                        crate::proto::chat::FileDownloadAck {
+                            received: received as u64,
+                        },
+                    )),
+                };
+                downloader.send_request(&ack).await.unwrap();
+                acked = received;
+            }
+            tokio::time::sleep(Duration::from_millis(1)).await;
+        }
+        (received, ahead)
+    }
+
+    #[tokio::test]
+    async fn slow_downloader_holds_the_upload_back() {
+        const WINDOW: usize = 4 * UPLOAD_CHUNK_SIZE;
+        let content = vec![7u8; 64 * UPLOAD_CHUNK_SIZE];
+        let filename = file_with(&content).await;
+        let throttle = FileThrottle::new(BandwidthLimits::default());
+
+        for window in [None, Some(WINDOW as u64)] {
+            // room for the whole file, only the window holds the uploader back
+            let (a, b) = duplex(4 * content.len());
+            let counters = Arc::new(Counters::default());
+            let mut uploader = StreamProtocol::new(Counting {
+                inner: a,
+                counters: counters.clone(),
+            });
+            let mut downloader = StreamProtocol::new(b);
+            let upload = upload_file(&mut uploader, &filename, None, window, (&throttle, "bob"));
+            let (uploaded, (received, ahead)) =
+                tokio::join!(upload, slow_download(&mut downloader, &counters));
+            uploaded.unwrap();
+            assert_eq!(received, content.len());
+            match window {
+                // everything is written long before it is read
+                None => assert!(ahead > content.len() / 2, "{} bytes ahead", ahead),
+                // the window, a chunk the uploader may be writing and framing
+                Some(_) => assert!(
+                    ahead <= WINDOW + 2 * UPLOAD_CHUNK_SIZE,
+                    "{} bytes ahead",
+                    ahead
+                ),
+            }
+        }
+        fs::remove_file(&filename).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn throttled_upload_stays_under_the_rate() {
+        const RATE: u64 = 4 * UPLOAD_CHUNK_SIZE as u64;
+        let content = vec![7u8; 12 * UPLOAD_CHUNK_SIZE];
+        let filename = file_with(&content).await;
+
+        let (a, b) = duplex(64 * 1024);
+        let mut uploader = StreamProtocol::new(a);
+        let mut downloader = StreamProtocol::new(b);
+        let throttle = FileThrottle::new(BandwidthLimits {
+            per_peer: Some(RATE),
+            global: None,
+        });
+        let start = Instant::now();
+        let upload = upload_file(&mut uploader, &filename, None, None, (&throttle, "bob"));
+        let (uploaded, received) = tokio::join!(upload, receive(&mut downloader));
+        uploaded.unwrap();
+        assert_eq!(received, content);
+        // the bucket starts with a second's worth, the rest goes out at the rate
+        let rate = (content.len() as u64 - RATE) as f64 / start.elapsed().as_secs_f64();
+        assert!(rate <= RATE as f64, "{} bytes/s", rate);
+        assert!(rate > RATE as f64 * 0.8, "{} bytes/s", rate);
+        fs::remove_file(&filename).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn upload_above_the_limit_is_refused() {
+        let content = vec![7u8; 3 * UPLOAD_CHUNK_SIZE];
+        let filename = file_with(&content).await;
+        let throttle = FileThrottle::new(BandwidthLimits::default());
+
+        let (a, b) = duplex(64 * 1024);
+        let (mut uploader, mut downloader) = (StreamProtocol::new(a), StreamProtocol::new(b));
+        let limit = Some(content.len() as u64 - 1);
+        let upload = upload_file(&mut uploader, &filename, limit, None, (&throttle, "bob"));
+        let (uploaded, refusal) = tokio::join!(upload, downloader.read_response::<ChatMessage>());
+        uploaded.unwrap();
+        let refusal = refusal.unwrap().unwrap().variant;
+        let Some(chat_message::Variant::FileDownloadResponse(refusal)) = refusal else {
+            panic!("expected a download response");
+        };
+        assert!(refusal.refused);
+        assert!(refusal.chunk.is_empty());
+        assert_eq!(refusal.size, content.len() as u64);
+        // nothing of the file follows
+        assert!(receive(&mut downloader).await.is_empty());
+        let err = check_download_chunk("file", None, 0, &refusal).unwrap_err();
+        assert!(err.to_string().contains("refused"));
+
+        // a file right at the limit is served
+        let (a, b) = duplex(64 * 1024);
+        let (mut uploader, mut downloader) = (StreamProtocol::new(a), StreamProtocol::new(b));
+        let limit = Some(content.len() as u64);
+        let upload = upload_file(&mut uploader, &filename, limit, None, (&throttle, "bob"));
+        let (uploaded, received) = tokio::join!(upload, receive(&mut downloader));
+        uploaded.unwrap();
+        assert_eq!(received, content);
+        fs::remove_file(&filename).await.unwrap();
+    }
+
+    #[test]
+    fn download_past_the_advertised_size_is_aborted() {
+        let chunk = |len: usize, size: u64| proto::chat::FileDownloadResponse {
+            chunk: vec![0; len],
+            size,
+            ..Default::default()
+        };
+        let size = check_download_chunk("file", None, 0, &chunk(6, 10)).unwrap();
+        assert_eq!(size, Some(10));
+        // filling the file exactly is fine, one byte more is not
+        let filled = check_download_chunk("file", size, 6, &chunk(4, 10)).unwrap();
+        assert_eq!(filled, size);
+        let err = check_download_chunk("file", size, 6, &chunk(5, 10)).unwrap_err();
+        assert!(err.to_string().contains("exceeds"));
+        // a later chunk can't raise the size announced first
+        let err = check_download_chunk("file", size, 6, &chunk(5, 1 << 30)).unwrap_err();
+        assert!(err.to_string().contains("10 bytes"));
+        // peers that predate the size aren't held to one
+        let unknown = check_download_chunk("file", None, 0, &chunk(1 << 20, 0)).unwrap();
+        assert_eq!(unknown, None);
+    }
+
+    #[test]
+    fn sync_now_queues_a_comparison_per_peer() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            use crate::peer_pool::Dialer as _;
+            // not started, so the queued tasks stay where the test can count them
+            let node = TestNode::new("alice", Config::default(), runtime).await;
+            let engine = &node.ctx.sync_engine;
+            for peer in ["bob", "carol"] {
+                node.ctx
+                    .dialer
+                    .add(peer.to_owned(), "127.0.0.1:1".to_owned())
+                    .await;
+            }
+            engine.sync_now(Some("bob".to_owned())).await.unwrap();
+            assert_eq!(engine.request_queue.pending(), 1);
+            // without a peer, every peer with an address
+            engine.sync_now(None).await.unwrap();
+            assert_eq!(engine.request_queue.pending(), 3);
+        });
+    }
+
+    fn with_policy(unknown_peer_policy: UnknownPeerPolicy) -> Config {
+        Config {
+            unknown_peer_policy,
+            ..Config::default()
+        }
+    }
+
+    /// The first message of `author` as a peer relays it, along with the record of `record_of`.
+    fn relayed(author: &SigningKey, record_of: &SigningKey) -> crate::proto::chat::Messages {
+        let author_id = peer_id(&author.verifying_key());
+        let mut msg = MessageBuilder::new("m1".to_owned(), 1, author_id.clone())
+            .text("hi".to_owned())
+            .build();
+        msg.counter = 1;
+        let record_id = peer_id(&record_of.verifying_key());
+        crate::proto::chat::Messages {
+            messages: vec![msg.into()],
+            peer_id: author_id,
+            peer: Some(crate::proto::chat::Peer {
+                id: record_id.clone(),
+                name: "alice".to_owned(),
+                pub_key: record_id,
+            }),
+        }
+    }
+
+    #[test]
+    fn messages_of_an_unknown_peer_follow_the_policy() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = SigningKey::generate(&mut OsRng);
+            let alice_id = peer_id(&alice.verifying_key());
+            // policy, whether the push is stored, whether the record sent along is saved
+            for (policy, stored, saved) in [
+                (UnknownPeerPolicy::Accept, true, true),
+                (UnknownPeerPolicy::Reject, false, false),
+                (UnknownPeerPolicy::FetchFirst, false, true),
+            ] {
+                let node = TestNode::new("bob", with_policy(policy), runtime.clone()).await;
+                let engine = &node.ctx.sync_engine;
+                let counter = engine
+                    .receive_messages("relay", relayed(&alice, &alice))
+                    .await
+                    .unwrap();
+                assert_eq!(counter == 1, stored, "{:?}", policy);
+                let record = node.ctx.peer_db.get_peer_by_id(&alice_id).await.unwrap();
+                assert_eq!(record.is_some(), saved, "{:?}", policy);
+
+                if policy == UnknownPeerPolicy::Reject {
+                    // e.g. through discovery
+                    let peer_db = &node.ctx.peer_db;
+                    let peer = peer_db.new_peer("alice".to_owned(), alice_id.clone());
+                    peer_db.save_peer(&peer.unwrap()).await.unwrap();
+                }
+                // with the record in place the next delivery is stored
+                let counter = engine
+                    .receive_messages("relay", relayed(&alice, &alice))
+                    .await
+                    .unwrap();
+                assert_eq!(counter, 1, "{:?}", policy);
+            }
+        });
+    }
+
+    #[test]
+    fn record_of_anyone_but_the_author_is_ignored() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = SigningKey::generate(&mut OsRng);
+            let mallory = SigningKey::generate(&mut OsRng);
+            for policy in [UnknownPeerPolicy::Accept, UnknownPeerPolicy::FetchFirst] {
+                let node = TestNode::new("bob", with_policy(policy), runtime.clone()).await;
+                let counter = node
+                    .ctx
+                    .sync_engine
+                    .receive_messages("mallory", relayed(&alice, &mallory))
+                    .await
+                    .unwrap();
+                assert_eq!(counter == 1, policy == UnknownPeerPolicy::Accept);
+                for key in [&alice, &mallory] {
+                    let id = peer_id(&key.verifying_key());
+                    let record = node.ctx.peer_db.get_peer_by_id(&id).await.unwrap();
+                    assert!(record.is_none());
+                }
+            }
+        });
+    }
+
+    /// A request of a later version, its variant has a tag this version doesn't know.
+    #[derive(Clone, PartialEq, prost::Message)]
+    struct FutureRequest {
+        #[prost(string, tag = "99")]
+        reaction: String,
+        #[prost(uint32, tag = "12")]
+        protocol_version: u32,
+    }
+
+    impl crate::stream_protocol::MessageEncoding for FutureRequest {
+        fn encode_message(&self) -> Vec<u8> {
+            prost::Message::encode_to_vec(self)
+        }
+
+        fn decode_message(bytes: &[u8]) -> anyhow::Result<Self> {
+            Ok(prost::Message::decode(bytes)?)
+        }
+
+        fn variant_name(&self) -> &'static str {
+            "Reaction"
+        }
+    }
+
+    /// Sends `req` on a stream of its own and returns the response.
+    async fn request<M>(peer: Arc<crate::peer_pool::EncryptedPeer>, req: &M) -> ChatMessage
+    where
+        M: crate::stream_protocol::MessageEncoding,
+    {
+        let mut protocol = StreamProtocol::new(peer.open_stream().await.unwrap());
+        protocol.send_request(req).await.unwrap();
+        protocol.read_response().await.unwrap().unwrap()
+    }
+
+    fn compare_request() -> ChatMessage {
+        ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
+            variant: Some(chat_message::Variant::CompareRequest(
+                crate::proto::chat::CompareRequest {
+                    compare_payload: vec![],
+                    capabilities: Capabilities::LOCAL.bits(),
+                },
+            )),
+        }
+    }
+
+    #[test]
+    fn unknown_request_is_unsupported_and_the_session_carries_on() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let peer = pool.get(&bob.id()).await.unwrap();
+
+            let future = FutureRequest {
+                reaction: "+1".to_owned(),
+                protocol_version: PROTOCOL_VERSION,
+            };
+            let resp = request(peer.clone(), &future).await;
+            assert!(matches!(
+                resp.variant,
+                Some(chat_message::Variant::Unsupported(_))
+            ));
+            assert_eq!(resp.protocol_version, PROTOCOL_VERSION);
+
+            // the same session still answers the requests bob knows
+            let resp = request(peer.clone(), &compare_request()).await;
+            let Some(chat_message::Variant::CompareResponse(resp)) = resp.variant else {
+                panic!("expected a compare response");
+            };
+            assert!(resp.peer_ids.contains(&bob.id()));
+            let current = pool.get(&bob.id()).await.unwrap();
+            assert!(Arc::ptr_eq(&peer, &current));
+        });
+    }
+
+    #[test]
+    fn request_of_another_protocol_version_is_refused() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let peer = pool.get(&bob.id()).await.unwrap();
+
+            let newer = ChatMessage {
+                protocol_version: PROTOCOL_VERSION + 1,
+                ..compare_request()
+            };
+            let resp = request(peer.clone(), &newer).await;
+            assert!(matches!(
+                resp.variant,
+                Some(chat_message::Variant::Unsupported(_))
+            ));
+            // the answer tells the peer which version we speak
+            assert_eq!(resp.protocol_version, PROTOCOL_VERSION);
+
+            // peers that predate the field send no version and are still served
+            let legacy = ChatMessage {
+                protocol_version: 0,
+                ..compare_request()
+            };
+            let resp = request(peer, &legacy).await;
+            assert!(matches!(
+                resp.variant,
+                Some(chat_message::Variant::CompareResponse(_))
+            ));
+        });
+    }
+
+    #[test]
+    fn response_of_another_protocol_version_fails_the_request() {
+        let newer = ChatMessage {
+            protocol_version: PROTOCOL_VERSION + 1,
+            ..compare_request()
+        };
+        let err = response_variant(Some(newer)).unwrap_err().to_string();
+        assert!(err.contains("incompatible peer"), "{}", err);
+        assert!(err.contains(&(PROTOCOL_VERSION + 1).to_string()), "{}", err);
+        let legacy = ChatMessage {
+            protocol_version: 0,
+            ..compare_request()
+        };
+        assert!(response_variant(Some(legacy)).unwrap().is_some());
+    }
+
+    #[test]
+    fn response_sent_as_a_request_is_unsupported() {
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            alice.learn(&bob).await;
+            bob.start().await;
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let peer = pool.get(&bob.id()).await.unwrap();
+
+            // a variant the handler knows, but never as a request
+            let accept = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
+                variant: Some(chat_message::Variant::MessageAccept(
+                    crate::proto::chat::MessageAccept { counter: 1 },
+                )),
+            };
+            let mut protocol = StreamProtocol::new(peer.clone().open_stream().await.unwrap());
+            protocol.send_request(&accept).await.unwrap();
+            let resp: Option<ChatMessage> = protocol.read_response().await.unwrap();
+            assert!(matches!(
+                resp.unwrap().variant,
+                Some(chat_message::Variant::Unsupported(_))
+            ));
+            // the stream is closed cleanly after the answer
+            let resp: Option<ChatMessage> = protocol.read_response().await.unwrap();
+            assert!(resp.is_none());
+
+            let resp = request(peer, &compare_request()).await;
+            assert!(matches!(
+                resp.variant,
+                Some(chat_message::Variant::CompareResponse(_))
+            ));
+        });
+    }
+
+    /// Asks `peer_id` for `file_id` on a stream of its own, returns once the first
+    /// chunk arrived.
+    async fn start_download(
+        pool: &EncryptedPool,
+        peer_id: &str,
+        file_id: &str,
+    ) -> StreamProtocol<StreamHandle> {
+        let peer = pool.get(peer_id).await.unwrap();
+        let mut protocol = StreamProtocol::new(peer.open_stream().await.unwrap());
+        let req = ChatMessage {
+            protocol_version: PROTOCOL_VERSION,
+            variant: Some(chat_message::Variant::FileDownloadRequest(
+                crate::proto::chat::FileDownloadRequest {
+                    file_id: file_id.to_owned(),
+                    peer_id: peer_id.to_owned(),
+                    flow_control: true,
+                },
+            )),
+        };
+        protocol.send_request(&req).await.unwrap();
+        let first: Option<ChatMessage> = protocol.read_response().await.unwrap();
+        assert!(first.is_some());
+        protocol
+    }
+
+    /// Reads what is left of a download, acking every chunk. Returns the bytes
+    /// received and whether the file was complete.
+    async fn finish_download(protocol: &mut StreamProtocol<StreamHandle>) -> (u64, bool) {
+        let mut received = UPLOAD_CHUNK_SIZE as u64;
+        loop {
+            let ack = ChatMessage {
+                protocol_version: PROTOCOL_VERSION,
+                variant: Some(chat_message::Variant::FileDownloadAck(
+                    crate::proto::chat::FileDownloadAck { received },
+                )),
+            };
+            // a replaced upload may have closed its side already
+            let _ = protocol.send_request(&ack).await;
+            let resp = match protocol.read_response::<ChatMessage>().await {
+                Ok(Some(resp)) => resp,
+                Ok(None) | Err(_) => return (received, false),
+            };
+            let Some(chat_message::Variant::FileDownloadResponse(resp)) = resp.variant else {
+                panic!("expected a download response");
+            };
+            received += resp.chunk.len() as u64;
+            if resp.last_chunk {
+                return (received, true);
+            }
+        }
+    }
+
+    #[test]
+    fn repeated_download_requests_replace_each_other() {
+        const SIZE: usize = 8 * UPLOAD_CHUNK_SIZE;
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            // one chunk in flight, an upload waits for the downloader after it
+            let config = Config {
+                max_upload_in_flight: Some(UPLOAD_CHUNK_SIZE as u64),
+                ..Config::default()
+            };
+            let bob = TestNode::new("bob", config, runtime.clone()).await;
+            let path = Path::new(&bob.root).join("big.bin");
+            fs::write(&path, vec![7u8; SIZE]).await.unwrap();
+            let file = crate::file_database::FileDescription {
+                id: "big".to_owned(),
+                format: "bin".to_owned(),
+                local_path: "big.bin".to_owned(),
+                timestamp: 0,
+            };
+            bob.ctx.file_db.save_owned(&file).await.unwrap();
+            alice.learn(&bob).await;
+            bob.start().await;
+
+            let pool = &alice.ctx.sync_engine.peer_pool;
+            let engine = &bob.ctx.sync_engine;
+            let mut downloads = Vec::new();
+            for _ in 0..3 {
+                downloads.push(start_download(pool, &bob.id(), "big").await);
+                // every retry takes the place of the upload before it
+                assert_eq!(engine.uploads.lock().unwrap().len(), 1);
+            }
+            let mut latest = downloads.pop().unwrap();
+            for mut replaced in downloads {
+                let (received, complete) = finish_download(&mut replaced).await;
+                assert!(!complete);
+                assert!(received < SIZE as u64);
+            }
+            assert_eq!(finish_download(&mut latest).await, (SIZE as u64, true));
+            wait_until("the upload is done", || async {
+                engine.uploads.lock().unwrap().is_empty()
+            })
+            .await;
+        });
+    }
+
+    #[test]
+    fn shutdown_stops_a_download_and_removes_the_partial_file() {
+        const SIZE: usize = 64 * UPLOAD_CHUNK_SIZE;
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            // the upload takes many seconds at this rate
+            let config = Config {
+                file_bandwidth: BandwidthLimits {
+                    per_peer: Some((4 * UPLOAD_CHUNK_SIZE) as u64),
+                    global: None,
+                },
+                ..Config::default()
+            };
+            let bob = TestNode::new("bob", config, runtime.clone()).await;
+            fs::write(Path::new(&bob.root).join("big.bin"), vec![7u8; SIZE])
+                .await
+                .unwrap();
+            let file = crate::file_database::FileDescription {
+                id: "big".to_owned(),
+                format: "bin".to_owned(),
+                local_path: "big.bin".to_owned(),
+                timestamp: 0,
+            };
+            bob.ctx.file_db.save_owned(&file).await.unwrap();
+            alice.learn(&bob).await;
+            bob.start().await;
+
+            let engine = alice.ctx.sync_engine.clone();
+            let (index_send, index_recv) = flume::unbounded();
+            let (resolve_send, resolve_recv) = flume::unbounded();
+            let task = Arc::new(FileTask {
+                index_sender: Arc::new(index_send),
+                resolve_sender: Arc::new(resolve_send),
+                file_id: "big".to_owned(),
+                file_storage: engine.file_storage.clone(),
+                folder: alice.root.clone(),
+                peer_ids: vec![bob.id()],
+                pool: engine.peer_pool.clone(),
+                chunk_listener: None,
+                events: alice.ctx.events.clone(),
+                throttle: engine.throttle.clone(),
+                cancel: engine.shutdown.child_token(),
+            });
+            let download = tokio::spawn(task.run());
+            let partial = Path::new(&alice.root).join("big");
+            wait_until("the download is under way", || {
+                let partial = partial.clone();
+                async move {
+                    let len = fs::metadata(&partial).await.map(|meta| meta.len());
+                    len.is_ok_and(|len| len > 0)
+                }
+            })
+            .await;
+
+            engine.shutdown();
+            let stopped = tokio::time::timeout(Duration::from_secs(2), download).await;
+            let Ok(res) = stopped else {
+                panic!("the download outlived the shutdown");
+            };
+            res.unwrap().unwrap();
+            assert!(!fs::try_exists(&partial).await.unwrap());
+            // neither indexed nor handed back for another try
+            assert!(index_recv.is_empty());
+            assert!(resolve_recv.is_empty());
+        });
+    }
+
+    #[test]
+    fn long_history_reports_progress_until_complete() {
+        use crate::events::ChatEvent;
+
+        const MESSAGES: u64 = 1200;
+        let runtime = Arc::new(tokio::runtime::Runtime::new().unwrap());
+        runtime.clone().block_on(async move {
+            let alice = TestNode::new("alice", Config::default(), runtime.clone()).await;
+            let carol = TestNode::new("carol", Config::default(), runtime.clone()).await;
+            let bob = TestNode::new("bob", Config::default(), runtime.clone()).await;
+            let manager = alice.ctx.sync_engine.get_manager();
+            for i in 0..MESSAGES {
+                let msg = MessageBuilder::new(format!("m{}", i), 1, alice.id())
+                    .text("hi".to_owned())
+                    .build();
+                manager.clone().add_own_message(msg).await.unwrap();
+            }
+            let total = MESSAGES + 1;
+            carol.learn(&alice).await;
+            alice.start().await;
+            carol.start().await;
+            carol.wait_for_counter(&alice.id(), total).await;
+            alice.stop().await;
+
+            // alice would push her history as soon as bob connects, carol only relays
+            // it when asked
+            bob.learn(&carol).await;
+            let events = bob.ctx.events.get_rx();
+            bob.start().await;
+            bob.wait_for_counter(&alice.id(), total).await;
+            let mut progress = Vec::new();
+            wait_until("the sync is reported complete", || {
+                progress.extend(events.try_iter().filter_map(|event| match event {
+                    ChatEvent::SyncProgress {
+                        peer_id,
+                        received,
+                        estimated_total,
+                    } if peer_id == alice.id() => Some((received, estimated_total)),
+                    _ => None,
+                }));
+                let done = progress.last() == Some(&(total, Some(total)));
+                async move { done }
+            })
+            .await;
+
+            // one event per page, each with more messages of the same total
+            let pages = total.div_ceil(BATCH_PAGE_SIZE as u64) as usize;
+            assert_eq!(progress.len(), pages);
+            for pair in progress.windows(2) {
+                assert!(pair[0].0 < pair[1].0);
+            }
+            let mut estimates = progress.iter().map(|(_, estimate)| *estimate);
+            assert!(estimates.all(|estimate| estimate == Some(total)));
+        });
+    }
 }
@@ -1,15 +1,11 @@
 use std::collections::HashMap;
 use std::io::{self, Write};
-use std::net::IpAddr;
-use std::str::FromStr;
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::Duration;
 
-use chat::{ChatDelegate, ChatError, ChatManager, DnsRecord, Event, Message, Peer};
-use log::info;
-use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
-use uuid::uuid;
+use chat::{ChatDelegate, ChatError, ChatManager, Event, Message, Peer, SystemInfo, SystemKind};
+use log::{info, warn};
 
 struct ChatClient {
     manager: Arc<ChatManager>,
@@ -34,83 +30,6 @@ impl ChatClient {
     }
 
     fn run(&self) -> Result<(), Box<dyn std::error::Error>> {
-        let mdns = ServiceDaemon::new()?;
-
-        let dns_record = self.manager.get_dns_record_map();
-        let hostname = format!("peer-{}.local.", self.manager.get_name());
-        let service_type = "_myapp._tcp.local.";
-        let instance_name = format!("Chat-{}", self.manager.get_name());
-
-        let service_info = ServiceInfo::new(
-            service_type,
-            &instance_name,
-            &hostname,
-            "0.0.0.0",
-            self.get_port_from_dns_record(&dns_record)?,
-            dns_record,
-        )?
-        .enable_addr_auto();
-        mdns.register(service_info.clone())?;
-        let refresh_mdns = mdns.clone();
-        let refresh_service = service_info.clone();
-        thread::spawn(move || {
-            loop {
-                thread::sleep(Duration::from_secs(20));
-                if let Err(e) = refresh_mdns.register(refresh_service.clone()) {
-                    eprintln!("Failed to refresh mDNS registration: {:?}", e);
-                }
-            }
-        });
-
-        let browse_handle = mdns.browse(service_type)?;
-        let peers_clone = self.peers.clone();
-        let manager_clone = self.manager.clone();
-
-        thread::spawn(move || {
-            while let Ok(event) = browse_handle.recv() {
-                match event {
-                    ServiceEvent::ServiceResolved(info) => {
-                        let props = info.get_properties().clone();
-                        let record = props.into_property_map_str();
-                        match manager_clone.verify_hashmap_record(&record) {
-                            Ok(dns_record) => {
-                                if dns_record.pub_key != manager_clone.get_pub_key() {
-                                    for addr in info.get_addresses() {
-                                        info!("print address: {}, {}", dns_record.name, addr);
-                                    }
-                                    let peer_addr = info
-                                        .get_addresses()
-                                        .iter()
-                                        .filter(|addr| match addr {
-                                            IpAddr::V4(addr) => addr.is_link_local(),
-                                            IpAddr::V6(_) => false,
-                                        })
-                                        .next()
-                                        .map(|ip| format!("{}:{}", ip, info.get_port()))
-                                        .unwrap_or_default();
-
-                                    let peer_addr = peer_addr.split(':').next().unwrap();
-                                    let peer_addr = format!("{}:{}", peer_addr, dns_record.port);
-                                    info!("Found peer: {}, {}", dns_record.name, peer_addr);
-                                    if !peer_addr.is_empty() {
-                                        if let Err(e) = manager_clone.set_peer(
-                                            dns_record.name.clone(),
-                                            peer_addr,
-                                            dns_record.pub_key.clone(),
-                                        ) {
-                                            eprintln!("Failed to set peer: {:?}", e);
-                                        }
-                                    }
-                                }
-                            }
-                            Err(e) => eprintln!("Failed to verify record: {:?}", e),
-                        }
-                    }
-                    _ => {}
-                }
-            }
-        });
-
         let server_manager = self.manager.clone();
         thread::spawn(move || {
             server_manager.run_server();
@@ -123,6 +42,7 @@ impl ChatClient {
         };
 
         self.manager.set_delegate(Arc::new(delegate));
+        self.manager.clone().refresh_discovery()?;
         let loop_manager = self.manager.clone();
         thread::spawn(move || {
             loop_manager.run_loop();
@@ -131,18 +51,6 @@ impl ChatClient {
         Ok(())
     }
 
-    fn get_port_from_dns_record(
-        &self,
-        record: &HashMap<String, String>,
-    ) -> Result<u16, Box<dyn std::error::Error>> {
-        record
-            .get("port")
-            .ok_or("Port not found in DNS record")?
-            .parse::<u16>()
-            .map_err(|e| e.into())
-            .map(|port| port)
-    }
-
     fn console_loop(&self) -> Result<(), Box<dyn std::error::Error>> {
         println!("P2P Chat Console");
         println!("Type 'help' for available commands");
@@ -166,8 +74,14 @@ impl ChatClient {
                     println!("  messages     - Show all messages");
                     println!("  send <text>  - Send a message");
                     println!("  file <path>  - Send a file");
+                    println!("  discover     - Look for peers on the network again");
                     println!("  exit         - Exit the application");
                 }
+                "discover" => {
+                    if let Err(e) = self.manager.clone().refresh_discovery() {
+                        eprintln!("Failed to refresh discovery: {:?}", e);
+                    }
+                }
                 "peers" => {
                     let peers = self.peers.lock().unwrap();
                     println!("Connected peers:");
@@ -179,19 +93,13 @@ impl ChatClient {
                     let messages = self.messages.lock().unwrap();
                     println!("Messages:");
                     for msg in messages.iter() {
-                        let peers = self.peers.lock().unwrap();
-                        let sender_name = peers
-                            .get(&msg.peer_id)
-                            .map(|p| p.name.clone())
-                            .unwrap_or_else(|| {
-                                if msg.peer_id == self.manager.get_name() {
-                                    "You".to_string()
-                                } else {
-                                    "Unknown".to_string()
-                                }
-                            });
+                        let sender_name = sender_name(msg);
 
-                        if let Some(file_id) = &msg.file_id {
+                        if msg.unsupported {
+                            println!("  {} sent an unsupported message type", sender_name);
+                        } else if let Some(system) = &msg.system {
+                            println!("  {}", describe_system(&sender_name, system));
+                        } else if let Some(file_id) = &msg.file_id {
                             println!("  {} sent a file (ID: {})", sender_name, file_id);
                             if let Some(file_path) = &msg.file_path {
                                 println!("    File saved at: {}", file_path);
@@ -217,15 +125,10 @@ impl ChatClient {
                 cmd if cmd.starts_with("file ") => {
                     let file_path = &cmd[5..];
                     if !file_path.is_empty() {
-                        let file_id = uuid::Uuid::new_v4().to_string();
-                        let format = file_path.split('.').last().unwrap_or("bin").to_string();
+                        let format = file_path.rsplit('.').next().unwrap_or("bin").to_string();
 
-                        match self.manager.set_file_path(
-                            file_id.clone(),
-                            format,
-                            file_path.to_string(),
-                        ) {
-                            Ok(_) => match self.manager.send_message(None, Some(file_id)) {
+                        match self.manager.register_file(format, file_path.to_string()) {
+                            Ok(file_id) => match self.manager.send_message(None, Some(file_id)) {
                                 Ok(_) => println!("File message sent"),
                                 Err(e) => println!("Failed to send file message: {:?}", e),
                             },
@@ -261,35 +164,130 @@ impl ChatDelegate for ChatClientDelegate {
                 let mut peers = self.peers.lock().unwrap();
                 peers.insert(peer.id.clone(), peer);
             }
-            Event::Message(message) => {
-                let peers = self.peers.lock().unwrap();
-                let sender_name = peers
-                    .get(&message.peer_id)
-                    .map(|p| p.name.clone())
-                    .unwrap_or_else(|| {
-                        if message.peer_id == self.manager.get_name() {
-                            "You".to_string()
-                        } else {
-                            "Unknown".to_string()
-                        }
-                    });
+            Event::PeerProfileUpdated(peer) => {
+                info!("{} updated their profile", peer.name);
+                let mut peers = self.peers.lock().unwrap();
+                peers.insert(peer.id.clone(), peer);
+            }
+            Event::MessagesBatch(batch) => {
+                println!("\n{} messages synced", batch.len());
+                print!("> ");
+                io::stdout().flush().unwrap();
 
-                if let Some(file_id) = &message.file_id {
-                    if message.file_path.is_some() {
-                        println!(
-                            "\n{} sent a file (saved at: {})",
-                            sender_name,
-                            message.file_path.as_ref().unwrap()
-                        );
+                let mut messages = self.messages.lock().unwrap();
+                messages.extend(batch);
+            }
+            Event::MessageRemoved(id) => {
+                let mut messages = self.messages.lock().unwrap();
+                messages.retain(|m| m.id != id);
+            }
+            Event::ConnectionRequest {
+                peer_id,
+                display_name,
+            } => {
+                info!("approving connection request from {}", display_name);
+                if let Err(e) = self.manager.approve_peer(peer_id) {
+                    warn!("failed to approve peer: {:?}", e);
+                }
+            }
+            Event::MessageStatusChanged { message_id, status } => {
+                info!("message {} is now {:?}", message_id, status);
+            }
+            Event::GroupChanged(group_id) => {
+                info!("members of group {} changed", group_id);
+            }
+            Event::PeerKeyChanged {
+                display_name,
+                was_verified,
+                ..
+            } => {
+                println!(
+                    "\nwarning: the key of {} changed (was verified: {})",
+                    display_name, was_verified
+                );
+            }
+            Event::StorageError { kind } => {
+                eprintln!("\ncouldn't store data: {:?}", kind);
+            }
+            Event::PollChanged(poll_id) => {
+                info!("votes of poll {} changed", poll_id);
+            }
+            Event::PeerOffline { display_name, .. } => {
+                info!("{} is offline", display_name);
+            }
+            Event::PeerRemoved(peer_id) => {
+                info!("removed stale peer {}", peer_id);
+            }
+            Event::SyncProgress {
+                peer_id,
+                received,
+                estimated_total,
+            } => {
+                info!(
+                    "synced {} of {:?} messages of {}",
+                    received, estimated_total, peer_id
+                );
+            }
+            Event::FileUnresolvable(file_id) => {
+                println!("\nfile {} is not available from any peer", file_id);
+            }
+            Event::FileDownloadPrompt {
+                file_id,
+                display_name,
+                ..
+            } => {
+                println!("\n{} sent file {}, not downloaded", display_name, file_id);
+            }
+            Event::PeerDiscovered { name, addr, .. } => {
+                info!("discovered peer {} at {}", name, addr);
+            }
+            Event::Notification {
+                display_name,
+                importance,
+                ..
+            } => {
+                info!("new message from {} ({:?})", display_name, importance);
+            }
+            Event::MessageDelivered {
+                message_id,
+                display_name,
+                ..
+            } => {
+                info!("message {} delivered to {}", message_id, display_name);
+            }
+            Event::DeliveryStatusChanged { message_id, status } => {
+                info!(
+                    "message {} delivered to {}/{}",
+                    message_id,
+                    status.delivered_to.len(),
+                    status.recipients
+                );
+            }
+            Event::ConnectionFailed {
+                display_name,
+                error,
+                ..
+            } => {
+                warn!("couldn't connect to {}: {:?}", display_name, error);
+            }
+            Event::Message(message) => {
+                let is_own = message.is_own;
+                let sender_name = sender_name(&message);
+
+                if message.unsupported {
+                    println!("\n{} sent an unsupported message type", sender_name);
+                } else if let Some(system) = &message.system {
+                    println!("\n{}", describe_system(&sender_name, system));
+                } else if message.file_id.is_some() {
+                    if let Some(file_path) = &message.file_path {
+                        println!("\n{} sent a file (saved at: {})", sender_name, file_path);
                     } else {
                         println!("\n{} sent a file (downloading...)", sender_name);
                     }
                 } else {
                     println!("\n{}: {}", sender_name, message.text);
 
-                    if message.text.to_lowercase().contains("explain")
-                        && message.peer_id != self.manager.get_name()
-                    {
+                    if message.text.to_lowercase().contains("explain") && !is_own {
                         thread::sleep(Duration::from_millis(500));
 
                         match self
@@ -312,11 +310,28 @@ impl ChatDelegate for ChatClientDelegate {
     }
 }
 
+fn sender_name(message: &Message) -> String {
+    if message.is_own {
+        "You".to_string()
+    } else {
+        message.display_name.clone()
+    }
+}
+
+fn describe_system(sender_name: &str, system: &SystemInfo) -> String {
+    match system.kind {
+        SystemKind::Joined => format!("{} joined", sender_name),
+        SystemKind::Left if system.value.is_empty() => format!("{} left", sender_name),
+        SystemKind::Left => format!("{} left", system.value),
+        SystemKind::Renamed => format!("{} is now known as {}", sender_name, system.value),
+    }
+}
+
 fn main() -> Result<(), Box<dyn std::error::Error>> {
     let name = if let Some(arg) = std::env::args().nth(1) {
         arg
     } else {
-        format!("User-{}", uuid::Uuid::new_v4().to_string()[..8].to_string())
+        format!("User-{}", &uuid::Uuid::new_v4().to_string()[..8])
     };
 
     let root_path = if let Some(arg) = std::env::args().nth(2) {
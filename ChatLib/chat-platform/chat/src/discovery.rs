@@ -0,0 +1,134 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::thread;
+use std::time::Duration;
+
+use mdns_sd::{Receiver, ServiceDaemon, ServiceEvent, ServiceInfo};
+use uniffi::deps::log::warn;
+
+const SERVICE_TYPE: &str = "_myapp._tcp.local.";
+/// Registrations are announced again this often so peers that missed them still resolve us.
+const REFRESH_INTERVAL: Duration = Duration::from_secs(20);
+/// Hex characters of the public key used in mDNS names.
+const FINGERPRINT_LEN: usize = 16;
+
+/// A service found on the local network, not verified yet.
+pub(crate) struct ResolvedService {
+    pub(crate) record: HashMap<String, String>,
+    pub(crate) addresses: Vec<IpAddr>,
+}
+
+/// Our mDNS registration and browse for other peers.
+pub(crate) struct Discovery {
+    daemon: ServiceDaemon,
+    service: ServiceInfo,
+}
+
+impl Discovery {
+    /// Registers the service and keeps announcing it in the background.
+    pub(crate) fn start(
+        pub_key: &str,
+        record: HashMap<String, String>,
+        port: u16,
+    ) -> Result<Self, mdns_sd::Error> {
+        let service = service_info(pub_key, record, port)?;
+        let daemon = ServiceDaemon::new()?;
+        daemon.register(service.clone())?;
+
+        let refresh_daemon = daemon.clone();
+        let refresh_service = service.clone();
+        thread::spawn(move || loop {
+            thread::sleep(REFRESH_INTERVAL);
+            if let Err(e) = refresh_daemon.register(refresh_service.clone()) {
+                warn!("failed to refresh mDNS registration: {:?}", e);
+            }
+        });
+        Ok(Self { daemon, service })
+    }
+
+    /// Announces the service again and restarts the browse, so peers already on
+    /// the network resolve again. The previous browse ends, calling this
+    /// repeatedly leaves a single browse running.
+    pub(crate) fn refresh(&self) -> Result<Receiver<ServiceEvent>, mdns_sd::Error> {
+        self.daemon.register(self.service.clone())?;
+        // fails when nothing is browsing yet, which is fine
+        let _ = self.daemon.stop_browse(SERVICE_TYPE);
+        self.daemon.browse(SERVICE_TYPE)
+    }
+}
+
+/// Our service, named after the key: names may repeat, the key fingerprint keeps mDNS
+/// names unique while the display name travels in the TXT record.
+fn service_info(
+    pub_key: &str,
+    record: HashMap<String, String>,
+    port: u16,
+) -> Result<ServiceInfo, mdns_sd::Error> {
+    let fingerprint: String = pub_key.chars().take(FINGERPRINT_LEN).collect();
+    let hostname = format!("peer-{}.local.", fingerprint);
+    let instance_name = format!("Chat-{}", fingerprint);
+    let service = ServiceInfo::new(
+        SERVICE_TYPE,
+        &instance_name,
+        &hostname,
+        "0.0.0.0",
+        port,
+        record,
+    )?;
+    Ok(service.enable_addr_auto())
+}
+
+/// Blocks on `events` until the browse stops, passing every resolved service to `on_resolved`.
+pub(crate) fn receive(events: Receiver<ServiceEvent>, on_resolved: impl Fn(ResolvedService)) {
+    while let Ok(event) = events.recv() {
+        match event {
+            ServiceEvent::ServiceResolved(info) => on_resolved(ResolvedService {
+                record: info.get_properties().clone().into_property_map_str(),
+                addresses: info.get_addresses().iter().copied().collect(),
+            }),
+            ServiceEvent::SearchStopped(_) => break,
+            _ => {}
+        }
+    }
+}
+
+/// Picks the address to dial, link-local IPv4 first as it works without a router.
+pub(crate) fn dial_address(addresses: &[IpAddr], port: u16) -> Option<String> {
+    let v4 = addresses.iter().filter(|addr| addr.is_ipv4());
+    v4.clone()
+        .find(|addr| match addr {
+            IpAddr::V4(addr) => addr.is_link_local(),
+            IpAddr::V6(_) => false,
+        })
+        .or_else(|| v4.clone().next())
+        .map(|addr| format!("{}:{}", addr, port))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ChatManager;
+
+    #[test]
+    fn peers_of_the_same_name_get_distinct_services() {
+        let services: Vec<ServiceInfo> = (0..2)
+            .map(|_| {
+                let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+                std::fs::create_dir_all(&root).unwrap();
+                let root = root.to_string_lossy().into_owned();
+                let mgr = ChatManager::new("alice".to_string(), root, 0).unwrap();
+                let service = service_info(&mgr.get_pub_key(), mgr.get_dns_record_map(), 0);
+                mgr.shutdown();
+                service.unwrap()
+            })
+            .collect();
+
+        assert_ne!(services[0].get_fullname(), services[1].get_fullname());
+        assert_ne!(services[0].get_hostname(), services[1].get_hostname());
+        for service in &services {
+            assert!(!service.get_fullname().contains("alice"));
+            let record = service.get_properties().clone().into_property_map_str();
+            assert_eq!(record.get("name").map(String::as_str), Some("alice"));
+        }
+    }
+}
@@ -0,0 +1,155 @@
+use std::path::Path;
+
+use chrono::DateTime;
+
+use crate::{ExportFormat, Message, SystemInfo, SystemKind};
+
+/// Renders `messages`, oldest first, as a readable transcript titled after the peer.
+/// Files are referenced by id and name, their contents are not included.
+pub(crate) fn render(peer_name: &str, messages: &[Message], format: ExportFormat) -> String {
+    let mut out = match format {
+        ExportFormat::PlainText => format!("Conversation with {}\n\n", peer_name),
+        ExportFormat::Markdown => format!("# Conversation with {}\n\n", escape(peer_name)),
+    };
+    for msg in messages {
+        let time = format_time(msg.timestamp);
+        let body = body(msg);
+        match format {
+            ExportFormat::PlainText => {
+                out.push_str(&format!("[{}] {}: {}\n", time, msg.display_name, body));
+            }
+            ExportFormat::Markdown => {
+                out.push_str(&format!(
+                    "**{}** _{}_\n\n{}\n\n",
+                    escape(&msg.display_name),
+                    time,
+                    escape(&body)
+                ));
+            }
+        }
+    }
+    out
+}
+
+fn body(msg: &Message) -> String {
+    if msg.unsupported {
+        return "(unsupported message type)".to_owned();
+    }
+    if let Some(system) = &msg.system {
+        return describe_system(system);
+    }
+    let mut body = msg.text.clone();
+    if let Some(file_id) = &msg.file_id {
+        let name = msg
+            .file_path
+            .as_deref()
+            .and_then(|path| Path::new(path).file_name())
+            .map(|name| format!(" {}", name.to_string_lossy()))
+            .unwrap_or_default();
+        if !body.is_empty() {
+            body.push(' ');
+        }
+        body.push_str(&format!("(file {}{})", file_id, name));
+    }
+    body
+}
+
+fn describe_system(system: &SystemInfo) -> String {
+    match system.kind {
+        SystemKind::Joined => "(joined)".to_owned(),
+        SystemKind::Left if system.value.is_empty() => "(left)".to_owned(),
+        SystemKind::Left => format!("({} left)", system.value),
+        SystemKind::Renamed => format!("(is now known as {})", system.value),
+    }
+}
+
+fn format_time(timestamp: i64) -> String {
+    DateTime::from_timestamp(timestamp, 0)
+        .map(|time| time.format("%Y-%m-%d %H:%M UTC").to_string())
+        .unwrap_or_default()
+}
+
+/// Keeps names and text from being read as markdown formatting.
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '#' | '<' | '>' | '|'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    fn message(id: &str, name: &str, timestamp: i64, text: &str) -> Message {
+        Message {
+            order: id.to_owned(),
+            id: id.to_owned(),
+            text: text.to_owned(),
+            file_id: None,
+            file_path: None,
+            peer_id: name.to_lowercase(),
+            display_name: name.to_owned(),
+            is_own: false,
+            system: None,
+            unsupported: false,
+            timestamp,
+            received_at: timestamp,
+            recipient: None,
+            status: None,
+            metadata: HashMap::new(),
+            is_poll: false,
+            link_preview: None,
+            expiry: None,
+            group_id: None,
+        }
+    }
+
+    fn conversation() -> Vec<Message> {
+        let mut joined = message("m1", "Bob", 1_700_000_000, "");
+        joined.system = Some(SystemInfo {
+            kind: SystemKind::Joined,
+            value: String::new(),
+        });
+        let mut photo = message("m3", "Bob", 1_700_000_120, "look");
+        photo.file_id = Some("f1".to_owned());
+        photo.file_path = Some("/data/files/cat.jpg".to_owned());
+        vec![
+            joined,
+            message("m2", "Alice", 1_700_000_060, "hi *bob*"),
+            photo,
+        ]
+    }
+
+    #[test]
+    fn conversation_renders_as_plain_text() {
+        let rendered = render("Bob", &conversation(), ExportFormat::PlainText);
+        assert_eq!(
+            rendered,
+            "Conversation with Bob\n\n\
+             [2023-11-14 22:13 UTC] Bob: (joined)\n\
+             [2023-11-14 22:14 UTC] Alice: hi *bob*\n\
+             [2023-11-14 22:15 UTC] Bob: look (file f1 cat.jpg)\n"
+        );
+    }
+
+    #[test]
+    fn conversation_renders_as_markdown() {
+        let rendered = render("Bob_", &conversation(), ExportFormat::Markdown);
+        assert_eq!(
+            rendered,
+            "# Conversation with Bob\\_\n\n\
+             **Bob** _2023-11-14 22:13 UTC_\n\n(joined)\n\n\
+             **Alice** _2023-11-14 22:14 UTC_\n\nhi \\*bob\\*\n\n\
+             **Bob** _2023-11-14 22:15 UTC_\n\nlook (file f1 cat.jpg)\n\n"
+        );
+    }
+}
@@ -1,18 +1,40 @@
 use chat_arch::app_context::{self, AppContext};
-use chat_arch::events::ChatEvent;
-use chat_arch::peer_pool::Dialer;
-use chat_arch::{file_database, models, peer_database};
+use chat_arch::config::{Config, RuntimeOptions};
+use chat_arch::events::{ChatEvent, FileChunkListener};
+use chat_arch::peer_pool::{self, Dialer};
+use chat_arch::{
+    file_database, fingerprint, health, models, peer_database, protocol_recorder, storage_error,
+};
+use discovery::{Discovery, ResolvedService};
 use ed25519_dalek::{Signature, Signer, SigningKey, Verifier};
-use std::collections::HashMap;
-use std::env;
+use std::collections::{HashMap, HashSet};
 use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
 use tokio::runtime::Runtime;
 use uniffi::deps::anyhow;
-use uniffi::deps::log::{info, LevelFilter};
-use oslog::OsLogger;
+use uniffi::deps::log::{info, warn};
 
 uniffi::setup_scaffolding!();
 
+mod discovery;
+mod export;
+
+/// Protocol frames kept by debug builds for `recent_protocol_events`.
+const PROTOCOL_TRACE_SIZE: usize = 1000;
+/// Messages dated further ahead are shown at the time they arrived.
+const MAX_CLOCK_SKEW: Duration = Duration::from_secs(24 * 60 * 60);
+
+fn default_config() -> Config {
+    Config {
+        // debug builds keep a trace of the protocol frames for bug reports
+        protocol_trace_size: if cfg!(debug_assertions) { PROTOCOL_TRACE_SIZE } else { 0 },
+        coalesce_batch_events: true,
+        max_clock_skew: Some(MAX_CLOCK_SKEW),
+        ..Config::default()
+    }
+}
+
 #[derive(uniffi::Record, Clone, Debug)]
 pub struct Message {
     pub order: String,
@@ -21,50 +43,590 @@ pub struct Message {
     pub file_id: Option<String>,
     pub file_path: Option<String>,
     pub peer_id: String,
+    /// Name of the sender at the time the message was loaded, falls back to a short id.
+    pub display_name: String,
+    /// Sent by this device's identity.
+    pub is_own: bool,
+    pub system: Option<SystemInfo>,
+    /// Sent by a newer app version, render as "unsupported message type".
+    pub unsupported: bool,
+    pub timestamp: i64,
+    /// When this device received the message.
+    pub received_at: i64,
+    /// Set on direct messages, only the sender and this peer can read them.
+    pub recipient: Option<String>,
+    /// Progress of our own messages, `None` for messages of other peers.
+    pub status: Option<MessageStatus>,
+    /// Entries attached with `send_message_with_metadata`.
+    pub metadata: HashMap<String, String>,
+    /// A poll created with `create_poll`, `text` is the question. Load the options
+    /// and votes with `get_poll(id)`.
+    pub is_poll: bool,
+    /// Preview card sent with `send_message_with_link_preview`.
+    pub link_preview: Option<LinkPreview>,
+    /// Set on messages sent with `send_expiring_message`, they disappear on their own
+    /// with a `MessageRemoved` event.
+    pub expiry: Option<MessageExpiry>,
+    /// The group the message was sent to with `send_group_message`.
+    pub group_id: Option<String>,
+}
+
+/// When a self-deleting message starts counting down.
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExpiryTrigger {
+    /// From when it was sent.
+    FromSend,
+    /// From when a recipient marks it read with `mark_conversation_read`, e.g. for
+    /// "view once" messages.
+    FromRead,
+}
+
+impl From<models::ExpiryTrigger> for ExpiryTrigger {
+    fn from(trigger: models::ExpiryTrigger) -> Self {
+        match trigger {
+            models::ExpiryTrigger::FromSend => ExpiryTrigger::FromSend,
+            models::ExpiryTrigger::FromRead => ExpiryTrigger::FromRead,
+        }
+    }
+}
+
+impl From<ExpiryTrigger> for models::ExpiryTrigger {
+    fn from(trigger: ExpiryTrigger) -> Self {
+        match trigger {
+            ExpiryTrigger::FromSend => models::ExpiryTrigger::FromSend,
+            ExpiryTrigger::FromRead => models::ExpiryTrigger::FromRead,
+        }
+    }
+}
+
+#[derive(uniffi::Record, Clone, Copy, Debug)]
+pub struct MessageExpiry {
+    pub seconds: u64,
+    pub trigger: ExpiryTrigger,
+}
+
+/// Preview card of a link, fetched by the sending app. The thumbnail is an ordinary
+/// file, download it with `resolve_file` and read it with `get_file_path`.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct LinkPreview {
+    pub url: String,
+    pub title: String,
+    pub description: String,
+    /// File id of a thumbnail registered with `register_file`.
+    pub image_file_id: Option<String>,
+}
+
+impl From<models::LinkPreview> for LinkPreview {
+    fn from(preview: models::LinkPreview) -> Self {
+        LinkPreview {
+            url: preview.url,
+            title: preview.title,
+            description: preview.description,
+            image_file_id: preview.image_file_id,
+        }
+    }
+}
+
+impl Message {
+    fn new(msg: models::IndexedMessage, display_name: String, own_id: &str) -> Self {
+        Message {
+            order: msg.order_id,
+            id: msg.id,
+            text: msg.text,
+            file_id: msg.file_id,
+            file_path: msg.file_path,
+            is_own: msg.peer_id == own_id,
+            peer_id: msg.peer_id,
+            display_name,
+            system: msg.system.map(|system| system.into()),
+            unsupported: msg.unsupported,
+            timestamp: msg.timestamp,
+            received_at: msg.received_at,
+            recipient: msg.recipient,
+            status: msg.status.map(|status| status.into()),
+            metadata: msg.metadata,
+            is_poll: msg.poll,
+            link_preview: msg.link_preview.map(|preview| preview.into()),
+            expiry: msg.expiry.map(|expiry| MessageExpiry {
+                seconds: expiry.seconds,
+                trigger: expiry.trigger.into(),
+            }),
+            group_id: msg.group_id,
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum MessageStatus {
+    Sending,
+    Sent,
+    Delivered,
+    Read,
+}
+
+impl From<models::MessageStatus> for MessageStatus {
+    fn from(status: models::MessageStatus) -> Self {
+        match status {
+            models::MessageStatus::Sending => MessageStatus::Sending,
+            models::MessageStatus::Sent => MessageStatus::Sent,
+            models::MessageStatus::Delivered => MessageStatus::Delivered,
+            models::MessageStatus::Read => MessageStatus::Read,
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NotificationImportance {
+    Low,
+    Normal,
+    High,
+}
+
+impl From<models::NotificationImportance> for NotificationImportance {
+    fn from(importance: models::NotificationImportance) -> Self {
+        match importance {
+            models::NotificationImportance::Low => NotificationImportance::Low,
+            models::NotificationImportance::Normal => NotificationImportance::Normal,
+            models::NotificationImportance::High => NotificationImportance::High,
+        }
+    }
+}
+
+impl From<NotificationImportance> for models::NotificationImportance {
+    fn from(importance: NotificationImportance) -> Self {
+        match importance {
+            NotificationImportance::Low => models::NotificationImportance::Low,
+            NotificationImportance::Normal => models::NotificationImportance::Normal,
+            NotificationImportance::High => models::NotificationImportance::High,
+        }
+    }
+}
+
+/// Per-conversation notification settings, stored on this device only.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct NotificationPref {
+    /// No notifications until this unix timestamp.
+    pub muted_until: Option<i64>,
+    /// Only notify about messages mentioning us.
+    pub mentions_only: bool,
+    pub importance: NotificationImportance,
+}
+
+impl From<models::NotificationPref> for NotificationPref {
+    fn from(pref: models::NotificationPref) -> Self {
+        NotificationPref {
+            muted_until: pref.muted_until,
+            mentions_only: pref.mentions_only,
+            importance: pref.importance.into(),
+        }
+    }
+}
+
+impl From<NotificationPref> for models::NotificationPref {
+    fn from(pref: NotificationPref) -> Self {
+        models::NotificationPref {
+            muted_until: pref.muted_until,
+            mentions_only: pref.mentions_only,
+            importance: pref.importance.into(),
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Debug)]
+pub enum SystemKind {
+    Joined,
+    Left,
+    Renamed,
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct SystemInfo {
+    pub kind: SystemKind,
+    pub value: String,
+}
+
+impl From<models::SystemInfo> for SystemInfo {
+    fn from(system: models::SystemInfo) -> Self {
+        SystemInfo {
+            kind: match system.kind {
+                models::SystemKind::Joined => SystemKind::Joined,
+                models::SystemKind::Left => SystemKind::Left,
+                models::SystemKind::Renamed => SystemKind::Renamed,
+            },
+            value: system.value,
+        }
+    }
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct MessageLocation {
+    pub order_id: String,
+    pub offset_from_end: u64,
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct Identity {
+    pub peer_id: String,
+    pub pub_key: String,
+    pub name: String,
 }
 
 #[derive(uniffi::Record, Clone, Debug)]
 pub struct Peer {
     pub id: String,
+    /// Display name, falls back to a short id when the peer has none.
     pub name: String,
+    /// The user confirmed the peer's safety number, see `mark_verified`.
+    pub verified: bool,
+    /// Avatar file from the peer's profile, downloaded by `get_peer_avatar_path`.
+    pub avatar_file_id: Option<String>,
+    /// Where the avatar is stored, `None` until it was downloaded.
+    pub avatar_path: Option<String>,
+    /// Unix timestamp of the last connection, `None` if it never connected.
+    pub last_seen: Option<i64>,
 }
 
 impl From<chat_arch::peer_database::Peer> for Peer {
     fn from(peer: chat_arch::peer_database::Peer) -> Self {
         Peer {
+            name: peer.display_name(),
             id: peer.id,
-            name: peer.name.unwrap_or("Default".to_owned()),
+            verified: peer.verified,
+            avatar_file_id: peer.avatar_file_id,
+            avatar_path: peer.avatar_path,
+            last_seen: peer.last_seen.map(|last_seen| last_seen.timestamp()),
+        }
+    }
+}
+
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct Conversation {
+    pub peer: Peer,
+    pub last_message: Option<Message>,
+    pub unread_count: u64,
+}
+
+/// How far one of our messages has spread, e.g. "delivered to 3/5".
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct DeliveryStatus {
+    /// Known peers that have accepted the message.
+    pub delivered_to: Vec<String>,
+    /// Number of known peers the message is synced to.
+    pub recipients: u64,
+}
+
+/// Result of `verify_files`.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct FileCheck {
+    pub checked: u64,
+    /// Files whose local copy was gone. Their messages now have no `file_path` and
+    /// they are downloaded again if a peer still has them.
+    pub missing: Vec<String>,
+}
+
+/// Liveness of the node, see `get_health`.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct Health {
+    /// The server accepts connections.
+    pub listening: bool,
+    /// The database answered within a few seconds.
+    pub db_responsive: bool,
+    /// Seconds since the last sync sweep started, `None` if syncing never started.
+    pub seconds_since_last_sweep: Option<u64>,
+    /// All of the above are fine and sweeps are on time.
+    pub healthy: bool,
+}
+
+impl From<health::Health> for Health {
+    fn from(health: health::Health) -> Self {
+        Health {
+            listening: health.listening,
+            db_responsive: health.db_responsive,
+            seconds_since_last_sweep: health.since_last_sweep.map(|since| since.as_secs()),
+            healthy: health.is_healthy(),
+        }
+    }
+}
+
+/// Bytes exchanged with a peer since startup, see `get_bandwidth_usage`.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct PeerBandwidth {
+    pub peer_id: String,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+/// A protocol frame exchanged with a peer, see `recent_protocol_events`.
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct ProtocolEvent {
+    pub timestamp_ms: i64,
+    pub peer_id: String,
+    /// Whether we wrote the frame, otherwise the peer did.
+    pub sent: bool,
+    /// Whether the frame opened the exchange, otherwise it answered it.
+    pub request: bool,
+    pub variant: String,
+    pub size: u64,
+    pub error: Option<String>,
+}
+
+impl From<protocol_recorder::ProtocolEvent> for ProtocolEvent {
+    fn from(event: protocol_recorder::ProtocolEvent) -> Self {
+        ProtocolEvent {
+            timestamp_ms: event.timestamp_ms,
+            peer_id: event.peer_id,
+            sent: event.direction == protocol_recorder::FrameDirection::Sent,
+            request: event.kind == protocol_recorder::FrameKind::Request,
+            variant: event.variant,
+            size: event.size,
+            error: event.error,
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Debug)]
+pub enum ConnectionError {
+    Timeout,
+    HandshakeFailed(String),
+    AuthRejected,
+    NoAddress,
+    Io(String),
+    Closed,
+    DecryptFailed,
+    PeerClosed(String),
+}
+
+impl From<peer_pool::ConnectionError> for ConnectionError {
+    fn from(error: peer_pool::ConnectionError) -> Self {
+        match error {
+            peer_pool::ConnectionError::Timeout => ConnectionError::Timeout,
+            peer_pool::ConnectionError::HandshakeFailed(reason) => {
+                ConnectionError::HandshakeFailed(reason)
+            }
+            peer_pool::ConnectionError::AuthRejected => ConnectionError::AuthRejected,
+            peer_pool::ConnectionError::NoAddress => ConnectionError::NoAddress,
+            peer_pool::ConnectionError::Io(reason) => ConnectionError::Io(reason),
+            peer_pool::ConnectionError::Closed => ConnectionError::Closed,
+            peer_pool::ConnectionError::DecryptFailed => ConnectionError::DecryptFailed,
+            peer_pool::ConnectionError::PeerClosed(reason) => ConnectionError::PeerClosed(reason),
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Debug)]
+pub enum PeerConnectionState {
+    Connected,
+    Connecting,
+    BackingOff { last_error: ConnectionError },
+    Disconnected,
+}
+
+impl From<peer_pool::PeerConnState> for PeerConnectionState {
+    fn from(state: peer_pool::PeerConnState) -> Self {
+        match state {
+            peer_pool::PeerConnState::Connected => PeerConnectionState::Connected,
+            peer_pool::PeerConnState::Connecting => PeerConnectionState::Connecting,
+            peer_pool::PeerConnState::BackingOff { last_error } => {
+                PeerConnectionState::BackingOff {
+                    last_error: last_error.into(),
+                }
+            }
+            peer_pool::PeerConnState::Disconnected => PeerConnectionState::Disconnected,
+        }
+    }
+}
+
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StorageErrorKind {
+    Full,
+    PermissionDenied,
+    ReadOnly,
+    Io,
+}
+
+impl From<storage_error::StorageErrorKind> for StorageErrorKind {
+    fn from(kind: storage_error::StorageErrorKind) -> Self {
+        match kind {
+            storage_error::StorageErrorKind::Full => StorageErrorKind::Full,
+            storage_error::StorageErrorKind::PermissionDenied => StorageErrorKind::PermissionDenied,
+            storage_error::StorageErrorKind::ReadOnly => StorageErrorKind::ReadOnly,
+            storage_error::StorageErrorKind::Io => StorageErrorKind::Io,
         }
     }
 }
 
+// uniffi records can't be boxed, the event is moved once per callback anyway
+#[allow(clippy::large_enum_variant)]
 #[derive(uniffi::Enum)]
 pub enum Event {
     Message(Message),
+    /// Messages synced together from one peer, delivered at once to save the UI
+    /// from inserting them one by one.
+    MessagesBatch(Vec<Message>),
+    MessageRemoved(String),
     Peer(Peer),
+    ConnectionFailed {
+        peer_id: String,
+        display_name: String,
+        error: ConnectionError,
+    },
+    MessageDelivered {
+        message_id: String,
+        peer_id: String,
+        display_name: String,
+    },
+    ConnectionRequest {
+        peer_id: String,
+        display_name: String,
+    },
+    MessageStatusChanged {
+        message_id: String,
+        status: MessageStatus,
+    },
+    /// Another peer accepted one of our messages, sent after its `MessageDelivered`
+    /// with what `get_delivery_status` now returns.
+    DeliveryStatusChanged {
+        message_id: String,
+        status: DeliveryStatus,
+    },
+    /// The member roster of a group changed, reload it with `get_group_members`.
+    GroupChanged(String),
+    /// Someone voted on the poll, reload it with `get_poll`.
+    PollChanged(String),
+    /// A new message the user should be alerted about, sent after its `Message`
+    /// event when the conversation's `NotificationPref` allows it.
+    Notification {
+        message_id: String,
+        peer_id: String,
+        display_name: String,
+        importance: NotificationImportance,
+    },
+    /// The peer published a new name or avatar.
+    PeerProfileUpdated(Peer),
+    /// A known peer presented a different public key and is no longer verified.
+    /// Show a prominent warning, someone may be impersonating the peer.
+    PeerKeyChanged {
+        peer_id: String,
+        display_name: String,
+        was_verified: bool,
+    },
+    /// Data couldn't be stored, e.g. the device is out of space. Warn the user,
+    /// syncing resumes once there is room again.
+    StorageError {
+        kind: StorageErrorKind,
+    },
+    /// No peer offered the file through all download retries. Show it as
+    /// unavailable, `resolve_file` tries again.
+    FileUnresolvable(String),
+    /// The session with the peer was closed after going unused, it is no longer
+    /// shown as connected. Sending to it dials it again.
+    PeerOffline {
+        peer_id: String,
+        display_name: String,
+    },
+    /// The peer went too long without a connection and was removed, drop it from
+    /// peer lists. Its messages are kept.
+    PeerRemoved(String),
+    /// Progress of catching up with the history of `peer_id`, e.g. right after it
+    /// was added. Done once `received` reaches `estimated_total`, which is `None`
+    /// for peers on an older version.
+    SyncProgress {
+        peer_id: String,
+        received: u64,
+        estimated_total: Option<u64>,
+    },
+    /// A received file wasn't downloaded because the `AutoDownloadPolicy` asks to
+    /// prompt for its sender. Download it with `resolve_file` if the user agrees.
+    FileDownloadPrompt {
+        file_id: String,
+        peer_id: String,
+        display_name: String,
+    },
+    /// `refresh_discovery` found a peer on the local network and saved it.
+    PeerDiscovered {
+        peer_id: String,
+        name: String,
+        addr: String,
+    },
 }
 
+/// Errors of `ChatManager` calls, specific enough for apps to tell the user what
+/// went wrong. Failures the app can't act on are reported as `Internal`.
 #[derive(Debug, PartialEq, thiserror::Error, uniffi::Error)]
 pub enum ChatError {
-    #[error("Failed to create a TCP listener.")]
-    FailedToCreateNew(String),
+    #[error("Not connected.")]
+    NotConnected(String),
+    #[error("Timed out.")]
+    Timeout,
+    #[error("Peer authentication failed.")]
+    AuthRejected,
+    #[error("Peer not found.")]
+    PeerNotFound(String),
+    #[error("File not found.")]
+    FileNotFound(String),
+    #[error("Message not found.")]
+    MessageNotFound(String),
+    #[error("Storage is full.")]
+    StorageFull,
+    #[error("Storage is not writable.")]
+    StorageUnavailable,
+    #[error("Invalid input.")]
+    InvalidInput(String),
     #[error("Failed to decode a TXT record.")]
     FailedToDecodeTxtRecord,
-    #[error("Failed to connect.")]
-    FailedToConnect,
     #[error("Failed to send.")]
     FailedToSend,
     #[error("Failed to download.")]
     FailedToDownload(String),
+    #[error("Internal error.")]
+    Internal(String),
 }
 
 impl ChatError {
-    fn create_new_error<T: std::fmt::Display>(e: T) -> ChatError {
-        ChatError::FailedToCreateNew(format!("{}", e))
+    /// Maps an error of the core to the variant it stands for, `Internal` if none does.
+    fn from_error<E: Into<anyhow::Error>>(e: E) -> ChatError {
+        let e = e.into();
+        ChatError::classify(&e).unwrap_or_else(|| ChatError::Internal(format!("{}", e)))
+    }
+
+    fn invalid_input<T: std::fmt::Display>(e: T) -> ChatError {
+        ChatError::InvalidInput(format!("{}", e))
+    }
+
+    fn failed_to_send(e: anyhow::Error) -> ChatError {
+        ChatError::classify(&e).unwrap_or(ChatError::FailedToSend)
+    }
+
+    fn failed_to_download(e: anyhow::Error) -> ChatError {
+        ChatError::classify(&e).unwrap_or_else(|| ChatError::FailedToDownload(format!("{}", e)))
     }
 
-    fn failed_to_download<T: std::fmt::Display>(e: T) -> ChatError {
-        ChatError::FailedToDownload(format!("{}", e))
+    /// Looks for a cause the app can act on anywhere in the chain of `e`.
+    fn classify(e: &anyhow::Error) -> Option<ChatError> {
+        if let Some(kind) = storage_error::classify(e) {
+            return Some(match kind {
+                storage_error::StorageErrorKind::Full => ChatError::StorageFull,
+                _ => ChatError::StorageUnavailable,
+            });
+        }
+        e.chain().find_map(|cause| {
+            if let Some(err) = cause.downcast_ref::<peer_pool::ConnectionError>() {
+                return Some(match err {
+                    peer_pool::ConnectionError::Timeout => ChatError::Timeout,
+                    peer_pool::ConnectionError::AuthRejected => ChatError::AuthRejected,
+                    err => ChatError::NotConnected(err.to_string()),
+                });
+            }
+            if cause.is::<tokio::time::error::Elapsed>() {
+                return Some(ChatError::Timeout);
+            }
+            let err = cause.downcast_ref::<std::io::Error>()?;
+            match err.kind() {
+                std::io::ErrorKind::TimedOut => Some(ChatError::Timeout),
+                std::io::ErrorKind::NotFound => Some(ChatError::FileNotFound(err.to_string())),
+                _ => None,
+            }
+        })
     }
 }
 
@@ -75,6 +637,99 @@ pub struct DnsRecord {
     pub pub_key: String,
 }
 
+#[derive(uniffi::Record, Clone, Debug)]
+pub struct Poll {
+    pub id: String,
+    pub question: String,
+    pub options: Vec<String>,
+    /// Number of votes of each option, in the order of `options`.
+    pub votes: Vec<u64>,
+    /// The option we voted for, if any.
+    pub own_vote: Option<u32>,
+}
+
+impl From<models::Poll> for Poll {
+    fn from(poll: models::Poll) -> Self {
+        Poll {
+            id: poll.id,
+            question: poll.question,
+            options: poll.options,
+            votes: poll.votes,
+            own_vote: poll.own_vote,
+        }
+    }
+}
+
+/// Order of `get_peers_sorted`.
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PeerSort {
+    /// Alphabetical, unnamed peers last.
+    Name,
+    /// Most recently added first.
+    Newest,
+}
+
+impl From<PeerSort> for peer_database::PeerSort {
+    fn from(sort: PeerSort) -> Self {
+        match sort {
+            PeerSort::Name => peer_database::PeerSort::Name,
+            PeerSort::Newest => peer_database::PeerSort::Newest,
+        }
+    }
+}
+
+/// Layout of `export_conversation`.
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    PlainText,
+    Markdown,
+}
+
+/// What happens to a file received from a peer, see `AutoDownloadPolicy`.
+#[derive(uniffi::Enum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DownloadAction {
+    /// Download right away.
+    Auto,
+    /// Send `Event::FileDownloadPrompt` and leave the download to the user.
+    Prompt,
+    /// Don't download, the message still shows the file id.
+    Never,
+}
+
+/// Which received files are downloaded without asking, by how much the sender is trusted.
+/// The default downloads from verified peers, prompts for other known peers and never
+/// downloads from senders without a peer record.
+#[derive(uniffi::Record, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AutoDownloadPolicy {
+    /// Peers whose safety number the user confirmed with `mark_verified`.
+    pub verified: DownloadAction,
+    /// Known peers that are not verified.
+    pub unverified: DownloadAction,
+    /// Senders we have no peer record for.
+    pub unknown: DownloadAction,
+}
+
+impl Default for AutoDownloadPolicy {
+    fn default() -> Self {
+        Self {
+            verified: DownloadAction::Auto,
+            unverified: DownloadAction::Prompt,
+            unknown: DownloadAction::Never,
+        }
+    }
+}
+
+impl AutoDownloadPolicy {
+    /// What to do with a file from `peer`, `None` for a sender without a peer record.
+    fn action(&self, peer: Option<&peer_database::Peer>) -> DownloadAction {
+        match peer {
+            Some(peer) if peer.verified => self.verified,
+            Some(_) => self.unverified,
+            None => self.unknown,
+        }
+    }
+}
+
 #[derive(uniffi::Object)]
 pub struct ChatManager {
     context: AppContext,
@@ -83,6 +738,9 @@ pub struct ChatManager {
     root_path: String,
     txt_record: Vec<u8>,
     txt_record_map: HashMap<String, String>,
+    port: u16,
+    discovery: Mutex<Option<Discovery>>,
+    auto_download: Mutex<AutoDownloadPolicy>,
     delegate: Arc<Mutex<Option<Arc<dyn ChatDelegate>>>>,
 }
 
@@ -91,48 +749,48 @@ pub trait ChatDelegate: Send + Sync {
     fn on_event(&self, event: Event);
 }
 
+#[uniffi::export(with_foreign)]
+pub trait FileStreamDelegate: Send + Sync {
+    fn on_file_chunk(&self, file_id: String, offset: u64, chunk: Vec<u8>);
+}
+
+struct FileStreamListener {
+    delegate: Arc<dyn FileStreamDelegate>,
+}
+
+impl FileChunkListener for FileStreamListener {
+    fn on_file_chunk(&self, file_id: &str, offset: u64, chunk: &[u8]) {
+        self.delegate
+            .on_file_chunk(file_id.to_owned(), offset, chunk.to_vec());
+    }
+}
+
 #[uniffi::export]
 impl ChatManager {
     #[uniffi::constructor]
     pub fn new(name: String, root_path: String, port: u16) -> Result<Self, ChatError> {
-        unsafe {
-            // env::set_var("RUST_LOG", "DEBUG");
-        }
-        env_logger::init();
-        // OsLogger::new("com.rust")
-        //     .level_filter(LevelFilter::Debug)
-        //     .init()
-        //     .unwrap();
-        let runtime = tokio::runtime::Runtime::new().map_err(|e| ChatError::create_new_error(e))?;
-        let runtime = Arc::new(runtime);
-        let addr = format!("0.0.0.0:{}", port);
-        let deps = runtime.block_on(async {
-            app_context::prepare_deps(&name, &addr, &root_path, runtime.clone())
-                .await
-                .map_err(|e| ChatError::create_new_error(e))
-        })?;
-        let name = deps.peer.get_name();
-        let mut map = HashMap::new();
-        let key = deps.signing_key.clone();
-        let signature = key.sign(name.as_bytes());
-        map.insert("signature".to_string(), hex::encode(signature.to_bytes()));
-        map.insert("port".to_string(), port.to_string());
-        map.insert("name".to_string(), name);
-        map.insert(
-            "pub_key".to_string(),
-            hex::encode(key.verifying_key().to_bytes()),
-        );
-        let txt_record = encode_txt_record(&map).unwrap();
-        let mgr = ChatManager {
-            root_path,
-            context: deps,
-            runtime,
-            signing_key: key,
-            delegate: Arc::new(Mutex::new(None)),
-            txt_record,
-            txt_record_map: map,
+        Self::new_with_runtime_options(name, root_path, port, None, None)
+    }
+
+    /// Like `new`, with `worker_threads` threads named `thread_name` instead of one
+    /// per core, e.g. to save memory on constrained devices.
+    #[uniffi::constructor]
+    pub fn new_with_runtime_options(
+        name: String,
+        root_path: String,
+        port: u16,
+        worker_threads: Option<u32>,
+        thread_name: Option<String>,
+    ) -> Result<Self, ChatError> {
+        let config = Config {
+            runtime: RuntimeOptions {
+                worker_threads: worker_threads.map(|threads| threads as usize),
+                thread_name,
+            },
+            ..default_config()
         };
-        Ok(mgr)
+        let runtime = config.runtime.build().map_err(ChatError::from_error)?;
+        Self::start(name, root_path, port, config, Arc::new(runtime))
     }
 
     pub fn set_delegate(&self, delegate: Arc<dyn ChatDelegate>) {
@@ -140,6 +798,13 @@ impl ChatManager {
         *guard = Some(delegate);
     }
 
+    pub fn set_file_stream_delegate(&self, delegate: Option<Arc<dyn FileStreamDelegate>>) {
+        let listener = delegate.map(|delegate| {
+            Arc::new(FileStreamListener { delegate }) as Arc<dyn FileChunkListener>
+        });
+        self.context.sync_engine.set_file_chunk_listener(listener);
+    }
+
     pub fn run_server(&self) {
         let ctx = self.context.clone();
         self.runtime.block_on(async {
@@ -158,9 +823,57 @@ impl ChatManager {
         self.context.server.stop();
     }
 
+    /// Stops the server and the file resolver, aborts downloads in flight and closes all sessions.
+    pub fn shutdown(&self) {
+        self.context.server.stop();
+        self.context.sync_engine.shutdown();
+        self.context.file_resolver.shutdown();
+        let ctx = self.context.clone();
+        self.runtime.block_on(async {
+            ctx.sync_engine.peer_pool.close_all().await;
+        });
+    }
+
+    /// Syncs with the peer, or all known peers, right away instead of on the next tick.
+    pub fn sync_now(&self, peer_id: Option<String>) -> Result<(), ChatError> {
+        self.runtime
+            .block_on(async { self.context.sync_engine.sync_now(peer_id).await })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Replaces the `AutoDownloadPolicy`, applies to files received from now on.
+    pub fn set_auto_download_policy(&self, policy: AutoDownloadPolicy) {
+        *self.auto_download.lock().unwrap() = policy;
+    }
+
+    pub fn get_auto_download_policy(&self) -> AutoDownloadPolicy {
+        *self.auto_download.lock().unwrap()
+    }
+
+    /// Whether we are connected to the peer, dialing it or waiting after a failed
+    /// dial, for status dots next to contacts. Doesn't touch the network.
+    pub fn peer_connection_state(&self, peer_id: String) -> PeerConnectionState {
+        self.runtime
+            .block_on(async { self.context.sync_engine.peer_pool.peer_state(&peer_id).await })
+            .into()
+    }
+
+    /// Call when the device switched networks. Drops all sessions, which were bound
+    /// to the old interface, and rebinds the server. The TXT record carries no address,
+    /// so the host only needs to announce `get_dns_record` again on the new network.
+    pub fn network_changed(&self) {
+        let ctx = self.context.clone();
+        self.runtime.block_on(async {
+            ctx.sync_engine.peer_pool.close_all().await;
+        });
+        self.context.server.rebind();
+    }
+
     pub fn run_loop(&self) {
         self.context.sync_engine.run();
         self.context.file_resolver.clone().run();
+        self.context.message_expiry.clone().run();
+        self.context.peer_pruner.clone().run();
         let rx = self.context.events.get_rx();
         while let Ok(event) = rx.recv() {
             match event {
@@ -168,87 +881,737 @@ impl ChatManager {
                     let file_id = msg.file_id.clone();
                     let file_path = msg.file_path.clone();
                     let peer_id = msg.peer_id.clone();
-                    let message = Message {
-                        order: msg.order_id,
-                        id: msg.id,
-                        text: msg.text,
-                        file_id: msg.file_id,
-                        file_path: msg.file_path,
-                        peer_id: msg.peer_id,
+                    let display_name = self.get_display_name(peer_id.clone());
+                    let notification = self.notification(&msg, &display_name);
+                    let prompt = match (file_id, file_path) {
+                        (Some(file_id), None) => {
+                            self.auto_download(file_id, peer_id, &display_name)
+                        }
+                        _ => None,
+                    };
+                    let event =
+                        Event::Message(Message::new(msg, display_name, &self.context.peer.id));
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                        if let Some(notification) = notification {
+                            delegate.on_event(notification);
+                        }
+                        if let Some(prompt) = prompt {
+                            delegate.on_event(prompt);
+                        }
+                    }
+                }
+                ChatEvent::MessagesBatch(msgs) => {
+                    let names = match self.names() {
+                        Ok(names) => names,
+                        Err(e) => {
+                            warn!("failed to load names: {:?}", e);
+                            continue;
+                        }
                     };
-                    let event = Event::Message(message);
+                    let prompts: Vec<Event> = msgs
+                        .iter()
+                        .filter_map(|msg| match (&msg.file_id, &msg.file_path) {
+                            (Some(file_id), None) => self.auto_download(
+                                file_id.clone(),
+                                msg.peer_id.clone(),
+                                &names.display_name(&msg.peer_id),
+                            ),
+                            _ => None,
+                        })
+                        .collect();
+                    // one alert for the newest message that warrants it, not one per message
+                    let notification = msgs.iter().rev().find_map(|msg| {
+                        self.notification(msg, &names.display_name(&msg.peer_id))
+                    });
+                    let event = Event::MessagesBatch(names.messages(msgs));
                     let guard = self.delegate.lock().unwrap();
-                    if file_id.is_some() && file_path.is_none() {
-                        self.resolve_file(file_id.unwrap(), Some(peer_id));
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                        if let Some(notification) = notification {
+                            delegate.on_event(notification);
+                        }
+                        for prompt in prompts {
+                            delegate.on_event(prompt);
+                        }
                     }
+                }
+                ChatEvent::MessageRemoved(id) => {
+                    let event = Event::MessageRemoved(id);
+                    let guard = self.delegate.lock().unwrap();
                     if let Some(delegate) = &*guard {
                         delegate.on_event(event);
                     }
                 }
                 ChatEvent::Peer(peer) => {
-                    let peer = Peer {
-                        id: peer.id,
-                        name: peer.name.unwrap_or("Unknown".to_owned()),
+                    let event = Event::Peer(peer.into());
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::PeerProfileUpdated(peer) => {
+                    let event = Event::PeerProfileUpdated(peer.into());
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::ConnectionFailed { peer_id, error } => {
+                    let event = Event::ConnectionFailed {
+                        display_name: self.get_display_name(peer_id.clone()),
+                        peer_id,
+                        error: error.into(),
                     };
-                    let event = Event::Peer(peer);
                     let guard = self.delegate.lock().unwrap();
                     if let Some(delegate) = &*guard {
                         delegate.on_event(event);
                     }
                 }
-            }
-        }
-    }
-
-    pub fn get_peers(&self) -> Result<Vec<Peer>, ChatError> {
-        self.runtime
-            .block_on(async { self.context.peer_db.get_all_peers().await })
-            .map(|peers| peers.into_iter().map(|peer| peer.into()).collect())
-            .map_err(|e| ChatError::create_new_error(e))
-    }
-
-    pub fn set_peer(&self, name: String, addr: String, pub_key: String) -> Result<(), ChatError> {
-        self.runtime.block_on(async {
-            let peer = match peer_database::Peer::new(pub_key.clone(), name, pub_key.clone()) {
-                Ok(peer) => peer,
-                Err(e) => return Err(ChatError::create_new_error(e)),
-            };
-            self.context
-                .peer_db
-                .save_peer(&peer)
-                .await
-                .map_err(|e| ChatError::create_new_error(e))?;
-            self.context.dialer.add(pub_key.clone(), addr).await;
-            Ok(())
-        })
+                ChatEvent::ConnectionRequest(peer_id) => {
+                    let event = Event::ConnectionRequest {
+                        display_name: self.get_display_name(peer_id.clone()),
+                        peer_id,
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::MessageDelivered {
+                    message_id,
+                    peer_id,
+                } => {
+                    let changed = match self.runtime.block_on(self.delivery_status(&message_id)) {
+                        Ok(status) => Some(Event::DeliveryStatusChanged {
+                            message_id: message_id.clone(),
+                            status,
+                        }),
+                        Err(e) => {
+                            warn!("failed to get delivery status: {:?}", e);
+                            None
+                        }
+                    };
+                    let event = Event::MessageDelivered {
+                        message_id,
+                        display_name: self.get_display_name(peer_id.clone()),
+                        peer_id,
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                        if let Some(changed) = changed {
+                            delegate.on_event(changed);
+                        }
+                    }
+                }
+                ChatEvent::MessageStatusChanged { message_id, status } => {
+                    let event = Event::MessageStatusChanged {
+                        message_id,
+                        status: status.into(),
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::GroupChanged(group_id) => {
+                    let event = Event::GroupChanged(group_id);
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::PollChanged(poll_id) => {
+                    let event = Event::PollChanged(poll_id);
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::FileUnresolvable(file_id) => {
+                    let event = Event::FileUnresolvable(file_id);
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::PeerOffline(peer_id) => {
+                    let event = Event::PeerOffline {
+                        display_name: self.get_display_name(peer_id.clone()),
+                        peer_id,
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::PeerRemoved(peer_id) => {
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(Event::PeerRemoved(peer_id));
+                    }
+                }
+                ChatEvent::SyncProgress {
+                    peer_id,
+                    received,
+                    estimated_total,
+                } => {
+                    let event = Event::SyncProgress {
+                        peer_id,
+                        received,
+                        estimated_total,
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::PeerKeyChanged {
+                    peer_id,
+                    was_verified,
+                } => {
+                    let event = Event::PeerKeyChanged {
+                        display_name: self.get_display_name(peer_id.clone()),
+                        peer_id,
+                        was_verified,
+                    };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+                ChatEvent::StorageError { kind } => {
+                    let event = Event::StorageError { kind: kind.into() };
+                    let guard = self.delegate.lock().unwrap();
+                    if let Some(delegate) = &*guard {
+                        delegate.on_event(event);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Answers `Event::ConnectionRequest`, the peer's session is opened for syncing.
+    /// Fails once the request expired, the peer has to connect again.
+    pub fn approve_peer(&self, peer_id: String) -> Result<(), ChatError> {
+        let approved = self
+            .runtime
+            .block_on(async { self.context.inbound_gate.approve(&peer_id).await })
+            .map_err(ChatError::from_error)?;
+        if !approved {
+            return Err(ChatError::InvalidInput(format!(
+                "no pending connection from {}, it may have expired",
+                peer_id
+            )));
+        }
+        Ok(())
+    }
+
+    /// Answers `Event::ConnectionRequest`, the peer's session is dropped.
+    pub fn reject_peer(&self, peer_id: String) {
+        self.runtime
+            .block_on(async { self.context.inbound_gate.reject(&peer_id).await });
+    }
+
+    /// Known peers by name, the order is the same on every call.
+    pub fn get_peers(&self) -> Result<Vec<Peer>, ChatError> {
+        self.get_peers_sorted(PeerSort::Name)
+    }
+
+    pub fn get_peers_sorted(&self, by: PeerSort) -> Result<Vec<Peer>, ChatError> {
+        self.runtime
+            .block_on(async { self.context.peer_db.get_all_peers_sorted(by.into()).await })
+            .map(|peers| peers.into_iter().map(|peer| peer.into()).collect())
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn set_peer(&self, name: String, addr: String, pub_key: String) -> Result<(), ChatError> {
+        self.runtime.block_on(async {
+            let peer_db = &self.context.peer_db;
+            let peer = match peer_db.new_peer(name, pub_key) {
+                Ok(peer) => peer,
+                Err(e) => return Err(ChatError::invalid_input(e)),
+            };
+            self.context
+                .peer_db
+                .save_peer(&peer)
+                .await
+                .map_err(ChatError::from_error)?;
+            // the id is the key in canonical form, whatever case the caller used
+            self.context.dialer.add(peer.id.clone(), addr).await;
+            // pushes that were pending for this peer, e.g. from before a restart
+            if let Err(e) = self.context.sync_engine.resend_pending(peer.id).await {
+                info!("failed to resend pending messages: {:?}", e);
+            }
+            Ok(())
+        })
     }
     
     pub fn get_name(&self) -> String {
-        self.context.peer.get_name()
+        // our own profile updates the record, the context keeps the name from startup
+        self.stored_peer(&self.context.peer.id)
+            .map(|peer| peer.display_name())
+            .unwrap_or_else(|_| self.context.peer.display_name())
+    }
+
+    /// Registers the image at `path`, relative to the root path like `register_file`,
+    /// and publishes it as our avatar. Peers download it like any other file.
+    pub fn set_my_avatar(&self, path: String) -> Result<(), ChatError> {
+        let format = std::path::Path::new(&path)
+            .extension()
+            .map(|ext| ext.to_string_lossy().into_owned())
+            .unwrap_or_else(|| "bin".to_owned());
+        let file_id = self.register_file(format, path)?;
+        let own = self.stored_peer(&self.context.peer.id)?;
+        self.send_own(|builder| builder.profile(own.get_name(), Some(file_id)))
+            .map(|_| ())
+    }
+
+    /// Path of the peer's avatar, `None` if it has none or it isn't downloaded yet.
+    /// A missing avatar is requested from the peer, `Event::PeerProfileUpdated`
+    /// follows once it arrived.
+    pub fn get_peer_avatar_path(&self, peer_id: String) -> Result<Option<String>, ChatError> {
+        let peer = self.stored_peer(&peer_id)?;
+        match (peer.avatar_file_id, peer.avatar_path) {
+            (_, Some(path)) => Ok(Some(path)),
+            (Some(file_id), None) => {
+                self.resolve_file(file_id, Some(peer_id))?;
+                Ok(None)
+            }
+            (None, None) => Ok(None),
+        }
+    }
+
+    /// Publishes a new name with our profile, peers replace the name they know us by.
+    /// A changed name is also shown as a `SystemKind::Renamed` message.
+    pub fn set_my_name(&self, name: String) -> Result<(), ChatError> {
+        if name.trim().is_empty() {
+            return Err(ChatError::invalid_input("the name is empty"));
+        }
+        let own = self.stored_peer(&self.context.peer.id)?;
+        let renamed = name != own.get_name();
+        self.send_own(|builder| {
+            let builder = builder.profile(name.clone(), own.avatar_file_id);
+            if renamed {
+                builder.system(models::SystemKind::Renamed, name)
+            } else {
+                builder
+            }
+        })
+        .map(|_| ())
+    }
+
+    pub fn get_display_name(&self, peer_id: String) -> String {
+        if peer_id == self.context.peer.id {
+            return self.get_name();
+        }
+        self.runtime
+            .block_on(async { self.context.peer_db.get_display_name(&peer_id).await })
+            .unwrap_or_else(|_| peer_database::short_id(&peer_id))
     }
     
     pub fn get_pub_key(&self) -> String {
-        self.context.peer.id.clone()
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    pub fn identity(&self) -> Identity {
+        Identity {
+            peer_id: self.context.peer.id.clone(),
+            pub_key: self.get_pub_key(),
+            name: self.get_name(),
+        }
+    }
+
+    /// Our key fingerprint, for the other side to compare with their `peer_fingerprint`.
+    pub fn my_fingerprint(&self) -> String {
+        fingerprint::fingerprint(&self.context.peer.public_key)
+    }
+
+    /// Fingerprint of the key we stored for `peer_id`. It matches the peer's
+    /// `my_fingerprint` unless the key was swapped on the way.
+    pub fn peer_fingerprint(&self, peer_id: String) -> Result<String, ChatError> {
+        let peer = self.stored_peer(&peer_id)?;
+        Ok(fingerprint::fingerprint(&peer.public_key))
+    }
+
+    /// Safety number of the conversation with `peer_id`, derived from both keys so
+    /// both users see the same digits and can compare them in person or by phone.
+    pub fn safety_number(&self, peer_id: String) -> Result<String, ChatError> {
+        let peer = self.stored_peer(&peer_id)?;
+        Ok(fingerprint::safety_number(
+            &self.context.peer.public_key,
+            &peer.public_key,
+        ))
+    }
+
+    /// Marks the peer as verified once the user confirmed its `safety_number`.
+    pub fn mark_verified(&self, peer_id: String) -> Result<(), ChatError> {
+        self.set_verified(&peer_id, true)
+    }
+
+    pub fn unverify(&self, peer_id: String) -> Result<(), ChatError> {
+        self.set_verified(&peer_id, false)
     }
 
     pub fn get_all_messages(&self) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
         let ctx = self.context.clone();
         self.runtime
             .block_on(async {
-                ctx.indexer.get_all_after_order_id("").await.map(|msgs| {
-                    msgs.into_iter()
-                        .map(|msg| Message {
-                            order: msg.order_id,
-                            id: msg.id,
-                            text: msg.text,
-                            file_id: msg.file_id,
-                            file_path: msg.file_path,
-                            peer_id: msg.peer_id,
-                        })
-                        .collect::<Vec<Message>>()
-                })
+                ctx.indexer
+                    .get_all_after_order_id("")
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_peer_messages(&self, peer_id: String) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_peer_after_order_id(&peer_id, "")
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// One entry per known peer other than us, conversations with the newest message come first
+    /// and peers without messages are listed last.
+    pub fn get_conversations(&self) -> Result<Vec<Conversation>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                let mut latest: HashMap<String, models::ConversationSummary> = ctx
+                    .indexer
+                    .latest_per_peer()
+                    .await?
+                    .into_iter()
+                    .map(|summary| (summary.last_message.peer_id.clone(), summary))
+                    .collect();
+                let mut conversations = Vec::new();
+                for peer in ctx.peer_db.get_all_peers().await? {
+                    // our own record isn't a conversation
+                    if peer.id == ctx.peer.id {
+                        continue;
+                    }
+                    let summary = latest.remove(&peer.id);
+                    conversations.push(Conversation {
+                        unread_count: summary.as_ref().map_or(0, |summary| summary.unread_count),
+                        last_message: summary.map(|summary| names.message(summary.last_message)),
+                        peer: peer.into(),
+                    });
+                }
+                conversations.sort_by(|a, b| {
+                    let a = a.last_message.as_ref().map(|msg| &msg.order);
+                    let b = b.last_message.as_ref().map(|msg| &msg.order);
+                    b.cmp(&a)
+                });
+                Ok::<_, anyhow::Error>(conversations)
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Up to `limit` messages of a conversation following the order `after`, oldest first.
+    pub fn get_conversation_messages(
+        &self,
+        peer_id: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_for_peer(&peer_id, after.as_deref(), limit)
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// The newest `limit` messages of a conversation, newest first.
+    pub fn get_recent_messages(&self, peer_id: String, limit: u32) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_recent(&peer_id, limit)
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// The page preceding the message with order `before`, newest first. Pass the
+    /// `order` of the oldest loaded message to page upwards.
+    pub fn get_older_messages(&self, before: String, limit: u32) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_older(&before, limit)
+                    .await
+                    .map(|msgs| names.messages(msgs))
             })
-            .map_err(|e| ChatError::create_new_error(e))
+            .map_err(ChatError::from_error)
+    }
+
+    /// Text search, newest first. `order` of a result can be passed to `locate_message`
+    /// or `get_messages_around` to jump to it. `start`/`end` are unix timestamps.
+    pub fn search(
+        &self,
+        query: String,
+        peer_id: Option<String>,
+        start: Option<i64>,
+        end: Option<i64>,
+        limit: u32,
+    ) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .search(&query, peer_id.as_deref(), start, end, limit)
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn locate_message(&self, id: String) -> Result<MessageLocation, ChatError> {
+        let ctx = self.context.clone();
+        self.runtime.block_on(async {
+            let msg = ctx
+                .indexer
+                .get_by_id(&id)
+                .await
+                .map_err(ChatError::from_error)?
+                .ok_or(ChatError::MessageNotFound(id))?;
+            let offset_from_end = ctx
+                .indexer
+                .count_after_order_id(&msg.order_id)
+                .await
+                .map_err(ChatError::from_error)?;
+            Ok(MessageLocation {
+                order_id: msg.order_id,
+                offset_from_end,
+            })
+        })
+    }
+
+    pub fn get_messages_around(
+        &self,
+        order_id: String,
+        before: u32,
+        after: u32,
+    ) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_around_order_id(&order_id, before, after)
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// A conversation as a transcript for sharing or archiving, with sender names,
+    /// times and the ids of attached files.
+    pub fn export_conversation(
+        &self,
+        peer_id: String,
+        format: ExportFormat,
+    ) -> Result<String, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        let messages = self
+            .runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_peer_after_order_id(&peer_id, "")
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)?;
+        Ok(export::render(&names.display_name(&peer_id), &messages, format))
+    }
+
+    /// Sets how long the messages between us and `peer_id` live, pass our own id for
+    /// our messages to everyone and `None` to keep them. The setting is synced, every
+    /// node expires the same messages, and either side can change it.
+    pub fn set_message_ttl(&self, peer_id: String, seconds: Option<u64>) -> Result<(), ChatError> {
+        self.runtime
+            .block_on(async {
+                self.context
+                    .message_expiry
+                    .set_ttl(&peer_id, seconds)
+                    .await
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Moves the conversation's read watermark forward and sends the peer a read
+    /// receipt, its messages up to `up_to_order_id` show as `MessageStatus::Read` there.
+    pub fn mark_conversation_read(
+        &self,
+        peer_id: String,
+        up_to_order_id: String,
+    ) -> Result<(), ChatError> {
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                let moved = ctx.indexer.mark_read(&peer_id, &up_to_order_id).await?;
+                // an observer reads without telling anyone
+                if !moved || peer_id == ctx.peer.id || ctx.observer {
+                    return Ok(());
+                }
+                let receipt = models::MessageBuilder::new(
+                    uuid::Uuid::new_v4().to_string(),
+                    ctx.clock.timestamp(),
+                    ctx.peer.id.clone(),
+                )
+                .read_receipt(up_to_order_id)
+                .build_direct(&peer_id, &ctx.direct_cipher)?;
+                ctx.sync_engine
+                    .get_manager()
+                    .add_own_message(receipt)
+                    .await
+                    .map(|_| ())
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Peers that have accepted one of our messages, empty while it is still in the outbox.
+    pub fn get_delivered_peers(&self, message_id: String) -> Result<Vec<String>, ChatError> {
+        self.runtime
+            .block_on(async {
+                self.context
+                    .sync_engine
+                    .get_outbox()
+                    .delivered_to(&message_id)
+                    .await
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_delivery_status(&self, message_id: String) -> Result<DeliveryStatus, ChatError> {
+        self.runtime
+            .block_on(self.delivery_status(&message_id))
+            .map_err(ChatError::from_error)
+    }
+
+    /// Whether the server listens, the database answers and syncing is on schedule,
+    /// for a supervisor to poll and restart the node when it wedges.
+    pub fn get_health(&self) -> Health {
+        self.runtime
+            .block_on(async { health::check(&self.context).await })
+            .into()
+    }
+
+    pub fn is_healthy(&self) -> bool {
+        self.get_health().healthy
+    }
+
+    /// Traffic with every peer seen since startup, counted over the sync protocol
+    /// before encryption, so the bytes on the wire are slightly more.
+    pub fn get_bandwidth_usage(&self) -> Vec<PeerBandwidth> {
+        let mut usage: Vec<PeerBandwidth> = self
+            .context
+            .sync_engine
+            .peer_pool
+            .meter()
+            .all()
+            .into_iter()
+            .map(|(peer_id, traffic)| PeerBandwidth {
+                peer_id,
+                bytes_in: traffic.bytes_in,
+                bytes_out: traffic.bytes_out,
+            })
+            .collect();
+        usage.sort_by(|a, b| a.peer_id.cmp(&b.peer_id));
+        usage
+    }
+
+    /// Flushes pending database writes to disk, call it when the app goes to background.
+    pub fn checkpoint(&self) -> Result<(), ChatError> {
+        self.runtime
+            .block_on(async { self.context.message_db.checkpoint().await })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Checks that the stored files still exist on disk and repairs the ones that don't.
+    pub fn verify_files(&self) -> Result<FileCheck, ChatError> {
+        self.runtime
+            .block_on(async { self.context.file_resolver.verify_files().await })
+            .map(|check| FileCheck {
+                checked: check.checked,
+                missing: check.missing,
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    /// The last protocol frames exchanged with peers, oldest first. Only metadata is
+    /// kept, empty unless tracing is enabled.
+    pub fn recent_protocol_events(&self) -> Vec<ProtocolEvent> {
+        self.context
+            .protocol_recorder
+            .as_ref()
+            .map(|recorder| recorder.recent().into_iter().map(|e| e.into()).collect())
+            .unwrap_or_default()
+    }
+
+    /// Regenerates every indexed message from the message store. Sends no events,
+    /// reload the message lists once it returns.
+    pub fn rebuild_index(&self) -> Result<(), ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.reindex_all().await })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_last_read_order_id(&self, peer_id: String) -> Result<Option<String>, ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.get_read_watermark(&peer_id).await })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn set_notification_pref(
+        &self,
+        peer_id: String,
+        pref: NotificationPref,
+    ) -> Result<(), ChatError> {
+        self.runtime
+            .block_on(async {
+                self.context
+                    .indexer
+                    .set_notification_pref(&peer_id, &pref.into())
+                    .await
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_notification_pref(&self, peer_id: String) -> Result<NotificationPref, ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.get_notification_pref(&peer_id).await })
+            .map(|pref| pref.into())
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_unread_count(&self, peer_id: String) -> Result<u64, ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.count_unread(&peer_id).await })
+            .map_err(ChatError::from_error)
     }
 
     pub fn resolve_file(&self, file_id: String, peer_id: Option<String>) -> Result<(), ChatError> {
@@ -266,16 +1629,42 @@ impl ChatManager {
                     .file_db
                     .get_by_id(&file_id)
                     .await
-                    .map_err(|_| ChatError::FailedToDownload("Failed to get file path".to_string()))
-            })
-            .and_then(|file| {
-                file.ok_or(ChatError::FailedToDownload(
-                    "Failed to get file path".to_string(),
-                ))
+                    .map_err(ChatError::from_error)
             })
+            .and_then(|file| file.ok_or(ChatError::FileNotFound(file_id)))
             .map(|file| file.local_path)
     }
 
+    /// Registers a file to send and returns its id. Registering the same path again
+    /// while its size and modification time are unchanged returns the existing id.
+    pub fn register_file(&self, format: String, file_path: String) -> Result<String, ChatError> {
+        let metadata = std::fs::metadata(std::path::Path::new(&self.root_path).join(&file_path))
+            .map_err(ChatError::from_error)?;
+        let size = metadata.len();
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(std::time::UNIX_EPOCH).ok())
+            .map(|duration| duration.as_secs() as i64)
+            .unwrap_or_default();
+        self.runtime
+            .block_on(async {
+                let file_db = &self.context.file_db;
+                if let Some(id) = file_db.get_by_fingerprint(&file_path, size, mtime).await? {
+                    return Ok(id);
+                }
+                let description = file_database::FileDescription {
+                    id: uuid::Uuid::new_v4().to_string(),
+                    local_path: file_path,
+                    format,
+                    timestamp: self.context.clock.timestamp(),
+                };
+                file_db.save_with_fingerprint(&description, size, mtime).await?;
+                Ok::<_, anyhow::Error>(description.id)
+            })
+            .map_err(ChatError::from_error)
+    }
+
     pub fn set_file_path(
         &self,
         file_id: String,
@@ -288,90 +1677,647 @@ impl ChatManager {
                     id: file_id,
                     local_path: file_path,
                     format,
-                    timestamp: chrono::Utc::now().timestamp(),
+                    timestamp: self.context.clock.timestamp(),
                 };
                 self.context.file_db.save(&description).await
             })
-            .map_err(|e| ChatError::FailedToDownload(format!("failed to set file path {:?}", e)))
+            .map_err(ChatError::failed_to_download)
     }
 
     pub fn send_message(
         &self,
         message: Option<String>,
         file_id: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.send(message, file_id, None, None, HashMap::new(), None)
+    }
+
+    /// Sends a message with app specific entries, e.g. a poll option, that peers
+    /// get back on `Message::metadata` untouched. Keys and values together are
+    /// limited to a few kilobytes.
+    pub fn send_message_with_metadata(
+        &self,
+        message: Option<String>,
+        file_id: Option<String>,
+        metadata: HashMap<String, String>,
+    ) -> Result<(), ChatError> {
+        if models::metadata_size(&metadata) > models::MAX_METADATA_SIZE {
+            return Err(ChatError::invalid_input("metadata is too large"));
+        }
+        self.send(message, file_id, None, None, metadata, None)
+    }
+
+    /// Sends a text with a preview card of a link in it. The crate doesn't fetch
+    /// anything, the app fills in the preview and registers the thumbnail first.
+    pub fn send_message_with_link_preview(
+        &self,
+        message: String,
+        preview: LinkPreview,
+    ) -> Result<(), ChatError> {
+        self.send_own(|builder| {
+            builder.text(message).link_preview(
+                preview.url,
+                preview.title,
+                preview.description,
+                preview.image_file_id,
+            )
+        })
+        .map(|_| ())
+    }
+
+    /// Sends a text or file that deletes itself on every node `seconds` after
+    /// `trigger`, independent of the conversation's `set_message_ttl`.
+    pub fn send_expiring_message(
+        &self,
+        message: Option<String>,
+        file_id: Option<String>,
+        seconds: u64,
+        trigger: ExpiryTrigger,
+    ) -> Result<(), ChatError> {
+        let expiry = models::Expiry {
+            seconds,
+            trigger: trigger.into(),
+        };
+        self.send(message, file_id, None, None, HashMap::new(), Some(expiry))
+    }
+
+    /// Sends a message only `recipient` can read. It is still synced through every
+    /// peer, the others just can't decrypt it.
+    pub fn send_direct_message(
+        &self,
+        recipient: String,
+        message: Option<String>,
+        file_id: Option<String>,
+    ) -> Result<(), ChatError> {
+        self.send(message, file_id, Some(recipient), None, HashMap::new(), None)
+    }
+
+    /// Creates a group with us as its only member and returns its id.
+    pub fn create_group(&self) -> Result<String, ChatError> {
+        let group_id = models::new_group_id(&self.context.peer.id);
+        self.change_group(group_id.clone(), self.context.peer.id.clone(), false)?;
+        Ok(group_id)
+    }
+
+    /// Adds a peer to a group, only its creator and members can. The group's entries
+    /// are synced with members only, so the new member gets the roster and the
+    /// members' messages from us on its next sync, and from the others after that.
+    pub fn add_group_member(&self, group_id: String, peer_id: String) -> Result<(), ChatError> {
+        self.change_group(group_id, peer_id, false)
+    }
+
+    pub fn remove_group_member(&self, group_id: String, peer_id: String) -> Result<(), ChatError> {
+        self.change_group(group_id, peer_id, true)
+    }
+
+    /// Sends a message that only the members of a group receive, we must be one.
+    pub fn send_group_message(
+        &self,
+        group_id: String,
+        message: Option<String>,
+        file_id: Option<String>,
+    ) -> Result<(), ChatError> {
+        if !self.is_group_member(&group_id)? {
+            return Err(ChatError::invalid_input("not a member of the group"));
+        }
+        self.send(message, file_id, None, Some(group_id), HashMap::new(), None)
+    }
+
+    /// Up to `limit` messages of a group following the order `after`, oldest first.
+    pub fn get_group_messages(
+        &self,
+        group_id: String,
+        after: Option<String>,
+        limit: u32,
+    ) -> Result<Vec<Message>, ChatError> {
+        let names = self.names()?;
+        let ctx = self.context.clone();
+        self.runtime
+            .block_on(async {
+                ctx.indexer
+                    .get_for_group(&group_id, after.as_deref(), limit)
+                    .await
+                    .map(|msgs| names.messages(msgs))
+            })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn get_group_members(&self, group_id: String) -> Result<Vec<Peer>, ChatError> {
+        let names = self.names()?;
+        self.runtime
+            .block_on(async { self.context.indexer.get_group_members(&group_id).await })
+            .map(|members| members.into_iter().map(|id| names.peer(id)).collect())
+            .map_err(ChatError::from_error)
+    }
+
+    /// Posts a poll to everyone and returns its id. Needs at least two options.
+    pub fn create_poll(&self, question: String, options: Vec<String>) -> Result<String, ChatError> {
+        if question.trim().is_empty() || options.len() < 2 {
+            return Err(ChatError::invalid_input("a poll needs a question and two options"));
+        }
+        self.send_own(|builder| builder.poll(question, options))
+    }
+
+    /// Votes for the option at index `option`, a later vote replaces the earlier one.
+    pub fn vote(&self, poll_id: String, option: u32) -> Result<(), ChatError> {
+        let poll = self
+            .get_poll(poll_id.clone())?
+            .ok_or_else(|| ChatError::MessageNotFound(poll_id.clone()))?;
+        if option as usize >= poll.options.len() {
+            return Err(ChatError::invalid_input("no such option"));
+        }
+        self.send_own(|builder| builder.vote(poll_id, option)).map(|_| ())
+    }
+
+    pub fn get_poll(&self, poll_id: String) -> Result<Option<Poll>, ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.get_poll(&poll_id).await })
+            .map(|poll| poll.map(|poll| poll.into()))
+            .map_err(ChatError::from_error)
+    }
+
+    /// Groups we are a member of.
+    pub fn get_groups(&self) -> Result<Vec<String>, ChatError> {
+        self.runtime
+            .block_on(async { self.context.indexer.get_groups_of(&self.context.peer.id).await })
+            .map_err(ChatError::from_error)
+    }
+
+    pub fn verify_record(&self, record: &[u8]) -> Result<DnsRecord, ChatError> {
+        let record = decode_txt_record(record).ok_or(ChatError::FailedToDecodeTxtRecord)?;
+        verify_txt_record(&record)
+    }
+    
+    pub fn verify_hashmap_record(&self, record: &HashMap<String, String>) -> Result<DnsRecord, ChatError> {
+        verify_txt_record(record)
+    }
+
+    pub fn get_dns_record(&self) -> Vec<u8> {
+        self.txt_record.clone()
+    }
+    
+    pub fn get_dns_record_map(&self) -> HashMap<String, String> {
+        self.txt_record_map.clone()
+    }
+
+    /// Announces this device over mDNS and looks for peers, e.g. when the user opens
+    /// the "add peer" screen. The first call starts discovery, which then keeps
+    /// announcing in the background, later calls announce again and restart the
+    /// browse. Peers are saved and reported with `Event::PeerDiscovered` as they resolve.
+    pub fn refresh_discovery(self: Arc<Self>) -> Result<(), ChatError> {
+        let events = {
+            let mut guard = self.discovery.lock().unwrap();
+            let discovery = match guard.take() {
+                Some(discovery) => discovery,
+                None => Discovery::start(&self.get_pub_key(), self.txt_record_map.clone(), self.port)
+                    .map_err(ChatError::from_error)?,
+            };
+            let events = discovery.refresh();
+            *guard = Some(discovery);
+            events.map_err(ChatError::from_error)?
+        };
+        thread::spawn(move || discovery::receive(events, |service| self.discovered(service)));
+        Ok(())
+    }
+}
+
+impl ChatManager {
+    /// Like `new`, running on a runtime the app already has instead of creating one.
+    /// The methods block on it, so they must not be called from its tasks.
+    pub fn with_runtime(
+        name: String,
+        root_path: String,
+        port: u16,
+        runtime: Arc<Runtime>,
+    ) -> Result<Self, ChatError> {
+        Self::start(name, root_path, port, default_config(), runtime)
+    }
+
+    fn start(
+        name: String,
+        root_path: String,
+        port: u16,
+        config: Config,
+        runtime: Arc<Runtime>,
+    ) -> Result<Self, ChatError> {
+        // unsafe { std::env::set_var("RUST_LOG", "DEBUG") };
+        // a second manager in the same process keeps the logger of the first
+        let _ = env_logger::try_init();
+        // oslog::OsLogger::new("com.rust")
+        //     .level_filter(uniffi::deps::log::LevelFilter::Debug)
+        //     .init()
+        //     .unwrap();
+        let addr = format!("0.0.0.0:{}", port);
+        let deps = runtime.block_on(async {
+            app_context::prepare_deps(&name, &addr, &root_path, config, runtime.clone())
+                .await
+                .map_err(ChatError::from_error)
+        })?;
+        let name = deps.peer.display_name();
+        let key = deps.signing_key.clone();
+        let map = sign_txt_record(&key, name, port);
+        let txt_record = encode_txt_record(&map)
+            .ok_or(ChatError::invalid_input("the name is too long for a TXT record"))?;
+        let mgr = ChatManager {
+            root_path,
+            context: deps,
+            runtime,
+            signing_key: key,
+            delegate: Arc::new(Mutex::new(None)),
+            txt_record,
+            txt_record_map: map,
+            port,
+            discovery: Mutex::new(None),
+            auto_download: Mutex::new(AutoDownloadPolicy::default()),
+        };
+        Ok(mgr)
+    }
+
+    /// Saves a peer found by discovery once its signed record checks out.
+    fn discovered(&self, service: ResolvedService) {
+        let record = match verify_txt_record(&service.record) {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("ignoring discovered service: {:?}", e);
+                return;
+            }
+        };
+        if record.pub_key == self.get_pub_key() {
+            return;
+        }
+        let Some(addr) = discovery::dial_address(&service.addresses, record.port) else {
+            warn!("no IPv4 address for discovered peer {}", record.name);
+            return;
+        };
+        info!("found peer: {}, {}", record.name, addr);
+        if let Err(e) = self.set_peer(record.name.clone(), addr.clone(), record.pub_key.clone()) {
+            warn!("failed to save discovered peer: {:?}", e);
+            return;
+        }
+        let event = Event::PeerDiscovered {
+            peer_id: record.pub_key,
+            name: record.name,
+            addr,
+        };
+        let guard = self.delegate.lock().unwrap();
+        if let Some(delegate) = &*guard {
+            delegate.on_event(event);
+        }
+    }
+
+    fn send(
+        &self,
+        message: Option<String>,
+        file_id: Option<String>,
+        recipient: Option<String>,
+        group: Option<String>,
+        metadata: HashMap<String, String>,
+        expiry: Option<models::Expiry>,
     ) -> Result<(), ChatError> {
         self.runtime
             .block_on(async {
                 let manager = self.context.sync_engine.get_manager();
-                let builder = models::MessageBuilder::new(
-                    uuid::Uuid::new_v4().to_string(),
-                    chrono::Utc::now().timestamp(),
-                    self.context.peer.id.clone(),
+                let builder = metadata.into_iter().fold(
+                    models::MessageBuilder::new(
+                        uuid::Uuid::new_v4().to_string(),
+                        self.context.clock.timestamp(),
+                        self.context.peer.id.clone(),
+                    ),
+                    |builder, (key, value)| builder.metadata(key, value),
                 );
+                let builder = match expiry {
+                    Some(expiry) => builder.expires(expiry.seconds, expiry.trigger),
+                    None => builder,
+                };
+                let builder = match &group {
+                    Some(group_id) => builder.group(group_id),
+                    None => builder,
+                };
                 let builder = if let Some(msg) = message {
                     builder.text(msg)
                 } else if let Some(file_id) = file_id {
-                    builder.file_id(file_id)
+                    match self.inline_file_data(&file_id).await? {
+                        Some((data, format)) => builder.file_id(file_id).inline_file(data, format),
+                        None => builder.file_id(file_id),
+                    }
                 } else {
                     return Err(anyhow::anyhow!("No message or filename"));
                 };
-                let message = builder.build();
+                let message = match recipient {
+                    Some(recipient) => {
+                        builder.build_direct(&recipient, &self.context.direct_cipher)?
+                    }
+                    None => builder.build(),
+                };
                 manager.add_own_message(message).await
             })
             .map(|_| ())
-            .map_err(|_| ChatError::FailedToSend)
+            .map_err(ChatError::failed_to_send)
     }
 
-    pub fn verify_record(&self, record: &[u8]) -> Result<DnsRecord, ChatError> {
-        let record = decode_txt_record(record).unwrap();
-        self.verify_hashmap_record(&record)
+    /// Stores a message of ours made by `build` and returns its id.
+    fn send_own(
+        &self,
+        build: impl FnOnce(models::MessageBuilder) -> models::MessageBuilder,
+    ) -> Result<String, ChatError> {
+        let id = uuid::Uuid::new_v4().to_string();
+        let message = build(models::MessageBuilder::new(
+            id.clone(),
+            self.context.clock.timestamp(),
+            self.context.peer.id.clone(),
+        ))
+        .build();
+        self.runtime
+            .block_on(async {
+                self.context
+                    .sync_engine
+                    .get_manager()
+                    .add_own_message(message)
+                    .await
+            })
+            .map(|_| id)
+            .map_err(ChatError::failed_to_send)
     }
-    
-    pub fn verify_hashmap_record(&self, record: &HashMap<String, String>) -> Result<DnsRecord, ChatError> {
-        let signature = record
-            .get("signature")
-            .ok_or(ChatError::FailedToDecodeTxtRecord)?;
-        let name = record
-            .get("name")
-            .ok_or(ChatError::FailedToDecodeTxtRecord)?;
-        let port = record
-            .get("port")
-            .ok_or(ChatError::FailedToDecodeTxtRecord)?;
-        let pub_key = record
-            .get("pub_key")
-            .ok_or(ChatError::FailedToDecodeTxtRecord)?;
-        let signature_bytes = hex::decode(signature)
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?
-            .try_into()
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
-        let signature = Signature::from_bytes(&signature_bytes);
-        let pub_key_bytes = hex::decode(pub_key)
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?
-            .try_into()
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
-        let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&pub_key_bytes)
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
-        verifying_key
-            .verify(name.as_bytes(), &signature)
-            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
-        Ok(DnsRecord {
-            port: port
-                .parse()
-                .map_err(|_| ChatError::FailedToDecodeTxtRecord)?,
-            name: name.clone(),
-            pub_key: pub_key.clone(),
+
+    /// Whether we are a member of the group. Its creator counts as one from the start,
+    /// before its own entry is indexed.
+    fn is_group_member(&self, group_id: &str) -> Result<bool, ChatError> {
+        let own_id = &self.context.peer.id;
+        if models::group_creator(group_id) == Some(own_id.as_str()) {
+            return Ok(true);
+        }
+        self.runtime
+            .block_on(async { self.context.indexer.is_group_member(group_id, own_id).await })
+            .map_err(ChatError::from_error)
+    }
+
+    /// Peers drop changes by anyone but the creator and members, so we don't send them.
+    /// A removal is shown in the group as a `SystemKind::Left` message naming the member.
+    fn change_group(&self, group_id: String, member: String, removed: bool) -> Result<(), ChatError> {
+        if !self.is_group_member(&group_id)? {
+            return Err(ChatError::invalid_input("only members can change a group"));
+        }
+        let left = removed.then(|| self.get_display_name(member.clone()));
+        self.runtime
+            .block_on(async {
+                let mut builder = models::MessageBuilder::new(
+                    uuid::Uuid::new_v4().to_string(),
+                    self.context.clock.timestamp(),
+                    self.context.peer.id.clone(),
+                )
+                .group_change(group_id, member, removed);
+                if let Some(name) = left {
+                    builder = builder.system(models::SystemKind::Left, name);
+                }
+                let message = builder.build();
+                self.context
+                    .sync_engine
+                    .get_manager()
+                    .add_own_message(message)
+                    .await
+            })
+            .map(|_| ())
+            .map_err(ChatError::failed_to_send)
+    }
+
+    /// The `Notification` event for a freshly indexed message, if its conversation's
+    /// preferences allow one.
+    fn notification(&self, msg: &models::IndexedMessage, display_name: &str) -> Option<Event> {
+        let pref = self
+            .runtime
+            .block_on(async { self.context.indexer.get_notification_pref(&msg.peer_id).await });
+        let pref = match pref {
+            Ok(pref) => pref,
+            Err(e) => {
+                warn!("failed to load notification preferences: {:?}", e);
+                return None;
+            }
+        };
+        if !pref.allows(msg, &self.context.peer.id, self.context.clock.timestamp()) {
+            return None;
+        }
+        Some(Event::Notification {
+            message_id: msg.id.clone(),
+            peer_id: msg.peer_id.clone(),
+            display_name: display_name.to_owned(),
+            importance: pref.importance.into(),
         })
     }
 
-    pub fn get_dns_record(&self) -> Vec<u8> {
-        self.txt_record.clone()
+    fn set_verified(&self, peer_id: &str, verified: bool) -> Result<(), ChatError> {
+        let known = self
+            .runtime
+            .block_on(async { self.context.peer_db.set_verified(peer_id, verified).await })
+            .map_err(ChatError::from_error)?;
+        if !known {
+            return Err(ChatError::PeerNotFound(peer_id.to_owned()));
+        }
+        Ok(())
     }
-    
-    pub fn get_dns_record_map(&self) -> HashMap<String, String> {
-        self.txt_record_map.clone()
+
+    fn stored_peer(&self, peer_id: &str) -> Result<peer_database::Peer, ChatError> {
+        self.runtime
+            .block_on(async { self.context.peer_db.get_peer_by_id(peer_id).await })
+            .map_err(ChatError::from_error)?
+            .ok_or_else(|| ChatError::PeerNotFound(peer_id.to_owned()))
+    }
+
+    /// Downloads a received file if the `AutoDownloadPolicy` allows it for the sender,
+    /// returns the prompt to deliver after the message when it asks the user first.
+    fn auto_download(&self, file_id: String, peer_id: String, display_name: &str) -> Option<Event> {
+        let peer = self
+            .runtime
+            .block_on(async { self.context.peer_db.get_peer_by_id(&peer_id).await });
+        let action = match peer {
+            Ok(peer) => self.auto_download.lock().unwrap().action(peer.as_ref()),
+            Err(e) => {
+                warn!("failed to load peer {}: {:?}", peer_id, e);
+                return None;
+            }
+        };
+        match action {
+            DownloadAction::Auto => {
+                if let Err(e) = self.resolve_file(file_id, Some(peer_id)) {
+                    warn!("failed to resolve file: {:?}", e);
+                }
+                None
+            }
+            DownloadAction::Prompt => Some(Event::FileDownloadPrompt {
+                file_id,
+                peer_id,
+                display_name: display_name.to_owned(),
+            }),
+            DownloadAction::Never => None,
+        }
+    }
+
+    /// Display names of all known peers, resolved once for a page of messages.
+    fn names(&self) -> Result<Names, ChatError> {
+        let peers = self
+            .runtime
+            .block_on(async { self.context.peer_db.get_all_peers().await })
+            .map_err(ChatError::from_error)?;
+        let verified: HashSet<String> = peers
+            .iter()
+            .filter(|peer| peer.verified)
+            .map(|peer| peer.id.clone())
+            .collect();
+        let avatars = peers
+            .iter()
+            .filter_map(|peer| {
+                let file_id = peer.avatar_file_id.clone()?;
+                Some((peer.id.clone(), (file_id, peer.avatar_path.clone())))
+            })
+            .collect();
+        let last_seen = peers
+            .iter()
+            .filter_map(|peer| Some((peer.id.clone(), peer.last_seen?.timestamp())))
+            .collect();
+        let mut names: HashMap<String, String> = peers
+            .into_iter()
+            .map(|peer| (peer.id.clone(), peer.display_name()))
+            .collect();
+        names.insert(self.context.peer.id.clone(), self.get_name());
+        Ok(Names {
+            own_id: self.context.peer.id.clone(),
+            names,
+            verified,
+            avatars,
+            last_seen,
+        })
+    }
+
+    /// Contents and format of a registered file small enough to send inline.
+    async fn inline_file_data(&self, file_id: &str) -> anyhow::Result<Option<(Vec<u8>, String)>> {
+        let file = match self.context.file_db.get_by_id(file_id).await? {
+            Some(file) => file,
+            None => return Ok(None),
+        };
+        let path = std::path::Path::new(&self.root_path).join(&file.local_path);
+        if std::fs::metadata(&path)?.len() > self.context.inline_file_limit {
+            return Ok(None);
+        }
+        Ok(Some((std::fs::read(&path)?, file.format)))
+    }
+
+    /// Known peers that accepted one of our messages, out of those it is synced to.
+    async fn delivery_status(&self, message_id: &str) -> anyhow::Result<DeliveryStatus> {
+        let recipients: Vec<String> = self
+            .context
+            .peer_db
+            .get_all_peers()
+            .await?
+            .into_iter()
+            .filter(|peer| peer.signing_key.is_none())
+            .map(|peer| peer.id)
+            .collect();
+        let delivered_to = self
+            .context
+            .sync_engine
+            .get_outbox()
+            .delivered_to(message_id)
+            .await?
+            .into_iter()
+            .filter(|peer_id| recipients.contains(peer_id))
+            .collect();
+        Ok(DeliveryStatus {
+            delivered_to,
+            recipients: recipients.len() as u64,
+        })
+    }
+}
+
+struct Names {
+    own_id: String,
+    names: HashMap<String, String>,
+    verified: HashSet<String>,
+    /// Avatar file id and path of the peers that have one.
+    avatars: HashMap<String, (String, Option<String>)>,
+    last_seen: HashMap<String, i64>,
+}
+
+impl Names {
+    fn display_name(&self, peer_id: &str) -> String {
+        self.names
+            .get(peer_id)
+            .cloned()
+            .unwrap_or_else(|| peer_database::short_id(peer_id))
+    }
+
+    fn message(&self, msg: models::IndexedMessage) -> Message {
+        let display_name = self.display_name(&msg.peer_id);
+        Message::new(msg, display_name, &self.own_id)
+    }
+
+    fn peer(&self, id: String) -> Peer {
+        let (avatar_file_id, avatar_path) = match self.avatars.get(&id) {
+            Some((file_id, path)) => (Some(file_id.clone()), path.clone()),
+            None => (None, None),
+        };
+        Peer {
+            name: self.display_name(&id),
+            verified: self.verified.contains(&id),
+            avatar_file_id,
+            avatar_path,
+            last_seen: self.last_seen.get(&id).copied(),
+            id,
+        }
+    }
+
+    fn messages(&self, msgs: Vec<models::IndexedMessage>) -> Vec<Message> {
+        msgs.into_iter().map(|msg| self.message(msg)).collect()
     }
 }
 
+fn sign_txt_record(key: &SigningKey, name: String, port: u16) -> HashMap<String, String> {
+    let mut map = HashMap::new();
+    let signature = key.sign(name.as_bytes());
+    map.insert("signature".to_string(), hex::encode(signature.to_bytes()));
+    map.insert("port".to_string(), port.to_string());
+    map.insert("name".to_string(), name);
+    map.insert(
+        "pub_key".to_string(),
+        hex::encode(key.verifying_key().to_bytes()),
+    );
+    map
+}
+
+fn verify_txt_record(record: &HashMap<String, String>) -> Result<DnsRecord, ChatError> {
+    let signature = record
+        .get("signature")
+        .ok_or(ChatError::FailedToDecodeTxtRecord)?;
+    let name = record
+        .get("name")
+        .ok_or(ChatError::FailedToDecodeTxtRecord)?;
+    let port = record
+        .get("port")
+        .ok_or(ChatError::FailedToDecodeTxtRecord)?;
+    let pub_key = record
+        .get("pub_key")
+        .ok_or(ChatError::FailedToDecodeTxtRecord)?;
+    let signature_bytes = hex::decode(signature)
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?
+        .try_into()
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
+    let signature = Signature::from_bytes(&signature_bytes);
+    let pub_key_bytes = hex::decode(pub_key)
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?
+        .try_into()
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
+    let verifying_key = ed25519_dalek::VerifyingKey::from_bytes(&pub_key_bytes)
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
+    verifying_key
+        .verify(name.as_bytes(), &signature)
+        .map_err(|_| ChatError::FailedToDecodeTxtRecord)?;
+    Ok(DnsRecord {
+        port: port
+            .parse()
+            .map_err(|_| ChatError::FailedToDecodeTxtRecord)?,
+        name: name.clone(),
+        pub_key: pub_key.clone(),
+    })
+}
+
 fn encode_txt_record(txt_record: &HashMap<String, String>) -> Option<Vec<u8>> {
     let mut result = Vec::new();
     for (key, value) in txt_record {
@@ -414,3 +2360,156 @@ fn decode_txt_record(data: &[u8]) -> Option<HashMap<String, String>> {
     }
     Some(result)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fresh_root() -> String {
+        let root = std::env::temp_dir().join(uuid::Uuid::new_v4().to_string());
+        std::fs::create_dir_all(&root).unwrap();
+        root.to_string_lossy().into_owned()
+    }
+
+    /// A manager on a fresh root folder.
+    fn manager(name: &str) -> ChatManager {
+        ChatManager::new(name.to_string(), fresh_root(), 0).unwrap()
+    }
+
+    fn assert_same_record(record: &DnsRecord, mgr: &ChatManager) {
+        assert_eq!(record.name, "alice");
+        assert_eq!(record.port, 0);
+        assert_eq!(record.pub_key, mgr.get_pub_key());
+    }
+
+    #[test]
+    fn own_record_verifies_as_bytes_and_as_map() {
+        let mgr = manager("alice");
+        let from_bytes = mgr.verify_record(&mgr.get_dns_record()).unwrap();
+        let from_map = mgr
+            .verify_hashmap_record(&mgr.get_dns_record_map())
+            .unwrap();
+        assert_same_record(&from_bytes, &mgr);
+        assert_same_record(&from_map, &mgr);
+        // the bytes decode to the map that is announced
+        assert_eq!(
+            decode_txt_record(&mgr.get_dns_record()),
+            Some(mgr.get_dns_record_map())
+        );
+        let encoded = encode_txt_record(&mgr.get_dns_record_map()).unwrap();
+        assert_same_record(&mgr.verify_record(&encoded).unwrap(), &mgr);
+        mgr.shutdown();
+    }
+
+    #[test]
+    fn manager_runs_on_the_apps_runtime() {
+        let runtime = Arc::new(
+            RuntimeOptions {
+                worker_threads: Some(2),
+                ..RuntimeOptions::default()
+            }
+            .build()
+            .unwrap(),
+        );
+        let mgr = ChatManager::with_runtime("alice".to_string(), fresh_root(), 0, runtime.clone())
+            .unwrap();
+        assert!(Arc::ptr_eq(&mgr.runtime, &runtime));
+        assert!(mgr.get_conversations().unwrap().is_empty());
+        mgr.shutdown();
+        drop(mgr);
+        // the app's runtime outlives the manager
+        assert_eq!(runtime.block_on(runtime.spawn(async { 1 })).unwrap(), 1);
+    }
+
+    #[test]
+    fn malformed_records_fail_to_decode() {
+        let mgr = manager("alice");
+        let bytes = mgr.get_dns_record();
+        let truncated = &bytes[..bytes.len() - 1];
+        let no_separator = [3, b'a', b'b', b'c'];
+        let not_utf8 = [3, b'a', b'=', 0xff];
+        for record in [truncated, &no_separator[..], &not_utf8[..]] {
+            let err = mgr.verify_record(record).unwrap_err();
+            assert!(matches!(err, ChatError::FailedToDecodeTxtRecord));
+        }
+
+        let mut renamed = mgr.get_dns_record_map();
+        renamed.insert("name".to_string(), "mallory".to_string());
+        let mut unsigned = mgr.get_dns_record_map();
+        unsigned.remove("signature");
+        for record in [renamed, unsigned] {
+            let err = mgr.verify_hashmap_record(&record).unwrap_err();
+            assert!(matches!(err, ChatError::FailedToDecodeTxtRecord));
+            let err = mgr
+                .verify_record(&encode_txt_record(&record).unwrap())
+                .unwrap_err();
+            assert!(matches!(err, ChatError::FailedToDecodeTxtRecord));
+        }
+        mgr.shutdown();
+    }
+
+    #[test]
+    fn only_files_within_the_limit_are_sent_inline() {
+        let mgr = manager("alice");
+        let limit = mgr.context.inline_file_limit as usize;
+        let mut ids = Vec::new();
+        for (name, size) in [("small.bin", limit), ("large.bin", limit + 1)] {
+            let path = std::path::Path::new(&mgr.root_path).join(name);
+            std::fs::write(path, vec![1u8; size]).unwrap();
+            let id = mgr.register_file("bin".to_string(), name.to_string());
+            ids.push(id.unwrap());
+        }
+
+        let small = mgr.runtime.block_on(mgr.inline_file_data(&ids[0])).unwrap();
+        assert_eq!(small, Some((vec![1u8; limit], "bin".to_string())));
+        let large = mgr.runtime.block_on(mgr.inline_file_data(&ids[1])).unwrap();
+        assert_eq!(large, None);
+        mgr.shutdown();
+    }
+
+    #[test]
+    fn downloads_follow_the_senders_trust() {
+        let mgr = manager("alice");
+        let key = |seed: u8| {
+            let key = SigningKey::from_bytes(&[seed; 32]).verifying_key();
+            hex::encode(key.to_bytes())
+        };
+        let (bob, carol, dave) = (key(1), key(2), key(3));
+        for (name, pub_key) in [("bob", &bob), ("carol", &carol)] {
+            let (name, addr) = (name.to_string(), "127.0.0.1:1".to_string());
+            mgr.set_peer(name, addr, pub_key.clone()).unwrap();
+        }
+        mgr.mark_verified(carol.clone()).unwrap();
+        let prompted = |peer_id: &str| {
+            let event = mgr.auto_download("file".to_string(), peer_id.to_string(), "name");
+            match event {
+                Some(Event::FileDownloadPrompt { peer_id: asked, .. }) => asked == peer_id,
+                _ => false,
+            }
+        };
+        let action = |peer_id: &str| {
+            let peer_db = &mgr.context.peer_db;
+            let peer = mgr.runtime.block_on(peer_db.get_peer_by_id(peer_id));
+            let policy = mgr.get_auto_download_policy();
+            policy.action(peer.unwrap().as_ref())
+        };
+
+        // verified carol is downloaded, bob asked about, dave unknown and ignored
+        assert_eq!(action(&carol), DownloadAction::Auto);
+        assert_eq!(action(&bob), DownloadAction::Prompt);
+        assert_eq!(action(&dave), DownloadAction::Never);
+        assert!(!prompted(&carol));
+        assert!(prompted(&bob));
+        assert!(!prompted(&dave));
+
+        mgr.set_auto_download_policy(AutoDownloadPolicy {
+            verified: DownloadAction::Prompt,
+            unverified: DownloadAction::Never,
+            unknown: DownloadAction::Prompt,
+        });
+        assert!(prompted(&carol));
+        assert!(!prompted(&bob));
+        assert!(prompted(&dave));
+        mgr.shutdown();
+    }
+}